@@ -0,0 +1,329 @@
+// libretro のコアを読み込んで動かす最小限のフロントエンド (動作確認用)。
+// 指定したフレーム数だけ実行し、セーブステートを保存/読み込みして同じ画像と音になるか確かめる
+//
+//   cargo build --release -p ps1_boot_libretro
+//   cargo run --release -p ps1_boot_libretro --example retro_test -- target/release/libps1_boot_libretro.so game.exe
+//
+// オプション:
+//   --frames <n>             実行するフレーム数 (既定 300)
+//   --system <dir>           BIOS を探すディレクトリ (なければ HLE BIOS)
+//   --option <key>=<value>   コアオプション (ps1_boot_resolution_scale=2x など)
+//   --press <button>@<frame> そのフレームからボタンを押す (start, cross など)
+//   --screenshot <path>      最後の画像を PPM で書き出す
+use std::{
+  ffi::{c_char, c_int, c_uint, c_void, CStr, CString},
+  fs::File,
+  io::{BufWriter, Write},
+  sync::Mutex,
+};
+
+use libloading::{Library, Symbol};
+
+const RETRO_ENVIRONMENT_GET_SYSTEM_DIRECTORY: c_uint = 9;
+const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+const RETRO_ENVIRONMENT_GET_VARIABLE: c_uint = 15;
+const RETRO_ENVIRONMENT_SET_VARIABLES: c_uint = 16;
+const RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE: c_uint = 17;
+const RETRO_ENVIRONMENT_SET_SYSTEM_AV_INFO: c_uint = 32;
+const RETRO_ENVIRONMENT_SET_GEOMETRY: c_uint = 37;
+const RETRO_PIXEL_FORMAT_XRGB8888: c_int = 1;
+const RETRO_DEVICE_JOYPAD: c_uint = 1;
+
+// RetroPad のボタン名と ID (B が ×、A が ○)
+const BUTTONS: [(&str, c_uint); 14] = [
+  ("cross", 0), ("square", 1), ("select", 2), ("start", 3),
+  ("up", 4), ("down", 5), ("left", 6), ("right", 7),
+  ("circle", 8), ("triangle", 9), ("l1", 10), ("r1", 11), ("l2", 12), ("r2", 13),
+];
+
+#[repr(C)]
+struct RetroSystemInfo {
+  library_name: *const c_char,
+  library_version: *const c_char,
+  valid_extensions: *const c_char,
+  need_fullpath: bool,
+  block_extract: bool,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct RetroSystemAvInfo {
+  base_width: c_uint,
+  base_height: c_uint,
+  max_width: c_uint,
+  max_height: c_uint,
+  aspect_ratio: f32,
+  fps: f64,
+  sample_rate: f64,
+}
+
+#[repr(C)]
+struct RetroGameInfo {
+  path: *const c_char,
+  data: *const c_void,
+  size: usize,
+  meta: *const c_char,
+}
+
+#[repr(C)]
+struct RetroVariable {
+  key: *const c_char,
+  value: *const c_char,
+}
+
+// コールバックには引数で状態を渡せないのでグローバルに置く
+struct State {
+  system_dir: Option<CString>,
+  options: Vec<(CString, CString)>,
+  // 押し始めるフレームとボタンの ID
+  presses: Vec<(u64, c_uint)>,
+  frame: u64,
+  // 最後の画像 (幅, 高さ, ピクセル)
+  image: Option<(u32, u32, Vec<u32>)>,
+  audio: Vec<i16>,
+  geometry_changes: u32,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+  system_dir: None,
+  options: Vec::new(),
+  presses: Vec::new(),
+  frame: 0,
+  image: None,
+  audio: Vec::new(),
+  geometry_changes: 0,
+});
+
+fn state() -> std::sync::MutexGuard<'static, State> {
+  STATE.lock().unwrap()
+}
+
+unsafe extern "C" fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+  match cmd {
+    RETRO_ENVIRONMENT_GET_SYSTEM_DIRECTORY => match &state().system_dir {
+      Some(dir) => {
+        *(data as *mut *const c_char) = dir.as_ptr();
+        true
+      }
+      None => false,
+    },
+    RETRO_ENVIRONMENT_SET_PIXEL_FORMAT => *(data as *const c_int) == RETRO_PIXEL_FORMAT_XRGB8888,
+    RETRO_ENVIRONMENT_SET_VARIABLES => {
+      let mut variable = data as *const RetroVariable;
+      while !(*variable).key.is_null() {
+        println!("option {}: {}", CStr::from_ptr((*variable).key).to_string_lossy(), CStr::from_ptr((*variable).value).to_string_lossy());
+        variable = variable.add(1);
+      }
+      true
+    }
+    RETRO_ENVIRONMENT_GET_VARIABLE => {
+      let variable = &mut *(data as *mut RetroVariable);
+      let key = CStr::from_ptr(variable.key);
+      // 指定のないオプションは既定値 (コアが決める)
+      match state().options.iter().find(|(k, _)| k.as_c_str() == key) {
+        Some((_, value)) => {
+          variable.value = value.as_ptr();
+          true
+        }
+        None => false,
+      }
+    }
+    RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE => {
+      *(data as *mut bool) = false;
+      true
+    }
+    RETRO_ENVIRONMENT_SET_SYSTEM_AV_INFO | RETRO_ENVIRONMENT_SET_GEOMETRY => {
+      state().geometry_changes += 1;
+      true
+    }
+    _ => false,
+  }
+}
+
+unsafe extern "C" fn video_refresh(data: *const c_void, width: c_uint, height: c_uint, pitch: usize) {
+  // NULL は前のフレームのまま
+  if data.is_null() {
+    return;
+  }
+  let mut pixels = Vec::with_capacity(width as usize * height as usize);
+  for y in 0..height as usize {
+    let line = (data as *const u8).add(y * pitch) as *const u32;
+    pixels.extend_from_slice(std::slice::from_raw_parts(line, width as usize));
+  }
+  state().image = Some((width, height, pixels));
+}
+
+unsafe extern "C" fn audio_sample(left: i16, right: i16) {
+  state().audio.extend_from_slice(&[left, right]);
+}
+
+unsafe extern "C" fn audio_sample_batch(data: *const i16, frames: usize) -> usize {
+  state().audio.extend_from_slice(std::slice::from_raw_parts(data, frames * 2));
+  frames
+}
+
+unsafe extern "C" fn input_poll() {}
+
+unsafe extern "C" fn input_state(port: c_uint, device: c_uint, _index: c_uint, id: c_uint) -> i16 {
+  let state = state();
+  let pressed = port == 0 && device == RETRO_DEVICE_JOYPAD
+      && state.presses.iter().any(|&(frame, button)| button == id && state.frame >= frame);
+  pressed as i16
+}
+
+// 画像と音のハッシュ (FNV-1a)
+fn hash(image: &Option<(u32, u32, Vec<u32>)>, audio: &[i16]) -> u64 {
+  let mut hash = 0xCBF2_9CE4_8422_2325u64;
+  let mut add = |bytes: &[u8]| {
+    for &b in bytes {
+      hash = (hash ^ b as u64).wrapping_mul(0x100_0000_01B3);
+    }
+  };
+  if let Some((width, height, pixels)) = image {
+    add(&width.to_le_bytes());
+    add(&height.to_le_bytes());
+    for pixel in pixels {
+      add(&pixel.to_le_bytes());
+    }
+  }
+  for sample in audio {
+    add(&sample.to_le_bytes());
+  }
+  hash
+}
+
+fn write_ppm(path: &str, width: u32, height: u32, pixels: &[u32]) -> std::io::Result<()> {
+  let mut out = BufWriter::new(File::create(path)?);
+  write!(out, "P6\n{} {}\n255\n", width, height)?;
+  for pixel in pixels {
+    out.write_all(&[(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8])?;
+  }
+  Ok(())
+}
+
+fn main() {
+  let mut args = std::env::args().skip(1);
+  let core_path = args.next().expect("Usage: retro_test <core> <game> [options]");
+  let game_path = args.next().expect("Usage: retro_test <core> <game> [options]");
+  let mut frames = 300u64;
+  let mut screenshot = None;
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--frames" => frames = args.next().expect("--frames requires a count").parse().expect("Invalid frame count"),
+      "--system" => state().system_dir = Some(CString::new(args.next().expect("--system requires a directory")).unwrap()),
+      "--option" => {
+        let option = args.next().expect("--option requires key=value");
+        let (key, value) = option.split_once('=').expect("--option requires key=value");
+        state().options.push((CString::new(key).unwrap(), CString::new(value).unwrap()));
+      }
+      "--press" => {
+        let press = args.next().expect("--press requires button@frame");
+        let (name, frame) = press.split_once('@').expect("--press requires button@frame");
+        let id = BUTTONS.iter().find(|(n, _)| *n == name).unwrap_or_else(|| panic!("Unknown button: {}", name)).1;
+        state().presses.push((frame.parse().expect("Invalid frame"), id));
+      }
+      "--screenshot" => screenshot = Some(args.next().expect("--screenshot requires a path")),
+      _ => panic!("Unknown argument: {}", arg),
+    }
+  }
+
+  unsafe {
+    let core = Library::new(&core_path).unwrap();
+    macro_rules! sym {
+      ($name:ident: $type:ty) => {
+        let $name: Symbol<$type> = core.get(concat!(stringify!($name), "\0").as_bytes()).unwrap();
+      };
+    }
+    sym!(retro_api_version: unsafe extern "C" fn() -> c_uint);
+    sym!(retro_get_system_info: unsafe extern "C" fn(*mut RetroSystemInfo));
+    sym!(retro_get_system_av_info: unsafe extern "C" fn(*mut RetroSystemAvInfo));
+    sym!(retro_set_environment: unsafe extern "C" fn(unsafe extern "C" fn(c_uint, *mut c_void) -> bool));
+    sym!(retro_set_video_refresh: unsafe extern "C" fn(unsafe extern "C" fn(*const c_void, c_uint, c_uint, usize)));
+    sym!(retro_set_audio_sample: unsafe extern "C" fn(unsafe extern "C" fn(i16, i16)));
+    sym!(retro_set_audio_sample_batch: unsafe extern "C" fn(unsafe extern "C" fn(*const i16, usize) -> usize));
+    sym!(retro_set_input_poll: unsafe extern "C" fn(unsafe extern "C" fn()));
+    sym!(retro_set_input_state: unsafe extern "C" fn(unsafe extern "C" fn(c_uint, c_uint, c_uint, c_uint) -> i16));
+    sym!(retro_set_controller_port_device: unsafe extern "C" fn(c_uint, c_uint));
+    sym!(retro_init: unsafe extern "C" fn());
+    sym!(retro_deinit: unsafe extern "C" fn());
+    sym!(retro_load_game: unsafe extern "C" fn(*const RetroGameInfo) -> bool);
+    sym!(retro_unload_game: unsafe extern "C" fn());
+    sym!(retro_run: unsafe extern "C" fn());
+    sym!(retro_serialize_size: unsafe extern "C" fn() -> usize);
+    sym!(retro_serialize: unsafe extern "C" fn(*mut c_void, usize) -> bool);
+    sym!(retro_unserialize: unsafe extern "C" fn(*const c_void, usize) -> bool);
+
+    let mut info = RetroSystemInfo { library_name: std::ptr::null(), library_version: std::ptr::null(), valid_extensions: std::ptr::null(), need_fullpath: false, block_extract: false };
+    retro_get_system_info(&mut info);
+    println!("{} {} (API {}, extensions {})",
+        CStr::from_ptr(info.library_name).to_string_lossy(),
+        CStr::from_ptr(info.library_version).to_string_lossy(),
+        retro_api_version(),
+        CStr::from_ptr(info.valid_extensions).to_string_lossy());
+
+    retro_set_environment(environment);
+    retro_set_video_refresh(video_refresh);
+    retro_set_audio_sample(audio_sample);
+    retro_set_audio_sample_batch(audio_sample_batch);
+    retro_set_input_poll(input_poll);
+    retro_set_input_state(input_state);
+    retro_init();
+
+    let path = CString::new(game_path.clone()).unwrap();
+    let game = RetroGameInfo { path: path.as_ptr(), data: std::ptr::null(), size: 0, meta: std::ptr::null() };
+    if !retro_load_game(&game) {
+      panic!("Failed to load {}", game_path);
+    }
+    retro_set_controller_port_device(0, RETRO_DEVICE_JOYPAD);
+    let mut av_info = RetroSystemAvInfo::default();
+    retro_get_system_av_info(&mut av_info);
+    println!("{}x{} (max {}x{}), {:.2} fps, {} Hz",
+        av_info.base_width, av_info.base_height, av_info.max_width, av_info.max_height, av_info.fps, av_info.sample_rate);
+
+    let run = |count: u64| {
+      for _ in 0..count {
+        retro_run();
+        state().frame += 1;
+      }
+    };
+    run(frames);
+
+    // 保存してから 60 フレーム進めた結果と、読み込み直して 60 フレーム進めた結果を比べる
+    let mut buf = vec![0u8; retro_serialize_size()];
+    assert!(retro_serialize(buf.as_mut_ptr() as *mut c_void, buf.len()), "retro_serialize failed");
+    let frame = state().frame;
+    state().audio.clear();
+    run(60);
+    let expected = {
+      let state = state();
+      hash(&state.image, &state.audio)
+    };
+    assert!(retro_unserialize(buf.as_ptr() as *const c_void, buf.len()), "retro_unserialize failed");
+    state().frame = frame;
+    state().audio.clear();
+    run(60);
+    let actual = {
+      let state = state();
+      hash(&state.image, &state.audio)
+    };
+
+    let state = state();
+    match &state.image {
+      Some((width, height, _)) => println!("{} frames, last image {}x{}, {} geometry changes", state.frame, width, height, state.geometry_changes),
+      None => println!("{} frames, no image", state.frame),
+    }
+    println!("savestate: {} bytes, {}", buf.len(), if actual == expected { "replay matches" } else { "replay DIFFERS" });
+    if let (Some(path), Some((width, height, pixels))) = (&screenshot, &state.image) {
+      write_ppm(path, *width, *height, pixels).unwrap();
+      println!("wrote {}", path);
+    }
+    let matches = actual == expected;
+    drop(state);
+
+    retro_unload_game();
+    retro_deinit();
+    if !matches {
+      std::process::exit(1);
+    }
+  }
+}
@@ -0,0 +1,101 @@
+// libretro.h のうちこのコアが使う部分
+use std::ffi::{c_char, c_int, c_uint, c_void};
+
+pub const RETRO_API_VERSION: c_uint = 1;
+
+pub const RETRO_ENVIRONMENT_SHUTDOWN: c_uint = 7;
+pub const RETRO_ENVIRONMENT_GET_SYSTEM_DIRECTORY: c_uint = 9;
+pub const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+pub const RETRO_ENVIRONMENT_GET_VARIABLE: c_uint = 15;
+pub const RETRO_ENVIRONMENT_SET_VARIABLES: c_uint = 16;
+pub const RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE: c_uint = 17;
+pub const RETRO_ENVIRONMENT_GET_LOG_INTERFACE: c_uint = 27;
+pub const RETRO_ENVIRONMENT_SET_SYSTEM_AV_INFO: c_uint = 32;
+pub const RETRO_ENVIRONMENT_SET_GEOMETRY: c_uint = 37;
+
+// enum retro_log_level
+pub const RETRO_LOG_INFO: c_int = 1;
+pub const RETRO_LOG_WARN: c_int = 2;
+pub const RETRO_LOG_ERROR: c_int = 3;
+
+// enum retro_pixel_format
+pub const RETRO_PIXEL_FORMAT_XRGB8888: c_int = 1;
+
+pub const RETRO_DEVICE_NONE: c_uint = 0;
+pub const RETRO_DEVICE_JOYPAD: c_uint = 1;
+
+pub const RETRO_DEVICE_ID_JOYPAD_B: c_uint = 0;
+pub const RETRO_DEVICE_ID_JOYPAD_Y: c_uint = 1;
+pub const RETRO_DEVICE_ID_JOYPAD_SELECT: c_uint = 2;
+pub const RETRO_DEVICE_ID_JOYPAD_START: c_uint = 3;
+pub const RETRO_DEVICE_ID_JOYPAD_UP: c_uint = 4;
+pub const RETRO_DEVICE_ID_JOYPAD_DOWN: c_uint = 5;
+pub const RETRO_DEVICE_ID_JOYPAD_LEFT: c_uint = 6;
+pub const RETRO_DEVICE_ID_JOYPAD_RIGHT: c_uint = 7;
+pub const RETRO_DEVICE_ID_JOYPAD_A: c_uint = 8;
+pub const RETRO_DEVICE_ID_JOYPAD_X: c_uint = 9;
+pub const RETRO_DEVICE_ID_JOYPAD_L: c_uint = 10;
+pub const RETRO_DEVICE_ID_JOYPAD_R: c_uint = 11;
+pub const RETRO_DEVICE_ID_JOYPAD_L2: c_uint = 12;
+pub const RETRO_DEVICE_ID_JOYPAD_R2: c_uint = 13;
+
+pub const RETRO_REGION_NTSC: c_uint = 0;
+
+pub type EnvironmentFn = unsafe extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+pub type VideoRefreshFn = unsafe extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+pub type AudioSampleFn = unsafe extern "C" fn(left: i16, right: i16);
+pub type AudioSampleBatchFn = unsafe extern "C" fn(data: *const i16, frames: usize) -> usize;
+pub type InputPollFn = unsafe extern "C" fn();
+pub type InputStateFn = unsafe extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+// printf と同じ書式を取る
+pub type LogPrintfFn = unsafe extern "C" fn(level: c_int, fmt: *const c_char, ...);
+
+#[repr(C)]
+pub struct RetroSystemInfo {
+  pub library_name: *const c_char,
+  pub library_version: *const c_char,
+  pub valid_extensions: *const c_char,
+  pub need_fullpath: bool,
+  pub block_extract: bool,
+}
+
+#[repr(C)]
+pub struct RetroGameGeometry {
+  pub base_width: c_uint,
+  pub base_height: c_uint,
+  pub max_width: c_uint,
+  pub max_height: c_uint,
+  // 0 のときは base_width / base_height
+  pub aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct RetroSystemTiming {
+  pub fps: f64,
+  pub sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct RetroSystemAvInfo {
+  pub geometry: RetroGameGeometry,
+  pub timing: RetroSystemTiming,
+}
+
+#[repr(C)]
+pub struct RetroGameInfo {
+  pub path: *const c_char,
+  pub data: *const c_void,
+  pub size: usize,
+  pub meta: *const c_char,
+}
+
+#[repr(C)]
+pub struct RetroVariable {
+  pub key: *const c_char,
+  pub value: *const c_char,
+}
+
+#[repr(C)]
+pub struct RetroLogCallback {
+  pub log: Option<LogPrintfFn>,
+}
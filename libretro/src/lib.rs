@@ -0,0 +1,646 @@
+// libretro のコア (RetroArch などのフロントエンドが読み込む共有ライブラリ)。
+// フロントエンドはコアを1つだけ使い、同時に2つのスレッドから呼ぶことはないが、
+// 呼ぶスレッドは変わることがあるので、状態は Mutex に入れてどのスレッドからも見えるようにする
+// ポインタを受け取る関数の約束 (有効な大きさなど) は libretro.h に従う
+#![allow(clippy::missing_safety_doc)]
+
+use std::{ffi::{c_char, c_int, c_uint, c_void, CStr, CString}, fs::File, io::{self, Error, ErrorKind, Read, Write}, panic::{self, AssertUnwindSafe}, path::{Path, PathBuf}, ptr, sync::{Mutex, PoisonError}};
+
+use ps1_boot::{
+  audio::SAMPLE_RATE,
+  bios::Bios,
+  boot::BootMode,
+  disc::Disc,
+  hle_bios::HleStatus,
+  pad::Button,
+  ram,
+  renderer::{Framebuffer, Renderer, SoftwareRenderer, VRAM_HEIGHT, VRAM_WIDTH},
+  system::System,
+};
+
+use api::*;
+
+mod api;
+
+// RetroPad とパッドのボタンの対応 (PlayStation のコアの慣例で下の B が ×、右の A が ○)
+const BUTTONS: [(c_uint, Button); 14] = [
+  (RETRO_DEVICE_ID_JOYPAD_UP, Button::Up),
+  (RETRO_DEVICE_ID_JOYPAD_DOWN, Button::Down),
+  (RETRO_DEVICE_ID_JOYPAD_LEFT, Button::Left),
+  (RETRO_DEVICE_ID_JOYPAD_RIGHT, Button::Right),
+  (RETRO_DEVICE_ID_JOYPAD_B, Button::Cross),
+  (RETRO_DEVICE_ID_JOYPAD_A, Button::Circle),
+  (RETRO_DEVICE_ID_JOYPAD_Y, Button::Square),
+  (RETRO_DEVICE_ID_JOYPAD_X, Button::Triangle),
+  (RETRO_DEVICE_ID_JOYPAD_L, Button::L1),
+  (RETRO_DEVICE_ID_JOYPAD_R, Button::R1),
+  (RETRO_DEVICE_ID_JOYPAD_L2, Button::L2),
+  (RETRO_DEVICE_ID_JOYPAD_R2, Button::R2),
+  (RETRO_DEVICE_ID_JOYPAD_START, Button::Start),
+  (RETRO_DEVICE_ID_JOYPAD_SELECT, Button::Select),
+];
+
+// システムディレクトリでこの順に BIOS を探す。なければ HLE BIOS で動かす
+const BIOS_NAMES: [&str; 6] = ["scph5501.bin", "scph1001.bin", "scph7001.bin", "scph5500.bin", "scph5502.bin", "scph101.bin"];
+
+// コアオプション (キー, "説明; 値|値|...")。最初の値が既定値
+const OPTION_RENDERER: &CStr = c"ps1_boot_renderer";
+const OPTION_RESOLUTION_SCALE: &CStr = c"ps1_boot_resolution_scale";
+const OPTION_VIDEO_OUTPUT: &CStr = c"ps1_boot_video_output";
+const OPTIONS: [(&CStr, &CStr); 3] = [
+  (OPTION_RENDERER, c"Renderer; software|none"),
+  (OPTION_RESOLUTION_SCALE, c"Internal resolution; 1x|2x|3x|4x"),
+  (OPTION_VIDEO_OUTPUT, c"Video output; display|vram"),
+];
+
+// run_frame は GPU がフレームを表示しなくても 1/60 秒で戻る
+const FPS: f64 = 60.0;
+const DISPLAY_ASPECT_RATIO: f32 = 4.0 / 3.0;
+// 表示領域が決まる前に伝える大きさ
+const DEFAULT_SIZE: (u32, u32) = (320, 240);
+// セーブステートは実行中に大きくなることがある (ヒープやファイルの数など) ので余裕を持たせる
+const STATE_MARGIN: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RendererKind {
+  Software,
+  // 描画しない (音だけ確かめるときなど)
+  None,
+}
+
+impl RendererKind {
+  fn parse(name: &str) -> Option<Self> {
+    match name {
+      "software" => Some(RendererKind::Software),
+      "none" => Some(RendererKind::None),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VideoOutput {
+  // GPU の表示領域
+  Display,
+  // VRAM 全体 (デバッグ用)
+  Vram,
+}
+
+impl VideoOutput {
+  fn parse(name: &str) -> Option<Self> {
+    match name {
+      "display" => Some(VideoOutput::Display),
+      "vram" => Some(VideoOutput::Vram),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Options {
+  renderer: RendererKind,
+  scale: usize,
+  output: VideoOutput,
+}
+
+impl Options {
+  fn default() -> Self {
+    Self { renderer: RendererKind::Software, scale: 1, output: VideoOutput::Display }
+  }
+
+  fn renderer(&self) -> Option<Box<dyn Renderer>> {
+    match self.renderer {
+      RendererKind::Software => Some(Box::new(SoftwareRenderer::with_scale(self.scale))),
+      RendererKind::None => None,
+    }
+  }
+
+  // 最大は VRAM 全体を表示するとき
+  fn geometry(&self, size: (u32, u32)) -> RetroGameGeometry {
+    RetroGameGeometry {
+      base_width: size.0,
+      base_height: size.1,
+      max_width: (VRAM_WIDTH * self.scale) as c_uint,
+      max_height: (VRAM_HEIGHT * self.scale) as c_uint,
+      aspect_ratio: match self.output {
+        VideoOutput::Display => DISPLAY_ASPECT_RATIO,
+        VideoOutput::Vram => 0.0,
+      },
+    }
+  }
+
+  fn av_info(&self, size: (u32, u32)) -> RetroSystemAvInfo {
+    RetroSystemAvInfo {
+      geometry: self.geometry(size),
+      timing: RetroSystemTiming { fps: FPS, sample_rate: SAMPLE_RATE as f64 },
+    }
+  }
+}
+
+// "2x" → 2
+fn parse_scale(name: &str) -> Option<usize> {
+  match name.strip_suffix('x')?.parse() {
+    Ok(scale @ 1..=4) => Some(scale),
+    _ => None,
+  }
+}
+
+// リセットで起動し直すときに使う
+struct Game {
+  bios: Option<Bios>,
+  exe: Option<Vec<u8>>,
+  disc: Option<PathBuf>,
+}
+
+struct Core {
+  system: System,
+  game: Game,
+  options: Options,
+  // 最後にフロントエンドに伝えた画像の大きさ
+  size: (u32, u32),
+}
+
+impl Core {
+  // フロントエンドは serialize_size の大きさのまま保存するので、先頭に本当の長さを書く
+  fn serialize_size(&self) -> usize {
+    8 + self.system.save_state().len() + STATE_MARGIN
+  }
+
+  fn serialize(&self, buf: &mut [u8]) -> bool {
+    let state = self.system.save_state();
+    if buf.len() < 8 + state.len() {
+      return false;
+    }
+    buf[..8].copy_from_slice(&(state.len() as u64).to_le_bytes());
+    buf[8..8 + state.len()].copy_from_slice(&state);
+    buf[8 + state.len()..].fill(0);
+    true
+  }
+
+  fn unserialize(&mut self, buf: &[u8]) -> Result<(), Error> {
+    let truncated = || Error::new(ErrorKind::InvalidData, "Truncated savestate");
+    let len = buf.get(..8).map(|len| u64::from_le_bytes(len.try_into().unwrap()) as usize).ok_or_else(truncated)?;
+    let state = buf.get(8..).and_then(|rest| rest.get(..len)).ok_or_else(truncated)?;
+    self.system.load_state(state)
+  }
+}
+
+struct Frontend {
+  environment: Option<EnvironmentFn>,
+  log: Option<LogPrintfFn>,
+  video_refresh: Option<VideoRefreshFn>,
+  audio_sample_batch: Option<AudioSampleBatchFn>,
+  input_poll: Option<InputPollFn>,
+  input_state: Option<InputStateFn>,
+  // ポートごとにパッドがつながっているか
+  ports: [bool; 2],
+  core: Option<Core>,
+}
+
+// System は Rc や Box<dyn Write> を持つので Send ではないが、
+// 中身は全て Frontend の中だけで使い、Mutex で一度に1つのスレッドからしか触らないので、スレッドをまたいで渡してよい
+struct SharedFrontend(Frontend);
+
+unsafe impl Send for SharedFrontend {}
+
+static FRONTEND: Mutex<SharedFrontend> = Mutex::new(SharedFrontend(Frontend::new()));
+
+// 前の呼び出しがパニックしていても、状態はそのまま使う。
+// パニックは extern "C" の関数を越えて巻き戻せないので、ここで止めてログに出し、フロントエンドに終了してもらう
+fn with_frontend<T: Default>(f: impl FnOnce(&mut Frontend) -> T) -> T {
+  let mut shared = FRONTEND.lock().unwrap_or_else(PoisonError::into_inner);
+  let frontend = &mut shared.0;
+  match panic::catch_unwind(AssertUnwindSafe(|| f(&mut *frontend))) {
+    Ok(result) => result,
+    Err(payload) => {
+      let message = payload.downcast_ref::<&str>().copied()
+          .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+          .unwrap_or("unknown panic");
+      frontend.log(RETRO_LOG_ERROR, &format!("The emulator panicked: {}", message));
+      frontend.environment(RETRO_ENVIRONMENT_SHUTDOWN, &mut ());
+      T::default()
+    }
+  }
+}
+
+// フロントエンドのログに出す。ログのインタフェースがなければ標準エラー出力に出す
+fn log(log: Option<LogPrintfFn>, level: c_int, message: &str) {
+  match (log, CString::new(format!("{}\n", message))) {
+    (Some(log), Ok(line)) => unsafe { log(level, c"%s".as_ptr(), line.as_ptr()) },
+    _ => eprintln!("{}", message),
+  }
+}
+
+// TTY 出力やエミュレータからのメッセージを1行ずつフロントエンドのログに流す
+struct FrontendLog {
+  log: Option<LogPrintfFn>,
+  line: Vec<u8>,
+}
+
+impl Write for FrontendLog {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    for &c in buf {
+      match c {
+        b'\n' => {
+          log(self.log, RETRO_LOG_INFO, &String::from_utf8_lossy(&self.line));
+          self.line.clear();
+        }
+        _ => self.line.push(c),
+      }
+    }
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+impl Frontend {
+  const fn new() -> Self {
+    Self {
+      environment: None,
+      log: None,
+      video_refresh: None,
+      audio_sample_batch: None,
+      input_poll: None,
+      input_state: None,
+      // 1P 側だけつながっている
+      ports: [true, false],
+      core: None,
+    }
+  }
+
+  fn environment<T>(&self, cmd: c_uint, data: &mut T) -> bool {
+    match self.environment {
+      Some(environment) => unsafe { environment(cmd, data as *mut T as *mut c_void) },
+      None => false,
+    }
+  }
+
+  fn log(&self, level: c_int, message: &str) {
+    log(self.log, level, message);
+  }
+
+  // core を取り出して f に渡す。f がパニックしても core を戻してからパニックを続ける
+  fn with_core(&mut self, f: impl FnOnce(&mut Self, &mut Core)) {
+    let Some(mut core) = self.core.take() else {
+      return;
+    };
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f(self, &mut core)));
+    self.core = Some(core);
+    if let Err(payload) = result {
+      panic::resume_unwind(payload);
+    }
+  }
+
+  fn variable(&self, key: &CStr) -> Option<String> {
+    let mut variable = RetroVariable { key: key.as_ptr(), value: ptr::null() };
+    if !self.environment(RETRO_ENVIRONMENT_GET_VARIABLE, &mut variable) || variable.value.is_null() {
+      return None;
+    }
+    Some(unsafe { CStr::from_ptr(variable.value) }.to_string_lossy().into_owned())
+  }
+
+  // 知らない値は既定値にする
+  fn options(&self) -> Options {
+    let default = Options::default();
+    Options {
+      renderer: self.variable(OPTION_RENDERER).and_then(|v| RendererKind::parse(&v)).unwrap_or(default.renderer),
+      scale: self.variable(OPTION_RESOLUTION_SCALE).and_then(|v| parse_scale(&v)).unwrap_or(default.scale),
+      output: self.variable(OPTION_VIDEO_OUTPUT).and_then(|v| VideoOutput::parse(&v)).unwrap_or(default.output),
+    }
+  }
+
+  fn options_updated(&self) -> bool {
+    let mut updated = false;
+    self.environment(RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE, &mut updated) && updated
+  }
+
+  fn system_directory(&self) -> Option<PathBuf> {
+    let mut dir: *const c_char = ptr::null();
+    if !self.environment(RETRO_ENVIRONMENT_GET_SYSTEM_DIRECTORY, &mut dir) || dir.is_null() {
+      return None;
+    }
+    Some(PathBuf::from(unsafe { CStr::from_ptr(dir) }.to_string_lossy().into_owned()))
+  }
+
+  fn load_bios(&self) -> Option<Bios> {
+    let dir = self.system_directory()?;
+    BIOS_NAMES.iter().find_map(|name| {
+      let path = dir.join(name);
+      let bios = std::fs::read(&path).and_then(Bios::new).ok()?;
+      self.log(RETRO_LOG_INFO, &format!("BIOS: {}", path.display()));
+      for warning in bios.warnings() {
+        self.log(RETRO_LOG_WARN, &format!("BIOS: {}", warning));
+      }
+      Some(bios)
+    })
+  }
+
+  // EXE (先頭が "PS-X EXE") はそのまま、それ以外はディスクイメージとして読み込む
+  fn load_game(&mut self, path: &Path) -> Result<(), Error> {
+    let mut pixel_format = RETRO_PIXEL_FORMAT_XRGB8888;
+    if !self.environment(RETRO_ENVIRONMENT_SET_PIXEL_FORMAT, &mut pixel_format) {
+      return Err(Error::new(ErrorKind::Unsupported, "The frontend does not support XRGB8888"));
+    }
+    let bios = self.load_bios();
+    if bios.is_none() {
+      self.log(RETRO_LOG_WARN, "No BIOS found in the system directory, using the HLE BIOS");
+    }
+    let mut head = Vec::new();
+    File::open(path)?.take(8).read_to_end(&mut head)?;
+    let game = match head == b"PS-X EXE" {
+      true => Game { bios, exe: Some(std::fs::read(path)?), disc: None },
+      false => Game { bios, exe: None, disc: Some(path.to_path_buf()) },
+    };
+    let options = self.options();
+    let system = self.new_system(&game, &options)?;
+    self.core = Some(Core { system, game, options, size: DEFAULT_SIZE });
+    Ok(())
+  }
+
+  fn new_system(&self, game: &Game, options: &Options) -> Result<System, Error> {
+    let mut system = System::new(game.bios.clone(), ram::RAM_SIZE_2MB, options.renderer());
+    system.set_console(Box::new(FrontendLog { log: self.log, line: Vec::new() }));
+    if let Some(path) = &game.disc {
+      system.load_disc(Disc::new(Box::new(File::open(path)?))?);
+    }
+    if let Some(exe) = &game.exe {
+      system.load_exe(exe.clone());
+    }
+    system.boot(BootMode::Fast)?;
+    for port in 0..self.ports.len() {
+      self.connect_port(&mut system, port);
+    }
+    Ok(system)
+  }
+
+  fn connect_port(&self, system: &mut System, port: usize) {
+    match self.ports[port] {
+      true => system.set_input(port, 0),
+      false => system.cpu.inter.pad.disconnect(port),
+    }
+  }
+
+  fn buttons(&self, port: usize) -> u16 {
+    let Some(input_state) = self.input_state else {
+      return 0;
+    };
+    BUTTONS.iter()
+        .filter(|(id, _)| unsafe { input_state(port as c_uint, RETRO_DEVICE_JOYPAD, 0, *id) } != 0)
+        .fold(0, |buttons, (_, button)| buttons | button.mask())
+  }
+
+  fn run(&mut self, core: &mut Core) {
+    if self.options_updated() {
+      self.update_options(core);
+    }
+
+    if let Some(input_poll) = self.input_poll {
+      unsafe { input_poll() };
+    }
+    for port in 0..self.ports.len() {
+      if self.ports[port] {
+        core.system.set_input(port, self.buttons(port));
+      }
+    }
+
+    core.system.run_frame();
+    // HLE BIOS でゲストが終了したか止まったら、フロントエンドにも終わってもらう
+    if core.system.status() != HleStatus::Running {
+      self.log(RETRO_LOG_INFO, &format!("Stopped: {:?}", core.system.status()));
+      self.environment(RETRO_ENVIRONMENT_SHUTDOWN, &mut ());
+    }
+    self.refresh_video(core);
+    self.output_audio(core);
+  }
+
+  // レンダラや倍率が変わったらレンダラを作り直す (VRAM の内容は引き継ぐ)
+  fn update_options(&self, core: &mut Core) {
+    let options = self.options();
+    if options.renderer != core.options.renderer || options.scale != core.options.scale {
+      if let Err(e) = core.system.set_renderer(options.renderer()) {
+        self.log(RETRO_LOG_ERROR, &format!("Failed to switch the renderer: {}", e));
+      }
+    }
+    if options != core.options {
+      core.options = options;
+      // 画像の大きさは次のフレームで伝え直す
+      core.size = DEFAULT_SIZE;
+      let mut av_info = options.av_info(core.size);
+      self.environment(RETRO_ENVIRONMENT_SET_SYSTEM_AV_INFO, &mut av_info);
+    }
+  }
+
+  fn refresh_video(&self, core: &mut Core) {
+    let Some(video_refresh) = self.video_refresh else {
+      return;
+    };
+    let frame: Option<Framebuffer> = match core.options.output {
+      VideoOutput::Display => core.system.framebuffer(),
+      VideoOutput::Vram => core.system.vram(),
+    };
+    let Some(frame) = frame else {
+      // 描画しないときは前の画像のまま
+      unsafe { video_refresh(ptr::null(), core.size.0, core.size.1, 0) };
+      return;
+    };
+    if (frame.width, frame.height) != core.size {
+      core.size = (frame.width, frame.height);
+      let mut geometry = core.options.geometry(core.size);
+      self.environment(RETRO_ENVIRONMENT_SET_GEOMETRY, &mut geometry);
+    }
+    unsafe { video_refresh(frame.pixels.as_ptr() as *const c_void, frame.width, frame.height, frame.width as usize * 4) };
+  }
+
+  fn output_audio(&self, core: &mut Core) {
+    let samples = core.system.audio_samples();
+    let Some(audio_sample_batch) = self.audio_sample_batch else {
+      return;
+    };
+    // フロントエンドが一度に受け取りきれないときは残りを渡し直す
+    let mut rest = &samples[..];
+    while !rest.is_empty() {
+      let frames = unsafe { audio_sample_batch(rest.as_ptr(), rest.len() / 2) };
+      if frames == 0 {
+        break;
+      }
+      rest = &rest[(frames * 2).min(rest.len())..];
+    }
+  }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+  RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(environment: EnvironmentFn) {
+  with_frontend(|frontend| {
+    frontend.environment = Some(environment);
+    let mut log = RetroLogCallback { log: None };
+    if frontend.environment(RETRO_ENVIRONMENT_GET_LOG_INTERFACE, &mut log) {
+      frontend.log = log.log;
+    }
+    let mut variables = OPTIONS.iter()
+        .map(|(key, value)| RetroVariable { key: key.as_ptr(), value: value.as_ptr() })
+        .collect::<Vec<_>>();
+    variables.push(RetroVariable { key: ptr::null(), value: ptr::null() });
+    frontend.environment(RETRO_ENVIRONMENT_SET_VARIABLES, &mut variables[0]);
+  });
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(video_refresh: VideoRefreshFn) {
+  with_frontend(|frontend| frontend.video_refresh = Some(video_refresh));
+}
+
+// サンプルはまとめて retro_set_audio_sample_batch のほうに渡す
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_audio_sample: AudioSampleFn) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(audio_sample_batch: AudioSampleBatchFn) {
+  with_frontend(|frontend| frontend.audio_sample_batch = Some(audio_sample_batch));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(input_poll: InputPollFn) {
+  with_frontend(|frontend| frontend.input_poll = Some(input_poll));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(input_state: InputStateFn) {
+  with_frontend(|frontend| frontend.input_state = Some(input_state));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+  with_frontend(|frontend| frontend.core = None);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut RetroSystemInfo) {
+  *info = RetroSystemInfo {
+    library_name: c"ps1_boot".as_ptr(),
+    library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+    valid_extensions: c"exe|psx|bin|iso".as_ptr(),
+    // ディスクイメージはメモリに読み込まずにファイルから読む
+    need_fullpath: true,
+    block_extract: false,
+  };
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut RetroSystemAvInfo) {
+  *info = with_frontend(|frontend| frontend.core.as_ref().map(|core| core.options.av_info(core.size)))
+      .unwrap_or_else(|| Options::default().av_info(DEFAULT_SIZE));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(port: c_uint, device: c_uint) {
+  with_frontend(|frontend| {
+    let port = port as usize;
+    if port >= frontend.ports.len() {
+      return;
+    }
+    frontend.ports[port] = device != RETRO_DEVICE_NONE;
+    frontend.with_core(|frontend, core| frontend.connect_port(&mut core.system, port));
+  });
+}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+  with_frontend(|frontend| frontend.with_core(|frontend, core| {
+    match frontend.new_system(&core.game, &core.options) {
+      Ok(system) => core.system = system,
+      Err(e) => frontend.log(RETRO_LOG_ERROR, &format!("Failed to reset: {}", e)),
+    }
+  }));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+  with_frontend(|frontend| frontend.with_core(Frontend::run));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+  with_frontend(|frontend| frontend.core.as_ref().map_or(0, Core::serialize_size))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+  let buf = std::slice::from_raw_parts_mut(data as *mut u8, size);
+  with_frontend(|frontend| frontend.core.as_ref().is_some_and(|core| core.serialize(buf)))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+  let buf = std::slice::from_raw_parts(data as *const u8, size);
+  with_frontend(|frontend| {
+    let result = match frontend.core.as_mut() {
+      Some(core) => core.unserialize(buf),
+      None => return false,
+    };
+    match result {
+      Ok(()) => true,
+      Err(e) => {
+        frontend.log(RETRO_LOG_ERROR, &format!("Failed to load the savestate: {}", e));
+        false
+      }
+    }
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const RetroGameInfo) -> bool {
+  if game.is_null() || (*game).path.is_null() {
+    return false;
+  }
+  let path = PathBuf::from(CStr::from_ptr((*game).path).to_string_lossy().into_owned());
+  with_frontend(|frontend| match frontend.load_game(&path) {
+    Ok(()) => true,
+    Err(e) => {
+      frontend.log(RETRO_LOG_ERROR, &format!("Failed to load {}: {}", path.display(), e));
+      false
+    }
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_game_type: c_uint, _info: *const RetroGameInfo, _num_info: usize) -> bool {
+  false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+  with_frontend(|frontend| frontend.core = None);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+  RETRO_REGION_NTSC
+}
+
+// メモリーカードや RAM の直接の読み書きはまだ提供しない
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(_id: c_uint) -> *mut c_void {
+  ptr::null_mut()
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(_id: c_uint) -> usize {
+  0
+}
@@ -0,0 +1,178 @@
+use std::{cell::RefCell, io::{Error, Seek, SeekFrom, Write}, rc::Rc};
+
+use crate::spu::VOICE_COUNT;
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+pub trait AudioSink {
+  fn push_sample(&mut self, left: i16, right: i16);
+}
+
+// 何も出力しない (テストやヘッドレス実行用)
+pub struct NullAudioSink;
+
+impl AudioSink for NullAudioSink {
+  fn push_sample(&mut self, _: i16, _: i16) {}
+}
+
+// 出力されたサンプルを溜めておき、フロントエンドがフレームごとに取り出す
+#[derive(Clone, Default)]
+pub struct SampleBuffer {
+  samples: Rc<RefCell<Vec<i16>>>,
+}
+
+impl SampleBuffer {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  // 溜まっているサンプル (L, R の順に並ぶ) を取り出す
+  pub fn take(&self) -> Vec<i16> {
+    std::mem::take(&mut *self.samples.borrow_mut())
+  }
+}
+
+impl AudioSink for SampleBuffer {
+  fn push_sample(&mut self, left: i16, right: i16) {
+    let mut samples = self.samples.borrow_mut();
+    samples.push(left);
+    samples.push(right);
+  }
+}
+
+// 線形補間で入力レートから出力レートへ変換する
+pub struct Resampler {
+  step: f64,
+  rate_adjust: f64,
+  position: f64,
+  prev: (i16, i16),
+}
+
+impl Resampler {
+  pub fn new(input_rate: u32, output_rate: u32) -> Self {
+    Self {
+      step: input_rate as f64 / output_rate as f64,
+      rate_adjust: 1.0,
+      position: 0.0,
+      prev: (0, 0),
+    }
+  }
+
+  // 1.0 より大きいと出力サンプルが増える
+  pub fn set_rate_adjust(&mut self, adjust: f64) {
+    self.rate_adjust = adjust;
+  }
+
+  pub fn push<F: FnMut(i16, i16)>(&mut self, left: i16, right: i16, mut out: F) {
+    let step = self.step / self.rate_adjust;
+    while self.position < 1.0 {
+      let t = self.position;
+      let l = self.prev.0 as f64 + (left as f64 - self.prev.0 as f64) * t;
+      let r = self.prev.1 as f64 + (right as f64 - self.prev.1 as f64) * t;
+      out(l as i16, r as i16);
+      self.position += step;
+    }
+    self.position -= 1.0;
+    self.prev = (left, right);
+  }
+}
+
+// 44.1kHz 16bit ステレオの WAV ファイルに書き出す
+pub struct WavWriter<W: Write + Seek> {
+  writer: W,
+  frames: u32,
+  // 書き込みに失敗したら録音をやめて、最初のエラーを finish で返す
+  error: Option<Error>,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+  pub fn new(mut writer: W) -> Result<Self, Error> {
+    write_wav_header(&mut writer, 0)?;
+    Ok(Self { writer, frames: 0, error: None })
+  }
+
+  pub fn frames(&self) -> u32 {
+    self.frames
+  }
+
+  // ヘッダをそれまでに書けたサンプル数で確定させる
+  pub fn finish(&mut self) -> Result<(), Error> {
+    self.writer.seek(SeekFrom::Start(0))?;
+    write_wav_header(&mut self.writer, self.frames)?;
+    self.writer.seek(SeekFrom::End(0))?;
+    self.writer.flush()?;
+    match self.error.take() {
+      Some(e) => Err(e),
+      None => Ok(()),
+    }
+  }
+}
+
+impl<W: Write + Seek> AudioSink for WavWriter<W> {
+  fn push_sample(&mut self, left: i16, right: i16) {
+    if self.error.is_some() {
+      return;
+    }
+    let mut frame = [0; 4];
+    frame[..2].copy_from_slice(&left.to_le_bytes());
+    frame[2..].copy_from_slice(&right.to_le_bytes());
+    match self.writer.write_all(&frame) {
+      Ok(()) => self.frames += 1,
+      Err(e) => {
+        eprintln!("Failed to write the WAV file, recording stopped: {}", e);
+        self.error = Some(e);
+      }
+    }
+  }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+  fn drop(&mut self) {
+    if let Err(e) = self.finish() {
+      eprintln!("Failed to finish the WAV file: {}", e);
+    }
+  }
+}
+
+fn write_wav_header<W: Write>(w: &mut W, frames: u32) -> Result<(), Error> {
+  let channels: u16 = 2;
+  let bits: u16 = 16;
+  let block_align = channels * bits / 8;
+  let data_size = frames * block_align as u32;
+
+  w.write_all(b"RIFF")?;
+  w.write_all(&(36 + data_size).to_le_bytes())?;
+  w.write_all(b"WAVE")?;
+  w.write_all(b"fmt ")?;
+  w.write_all(&16u32.to_le_bytes())?;
+  w.write_all(&1u16.to_le_bytes())?; // PCM
+  w.write_all(&channels.to_le_bytes())?;
+  w.write_all(&SAMPLE_RATE.to_le_bytes())?;
+  w.write_all(&(SAMPLE_RATE * block_align as u32).to_le_bytes())?;
+  w.write_all(&block_align.to_le_bytes())?;
+  w.write_all(&bits.to_le_bytes())?;
+  w.write_all(b"data")?;
+  w.write_all(&data_size.to_le_bytes())
+}
+
+// WAV に録音する SPU 内部の信号
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AudioTap {
+  Mixed,
+  Voice(usize),
+  Reverb,
+}
+
+impl AudioTap {
+  // "mixed", "reverb", "voice0" ~ "voice23"
+  pub fn parse(name: &str) -> Option<Self> {
+    match name {
+      "mixed" => Some(AudioTap::Mixed),
+      "reverb" => Some(AudioTap::Reverb),
+      _ => match name.strip_prefix("voice")?.parse() {
+        Ok(index) if index < VOICE_COUNT => Some(AudioTap::Voice(index)),
+        _ => None,
+      },
+    }
+  }
+}
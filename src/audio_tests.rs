@@ -0,0 +1,102 @@
+// WAV 出力のテスト
+use std::io::{Cursor, Error, ErrorKind, Seek, SeekFrom, Write};
+
+use crate::{audio::{AudioSink, AudioTap, NullAudioSink, WavWriter, SAMPLE_RATE}, spu::{Spu, VOICE_COUNT}};
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+  u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+  u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+#[test]
+fn wav_header_and_samples() {
+  let mut out = Cursor::new(Vec::new());
+  {
+    let mut writer = WavWriter::new(&mut out).unwrap();
+    writer.push_sample(1, -1);
+    writer.push_sample(0x1234, -0x8000);
+    writer.push_sample(0x7FFF, 0);
+    writer.finish().unwrap();
+    assert_eq!(writer.frames(), 3);
+  }
+  let bytes = out.into_inner();
+
+  assert_eq!(bytes.len(), 44 + 3 * 4);
+  assert_eq!(&bytes[0..4], b"RIFF");
+  assert_eq!(u32_at(&bytes, 4), 36 + 3 * 4);
+  assert_eq!(&bytes[8..16], b"WAVEfmt ");
+  assert_eq!(u32_at(&bytes, 16), 16);
+  assert_eq!(u16_at(&bytes, 20), 1);
+  assert_eq!(u16_at(&bytes, 22), 2);
+  assert_eq!(u32_at(&bytes, 24), SAMPLE_RATE);
+  assert_eq!(u32_at(&bytes, 28), SAMPLE_RATE * 4);
+  assert_eq!(u16_at(&bytes, 32), 4);
+  assert_eq!(u16_at(&bytes, 34), 16);
+  assert_eq!(&bytes[36..40], b"data");
+  assert_eq!(u32_at(&bytes, 40), 3 * 4);
+
+  let samples: Vec<i16> = bytes[44..].chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+  assert_eq!(samples, [1, -1, 0x1234, -0x8000, 0x7FFF, 0]);
+}
+
+// 決まったバイト数を超えると書き込みに失敗する
+struct FullDisk {
+  inner: Cursor<Vec<u8>>,
+  capacity: u64,
+}
+
+impl Write for FullDisk {
+  fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+    if self.inner.position() + buf.len() as u64 > self.capacity {
+      return Err(Error::new(ErrorKind::StorageFull, "disk full"));
+    }
+    self.inner.write(buf)
+  }
+
+  fn flush(&mut self) -> Result<(), Error> {
+    Ok(())
+  }
+}
+
+impl Seek for FullDisk {
+  fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+    self.inner.seek(pos)
+  }
+}
+
+#[test]
+fn wav_write_error_stops_recording() {
+  let mut out = FullDisk { inner: Cursor::new(Vec::new()), capacity: 44 + 2 * 4 };
+  let mut writer = WavWriter::new(&mut out).unwrap();
+  for i in 0..10 {
+    writer.push_sample(i, i);
+  }
+  assert_eq!(writer.frames(), 2);
+  // ヘッダは書けたぶんで確定し、最初のエラーを返す
+  assert_eq!(writer.finish().unwrap_err().kind(), ErrorKind::StorageFull);
+  writer.finish().unwrap();
+  drop(writer);
+  let bytes = out.inner.into_inner();
+  assert_eq!(u32_at(&bytes, 40), 2 * 4);
+}
+
+#[test]
+fn audio_tap_names() {
+  assert_eq!(AudioTap::parse("mixed"), Some(AudioTap::Mixed));
+  assert_eq!(AudioTap::parse("reverb"), Some(AudioTap::Reverb));
+  assert_eq!(AudioTap::parse("voice0"), Some(AudioTap::Voice(0)));
+  assert_eq!(AudioTap::parse("voice23"), Some(AudioTap::Voice(23)));
+  assert_eq!(AudioTap::parse("voice24"), None);
+  assert_eq!(AudioTap::parse("voice"), None);
+  assert_eq!(AudioTap::parse("voice-1"), None);
+}
+
+#[test]
+fn record_rejects_unknown_voice() {
+  let mut spu = Spu::new(Box::new(NullAudioSink));
+  assert!(spu.record(AudioTap::Voice(23), Box::new(NullAudioSink)).is_ok());
+  assert!(spu.record(AudioTap::Voice(VOICE_COUNT), Box::new(NullAudioSink)).is_err());
+}
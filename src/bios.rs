@@ -1,21 +1,151 @@
-use std::{fs::File, io::{Error, ErrorKind, Read}, path::Path};
+use std::io::{Error, ErrorKind};
 
-const BIOS_SIZE: u64 = 512 * 1024;
+use crate::md5;
 
+const BIOS_SIZE: usize = 512 * 1024;
+
+#[derive(Clone)]
 pub struct Bios {
   data: Vec<u8>
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BiosRegion {
+  Japan,
+  NorthAmerica,
+  Europe,
+}
+
+// 既知の BIOS イメージ
+pub struct BiosInfo {
+  pub model: &'static str,
+  pub version: &'static str,
+  pub region: BiosRegion,
+  pub md5: &'static str,
+  // ファストブートのパッチ (シェルのエントリを差し替える) が使えるか
+  pub fast_boot_patch: bool,
+  // TTY 有効化のパッチのアドレスが確認できているか
+  pub tty_patch: bool,
+}
+
+const KNOWN_BIOSES: [BiosInfo; 9] = [
+  BiosInfo { model: "SCPH-1000", version: "1.0 (1994-09-22)", region: BiosRegion::Japan, md5: "239665b1a3dade1b5a52c06338011044", fast_boot_patch: false, tty_patch: false },
+  BiosInfo { model: "SCPH-1001", version: "2.2 (1995-12-04)", region: BiosRegion::NorthAmerica, md5: "924e392ed05558ffdb115408c263dccf", fast_boot_patch: true, tty_patch: true },
+  BiosInfo { model: "SCPH-5500", version: "3.0 (1996-09-09)", region: BiosRegion::Japan, md5: "8dd7d5296a650fac7319bce665a6a53c", fast_boot_patch: true, tty_patch: false },
+  BiosInfo { model: "SCPH-5501", version: "3.0 (1996-11-18)", region: BiosRegion::NorthAmerica, md5: "490f666e1afb15b7362b406ed1cea246", fast_boot_patch: true, tty_patch: false },
+  BiosInfo { model: "SCPH-5502", version: "3.0 (1997-01-06)", region: BiosRegion::Europe, md5: "32736f17079d0b2b7024407c39bd3050", fast_boot_patch: true, tty_patch: false },
+  BiosInfo { model: "SCPH-7000", version: "4.0 (1997-08-18)", region: BiosRegion::Japan, md5: "8e4c14f567745eff2f0408c8129f72a6", fast_boot_patch: true, tty_patch: false },
+  BiosInfo { model: "SCPH-7001", version: "4.1 (1997-12-16)", region: BiosRegion::NorthAmerica, md5: "1e68c231d0896b7eadcad1d7d8e76129", fast_boot_patch: true, tty_patch: false },
+  BiosInfo { model: "SCPH-7502", version: "4.1 (1997-12-16)", region: BiosRegion::Europe, md5: "b9d9a0286c33dc6b7237bb13cd46fdee", fast_boot_patch: true, tty_patch: false },
+  BiosInfo { model: "SCPH-101", version: "4.5 (2000-05-25)", region: BiosRegion::NorthAmerica, md5: "6e3735ff4c7dc899ee98981385f6f3d0", fast_boot_patch: true, tty_patch: false },
+];
+
+// BIOS に適用できるパッチ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BiosPatch {
+  // ロゴとアニメーションを表示せずにディスクから起動する
+  FastBoot,
+  // TTY (DUART) への出力を有効にする
+  Tty,
+}
+
+impl BiosPatch {
+  pub fn parse(name: &str) -> Option<Self> {
+    match name {
+      "fast-boot" => Some(BiosPatch::FastBoot),
+      "tty" => Some(BiosPatch::Tty),
+      _ => None,
+    }
+  }
+}
+
 impl Bios {
-  pub fn new(path: &Path) -> Result<Self, Error> {
-    let file = File::open(path)?;
-    let mut data = Vec::new();
-    file.take(BIOS_SIZE).read_to_end(&mut data)?;
-    if data.len() == BIOS_SIZE as usize {
-      Ok(Self { data })
+  // ROM イメージのファイルの中身から作る
+  pub fn new(data: Vec<u8>) -> Result<Self, Error> {
+    if data.len() != BIOS_SIZE {
+      return Err(Error::new(ErrorKind::InvalidInput, "Invalid BIOS size"));
+    }
+    Ok(Self { data })
+  }
+
+  // 読み込めたが動かないかもしれないイメージへの警告
+  pub fn warnings(&self) -> Vec<String> {
+    if !self.has_copyright() {
+      vec!["the BIOS image does not look like a PlayStation BIOS (corrupt image?)".to_string()]
+    } else if self.info().is_none() {
+      vec![format!("unknown BIOS image (MD5 {})", self.md5())]
     } else {
-      Err(Error::new(ErrorKind::InvalidInput, "Invalid BIOS size"))
+      Vec::new()
+    }
+  }
+
+  pub fn md5(&self) -> String {
+    md5::to_hex(&md5::md5(&self.data))
+  }
+
+  pub fn info(&self) -> Option<&'static BiosInfo> {
+    let hash = self.md5();
+    KNOWN_BIOSES.iter().find(|info| info.md5 == hash)
+  }
+
+  // 0x100 カーネルの日付 (BCD, 0xYYYYMMDD)
+  pub fn kernel_date(&self) -> u32 {
+    self.load32(0x100)
+  }
+
+  // 0x108 の著作権表示
+  fn has_copyright(&self) -> bool {
+    self.data[0x108..].starts_with(b"Sony Computer Entertainment Inc.")
+  }
+
+  // ROM に埋め込まれた版数などの文字列 ("System ROM Version 4.1 12/16/97 A" など)
+  pub fn version_strings(&self) -> Vec<String> {
+    const MARKERS: [&[u8]; 3] = [b"System ROM Version", b"Sony Computer Entertainment Inc.", b"CEX-"];
+    let mut strings = Vec::new();
+    for marker in MARKERS {
+      if let Some(start) = self.data.windows(marker.len()).position(|w| w == marker) {
+        let end = self.data[start..].iter().position(|&c| c == 0 || !(0x20..0x7F).contains(&c))
+            .map_or(self.data.len(), |n| start + n);
+        strings.push(String::from_utf8_lossy(&self.data[start..end]).into_owned());
+      }
+    }
+    strings
+  }
+
+  pub fn apply_patch(&mut self, patch: BiosPatch) -> Result<(), String> {
+    let info = self.info().ok_or("Cannot patch an unknown BIOS image")?;
+    match patch {
+      BiosPatch::FastBoot => {
+        if !info.fast_boot_patch {
+          return Err(format!("Fast boot patch is not supported for {}", info.model));
+        }
+        // シェル (0x1FC1_8000 から RAM にコピーされて実行される) の先頭を
+        // 画面表示を有効にしてすぐ戻る処理に置き換える
+        self.patch(0x1_8000, 0x3C01_1F80); // lui at, 0x1F80
+        self.patch(0x1_8004, 0x3C0A_0300); // lui t2, 0x0300
+        self.patch(0x1_8008, 0xAC2A_1814); // sw t2, 0x1814(at)
+        self.patch(0x1_800C, 0x03E0_0008); // jr ra
+        self.patch(0x1_8010, 0x0000_0000); // nop
+      }
+      BiosPatch::Tty => {
+        if !info.tty_patch {
+          return Err(format!("TTY patch is not supported for {}", info.model));
+        }
+        // カーネルの TTY 有効フラグを 1 にする
+        self.patch(0x6F0C, 0x2401_0001); // addiu at, zero, 1
+        self.patch(0x6F14, 0xAF81_A9C0); // sw at, -0x5640(gp)
+      }
     }
+    Ok(())
+  }
+
+  fn patch(&mut self, offset: usize, val: u32) {
+    self.data[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+  }
+
+  // HLE BIOS 用の空の ROM
+  pub fn hle() -> Self {
+    Self { data: vec![0; BIOS_SIZE] }
   }
 
   pub fn load32(&self, offset: u32) -> u32 {
@@ -29,6 +159,15 @@ impl Bios {
     b0 | (b1 << 8) | (b2 << 16) | (b3 << 24)
   }
 
+  pub fn load16(&self, offset: u32) -> u16 {
+    let offset = offset as usize;
+
+    let b0 = self.data[offset + 0] as u16;
+    let b1 = self.data[offset + 1] as u16;
+
+    b0 | (b1 << 8)
+  }
+
   pub fn load8(&self, offset: u32) -> u8 {
     self.data[offset as usize]
   }
@@ -0,0 +1,47 @@
+use ps1_boot::bios::Bios;
+
+// BIOS イメージの識別結果と埋め込まれた文字列を表示する
+// 使い方: bios-info <BIOS.ROM>...
+fn main() {
+  let paths: Vec<String> = std::env::args().skip(1).collect();
+  if paths.is_empty() {
+    eprintln!("Usage: bios-info <bios image>...");
+    std::process::exit(1);
+  }
+
+  for path in paths {
+    println!("{}", path);
+    let bios = match std::fs::read(&path).and_then(Bios::new) {
+      Ok(bios) => bios,
+      Err(e) => {
+        println!("  Error: {}", e);
+        continue;
+      }
+    };
+
+    println!("  MD5:         {}", bios.md5());
+    for warning in bios.warnings() {
+      println!("  Warning:     {}", warning);
+    }
+    match bios.info() {
+      Some(info) => {
+        println!("  Model:       {} ({:?})", info.model, info.region);
+        println!("  Version:     {}", info.version);
+        let mut patches = Vec::new();
+        if info.fast_boot_patch {
+          patches.push("fast-boot");
+        }
+        if info.tty_patch {
+          patches.push("tty");
+        }
+        println!("  Patches:     {}", if patches.is_empty() { "none".to_string() } else { patches.join(", ") });
+      }
+      None => println!("  Model:       unknown"),
+    }
+    let date = bios.kernel_date();
+    println!("  Kernel date: {:04X}-{:02X}-{:02X}", date >> 16, (date >> 8) & 0xFF, date & 0xFF);
+    for s in bios.version_strings() {
+      println!("  String:      {}", s);
+    }
+  }
+}
@@ -0,0 +1,451 @@
+use crate::{console::Console, interconnect::Interconnect};
+
+// BIOS の関数テーブル。0xA0/0xB0/0xC0 にジャンプし、t1 で関数番号を指定する
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BiosTable {
+  A,
+  B,
+  C,
+}
+
+impl BiosTable {
+  pub fn from_pc(pc: u32) -> Option<Self> {
+    match pc & 0x1FFF_FFFF {
+      0xA0 => Some(BiosTable::A),
+      0xB0 => Some(BiosTable::B),
+      0xC0 => Some(BiosTable::C),
+      _ => None,
+    }
+  }
+
+  fn letter(self) -> char {
+    match self {
+      BiosTable::A => 'A',
+      BiosTable::B => 'B',
+      BiosTable::C => 'C',
+    }
+  }
+
+  fn names(self) -> &'static [&'static str] {
+    match self {
+      BiosTable::A => &A_FUNCTIONS,
+      BiosTable::B => &B_FUNCTIONS,
+      BiosTable::C => &C_FUNCTIONS,
+    }
+  }
+}
+
+pub fn function_name(table: BiosTable, num: u32) -> &'static str {
+  table.names().get(num as usize).copied().unwrap_or("unknown")
+}
+
+// 呼び出しの引数 (a0-a3 とスタック上の残り)
+pub struct BiosCall {
+  pub table: BiosTable,
+  pub num: u32,
+  pub args: [u32; 4],
+  pub sp: u32,
+}
+
+impl BiosCall {
+  // 5番目以降の引数は呼び出し元がスタックの sp+16 以降に置く
+  fn arg(&self, inter: &Interconnect, index: usize) -> u32 {
+    match index {
+      0..=3 => self.args[index],
+      _ => peek32(inter, self.sp.wrapping_add(index as u32 * 4)),
+    }
+  }
+}
+
+pub struct BiosTracer {
+  // None のときはトレースしない。空のときはすべての呼び出しをトレースする
+  filter: Option<Vec<(BiosTable, Option<u32>)>>,
+  forward_tty: bool,
+  line: Vec<u8>,
+}
+
+impl Default for BiosTracer {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl BiosTracer {
+  pub fn new() -> Self {
+    Self {
+      filter: None,
+      forward_tty: false,
+      line: Vec::new(),
+    }
+  }
+
+  pub fn active(&self) -> bool {
+    self.filter.is_some() || self.forward_tty
+  }
+
+  // "all" またはカンマ区切りの "A3F,B35,C" (番号を省略するとテーブル全体)
+  pub fn set_filter(&mut self, spec: &str) -> Result<(), String> {
+    let mut filter = Vec::new();
+    if spec != "all" {
+      for item in spec.split(',') {
+        let table = match item.chars().next() {
+          Some('A') | Some('a') => BiosTable::A,
+          Some('B') | Some('b') => BiosTable::B,
+          Some('C') | Some('c') => BiosTable::C,
+          _ => return Err(format!("Invalid BIOS table: {}", item)),
+        };
+        let num = match &item[1..] {
+          "" => None,
+          n => Some(u32::from_str_radix(n.trim_end_matches(['h', 'H']), 16)
+              .map_err(|_| format!("Invalid BIOS function number: {}", item))?),
+        };
+        filter.push((table, num));
+      }
+    }
+    self.filter = Some(filter);
+    Ok(())
+  }
+
+  // std_out_putchar などの出力をホストのコンソールに流す
+  pub fn set_forward_tty(&mut self, forward: bool) {
+    self.forward_tty = forward;
+  }
+
+  fn traced(&self, table: BiosTable, num: u32) -> bool {
+    match &self.filter {
+      None => false,
+      Some(filter) if filter.is_empty() => true,
+      Some(filter) => filter.iter().any(|&(t, n)| t == table && n.is_none_or(|n| n == num)),
+    }
+  }
+
+  pub fn on_call(&mut self, call: &BiosCall, inter: &Interconnect) {
+    if self.traced(call.table, call.num) {
+      let line = format!("{}({:02X}h) {}({})", call.table.letter(), call.num, function_name(call.table, call.num), format_args(call, inter));
+      inter.console.message(&line);
+    }
+    if self.forward_tty {
+      self.forward(call, inter);
+    }
+  }
+
+  // printf も最終的には std_out_putchar を呼ぶので、1文字単位の出力だけを拾う
+  fn forward(&mut self, call: &BiosCall, inter: &Interconnect) {
+    match (call.table, call.num) {
+      // putchar(char)
+      (BiosTable::A, 0x3C) | (BiosTable::B, 0x3D) => self.tty_write(&inter.console, call.args[0] as u8),
+      // FilePutc(char, fd)
+      (BiosTable::A, 0x09) | (BiosTable::B, 0x3B) if call.args[1] == 1 => self.tty_write(&inter.console, call.args[0] as u8),
+      // FileWrite(fd, src, length)
+      (BiosTable::A, 0x03) | (BiosTable::B, 0x35) if call.args[0] == 1 => {
+        for i in 0..call.args[2] {
+          self.tty_write(&inter.console, peek8(inter, call.args[1].wrapping_add(i)));
+        }
+      }
+      _ => {}
+    }
+  }
+
+  fn tty_write(&mut self, console: &Console, val: u8) {
+    match val {
+      b'\r' => {}
+      b'\n' => {
+        self.line.push(b'\n');
+        console.write(&self.line);
+        self.line.clear();
+      }
+      _ => self.line.push(val),
+    }
+  }
+}
+
+// 引数の型
+// s: 文字列, x: 16進数, d: 10進数, c: 文字, f: printf 形式 (残りは可変長引数)
+fn signature(table: BiosTable, num: u32) -> Option<&'static str> {
+  let sig = match (table, num) {
+    (BiosTable::A, 0x00) | (BiosTable::B, 0x32) => "sx",
+    (BiosTable::A, 0x01) | (BiosTable::B, 0x33) => "ddd",
+    (BiosTable::A, 0x02) | (BiosTable::B, 0x34) => "dxd",
+    (BiosTable::A, 0x03) | (BiosTable::B, 0x35) => "dxd",
+    (BiosTable::A, 0x04) | (BiosTable::B, 0x36) => "d",
+    (BiosTable::A, 0x05) | (BiosTable::B, 0x37) => "dxx",
+    (BiosTable::A, 0x06) | (BiosTable::B, 0x38) => "d",
+    (BiosTable::A, 0x08) | (BiosTable::B, 0x3A) => "d",
+    (BiosTable::A, 0x09) | (BiosTable::B, 0x3B) => "cd",
+    (BiosTable::A, 0x0C) | (BiosTable::A, 0x0D) => "sxd",
+    (BiosTable::A, 0x0E) | (BiosTable::A, 0x0F) => "d",
+    (BiosTable::A, 0x10) | (BiosTable::A, 0x11) => "s",
+    (BiosTable::A, 0x13) => "x",
+    (BiosTable::A, 0x14) => "xd",
+    (BiosTable::A, 0x15) | (BiosTable::A, 0x19) => "xs",
+    (BiosTable::A, 0x16) | (BiosTable::A, 0x1A) => "xsd",
+    (BiosTable::A, 0x17) | (BiosTable::A, 0x24) => "ss",
+    (BiosTable::A, 0x18) => "ssd",
+    (BiosTable::A, 0x1B) => "s",
+    (BiosTable::A, 0x1C..=0x1F) => "sc",
+    (BiosTable::A, 0x25) | (BiosTable::A, 0x26) => "c",
+    (BiosTable::A, 0x27) | (BiosTable::A, 0x29..=0x2E) => "xxd",
+    (BiosTable::A, 0x28) => "xd",
+    (BiosTable::A, 0x30) | (BiosTable::A, 0x33) => "d",
+    (BiosTable::A, 0x34) => "x",
+    (BiosTable::A, 0x37) => "dd",
+    (BiosTable::A, 0x38) | (BiosTable::A, 0x39) => "xd",
+    (BiosTable::A, 0x3A) => "d",
+    (BiosTable::A, 0x3C) | (BiosTable::B, 0x3D) => "c",
+    (BiosTable::A, 0x3E) | (BiosTable::B, 0x3F) => "s",
+    (BiosTable::A, 0x3F) => "f",
+    (BiosTable::A, 0x41) | (BiosTable::A, 0x42) => "sx",
+    (BiosTable::A, 0x43) => "xxx",
+    (BiosTable::A, 0x51) => "sxx",
+    (BiosTable::A, 0x9C) | (BiosTable::A, 0x9D) => "xxx",
+    (BiosTable::A, 0x9F) | (BiosTable::A, 0xB4) => "d",
+    (BiosTable::B, 0x00) | (BiosTable::B, 0x03..=0x06) => "d",
+    (BiosTable::B, 0x01) => "x",
+    (BiosTable::B, 0x02) => "dxx",
+    (BiosTable::B, 0x07) | (BiosTable::B, 0x20) => "xx",
+    (BiosTable::B, 0x08) => "xxxx",
+    (BiosTable::B, 0x09..=0x0D) | (BiosTable::B, 0x0F) | (BiosTable::B, 0x10) => "x",
+    (BiosTable::B, 0x0E) => "xxx",
+    (BiosTable::B, 0x12) => "xdxd",
+    (BiosTable::B, 0x19) | (BiosTable::B, 0x43) | (BiosTable::B, 0x47) => "x",
+    (BiosTable::B, 0x40) | (BiosTable::B, 0x41) | (BiosTable::B, 0x45) | (BiosTable::B, 0x46) | (BiosTable::B, 0x48) => "s",
+    (BiosTable::B, 0x42) => "sx",
+    (BiosTable::B, 0x44) => "ss",
+    (BiosTable::B, 0x5B) => "d",
+    (BiosTable::C, 0x02) | (BiosTable::C, 0x03) => "dx",
+    (BiosTable::C, 0x0A) | (BiosTable::C, 0x0D) => "dd",
+    (_, _) if function_name(table, num) == "return_0" => "",
+    _ => return None,
+  };
+  Some(sig)
+}
+
+fn format_args(call: &BiosCall, inter: &Interconnect) -> String {
+  let sig = match signature(call.table, call.num) {
+    Some(sig) => sig,
+    // 型が分からないときはレジスタの値をそのまま出す
+    None => return call.args.iter().map(|a| format!("{:08X}h", a)).collect::<Vec<_>>().join(", "),
+  };
+
+  let mut out = Vec::new();
+  for (i, kind) in sig.chars().enumerate() {
+    let v = call.arg(inter, i);
+    out.push(match kind {
+      's' => format!("{:?}", peek_string(inter, v)),
+      'd' => format!("{}", v as i32),
+      'c' => format!("{:?}", v as u8 as char),
+      'f' => {
+        let fmt = peek_string(inter, v);
+        let formatted = printf(&fmt, |n| call.arg(inter, i + 1 + n), |addr| peek_string(inter, addr));
+        format!("{:?} -> {:?}", fmt, formatted)
+      }
+      _ => format!("{:08X}h", v),
+    });
+  }
+  out.join(", ")
+}
+
+// BIOS の printf 相当の整形。arg(n) は n 番目の可変長引数を返す
+pub fn printf<A: Fn(usize) -> u32, S: Fn(u32) -> String>(fmt: &str, arg: A, string: S) -> String {
+  let mut out = String::new();
+  let mut chars = fmt.chars().peekable();
+  let mut n = 0;
+  while let Some(c) = chars.next() {
+    if c != '%' {
+      out.push(c);
+      continue;
+    }
+
+    let mut left = false;
+    let mut zero = false;
+    while let Some(&f) = chars.peek() {
+      match f {
+        '-' => left = true,
+        '0' => zero = true,
+        '+' | ' ' | '#' => {}
+        _ => break,
+      }
+      chars.next();
+    }
+    let mut width = 0;
+    while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+      width = width * 10 + d as usize;
+      chars.next();
+    }
+    let mut precision = None;
+    if chars.peek() == Some(&'.') {
+      chars.next();
+      let mut p = 0;
+      while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+        p = p * 10 + d as usize;
+        chars.next();
+      }
+      precision = Some(p);
+    }
+    while let Some('l') | Some('h') = chars.peek() {
+      chars.next();
+    }
+
+    let body = match chars.next() {
+      Some('%') => { out.push('%'); continue; }
+      Some('d') | Some('i') => { n += 1; format!("{}", arg(n - 1) as i32) }
+      Some('u') => { n += 1; format!("{}", arg(n - 1)) }
+      Some('x') => { n += 1; format!("{:x}", arg(n - 1)) }
+      Some('X') => { n += 1; format!("{:X}", arg(n - 1)) }
+      Some('o') => { n += 1; format!("{:o}", arg(n - 1)) }
+      Some('p') => { n += 1; format!("{:08x}", arg(n - 1)) }
+      Some('c') => { n += 1; (arg(n - 1) as u8 as char).to_string() }
+      Some('s') => {
+        n += 1;
+        let s = string(arg(n - 1));
+        match precision {
+          Some(p) => s.chars().take(p).collect(),
+          None => s,
+        }
+      }
+      Some(other) => { out.push('%'); out.push(other); continue; }
+      None => { out.push('%'); break; }
+    };
+
+    let pad = width.saturating_sub(body.chars().count());
+    if left {
+      out.push_str(&body);
+      out.extend(std::iter::repeat_n(' ', pad));
+    } else if zero && body.starts_with('-') {
+      out.push('-');
+      out.extend(std::iter::repeat_n('0', pad));
+      out.push_str(&body[1..]);
+    } else {
+      out.extend(std::iter::repeat_n(if zero { '0' } else { ' ' }, pad));
+      out.push_str(&body);
+    }
+  }
+  out
+}
+
+fn peek8(inter: &Interconnect, addr: u32) -> u8 {
+  inter.peek8(addr).unwrap_or(0)
+}
+
+pub fn peek32(inter: &Interconnect, addr: u32) -> u32 {
+  (0..4).fold(0, |v, i| v | (peek8(inter, addr.wrapping_add(i)) as u32) << (i * 8))
+}
+
+// NUL 終端の文字列を読む (長すぎるものは途中で切る)
+pub fn peek_string(inter: &Interconnect, addr: u32) -> String {
+  let mut s = String::new();
+  for i in 0..256 {
+    match peek8(inter, addr.wrapping_add(i)) {
+      0 => break,
+      c => s.push(c as char),
+    }
+  }
+  s
+}
+
+const A_FUNCTIONS: [&str; 0xB5] = [
+  // 00h
+  "FileOpen", "FileSeek", "FileRead", "FileWrite",
+  "FileClose", "FileIoctl", "exit", "FileGetDeviceFlag",
+  "FileGetc", "FilePutc", "todigit", "atof",
+  "strtoul", "strtol", "abs", "labs",
+  // 10h
+  "atoi", "atol", "atob", "SaveState",
+  "RestoreState", "strcat", "strncat", "strcmp",
+  "strncmp", "strcpy", "strncpy", "strlen",
+  "index", "rindex", "strchr", "strrchr",
+  // 20h
+  "strpbrk", "strspn", "strcspn", "strtok",
+  "strstr", "toupper", "tolower", "bcopy",
+  "bzero", "bcmp", "memcpy", "memset",
+  "memmove", "memcmp", "memchr", "rand",
+  // 30h
+  "srand", "qsort", "strtod", "malloc",
+  "free", "lsearch", "bsearch", "calloc",
+  "realloc", "InitHeap", "SystemErrorExit", "std_in_getchar",
+  "std_out_putchar", "std_in_gets", "std_out_puts", "printf",
+  // 40h
+  "SystemErrorUnresolvedException", "LoadExeHeader", "LoadExeFile", "DoExecute",
+  "FlushCache", "init_a0_b0_c0_vectors", "GPU_dw", "gpu_send_dma",
+  "SendGP1Command", "GPU_cw", "GPU_cwp", "send_gpu_linked_list",
+  "gpu_abort_dma", "GetGPUStatus", "gpu_sync", "SystemError",
+  // 50h
+  "SystemError", "LoadAndExecute", "GetSysSp", "SystemError",
+  "CdInit", "_bu_init", "CdRemove", "return_0",
+  "return_0", "return_0", "return_0", "dev_tty_init",
+  "dev_tty_open", "dev_tty_in_out", "dev_tty_ioctl", "dev_cd_open",
+  // 60h
+  "dev_cd_read", "dev_cd_close", "dev_cd_firstfile", "dev_cd_nextfile",
+  "dev_cd_chdir", "dev_card_open", "dev_card_read", "dev_card_write",
+  "dev_card_close", "dev_card_firstfile", "dev_card_nextfile", "dev_card_erase",
+  "dev_card_undelete", "dev_card_format", "dev_card_rename", "card_clear_error",
+  // 70h
+  "_bu_init", "CdInit", "CdRemove", "return_0",
+  "return_0", "return_0", "return_0", "return_0",
+  "CdAsyncSeekL", "return_0", "return_0", "return_0",
+  "CdAsyncGetStatus", "return_0", "CdAsyncReadSector", "return_0",
+  // 80h
+  "return_0", "CdAsyncSetMode", "return_0", "return_0",
+  "return_0", "return_0", "return_0", "return_0",
+  "return_0", "return_0", "return_0", "return_0",
+  "return_0", "return_0", "return_0", "return_0",
+  // 90h
+  "CdromIoIrqFunc1", "CdromDmaIrqFunc1", "CdromIoIrqFunc2", "CdromDmaIrqFunc2",
+  "CdromGetInt5errCode", "CdInitSubFunc", "AddCDROMDevice", "AddMemCardDevice",
+  "AddDuartTtyDevice", "AddDummyTtyDevice", "SystemError", "SystemError",
+  "SetConf", "GetConf", "SetCdromIrqAutoAbort", "SetMemSize",
+  // A0h
+  "WarmBoot", "SystemErrorBootOrDiskFailure", "EnqueueCdIntr", "DequeueCdIntr",
+  "CdGetLbn", "CdReadSector", "CdGetStatus", "bufs_cb_0",
+  "bufs_cb_1", "bufs_cb_2", "bufs_cb_3", "_card_info",
+  "_card_load", "_card_auto", "bufs_cb_4", "card_write_test",
+  // B0h
+  "return_0", "return_0", "ioabort_raw", "return_0",
+  "GetSystemInfo",
+];
+
+const B_FUNCTIONS: [&str; 0x5E] = [
+  // 00h
+  "alloc_kernel_memory", "free_kernel_memory", "init_timer", "get_timer",
+  "enable_timer_irq", "disable_timer_irq", "restart_timer", "DeliverEvent",
+  "OpenEvent", "CloseEvent", "WaitEvent", "TestEvent",
+  "EnableEvent", "DisableEvent", "OpenThread", "CloseThread",
+  // 10h
+  "ChangeThread", "jump_to_00000000h", "InitPad", "StartPad",
+  "StopPad", "OutdatedPadInitAndStart", "OutdatedPadGetButtons", "ReturnFromException",
+  "SetDefaultExitFromException", "SetCustomExitFromException", "SystemError", "SystemError",
+  "SystemError", "SystemError", "SystemError", "SystemError",
+  // 20h
+  "UnDeliverEvent", "SystemError", "SystemError", "SystemError",
+  "jump_to_00000000h", "jump_to_00000000h", "jump_to_00000000h", "jump_to_00000000h",
+  "jump_to_00000000h", "jump_to_00000000h", "SystemError", "SystemError",
+  "jump_to_00000000h", "jump_to_00000000h", "jump_to_00000000h", "jump_to_00000000h",
+  // 30h
+  "jump_to_00000000h", "jump_to_00000000h", "FileOpen", "FileSeek",
+  "FileRead", "FileWrite", "FileClose", "FileIoctl",
+  "exit", "FileGetDeviceFlag", "FileGetc", "FilePutc",
+  "std_in_getchar", "std_out_putchar", "std_in_gets", "std_out_puts",
+  // 40h
+  "chdir", "FormatDevice", "firstfile", "nextfile",
+  "FileRename", "FileDelete", "FileUndelete", "AddDevice",
+  "RemoveDevice", "PrintInstalledDevices", "InitCard", "StartCard",
+  "StopCard", "_card_info_subfunc", "write_card_sector", "read_card_sector",
+  // 50h
+  "allow_new_card", "Krom2RawAdd", "SystemError", "Krom2Offset",
+  "GetLastError", "GetLastFileError", "GetC0Table", "GetB0Table",
+  "get_bu_callback_port", "testdevice", "SystemError", "ChangeClearPad",
+  "get_card_status", "wait_card_status",
+];
+
+const C_FUNCTIONS: [&str; 0x1E] = [
+  // 00h
+  "EnqueueTimerAndVblankIrqs", "EnqueueSyscallHandler", "SysEnqIntRP", "SysDeqIntRP",
+  "get_free_EvCB_slot", "get_free_TCB_slot", "ExceptionHandler", "InstallExceptionHandlers",
+  "SysInitMemory", "SysInitKernelVariables", "ChangeClearRCnt", "SystemError",
+  "InitDefInt", "SetIrqAutoAck", "dev_sio_init", "dev_sio_open",
+  // 10h
+  "dev_sio_in_out", "dev_sio_ioctl", "InstallDevices", "FlushStdInOutPut",
+  "return_0", "tty_cdevinput", "tty_cdevscan", "tty_circgetc",
+  "tty_circputc", "ioabort", "set_card_find_mode", "KernelRedirect",
+  "AdjustA0Table", "get_card_find_mode",
+];
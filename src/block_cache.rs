@@ -0,0 +1,73 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{cpu::{Instruction, OpHandler}, ram::CODE_PAGE_SHIFT};
+
+// デコード済みの命令
+#[derive(Clone, Copy)]
+pub struct Op {
+  pub instruction: Instruction,
+  pub handler: OpHandler,
+}
+
+// 基本ブロックのキャッシュ (キーは Interconnect::code_address)
+// T はバックエンドごとのブロックの中身 (キャッシュインタプリタは [Op]、JIT は JitBlock)
+pub struct BlockCache<T: ?Sized> {
+  blocks: HashMap<u32, Rc<T>>,
+  // ページごとのそこから始まるブロック
+  pages: HashMap<u32, Vec<u32>>,
+  // キャッシュ分離中の書き込みがあった
+  flush_pending: bool,
+}
+
+impl<T: ?Sized> Default for BlockCache<T> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<T: ?Sized> BlockCache<T> {
+  pub fn new() -> Self {
+    Self {
+      blocks: HashMap::new(),
+      pages: HashMap::new(),
+      flush_pending: false,
+    }
+  }
+
+  pub fn get(&self, code_address: u32) -> Option<Rc<T>> {
+    self.blocks.get(&code_address).cloned()
+  }
+
+  pub fn insert(&mut self, code_address: u32, block: Rc<T>) {
+    // 作り直したブロックはページに登録済み
+    if self.blocks.insert(code_address, block).is_none() {
+      self.pages.entry(code_address >> CODE_PAGE_SHIFT).or_default().push(code_address);
+    }
+  }
+
+  // ブロックはページをまたがないので、そのページから始まるものだけ消せばよい
+  pub fn invalidate_page(&mut self, page: u32) {
+    if let Some(addresses) = self.pages.remove(&page) {
+      for address in addresses {
+        self.blocks.remove(&address);
+      }
+    }
+  }
+
+  // 次のブロックの実行前に全て捨てる
+  pub fn request_flush(&mut self) {
+    self.flush_pending = true;
+  }
+
+  pub fn flush_if_requested(&mut self) {
+    if self.flush_pending {
+      self.flush_pending = false;
+      self.flush();
+    }
+  }
+
+  pub fn flush(&mut self) {
+    self.blocks.clear();
+    self.pages.clear();
+  }
+}
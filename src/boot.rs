@@ -0,0 +1,83 @@
+use std::io::{Error, ErrorKind};
+
+use crate::{cpu::Cpu, disc::{Disc, SystemCnf}, exe::{self, ExeHeader, PsxExe}};
+
+// BIOS がシェルを起動するアドレス。ファストブートではここで EXE に差し替える
+pub const SHELL_ENTRY: u32 = 0x8003_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BootMode {
+  // BIOS のロゴとアニメーションを通常どおり表示する
+  Normal,
+  // シェルに入る直前でディスクの起動 EXE (または指定した EXE) に飛ぶ
+  Fast,
+  // ディスクを無視して BIOS のシェル (メモリーカード管理画面) を起動する
+  Shell,
+}
+
+impl BootMode {
+  pub fn parse(name: &str) -> Option<Self> {
+    match name {
+      "normal" => Some(BootMode::Normal),
+      "fast" => Some(BootMode::Fast),
+      "shell" => Some(BootMode::Shell),
+      _ => None,
+    }
+  }
+}
+
+// 起動する EXE と SYSTEM.CNF で指定されたスタック
+pub struct BootImage {
+  pub exe: PsxExe,
+  pub stack: Option<u32>,
+}
+
+impl BootImage {
+  pub fn from_exe(data: &[u8]) -> Result<Self, Error> {
+    Ok(Self { exe: PsxExe::parse(data)?, stack: None })
+  }
+
+  // SYSTEM.CNF の BOOT (なければ PSX.EXE) を読む
+  pub fn from_disc(disc: &mut Disc) -> Result<Self, Error> {
+    let (path, stack) = match disc.read_file("SYSTEM.CNF") {
+      Ok(cnf) => {
+        let cnf = SystemCnf::parse(&String::from_utf8_lossy(&cnf))
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "No BOOT entry in SYSTEM.CNF"))?;
+        (cnf.boot, cnf.stack)
+      }
+      Err(_) => ("cdrom:\\PSX.EXE;1".to_string(), None),
+    };
+    // BOOT の後ろに引数が続くことがある
+    let path = path.split_whitespace().next().unwrap_or("").to_string();
+    Ok(Self { exe: PsxExe::parse(&disc.read_file(&path)?)?, stack })
+  }
+
+  pub fn initial_sp(&self) -> u32 {
+    self.exe.header.initial_sp().or(self.stack).unwrap_or(exe::DEFAULT_STACK)
+  }
+
+  // EXE を RAM に読み込んでエントリポイントへ飛ぶ
+  pub fn run(&self, cpu: &mut Cpu) {
+    load_exe(cpu, &self.exe);
+    cpu.flush_icache();
+    execute_exe(cpu, &self.exe.header, self.initial_sp(), 0, 0);
+  }
+}
+
+pub fn load_exe(cpu: &mut Cpu, exe: &PsxExe) {
+  for (i, &b) in exe.text.iter().enumerate() {
+    cpu.inter.store8(exe.header.text_addr.wrapping_add(i as u32), b);
+  }
+}
+
+pub fn execute_exe(cpu: &mut Cpu, header: &ExeHeader, sp: u32, arg0: u32, arg1: u32) {
+  for i in 0..header.bss_size {
+    cpu.inter.store8(header.bss_addr.wrapping_add(i), 0);
+  }
+  cpu.set_gpr(28, header.gp); // gp
+  cpu.set_gpr(29, sp); // sp
+  cpu.set_gpr(30, sp); // fp
+  cpu.set_gpr(4, arg0); // a0
+  cpu.set_gpr(5, arg1); // a1
+  cpu.set_pc(header.pc);
+}
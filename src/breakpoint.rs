@@ -0,0 +1,125 @@
+// COP0 のハードウェアブレークポイント
+// cop0r3 BPC, cop0r5 BDA, cop0r6 TAR (JUMPDEST), cop0r7 DCIC, cop0r9 BDAM, cop0r11 BPCM
+pub struct Breakpoints {
+  bpc: u32,
+  bpcm: u32,
+  bda: u32,
+  bdam: u32,
+  dcic: u32,
+  // 最後に分岐した先のアドレス
+  tar: u32,
+}
+
+// JIT のコードが分岐先を直接書き込む TAR の位置
+pub const TAR_OFFSET: usize = std::mem::offset_of!(Breakpoints, tar);
+
+// DCIC のステータスビット (ブレーク時にハードウェアが立てる)
+const STATUS_ANY: u32 = 1 << 0;
+const STATUS_CODE: u32 = 1 << 1;
+const STATUS_DATA: u32 = 1 << 2;
+const STATUS_READ: u32 = 1 << 3;
+const STATUS_WRITE: u32 = 1 << 4;
+const STATUS_JUMP: u32 = 1 << 5;
+
+// DCIC の有効ビット
+const ENABLE_CODE: u32 = 1 << 24;
+const ENABLE_DATA: u32 = 1 << 25;
+const ENABLE_READ: u32 = 1 << 26;
+const ENABLE_WRITE: u32 = 1 << 27;
+const ENABLE_JUMP: u32 = 1 << 28;
+// ビット28 のマスター
+const MASTER_JUMP: u32 = 1 << 29;
+// ビット24-27 のマスター
+const MASTER_ACCESS: u32 = 1 << 30;
+// ビット23 とビット31 の両方が立っていないとどれも有効にならない
+const SUPER_MASTER: u32 = (1 << 23) | (1 << 31);
+
+// 0-5, 12-15, 23-31 以外は常に0
+const DCIC_WRITABLE: u32 = 0xFF80_F03F;
+
+impl Default for Breakpoints {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Breakpoints {
+  pub fn new() -> Self {
+    Self {
+      bpc: 0,
+      bpcm: 0,
+      bda: 0,
+      bdam: 0,
+      dcic: 0,
+      tar: 0,
+    }
+  }
+
+  pub fn read(&self, reg: u32) -> u32 {
+    match reg {
+      3 => self.bpc,
+      5 => self.bda,
+      6 => self.tar,
+      7 => self.dcic,
+      9 => self.bdam,
+      11 => self.bpcm,
+      _ => unreachable!("Not a breakpoint register: cop0r{}", reg),
+    }
+  }
+
+  pub fn write(&mut self, reg: u32, val: u32) {
+    match reg {
+      3 => self.bpc = val,
+      5 => self.bda = val,
+      // TAR は読み出し専用
+      6 => {}
+      7 => self.dcic = val & DCIC_WRITABLE,
+      9 => self.bdam = val,
+      11 => self.bpcm = val,
+      _ => unreachable!("Not a breakpoint register: cop0r{}", reg),
+    }
+  }
+
+  fn enabled(&self, master: u32, enable: u32) -> bool {
+    self.dcic & (SUPER_MASTER | master | enable) == SUPER_MASTER | master | enable
+  }
+
+  pub fn execute_enabled(&self) -> bool {
+    self.enabled(MASTER_ACCESS, ENABLE_CODE)
+  }
+
+  pub fn jump_enabled(&self) -> bool {
+    self.enabled(MASTER_JUMP, ENABLE_JUMP)
+  }
+
+  // 命令の実行前に呼ぶ。ブレークするときは true
+  pub fn check_execute(&mut self, pc: u32) -> bool {
+    if !self.enabled(MASTER_ACCESS, ENABLE_CODE) || (pc ^ self.bpc) & self.bpcm != 0 {
+      return false;
+    }
+    self.dcic |= STATUS_ANY | STATUS_CODE;
+    true
+  }
+
+  pub fn check_data(&mut self, addr: u32, write: bool) -> bool {
+    let (enable, status) = match write {
+      true => (ENABLE_WRITE, STATUS_WRITE),
+      false => (ENABLE_READ, STATUS_READ),
+    };
+    if !self.enabled(MASTER_ACCESS, ENABLE_DATA | enable) || (addr ^ self.bda) & self.bdam != 0 {
+      return false;
+    }
+    self.dcic |= STATUS_ANY | STATUS_DATA | status;
+    true
+  }
+
+  // 分岐/ジャンプが成立したときに呼ぶ。分岐先でブレークするときは true
+  pub fn on_jump(&mut self, target: u32) -> bool {
+    self.tar = target;
+    if !self.enabled(MASTER_JUMP, ENABLE_JUMP) {
+      return false;
+    }
+    self.dcic |= STATUS_ANY | STATUS_JUMP;
+    true
+  }
+}
@@ -14,6 +14,23 @@ pub struct Channel {
 
   block_size: u16,
   block_count: u16,
+
+  // 転送中の状態
+  running: bool,
+  cur_addr: u32,
+  words_left: u32,
+  resume_at: u64,
+}
+
+impl_state!(Channel {
+  enable, direction, step, sync, trigger, chop, chop_dma_sz, chop_cpu_sz, dummy,
+  base, block_size, block_count, running, cur_addr, words_left, resume_at,
+});
+
+impl Default for Channel {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl Channel {
@@ -33,6 +50,11 @@ impl Channel {
 
       block_size: 0,
       block_count: 0,
+
+      running: false,
+      cur_addr: 0,
+      words_left: 0,
+      resume_at: 0,
     }
   }
 
@@ -96,7 +118,77 @@ impl Channel {
       Sync::Manual => self.trigger,
       _ => true,
     };
-    self.enable && trigger
+    self.enable && (self.running || trigger)
+  }
+
+  pub fn running(&self) -> bool {
+    self.running
+  }
+
+  pub fn resume_at(&self) -> u64 {
+    self.resume_at
+  }
+
+  pub fn set_resume_at(&mut self, cycle: u64) {
+    self.resume_at = cycle;
+  }
+
+  // 転送開始時に MADR/BCR から内部カウンタを初期化する
+  pub fn start(&mut self) {
+    self.running = true;
+    // 手動モードのトリガーは開始時にクリアされる
+    self.trigger = false;
+    self.cur_addr = self.base;
+    self.words_left = match self.sync {
+      Sync::Manual => match self.block_size {
+        0 => 0x1_0000,
+        n => n as u32,
+      },
+      Sync::Request => self.block_size as u32,
+      Sync::LinkedList => 0,
+    };
+  }
+
+  pub fn cur_addr(&self) -> u32 {
+    self.cur_addr
+  }
+
+  pub fn set_cur_addr(&mut self, addr: u32) {
+    self.cur_addr = addr & 0x00FF_FFFF;
+  }
+
+  pub fn words_left(&self) -> u32 {
+    self.words_left
+  }
+
+  pub fn set_words_left(&mut self, words: u32) {
+    self.words_left = words;
+  }
+
+  pub fn chop(&self) -> bool {
+    self.chop
+  }
+
+  // チョッピング時に DMA が連続して転送するワード数
+  pub fn chop_dma_words(&self) -> u32 {
+    1 << self.chop_dma_sz
+  }
+
+  // チョッピング時に CPU に明け渡すサイクル数
+  pub fn chop_cpu_cycles(&self) -> u64 {
+    1 << self.chop_cpu_sz
+  }
+
+  pub fn block_size(&self) -> u32 {
+    self.block_size as u32
+  }
+
+  // 同期モード1で1ブロック転送し終わるたびに MADR と BCR が更新される
+  pub fn finish_block(&mut self) -> bool {
+    self.base = self.cur_addr;
+    self.block_count = self.block_count.wrapping_sub(1);
+    self.words_left = self.block_size as u32;
+    self.block_count == 0
   }
 
   pub fn direction(&self) -> Direction {
@@ -111,19 +203,10 @@ impl Channel {
     self.sync
   }
 
-  pub fn transfer_size(&self) -> Option<u32> {
-    let bs = self.block_size as u32;
-    let bc = self.block_count as u32;
-    match self.sync {
-      Sync::Manual => Some(bs),
-      Sync::Request => Some(bc * bs),
-      Sync::LinkedList => None,
-    }
-  }
-
   pub fn done(&mut self) {
     self.enable = false;
     self.trigger = false;
+    self.running = false;
   }
 }
 
@@ -146,3 +229,6 @@ pub enum Sync {
   LinkedList = 2,
 }
 
+impl_state_enum!(Direction { ToRam, FromRam });
+impl_state_enum!(Step { Increment, Decrement });
+impl_state_enum!(Sync { Manual, Request, LinkedList });
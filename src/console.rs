@@ -0,0 +1,36 @@
+use std::{cell::RefCell, io::{self, Write}, rc::Rc};
+
+// ゲストの TTY 出力やエミュレータからのメッセージの出力先。
+// DUART、HLE BIOS、BIOS のトレースなどで共有し、フロントエンドが差し替える (既定では捨てる)
+#[derive(Clone)]
+pub struct Console {
+  out: Rc<RefCell<Box<dyn Write>>>,
+}
+
+impl Default for Console {
+  fn default() -> Self {
+    Self::new(Box::new(io::sink()))
+  }
+}
+
+impl Console {
+  pub fn new(out: Box<dyn Write>) -> Self {
+    Self { out: Rc::new(RefCell::new(out)) }
+  }
+
+  // 共有しているすべての出力先を差し替える
+  pub fn set(&self, out: Box<dyn Write>) {
+    *self.out.borrow_mut() = out;
+  }
+
+  // 書き込みに失敗しても止めない
+  pub fn write(&self, data: &[u8]) {
+    let mut out = self.out.borrow_mut();
+    let _ = out.write_all(data);
+    let _ = out.flush();
+  }
+
+  pub fn message(&self, message: &str) {
+    self.write(format!("{}\n", message).as_bytes());
+  }
+}
@@ -1,4 +1,35 @@
-use crate::interconnect::Interconnect;
+use std::rc::Rc;
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+use crate::jit::Jit;
+use crate::{block_cache::{BlockCache, Op}, breakpoint::Breakpoints, bios_trace::{BiosCall, BiosTable, BiosTracer}, hle_bios::{HleBios, HleStatus}, interconnect::Interconnect, mem_control::Width, ram, savestate::invalid_state};
+
+// 命令を実行する関数 (decode の結果)
+pub type OpHandler = fn(&mut Cpu, Instruction);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CpuBackend {
+  // 1命令ずつフェッチしてデコードする
+  Interpreter,
+  // 基本ブロックごとにデコード結果をキャッシュする
+  CachedInterpreter,
+  // 基本ブロックを x86-64 のコードに変換する (x86-64 Linux のみ)
+  Jit,
+}
+
+impl CpuBackend {
+  pub fn parse(name: &str) -> Option<Self> {
+    match name {
+      "interpreter" => Some(CpuBackend::Interpreter),
+      "cached" => Some(CpuBackend::CachedInterpreter),
+      "jit" => Some(CpuBackend::Jit),
+      _ => None,
+    }
+  }
+}
+
+// 1ブロックの最大命令数
+const MAX_BLOCK_LENGTH: usize = 64;
 
 pub struct Cpu {
   pc: u32,
@@ -13,6 +44,11 @@ pub struct Cpu {
   current_pc: u32,
   cause: u32,
   epc: u32,
+  // アドレスエラーを起こしたアドレス (cop0r8)
+  bad_vaddr: u32,
+  breakpoints: Breakpoints,
+  // 分岐先の命令の実行前にブレークする
+  jump_break: bool,
 
   load: (RegisterIndex, u32),
 
@@ -21,6 +57,53 @@ pub struct Cpu {
 
   branch: bool,
   delay_slot: bool,
+
+  icache: [ICacheLine; 0x100],
+  pub cycles: u64,
+  pub bios_tracer: BiosTracer,
+  // BIOS ROM の代わりに関数を直接実行する
+  hle: Option<HleBios>,
+  // 最後に実行した HLE BIOS の関数が止まったかどうか
+  hle_status: HleStatus,
+  backend: CpuBackend,
+  blocks: BlockCache<[Op]>,
+  #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+  jit: Option<Jit>,
+  #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+  jit_run: JitRun,
+}
+
+// JIT のコードを実行している間の、フェッチとサイクルの精算の進み具合
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+#[derive(Clone, Copy, Default)]
+struct JitRun {
+  // ブロックの先頭のアドレス
+  pc: u32,
+  fetch_cycles: Option<u32>,
+  // フェッチを精算した命令の数
+  fetched: u32,
+  // 最後にフェッチを精算した命令のサイクルがまだ終わっていない
+  cycle_pending: bool,
+}
+
+// JIT のコードが直接読み書きする Cpu のフィールドの位置
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub mod jit_layout {
+  use std::mem::offset_of;
+
+  use super::Cpu;
+
+  pub const PC: usize = offset_of!(Cpu, pc);
+  pub const NEXT_PC: usize = offset_of!(Cpu, next_pc);
+  pub const REGS: usize = offset_of!(Cpu, regs);
+  pub const OUT_REGS: usize = offset_of!(Cpu, out_regs);
+  pub const LOAD_REGISTER: usize = offset_of!(Cpu, load.0.0);
+  pub const LOAD_VALUE: usize = offset_of!(Cpu, load.1);
+  pub const HI: usize = offset_of!(Cpu, hi);
+  pub const LO: usize = offset_of!(Cpu, lo);
+  pub const BRANCH: usize = offset_of!(Cpu, branch);
+  pub const DELAY_SLOT: usize = offset_of!(Cpu, delay_slot);
+  pub const TAR: usize = offset_of!(Cpu, breakpoints) + crate::breakpoint::TAR_OFFSET;
 }
 
 impl Cpu {
@@ -39,22 +122,372 @@ impl Cpu {
       current_pc: 0,
       cause: 0,
       epc: 0,
+      bad_vaddr: 0,
+      breakpoints: Breakpoints::new(),
+      jump_break: false,
       load: (RegisterIndex(0), 0),
       hi: 0xDEAD_BEEF,
       lo: 0xDEAD_BEEF,
       branch: false,
       delay_slot: false,
+      icache: [ICacheLine::new(); 0x100],
+      cycles: 0,
+      bios_tracer: BiosTracer::new(),
+      hle: None,
+      hle_status: HleStatus::Running,
+      backend: CpuBackend::Interpreter,
+      blocks: BlockCache::new(),
+      #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+      jit: None,
+      #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+      jit_run: JitRun::default(),
+    }
+  }
+
+  pub fn set_backend(&mut self, backend: CpuBackend) {
+    let backend = match backend {
+      CpuBackend::Jit if !cfg!(all(target_arch = "x86_64", target_os = "linux")) => {
+        self.inter.console.message("The JIT is only available on x86-64 Linux, using the cached interpreter");
+        CpuBackend::CachedInterpreter
+      }
+      backend => backend,
+    };
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    if backend == CpuBackend::Jit && self.jit.is_none() {
+      self.jit = Some(Jit::new());
+    }
+    self.backend = backend;
+  }
+
+  // 選択されたバックエンドで1命令 (キャッシュインタプリタでは1ブロック) 進める
+  pub fn step(&mut self) {
+    match self.backend {
+      CpuBackend::Interpreter => self.run_next_instruction(),
+      CpuBackend::CachedInterpreter => self.run_cached_block(),
+      CpuBackend::Jit => self.run_jit_block(),
     }
   }
 
   pub fn run_next_instruction(&mut self) {
-    self.current_pc = self.pc;
-    if self.current_pc % 4 != 0 {
-      self.exception(Exception::LoadAddressError);
+    if !self.begin_instruction() {
       return;
     }
-    let instruction = Instruction(self.load32(self.pc));
-    // println!("PC: {:08X} => 0x{:08X} ({:02X}|{:02X}) {:?} REGS:{:?} B0={:08X}", self.pc, instruction.0, instruction.function(), instruction.subfunction(), instruction_name(instruction), self.regs.iter().map(|x| format!("{:08X}, ", x)).collect::<String>(), self.load32(0x000000B0));
+
+    let instruction = match self.fetch_instruction() {
+      Ok(instruction) => instruction,
+      Err(cause) => {
+        self.flush_pipeline();
+        self.exception(cause);
+        self.finish_cycle();
+        return;
+      }
+    };
+    self.execute(instruction, decode(instruction));
+  }
+
+  // 次のブロックの位置。例外になるフェッチやキャッシュできない場所では None
+  fn block_address(&mut self) -> Option<u32> {
+    self.blocks.flush_if_requested();
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    if let Some(jit) = self.jit.as_mut() {
+      jit.blocks.flush_if_requested();
+    }
+    self.invalidate_modified_code();
+
+    match self.address_valid(self.pc, Width::Word) {
+      true => self.inter.code_address(self.pc),
+      false => None,
+    }
+  }
+
+  // 命令キャッシュを通らないときのフェッチ1回のサイクル (通るときは None)
+  fn block_fetch_cycles(&self) -> Option<u32> {
+    let uncached = self.pc >= 0xA000_0000 || !self.inter.cache_control().icache_enabled();
+    match uncached {
+      true => Some(self.inter.fetch_cycles(self.pc)),
+      false => None,
+    }
+  }
+
+  // デコード済みの命令を実行するときも、フェッチのサイクルと命令キャッシュの状態は
+  // インタプリタと同じになるようにする
+  fn account_fetch(&mut self, uncached_cycles: Option<u32>) {
+    match uncached_cycles {
+      Some(cycles) => self.inter.add_pending_cycles(cycles),
+      // RAM と BIOS からの読み込みなのでバスエラーにはならない
+      None => {
+        let _ = self.fetch_cached(self.current_pc);
+      }
+    }
+  }
+
+  // デコード済みの1命令を実行する。分岐や例外でブロックの外に出ていたら false
+  fn run_decoded(&mut self, op: Op, pc: u32, uncached_cycles: Option<u32>) -> bool {
+    if self.pc != pc || !self.begin_instruction() {
+      return false;
+    }
+    self.account_fetch(uncached_cycles);
+    self.execute(op.instruction, op.handler);
+    !self.invalidate_modified_code()
+  }
+
+  fn run_cached_block(&mut self) {
+    let code_address = match self.block_address() {
+      Some(code_address) => code_address,
+      None => return self.run_next_instruction(),
+    };
+    let block = match self.blocks.get(code_address) {
+      Some(block) => block,
+      None => {
+        let ops: Vec<Op> = self.read_block(code_address).into_iter()
+          .map(|instruction| Op { instruction, handler: decode(instruction) })
+          .collect();
+        let block: Rc<[Op]> = ops.into();
+        self.blocks.insert(code_address, block.clone());
+        block
+      }
+    };
+
+    let pc = self.pc;
+    let fetch_cycles = self.block_fetch_cycles();
+
+    for (i, &op) in block.iter().enumerate() {
+      // キャッシュ制御レジスタへのストアで命令キャッシュが有効/無効になったら、フェッチのサイクルを決め直す
+      if !self.run_decoded(op, pc.wrapping_add(i as u32 * 4), fetch_cycles) || self.block_fetch_cycles() != fetch_cycles {
+        break;
+      }
+    }
+  }
+
+  #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+  fn run_jit_block(&mut self) {
+    // 命令ごとのフックが必要なときはキャッシュインタプリタで実行する
+    if self.bios_tracer.active() || self.breakpoints.execute_enabled() || self.breakpoints.jump_enabled() || self.inter.tracing() {
+      return self.run_cached_block();
+    }
+    let code_address = match self.block_address() {
+      Some(code_address) => code_address,
+      None => return self.run_next_instruction(),
+    };
+    // HLE BIOS の関数の入口は BIOS 領域にある
+    if self.hle.is_some() && self.inter.is_bios_code(code_address) {
+      return self.run_cached_block();
+    }
+    let pc = self.pc;
+    // 同じ物理アドレスでも別のセグメントから実行すると分岐先やリンク先が変わるので作り直す
+    let cached = self.jit.as_ref().unwrap().blocks.get(code_address).filter(|block| block.pc == pc);
+    let block = match cached {
+      Some(block) => block,
+      None => {
+        let instructions = self.read_block(code_address);
+        let jit = self.jit.as_mut().unwrap();
+        let block = Rc::new(jit.compile(pc, &instructions));
+        jit.blocks.insert(code_address, block.clone());
+        block
+      }
+    };
+
+    let fetch_cycles = self.block_fetch_cycles();
+    // 遅延スロットから始まるとき (分岐先がまだわからない) と、DMA 中 (どのサイクルでも
+    // 割り込みが起きうる) は1命令ずつ実行する
+    if self.branch || self.inter.dma_active() {
+      for (i, &op) in block.fallback.iter().enumerate() {
+        if !self.run_decoded(op, pc.wrapping_add(i as u32 * 4), fetch_cycles) || self.block_fetch_cycles() != fetch_cycles {
+          break;
+        }
+      }
+      return;
+    }
+    // ブロックの途中では割り込みの状態は変わらない (変わればネイティブコードを抜ける) ので、
+    // 割り込みの判定は最初の命令の前だけでよい
+    if !self.begin_instruction() {
+      return;
+    }
+    self.jit_run = JitRun { pc, fetch_cycles, fetched: 0, cycle_pending: false };
+    let executed = unsafe { (block.code)(self) };
+    self.jit_settle(executed);
+  }
+
+  // index 番目の命令の前までのフェッチとサイクルを、インタプリタと同じ順に済ませる
+  #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+  fn jit_settle(&mut self, index: u32) {
+    if self.jit_run.cycle_pending {
+      self.jit_run.cycle_pending = false;
+      self.finish_cycle();
+    }
+    while self.jit_run.fetched < index {
+      self.current_pc = self.jit_run.pc.wrapping_add(self.jit_run.fetched * 4);
+      self.account_fetch(self.jit_run.fetch_cycles);
+      self.finish_cycle();
+      self.jit_run.fetched += 1;
+    }
+  }
+
+  // JIT のコードから呼ばれた index 番目の命令が、バスやインタプリタの関数に触れる前の準備
+  #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+  fn jit_begin(&mut self, index: u32) {
+    self.jit_settle(index);
+    self.current_pc = self.jit_run.pc.wrapping_add(index * 4);
+    self.account_fetch(self.jit_run.fetch_cycles);
+    self.jit_run.fetched = index + 1;
+    self.jit_run.cycle_pending = true;
+  }
+
+  // 命令の後もネイティブコードを続けてよいか。pc は命令の前の値
+  #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+  fn jit_continue(&self, pc: u32) -> bool {
+    // 割り込みの出力が変わると、次の命令の前に CAUSE を更新して割り込みを判定する必要がある
+    let irq = self.cause & (1 << 10) != 0;
+    self.pc == pc && self.inter.irq_active() == irq && !self.inter.dma_active() && !self.inter.code_modified()
+      && self.block_fetch_cycles() == self.jit_run.fetch_cycles
+  }
+
+  // 以下の3つは JIT のコードから呼ぶ。pc、next_pc、遅延スロットの状態はコード側で書いてある
+
+  // インタプリタの関数で1命令実行する
+  #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+  pub extern "sysv64" fn jit_interpret(&mut self, index: u32, instruction: u32) -> bool {
+    self.jit_begin(index);
+    let pc = self.pc;
+    let instruction = Instruction(instruction);
+    decode(instruction)(self, instruction);
+    self.regs = self.out_regs;
+    self.jit_continue(pc)
+  }
+
+  // LB, LH, LW, LBU, LHU (addr はコード側で計算してある)
+  #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+  pub extern "sysv64" fn jit_load(&mut self, index: u32, instruction: u32, addr: u32) -> bool {
+    self.jit_begin(index);
+    let pc = self.pc;
+    let instruction = Instruction(instruction);
+    let width = match instruction.function() {
+      0x20 | 0x24 => Width::Byte,
+      0x21 | 0x25 => Width::HalfWord,
+      _ => Width::Word,
+    };
+    if let Some(v) = self.load_mem(addr, width) {
+      let v = match instruction.function() {
+        0x20 => v as i8 as u32,
+        0x21 => v as i16 as u32,
+        _ => v,
+      };
+      self.load = (instruction.t(), v);
+    }
+    self.jit_continue(pc)
+  }
+
+  // SB, SH, SW
+  #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+  pub extern "sysv64" fn jit_store(&mut self, index: u32, instruction: u32, addr: u32, val: u32) -> bool {
+    self.jit_begin(index);
+    let pc = self.pc;
+    let width = match Instruction(instruction).function() {
+      0x28 => Width::Byte,
+      0x29 => Width::HalfWord,
+      _ => Width::Word,
+    };
+    self.store_mem(addr, width, val);
+    self.jit_continue(pc)
+  }
+
+  #[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+  fn run_jit_block(&mut self) {
+    self.run_cached_block()
+  }
+
+  // 分岐命令の遅延スロット、ページの終わり、COP0 命令のいずれかまでを1ブロックにする
+  fn read_block(&mut self, code_address: u32) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    let mut addr = code_address;
+    let mut in_delay_slot = false;
+    loop {
+      let instruction = Instruction(self.inter.peek_code(addr));
+      instructions.push(instruction);
+      addr = addr.wrapping_add(4);
+
+      if in_delay_slot || instruction.function() == 0x10 {
+        break;
+      }
+      if addr % (1 << ram::CODE_PAGE_SHIFT) == 0 || instructions.len() >= MAX_BLOCK_LENGTH {
+        break;
+      }
+      in_delay_slot = instruction.is_branch();
+    }
+
+    self.inter.mark_code(code_address);
+    instructions
+  }
+
+  // 命令が書き換えられていたらそのページのブロックを捨てる
+  fn invalidate_modified_code(&mut self) -> bool {
+    if !self.inter.code_modified() {
+      return false;
+    }
+    for page in self.inter.take_modified_code_pages() {
+      self.blocks.invalidate_page(page);
+      #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+      if let Some(jit) = self.jit.as_mut() {
+        jit.blocks.invalidate_page(page);
+      }
+    }
+    true
+  }
+
+  // 割り込み、ブレークポイント、HLE BIOS の処理。命令を実行するときは true
+  fn begin_instruction(&mut self) -> bool {
+    self.current_pc = self.pc;
+
+    // CAUSE のビット10 は割り込みコントローラの出力をそのまま反映する
+    if self.inter.irq_active() {
+      self.cause |= 1 << 10;
+    } else {
+      self.cause &= !(1 << 10);
+    }
+    // SR ビット0 (IEc) と IM ビット8-15 でマスクされる
+    let pending = (self.sr & 1) != 0 && (self.sr & self.cause & 0xFF00) != 0;
+    if pending {
+      self.flush_pipeline();
+      self.exception(Exception::Interrupt);
+      self.finish_cycle();
+      return false;
+    }
+
+    if self.bios_tracer.active() {
+      if let Some(table) = BiosTable::from_pc(self.current_pc) {
+        self.trace_bios_call(table);
+      }
+    }
+
+    // 遅延スロットを実行し終えて分岐先に着いたとき
+    let jump_break = self.jump_break && !self.branch;
+    if jump_break {
+      self.jump_break = false;
+    }
+    if jump_break || self.breakpoints.check_execute(self.current_pc) {
+      self.flush_pipeline();
+      self.debug_exception();
+      self.finish_cycle();
+      return false;
+    }
+
+    if self.hle.as_ref().map_or(false, |hle| hle.handles(self.current_pc)) {
+      self.run_hle();
+      return false;
+    }
+    true
+  }
+
+  fn execute(&mut self, instruction: Instruction, handler: OpHandler) {
+    // トレース中は実行前のレジスタを覚えておき、値が変わったものを記録する
+    let before = match self.inter.tracing() {
+      true => {
+        self.inter.begin_trace(self.cycles, self.current_pc);
+        Some(self.register_snapshot())
+      }
+      false => None,
+    };
+
     self.pc = self.next_pc;
     self.next_pc = self.next_pc.wrapping_add(4);
 
@@ -63,108 +496,325 @@ impl Cpu {
     self.load = (RegisterIndex(0), 0);
     self.delay_slot = self.branch;
     self.branch = false;
-    self.decode_and_execute(instruction);
+    handler(self, instruction);
     self.regs = self.out_regs;
+    if let Some(before) = before {
+      let changes = before.iter().zip(self.register_snapshot())
+        .enumerate()
+        .filter(|&(_, (&a, b))| a != b)
+        .map(|(i, (_, b))| (i as u8, b))
+        .collect();
+      self.inter.end_trace(instruction.0, changes);
+    }
+    self.finish_cycle();
   }
 
-  fn load32(&self, addr: u32) -> u32 {
-    self.inter.load32(addr)
-  }
-
-  fn store32(&mut self, addr: u32, val: u32) {
-    self.inter.store32(addr, val)
-  }
-
-  fn load16(&self, addr: u32) -> u16 {
-    self.inter.load16(addr)
-  }
-
-  fn store16(&mut self, addr: u32, val: u16) {
-    self.inter.store16(addr, val)
-  }
-
-  fn store8(&mut self, addr: u32, val: u8) {
-    self.inter.store8(addr, val)
-  }
-
-  fn load8(&self, addr: u32) -> u8 {
-    self.inter.load8(addr)
-  }
-
-  fn decode_and_execute(&mut self, instruction: Instruction) {
-    match instruction.function() {
-      0b000000 => match instruction.subfunction() {
-        0x00 => self.op_sll(instruction),
-        0x02 => self.op_srl(instruction),
-        0x03 => self.op_sra(instruction),
-        0x04 => self.op_sllv(instruction),
-        0x06 => self.op_srlv(instruction),
-        0x07 => self.op_srav(instruction),
-        0x08 => self.op_jr(instruction),
-        0x09 => self.op_jalr(instruction),
-        0x0C => self.op_syscall(instruction),
-        0x0D => self.op_break(instruction),
-        0x10 => self.op_mfhi(instruction),
-        0x11 => self.op_mthi(instruction),
-        0x12 => self.op_mflo(instruction),
-        0x13 => self.op_mtlo(instruction),
-        0x18 => self.op_mult(instruction),
-        0x19 => self.op_multu(instruction),
-        0x1A => self.op_div(instruction),
-        0x1B => self.op_divu(instruction),
-        0x20 => self.op_add(instruction),
-        0x21 => self.op_addu(instruction),
-        0x22 => self.op_sub(instruction),
-        0x23 => self.op_subu(instruction),
-        0x24 => self.op_and(instruction),
-        0x25 => self.op_or(instruction),
-        0x26 => self.op_xor(instruction),
-        0x27 => self.op_nor(instruction),
-        0x2A => self.op_slt(instruction),
-        0x2B => self.op_sltu(instruction),
-        _ => self.op_illegal(instruction),
-      },
-      0x01 => self.op_bxx(instruction),
-      0x02 => self.op_j(instruction),
-      0x03 => self.op_jal(instruction),
-      0x04 => self.op_beq(instruction),
-      0x05 => self.op_bne(instruction),
-      0x06 => self.op_blez(instruction),
-      0x07 => self.op_bgtz(instruction),
-      0x08 => self.op_addi(instruction),
-      0x09 => self.op_addiu(instruction),
-      0x0A => self.op_slti(instruction),
-      0x0B => self.op_sltiu(instruction),
-      0x0C => self.op_andi(instruction),
-      0x0D => self.op_ori(instruction),
-      0x0E => self.op_xori(instruction),
-      0x0F => self.op_lui(instruction),
-      0x10 => self.op_cop0(instruction),
-      0x11 => self.op_cop1(instruction),
-      0x12 => self.op_cop2(instruction),
-      0x13 => self.op_cop3(instruction),
-      0x20 => self.op_lb(instruction),
-      0x21 => self.op_lh(instruction),
-      0x22 => self.op_lwl(instruction),
-      0x23 => self.op_lw(instruction),
-      0x24 => self.op_lbu(instruction),
-      0x25 => self.op_lhu(instruction),
-      0x26 => self.op_lwr(instruction),
-      0x28 => self.op_sb(instruction),
-      0x29 => self.op_sh(instruction),
-      0x2A => self.op_swl(instruction),
-      0x2B => self.op_sw(instruction),
-      0x2E => self.op_swr(instruction),
-      0x30 => self.op_lwc0(instruction),
-      0x31 => self.op_lwc1(instruction),
-      0x32 => self.op_lwc2(instruction),
-      0x33 => self.op_lwc3(instruction),
-      0x38 => self.op_swc0(instruction),
-      0x39 => self.op_swc1(instruction),
-      0x3A => self.op_swc2(instruction),
-      0x3B => self.op_swc3(instruction),
-      _ => self.op_illegal(instruction),
+  // 汎用レジスタと hi/lo (トレースでの番号は 32, 33)
+  fn register_snapshot(&self) -> [u32; 34] {
+    let mut regs = [0; 34];
+    regs[..32].copy_from_slice(&self.out_regs);
+    regs[32] = self.hi;
+    regs[33] = self.lo;
+    regs
+  }
+
+  pub fn set_hle(&mut self, hle: HleBios) {
+    self.hle = Some(hle);
+  }
+
+  // セーブステートを読み込んだ新しい Cpu に、保存しないもの
+  // (ブレークポイント、BIOS のトレース、バックエンド、ディスクなど) を old から移す
+  pub fn take_host(&mut self, old: &mut Cpu) {
+    std::mem::swap(&mut self.breakpoints, &mut old.breakpoints);
+    std::mem::swap(&mut self.bios_tracer, &mut old.bios_tracer);
+    self.set_backend(old.backend);
+    if let (Some(hle), Some(old_hle)) = (self.hle.as_mut(), old.hle.as_mut()) {
+      hle.take_host(old_hle);
     }
+    self.inter.take_host(&mut old.inter);
+  }
+
+  pub fn hle_status(&self) -> HleStatus {
+    self.hle_status
+  }
+
+  // HLE BIOS の関数を1命令として実行する
+  fn run_hle(&mut self) {
+    self.flush_pipeline();
+
+    let mut hle = self.hle.take().unwrap();
+    self.hle_status = hle.execute(self);
+    self.hle = Some(hle);
+    self.regs = self.out_regs;
+    self.finish_cycle();
+  }
+
+  // 命令を実行せずに遅延ロードを反映する (割り込みや命令フェッチの例外の前)
+  fn flush_pipeline(&mut self) {
+    let (reg, val) = self.load;
+    self.set_reg(reg, val);
+    self.load = (RegisterIndex(0), 0);
+    self.regs = self.out_regs;
+    self.delay_slot = self.branch;
+    self.branch = false;
+  }
+
+  // 以下は HLE BIOS からレジスタを操作するためのもの
+  pub fn gpr(&self, index: usize) -> u32 {
+    self.out_regs[index]
+  }
+
+  pub fn set_gpr(&mut self, index: usize, val: u32) {
+    self.set_reg(RegisterIndex(index as u32), val);
+  }
+
+  pub fn hi_lo(&self) -> (u32, u32) {
+    (self.hi, self.lo)
+  }
+
+  pub fn set_hi_lo(&mut self, hi: u32, lo: u32) {
+    self.hi = hi;
+    self.lo = lo;
+  }
+
+  // 次に実行する命令のアドレス
+  pub fn pc(&self) -> u32 {
+    self.pc
+  }
+
+  pub fn current_pc(&self) -> u32 {
+    self.current_pc
+  }
+
+  pub fn set_pc(&mut self, pc: u32) {
+    self.pc = pc;
+    self.next_pc = pc.wrapping_add(4);
+  }
+
+  pub fn sr(&self) -> u32 {
+    self.sr
+  }
+
+  pub fn set_sr(&mut self, sr: u32) {
+    self.sr = sr;
+  }
+
+  pub fn cause(&self) -> u32 {
+    self.cause
+  }
+
+  pub fn epc(&self) -> u32 {
+    self.epc
+  }
+
+  // MFC0 で読める値
+  pub fn cop0(&self, reg: u32) -> u32 {
+    match reg {
+      3 | 5 | 6 | 7 | 9 | 11 => self.breakpoints.read(reg),
+      8 => self.bad_vaddr,
+      12 => self.sr,
+      13 => self.cause,
+      14 => self.epc,
+      // PRId: R3000A
+      15 => 0x0000_0002,
+      // 存在しないレジスタ
+      _ => 0,
+    }
+  }
+
+  // 遅延スロットの後で書き込まれるロード (レジスタ番号, 値)
+  pub fn pending_load(&self) -> (u32, u32) {
+    (self.load.0.0, self.load.1)
+  }
+
+  // RFE 相当 (割り込み許可/モードのスタックを戻す)
+  pub fn return_from_exception(&mut self) {
+    // 旧モード (ビット4-5) はそのまま残る
+    let mode = self.sr & 0x3C;
+    self.sr = self.sr & !0xF;
+    self.sr = self.sr | mode >> 2;
+  }
+
+  pub fn flush_icache(&mut self) {
+    for line in self.icache.iter_mut() {
+      line.invalidate();
+    }
+  }
+
+  fn trace_bios_call(&mut self, table: BiosTable) {
+    // 遅延スロットのロードがまだ反映されていないことがある
+    let reg = |cpu: &Cpu, i: u32| match cpu.load {
+      (RegisterIndex(r), v) if r == i && r != 0 => v,
+      _ => cpu.regs[i as usize],
+    };
+    let call = BiosCall {
+      table,
+      num: reg(self, 9) & 0xFF,
+      args: [reg(self, 4), reg(self, 5), reg(self, 6), reg(self, 7)],
+      sp: reg(self, 29),
+    };
+    self.bios_tracer.on_call(&call, &self.inter);
+  }
+
+  fn finish_cycle(&mut self) {
+    let cycles = 1 + self.inter.take_pending_cycles() as u64;
+    self.cycles += cycles;
+    self.inter.tick(cycles);
+  }
+
+  fn fetch_instruction(&mut self) -> Result<Instruction, Exception> {
+    let pc = self.current_pc;
+    if !self.address_valid(pc, Width::Word) {
+      self.bad_vaddr = pc;
+      return Err(Exception::LoadAddressError);
+    }
+    let cc = self.inter.cache_control();
+
+    // KSEG1 (0xA000_0000～) はキャッシュされない
+    let cached = pc < 0xA000_0000;
+    if !cached || !cc.icache_enabled() {
+      let v = self.inter.fetch32(pc);
+      if self.inter.bus_error() {
+        return Err(Exception::BusErrorInstruction);
+      }
+      return Ok(Instruction(v));
+    }
+
+    self.fetch_cached(pc)
+  }
+
+  // 命令キャッシュからの読み出し
+  fn fetch_cached(&mut self, pc: u32) -> Result<Instruction, Exception> {
+    let tag = pc & 0x7FFF_F000;
+    let line_index = ((pc >> 4) & 0xFF) as usize;
+    let index = (pc >> 2) & 3;
+
+    let line = self.icache[line_index];
+    if line.tag() != tag || line.valid_index() > index {
+      // キャッシュミス: 現在のワードから行末までをまとめて読み込む
+      // (読み込みのサイクルはバスアクセスとして加算される)
+      let mut line = line;
+      let mut cpc = pc;
+      for i in index..4 {
+        line.set_instruction(i, Instruction(self.inter.fetch32(cpc)));
+        if self.inter.bus_error() {
+          return Err(Exception::BusErrorInstruction);
+        }
+        cpc = cpc.wrapping_add(4);
+      }
+      line.set_tag_valid(pc);
+      self.icache[line_index] = line;
+    }
+
+    Ok(self.icache[line_index].instruction(index))
+  }
+
+  // コードが書き換わったので、キャッシュ済みのブロックを次の実行前に全て捨てる
+  fn request_block_flush(&mut self) {
+    self.blocks.request_flush();
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    if let Some(jit) = self.jit.as_mut() {
+      jit.blocks.request_flush();
+    }
+  }
+
+  // キャッシュ済みのブロックは読み込んだメモリと合わないので作り直す。
+  // HLE BIOS で止まっていたときも、読み込んだ状態から実行し直す
+  fn after_load(&mut self) -> Result<(), std::io::Error> {
+    self.request_block_flush();
+    self.hle_status = HleStatus::Running;
+    Ok(())
+  }
+
+  // キャッシュ分離中のストアはメモリではなく命令キャッシュに書き込まれる
+  fn cache_maintenance(&mut self, addr: u32, width: Width, val: u32) {
+    self.request_block_flush();
+    let cc = self.inter.cache_control();
+    if !cc.icache_enabled() {
+      return;
+    }
+
+    let line = &mut self.icache[((addr >> 4) & 0xFF) as usize];
+    if cc.tag_test_mode() {
+      // タグテストモードでは行全体が無効化される
+      line.invalidate();
+    } else {
+      // SB/SH はワードのうちアドレスのバイト/ハーフワードだけを書き換える
+      let index = (addr >> 2) & 3;
+      let shift = (addr & 3) * 8;
+      let mask = width.mask(!0) << shift;
+      let Instruction(old) = line.instruction(index);
+      line.set_instruction(index, Instruction((old & !mask) | ((val << shift) & mask)));
+    }
+  }
+
+  fn cache_isolated(&self) -> bool {
+    self.sr & 0x1_0000 != 0
+  }
+
+  // KUc (SR ビット1) が立っているときはユーザーモード
+  fn user_mode(&self) -> bool {
+    self.sr & 0x02 != 0
+  }
+
+  // ユーザーモードからは KUSEG (0x0000_0000～0x7FFF_FFFF) にしかアクセスできない
+  fn address_valid(&self, addr: u32, width: Width) -> bool {
+    addr % width.bytes() == 0 && !(self.user_mode() && addr >= 0x8000_0000)
+  }
+
+  // 例外が起きたときは None (ロード先のレジスタは変更されない)
+  fn load_mem(&mut self, addr: u32, width: Width) -> Option<u32> {
+    if !self.address_valid(addr, width) {
+      self.bad_vaddr = addr;
+      self.exception(Exception::LoadAddressError);
+      return None;
+    }
+    if self.breakpoints.check_data(addr, false) {
+      self.debug_exception();
+      return None;
+    }
+    if self.cache_isolated() && width == Width::Word {
+      // キャッシュ分離中はキャッシュの内容が読める
+      let line = &self.icache[((addr >> 4) & 0xFF) as usize];
+      return Some(line.instruction((addr >> 2) & 3).0);
+    }
+    let v = self.inter.load(addr, width);
+    if self.inter.bus_error() {
+      self.exception(Exception::BusErrorData);
+      return None;
+    }
+    Some(v)
+  }
+
+  fn store_mem(&mut self, addr: u32, width: Width, val: u32) {
+    if !self.address_valid(addr, width) {
+      self.bad_vaddr = addr;
+      self.exception(Exception::StoreAddressError);
+      return;
+    }
+    if self.breakpoints.check_data(addr, true) {
+      self.debug_exception();
+      return;
+    }
+    if self.cache_isolated() {
+      self.cache_maintenance(addr, width, val);
+      return;
+    }
+    self.inter.store(addr, width, width.mask(val));
+    if self.inter.bus_error() {
+      self.exception(Exception::BusErrorData);
+    }
+  }
+
+  // CU0-3 (SR ビット28-31)。COP0 はカーネルモードなら常に使える
+  fn coprocessor_enabled(&self, n: u32) -> bool {
+    self.sr & (1 << (28 + n)) != 0 || (n == 0 && !self.user_mode())
+  }
+
+  fn coprocessor_unusable(&mut self, n: u32) {
+    self.exception(Exception::CoprocessorError);
+    // CE (CAUSE ビット28-29) に使おうとしたコプロセッサの番号が入る
+    self.cause |= n << 28;
   }
 
   fn reg(&self, index: RegisterIndex) -> u32 {
@@ -192,22 +842,13 @@ impl Cpu {
   }
 
   fn op_sw(&mut self, instruction: Instruction) {
-    if self.sr & 0x1_0000 != 0 {
-      println!("Ignoring load while cache is isolated");
-      return;
-    }
-
     let i = instruction.imm_se();
     let t = instruction.t();
     let s = instruction.s();
 
     let addr = self.reg(s).wrapping_add(i);
-    if addr % 4 == 0 {
-      let v = self.reg(t);
-      self.store32(addr, v);
-    } else {
-      self.exception(Exception::StoreAddressError);
-    }
+    let v = self.reg(t);
+    self.store_mem(addr, Width::Word, v);
   }
 
   fn op_sll(&mut self, instruction: Instruction) {
@@ -229,8 +870,9 @@ impl Cpu {
   }
 
   fn op_j(&mut self, instruction: Instruction) {
+    self.branch = true;
     let i = instruction.imm_jump();
-    self.next_pc = (self.pc & 0xF000_0000) | (i << 2);
+    self.jump((self.pc & 0xF000_0000) | (i << 2));
   }
 
   fn op_or(&mut self, instruction: Instruction) {
@@ -243,11 +885,15 @@ impl Cpu {
   }
 
   fn op_cop0(&mut self, instruction: Instruction) {
+    if !self.coprocessor_enabled(0) {
+      self.coprocessor_unusable(0);
+      return;
+    }
     match instruction.cop_opcode() {
       0b00100 => self.op_mtc0(instruction),
       0b00000 => self.op_mfc0(instruction),
       0b10000 => self.op_rfe(instruction),
-      _ => panic!("Unhandled cop0 instruction {:08X} (op: 0b{:06b})", instruction.0, instruction.cop_opcode()),
+      _ => self.op_illegal(instruction),
     }
   }
 
@@ -258,20 +904,16 @@ impl Cpu {
     let v = self.reg(cpu_r);
 
     match cop_r {
-      3 | 5 | 6 | 7 | 9 | 11 => {
-        // breakpoint registers
-        if v != 0 {
-          panic!("Unhandled write to cop0r{}", cop_r)
-        }
-      }
+      3 | 5 | 6 | 7 | 9 | 11 => self.breakpoints.write(cop_r, v),
+      // BadVaddr, EPC, PRId は読み出し専用
+      8 | 14 | 15 => {}
       12 => self.sr = v,
       13 => {
-        // CAUSE register
-        if v != 0 {
-          panic!("Unhandled write to CAUSE register.")
-        }
+        // CAUSE register: ソフトウェア割り込みビット(8-9)のみ書き込める
+        self.cause = (self.cause & !0x300) | (v & 0x300);
       }
-      n => panic!("Unhandled cop0 register: {:08X}", n),
+      // 存在しない/未実装のレジスタへの書き込みは無視する (読み出しは 0)
+      _ => {}
     }
   }
 
@@ -279,11 +921,19 @@ impl Cpu {
     let offset = offset << 2;
     let mut pc = self.pc;
     pc = pc.wrapping_add(offset);
-    self.next_pc = pc;
-    self.branch = true;
+    self.jump(pc);
+  }
+
+  fn jump(&mut self, target: u32) {
+    self.next_pc = target;
+    if self.breakpoints.on_jump(target) {
+      self.jump_break = true;
+    }
   }
 
   fn op_bne(&mut self, instruction: Instruction) {
+    // 分岐しなくても次の命令は遅延スロット (例外時に CAUSE.BD が立つ)
+    self.branch = true;
     let i = instruction.imm_se();
     let s = instruction.s();
     let t = instruction.t();
@@ -306,20 +956,13 @@ impl Cpu {
   }
 
   fn op_lw(&mut self, instruction: Instruction) {
-    if self.sr & 0x1_0000 != 0 {
-      println!("Ignoring load while cache is isolated");
-      return;
-    }
     let i = instruction.imm_se();
     let t = instruction.t();
     let s = instruction.s();
 
     let addr = self.reg(s).wrapping_add(i);
-    if addr % 4 == 0 {
-      let v = self.load32(addr);
+    if let Some(v) = self.load_mem(addr, Width::Word) {
       self.load = (t, v);
-    } else {
-      self.exception(Exception::LoadAddressError);
     }
   }
 
@@ -342,23 +985,13 @@ impl Cpu {
   }
 
   fn op_sh(&mut self, instruction: Instruction) {
-
-    if self.sr & 0x1_0000 != 0 {
-      println!("Ignoring store while cache is isolated");
-      return;
-    }
-
     let i = instruction.imm_se();
     let t = instruction.t();
     let s = instruction.s();
 
     let addr = self.reg(s).wrapping_add(i);
-    if addr % 2 == 0 {
-      let v = self.reg(t);
-      self.store16(addr, v as u16);
-    } else {
-      self.exception(Exception::StoreAddressError);
-    }
+    let v = self.reg(t);
+    self.store_mem(addr, Width::HalfWord, v);
   }
 
   fn op_jal(&mut self, instruction: Instruction) {
@@ -376,23 +1009,20 @@ impl Cpu {
   }
 
   fn op_sb(&mut self, instruction: Instruction) {
-    if self.sr & 0x1_0000 != 0 {
-      println!("Ignoring store while cache is isolated");
-      return;
-    }
-
     let i = instruction.imm_se();
     let t = instruction.t();
     let s = instruction.s();
 
     let addr = self.reg(s).wrapping_add(i);
     let v = self.reg(t);
-    self.store8(addr, v as u8);
+    self.store_mem(addr, Width::Byte, v);
   }
 
   fn op_jr(&mut self, instruction: Instruction) {
+    self.branch = true;
     let s = instruction.s();
-    self.next_pc = self.reg(s);
+    let target = self.reg(s);
+    self.jump(target);
   }
 
   fn op_lb(&mut self, instruction: Instruction) {
@@ -401,11 +1031,13 @@ impl Cpu {
     let s = instruction.s();
 
     let addr = self.reg(s).wrapping_add(i);
-    let v = self.load8(addr) as i8;
-    self.load = (t, v as u32);
+    if let Some(v) = self.load_mem(addr, Width::Byte) {
+      self.load = (t, v as i8 as u32);
+    }
   }
 
   fn op_beq(&mut self, instruction: Instruction) {
+    self.branch = true;
     let i = instruction.imm_se();
     let s = instruction.s();
     let t = instruction.t();
@@ -419,12 +1051,7 @@ impl Cpu {
     let cpu_r = instruction.t();
     let cop_r = instruction.d().0;
 
-    let v = match cop_r {
-      12 => self.sr,
-      13 => self.cause,
-      14 => self.epc,
-      _ => panic!("Unhandled read from cop0r{}", cop_r)
-    };
+    let v = self.cop0(cop_r);
     self.load = (cpu_r, v)
   }
 
@@ -452,6 +1079,7 @@ impl Cpu {
   }
 
   fn op_bgtz(&mut self, instruction: Instruction) {
+    self.branch = true;
     let i = instruction.imm_se();
     let s = instruction.s();
 
@@ -462,6 +1090,7 @@ impl Cpu {
   }
 
   fn op_blez(&mut self, instruction: Instruction) {
+    self.branch = true;
     let i = instruction.imm_se();
     let s = instruction.s();
 
@@ -478,20 +1107,25 @@ impl Cpu {
     let s = instruction.s();
 
     let addr = self.reg(s).wrapping_add(i);
-    let v = self.load8(addr);
-    self.load = (t, v as u32);
+    if let Some(v) = self.load_mem(addr, Width::Byte) {
+      self.load = (t, v);
+    }
   }
 
   fn op_jalr(&mut self, instruction: Instruction) {
+    self.branch = true;
     let d = instruction.d();
     let s = instruction.s();
-    let ra = self.pc;
+    // 戻り先は遅延スロットの次 (self.pc は遅延スロットを指している)
+    let ra = self.pc.wrapping_add(4);
     self.set_reg(d, ra);
-    self.next_pc = self.reg(s);
+    let target = self.reg(s);
+    self.jump(target);
   }
 
   // BGEZ, BLTZ, BGEZAL, BLTZAL => BcondZ
   fn op_bxx(&mut self, instruction: Instruction) {
+    self.branch = true;
     let i = instruction.imm_se();
     let s = instruction.s();
 
@@ -507,7 +1141,7 @@ impl Cpu {
     let test = test ^ is_bgez;
 
     if is_link {
-      let ra = self.pc;
+      let ra = self.pc.wrapping_add(4);
       self.set_reg(RegisterIndex(31), ra);
     }
 
@@ -626,16 +1260,27 @@ impl Cpu {
   }
 
   fn exception(&mut self, cause: Exception) {
+    self.exception_to(cause, 0x80);
+  }
+
+  // ハードウェアブレークポイントは BREAK と同じ例外コードで 0x40 のベクタに飛ぶ
+  fn debug_exception(&mut self) {
+    self.exception_to(Exception::Break, 0x40);
+  }
+
+  fn exception_to(&mut self, cause: Exception, vector: u32) {
+    // BEV (SR ビット22) が立っているときは BIOS ROM 内のベクタ
     let handler = match self.sr & (1 << 22) != 0 {
-      true => 0xBFC0_0180,
-      false => 0x8000_0080,
+      true => 0xBFC0_0100 + vector,
+      false => 0x8000_0000 + vector,
     };
 
     let mode = self.sr & 0x3F;
     self.sr = self.sr & (!0x3F);
     self.sr = self.sr | ((mode << 2) & 0x3F);
 
-    self.cause = (cause as u32) << 2;
+    // 割り込み保留ビット (8-15) は保持する
+    self.cause = (self.cause & 0xFF00) | ((cause as u32) << 2);
     self.epc = self.current_pc;
 
     if self.delay_slot {
@@ -662,12 +1307,15 @@ impl Cpu {
   }
 
   fn op_rfe(&mut self, instruction: Instruction) {
+    // TLB 命令などは R3000A には存在しない
     if instruction.0 & 0x3F != 0b010000 {
-      panic!("Invalid cop0 instruction: {:?}", instruction);
+      self.op_illegal(instruction);
+      return;
     }
 
-    let mode = self.sr & 0x3F;
-    self.sr = self.sr & !0x3F;
+    // 旧モード (ビット4-5) はそのまま残る
+    let mode = self.sr & 0x3C;
+    self.sr = self.sr & !0xF;
     self.sr = self.sr | mode >> 2;
   }
 
@@ -677,11 +1325,8 @@ impl Cpu {
     let s = instruction.s();
 
     let addr = self.reg(s).wrapping_add(i);
-    if addr % 2 == 0 {
-      let v = self.load16(addr);
-      self.load = (t, v as u32);
-    } else {
-      self.exception(Exception::LoadAddressError);
+    if let Some(v) = self.load_mem(addr, Width::HalfWord) {
+      self.load = (t, v);
     }
   }
 
@@ -700,8 +1345,9 @@ impl Cpu {
     let s = instruction.s();
 
     let addr = self.reg(s).wrapping_add(i);
-    let v = self.load16(addr) as i16;
-    self.load = (t, v as u32);
+    if let Some(v) = self.load_mem(addr, Width::HalfWord) {
+      self.load = (t, v as i16 as u32);
+    }
   }
 
   fn op_nor(&mut self, instruction: Instruction) {
@@ -789,15 +1435,20 @@ impl Cpu {
     self.set_reg(t, v);
   }
 
+  // COP1/COP3 はつながっていない
   fn op_cop1(&mut self, _: Instruction) {
-    self.exception(Exception::CoprocessorError);
+    self.coprocessor_unusable(1);
   }
 
   fn op_cop3(&mut self, _: Instruction) {
-    self.exception(Exception::CoprocessorError);
+    self.coprocessor_unusable(3);
   }
 
   fn op_cop2(&mut self, instruction: Instruction) {
+    if !self.coprocessor_enabled(2) {
+      self.coprocessor_unusable(2);
+      return;
+    }
     panic!("unhandled GTE instruction: {:?}", instruction)
   }
 
@@ -810,7 +1461,10 @@ impl Cpu {
     let cur_v = self.out_regs[t.0 as usize];
 
     let aligned_addr = addr & !0x03;
-    let aligned_word = self.load32(aligned_addr);
+    let aligned_word = match self.load_mem(aligned_addr, Width::Word) {
+      Some(v) => v,
+      None => return,
+    };
 
     let v = match addr & 0x03 {
       0 => (cur_v & 0x00FFFFFF) | (aligned_word << 24),
@@ -832,7 +1486,10 @@ impl Cpu {
     let cur_v = self.out_regs[t.0 as usize];
 
     let aligned_addr = addr & !0x03;
-    let aligned_word = self.load32(aligned_addr);
+    let aligned_word = match self.load_mem(aligned_addr, Width::Word) {
+      Some(v) => v,
+      None => return,
+    };
 
     let v = match addr & 0x03 {
       0 => (cur_v & 0x00000000) | (aligned_word >> 0),
@@ -854,7 +1511,10 @@ impl Cpu {
     let v = self.reg(t);
 
     let aligned_addr = addr & !0x03;
-    let cur_mem = self.load32(aligned_addr);
+    let cur_mem = match self.load_mem(aligned_addr, Width::Word) {
+      Some(v) => v,
+      None => return,
+    };
 
     let mem = match addr & 0x03 {
       0 => (cur_mem & 0xFFFFFF00) | (v >> 24),
@@ -864,7 +1524,7 @@ impl Cpu {
       _ => unreachable!(),
     };
 
-    self.store32(aligned_addr, mem);
+    self.store_mem(aligned_addr, Width::Word, mem);
   }
 
   fn op_swr(&mut self, instruction: Instruction) {
@@ -876,7 +1536,10 @@ impl Cpu {
     let v = self.reg(t);
 
     let aligned_addr = addr & !0x03;
-    let cur_mem = self.load32(aligned_addr);
+    let cur_mem = match self.load_mem(aligned_addr, Width::Word) {
+      Some(v) => v,
+      None => return,
+    };
 
     let mem = match addr & 0x03 {
       0 => (cur_mem & 0x00000000) | (v << 0),
@@ -886,91 +1549,157 @@ impl Cpu {
       _ => unreachable!(),
     };
 
-    self.store32(aligned_addr, mem);
+    self.store_mem(aligned_addr, Width::Word, mem);
   }
 
-  fn op_lwc0(&mut self, instruction: Instruction) {
-    self.exception(Exception::CoprocessorError);
+  fn op_lwc0(&mut self, _: Instruction) {
+    self.coprocessor_unusable(0);
   }
-  fn op_lwc1(&mut self, instruction: Instruction) {
-    self.exception(Exception::CoprocessorError);
+  fn op_lwc1(&mut self, _: Instruction) {
+    self.coprocessor_unusable(1);
   }
   fn op_lwc2(&mut self, instruction: Instruction) {
+    if !self.coprocessor_enabled(2) {
+      self.coprocessor_unusable(2);
+      return;
+    }
     panic!("unhandled GTE LWC: {:08X}", instruction.0);
   }
-  fn op_lwc3(&mut self, instruction: Instruction) {
-    self.exception(Exception::CoprocessorError);
+  fn op_lwc3(&mut self, _: Instruction) {
+    self.coprocessor_unusable(3);
   }
 
-  fn op_swc0(&mut self, instruction: Instruction) {
-    self.exception(Exception::CoprocessorError);
+  fn op_swc0(&mut self, _: Instruction) {
+    self.coprocessor_unusable(0);
   }
-  fn op_swc1(&mut self, instruction: Instruction) {
-    self.exception(Exception::CoprocessorError);
+  fn op_swc1(&mut self, _: Instruction) {
+    self.coprocessor_unusable(1);
   }
   fn op_swc2(&mut self, instruction: Instruction) {
+    if !self.coprocessor_enabled(2) {
+      self.coprocessor_unusable(2);
+      return;
+    }
     panic!("unhandled GTE SWC: {:08X}", instruction.0);
   }
-  fn op_swc3(&mut self, instruction: Instruction) {
-    self.exception(Exception::CoprocessorError);
+  fn op_swc3(&mut self, _: Instruction) {
+    self.coprocessor_unusable(3);
   }
 
   fn op_illegal(&mut self, instruction: Instruction) {
-    println!("Illegal instruction {:?}!", instruction);
+    self.inter.console.message(&format!("Illegal instruction {:?}!", instruction));
     self.exception(Exception::IllegalInstruction);
   }
 
 }
 
+// ブレークポイントやトレース、バックエンドの設定はセーブステートに含めない
+impl_state!(Cpu {
+  pc, next_pc, regs, out_regs, inter, next_instruction,
+  sr, current_pc, cause, epc, bad_vaddr, jump_break,
+  load, hi, lo, branch, delay_slot, icache, cycles, hle,
+} => Cpu::after_load);
+
+// 命令キャッシュの1ライン (4ワード)
+#[derive(Debug, Clone, Copy)]
+struct ICacheLine {
+  // ビット31-12: タグ、ビット4-2: 最初の有効ワードのインデックス
+  tag_valid: u32,
+  line: [Instruction; 4],
+}
+
+impl ICacheLine {
+  fn new() -> Self {
+    // 起動時は全て無効
+    Self {
+      tag_valid: 0x10,
+      line: [Instruction(0x00BA_DBAD); 4],
+    }
+  }
+
+  fn tag(&self) -> u32 {
+    self.tag_valid & 0xFFFF_F000
+  }
+
+  fn valid_index(&self) -> u32 {
+    (self.tag_valid >> 2) & 0x7
+  }
+
+  fn set_tag_valid(&mut self, pc: u32) {
+    self.tag_valid = pc & 0x7FFF_F00C;
+  }
+
+  fn invalidate(&mut self) {
+    // インデックス4以上は全ワードが無効
+    self.tag_valid |= 0x10;
+  }
+
+  fn instruction(&self, index: u32) -> Instruction {
+    self.line[index as usize]
+  }
+
+  fn set_instruction(&mut self, index: u32, instruction: Instruction) {
+    self.line[index as usize] = instruction;
+  }
+}
+
+impl_state!(ICacheLine { tag_valid, line });
+
 #[derive(Debug, Clone, Copy)]
-struct RegisterIndex(u32);
+pub struct RegisterIndex(pub u32);
 
 #[derive(Debug, Clone, Copy)]
-struct Instruction(u32);
+pub struct Instruction(pub u32);
+
+impl_state!(RegisterIndex { 0 } => |index| match index.0 {
+  0..=31 => Ok(()),
+  _ => Err(invalid_state("Invalid register index")),
+});
+impl_state!(Instruction { 0 });
 
 impl Instruction {
-  fn function(&self) -> u32 {
+  pub fn function(&self) -> u32 {
     let Instruction(op) = self;
     op >> 26
   }
 
-  fn s(&self) -> RegisterIndex {
+  pub fn s(&self) -> RegisterIndex {
     let Instruction(op) = self;
     RegisterIndex((op >> 21) & 0x1F)
   }
 
-  fn t(&self) -> RegisterIndex {
+  pub fn t(&self) -> RegisterIndex {
     let Instruction(op) = self;
     RegisterIndex((op >> 16) & 0x1F)
   }
 
-  fn imm(&self) -> u32 {
+  pub fn imm(&self) -> u32 {
     let Instruction(op) = self;
     op & 0xFFFF
   }
 
-  fn imm_se(&self) -> u32 {
+  pub fn imm_se(&self) -> u32 {
     let Instruction(op) = self;
     let v = (op & 0xFFFF) as i16;
     v as u32
   }
 
-  fn d(&self) -> RegisterIndex {
+  pub fn d(&self) -> RegisterIndex {
     let Instruction(op) = self;
     RegisterIndex((op >> 11) & 0x1F)
   }
 
-  fn subfunction(&self) -> u32 {
+  pub fn subfunction(&self) -> u32 {
     let Instruction(op) = self;
     op & 0x3F
   }
 
-  fn shift(&self) -> u32 {
+  pub fn shift(&self) -> u32 {
     let Instruction(op) = self;
     (op >> 6) & 0x1F
   }
 
-  fn imm_jump(&self) -> u32 {
+  pub fn imm_jump(&self) -> u32 {
     let Instruction(op) = self;
     op & 0x03FF_FFFF
   }
@@ -980,90 +1709,102 @@ impl Instruction {
     (op >> 21) & 0x1F
   }
 
+  // 遅延スロットを持つ命令 (J, JAL, 条件分岐, JR, JALR)
+  pub fn is_branch(self) -> bool {
+    match self.function() {
+      0x01..=0x07 => true,
+      0x00 => matches!(self.subfunction(), 0x08 | 0x09),
+      _ => false,
+    }
+  }
+
+}
+
+pub fn decode(instruction: Instruction) -> OpHandler {
+  match instruction.function() {
+    0b000000 => match instruction.subfunction() {
+      0x00 => Cpu::op_sll,
+      0x02 => Cpu::op_srl,
+      0x03 => Cpu::op_sra,
+      0x04 => Cpu::op_sllv,
+      0x06 => Cpu::op_srlv,
+      0x07 => Cpu::op_srav,
+      0x08 => Cpu::op_jr,
+      0x09 => Cpu::op_jalr,
+      0x0C => Cpu::op_syscall,
+      0x0D => Cpu::op_break,
+      0x10 => Cpu::op_mfhi,
+      0x11 => Cpu::op_mthi,
+      0x12 => Cpu::op_mflo,
+      0x13 => Cpu::op_mtlo,
+      0x18 => Cpu::op_mult,
+      0x19 => Cpu::op_multu,
+      0x1A => Cpu::op_div,
+      0x1B => Cpu::op_divu,
+      0x20 => Cpu::op_add,
+      0x21 => Cpu::op_addu,
+      0x22 => Cpu::op_sub,
+      0x23 => Cpu::op_subu,
+      0x24 => Cpu::op_and,
+      0x25 => Cpu::op_or,
+      0x26 => Cpu::op_xor,
+      0x27 => Cpu::op_nor,
+      0x2A => Cpu::op_slt,
+      0x2B => Cpu::op_sltu,
+      _ => Cpu::op_illegal,
+    },
+    0x01 => Cpu::op_bxx,
+    0x02 => Cpu::op_j,
+    0x03 => Cpu::op_jal,
+    0x04 => Cpu::op_beq,
+    0x05 => Cpu::op_bne,
+    0x06 => Cpu::op_blez,
+    0x07 => Cpu::op_bgtz,
+    0x08 => Cpu::op_addi,
+    0x09 => Cpu::op_addiu,
+    0x0A => Cpu::op_slti,
+    0x0B => Cpu::op_sltiu,
+    0x0C => Cpu::op_andi,
+    0x0D => Cpu::op_ori,
+    0x0E => Cpu::op_xori,
+    0x0F => Cpu::op_lui,
+    0x10 => Cpu::op_cop0,
+    0x11 => Cpu::op_cop1,
+    0x12 => Cpu::op_cop2,
+    0x13 => Cpu::op_cop3,
+    0x20 => Cpu::op_lb,
+    0x21 => Cpu::op_lh,
+    0x22 => Cpu::op_lwl,
+    0x23 => Cpu::op_lw,
+    0x24 => Cpu::op_lbu,
+    0x25 => Cpu::op_lhu,
+    0x26 => Cpu::op_lwr,
+    0x28 => Cpu::op_sb,
+    0x29 => Cpu::op_sh,
+    0x2A => Cpu::op_swl,
+    0x2B => Cpu::op_sw,
+    0x2E => Cpu::op_swr,
+    0x30 => Cpu::op_lwc0,
+    0x31 => Cpu::op_lwc1,
+    0x32 => Cpu::op_lwc2,
+    0x33 => Cpu::op_lwc3,
+    0x38 => Cpu::op_swc0,
+    0x39 => Cpu::op_swc1,
+    0x3A => Cpu::op_swc2,
+    0x3B => Cpu::op_swc3,
+    _ => Cpu::op_illegal,
+  }
 }
 
 enum Exception {
+  Interrupt = 0x00,
   LoadAddressError = 0x04,
   StoreAddressError = 0x05,
+  BusErrorInstruction = 0x06,
+  BusErrorData = 0x07,
   SysCall = 0x08,
   Break = 0x09,
   IllegalInstruction = 0x0A,
   CoprocessorError = 0x0B,
   Overflow = 0x0C,
 }
-
-fn instruction_name(instruction: Instruction) -> &'static str {
-  match instruction.function() {
-    0b000000 => match instruction.subfunction() {
-      0x00 => "sll",
-      0x02 => "srl",
-      0x03 => "sra",
-      0x04 => "sllv",
-      0x06 => "srlv",
-      0x07 => "srav",
-      0x08 => "jr",
-      0x09 => "jalr",
-      0x0C => "syscall",
-      0x0D => "break",
-      0x10 => "mfhi",
-      0x11 => "mthi",
-      0x12 => "mflo",
-      0x13 => "mtlo",
-      0x18 => "mult",
-      0x19 => "multu",
-      0x1A => "div",
-      0x1B => "divu",
-      0x20 => "add",
-      0x21 => "addu",
-      0x22 => "sub",
-      0x23 => "subu",
-      0x24 => "and",
-      0x25 => "or",
-      0x26 => "xor",
-      0x27 => "nor",
-      0x2A => "slt",
-      0x2B => "sltu",
-      _ => "illegal",
-    },
-    0x01 => "bxx",
-    0x02 => "j",
-    0x03 => "jal",
-    0x04 => "beq",
-    0x05 => "bne",
-    0x06 => "blez",
-    0x07 => "bgtz",
-    0x08 => "addi",
-    0x09 => "addiu",
-    0x0A => "slti",
-    0x0B => "sltiu",
-    0x0C => "andi",
-    0x0D => "ori",
-    0x0E => "xori",
-    0x0F => "lui",
-    0x10 => "cop0",
-    0x11 => "cop1",
-    0x12 => "cop2",
-    0x13 => "cop3",
-    0x20 => "lb",
-    0x21 => "lh",
-    0x22 => "lwl",
-    0x23 => "lw",
-    0x24 => "lbu",
-    0x25 => "lhu",
-    0x26 => "lwr",
-    0x28 => "sb",
-    0x29 => "sh",
-    0x2A => "swl",
-    0x2B => "sw",
-    0x2E => "swr",
-    0x30 => "lwc0",
-    0x31 => "lwc1",
-    0x32 => "lwc2",
-    0x33 => "lwc3",
-    0x38 => "swc0",
-    0x39 => "swc1",
-    0x3A => "swc2",
-    0x3B => "swc3",
-    _ => "illegal",
-  }
-}
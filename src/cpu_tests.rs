@@ -0,0 +1,863 @@
+// 命令単位の CPU のテスト
+// RAM にアセンブルしたプログラムを置いてインタプリタで実行し、レジスタ/COP0/メモリの状態を確かめる。
+// キャッシュインタプリタと JIT でも同じ状態から実行し、インタプリタと食い違わないことを確かめる
+use crate::{audio::NullAudioSink, bios::Bios, cpu::{Cpu, CpuBackend, RegisterIndex}, disasm, gpu::Gpu, interconnect::Interconnect, lockstep::Lockstep, ram::{self, Ram}, savestate::{State, StateReader, StateWriter}, spu::Spu};
+
+const BASE: u32 = 0x8001_0000;
+const DATA: u32 = 0x8002_0000;
+// SR.BEV が0のときの一般例外のベクタ
+const EXCEPTION_VECTOR: u32 = 0x8000_0080;
+const MAX_STEPS: usize = 1000;
+
+const V0: usize = 2;
+const A0: usize = 4;
+const T0: usize = 8;
+const T1: usize = 9;
+const T2: usize = 10;
+const T3: usize = 11;
+const T4: usize = 12;
+const RA: usize = 31;
+
+// CAUSE の例外コード
+const LOAD_ADDRESS_ERROR: u32 = 0x04;
+const STORE_ADDRESS_ERROR: u32 = 0x05;
+const SYSCALL: u32 = 0x08;
+const BREAK: u32 = 0x09;
+const ILLEGAL_INSTRUCTION: u32 = 0x0A;
+const COPROCESSOR_UNUSABLE: u32 = 0x0B;
+const OVERFLOW: u32 = 0x0C;
+
+fn new_cpu() -> Cpu {
+  let inter = Interconnect::new(Bios::hle(), Ram::new(ram::RAM_SIZE_2MB), Gpu::headless(), Spu::new(Box::new(NullAudioSink)));
+  Cpu::new(inter)
+}
+
+// base に置いたプログラムの終わりのアドレスを返す。
+// ブロック単位で実行するバックエンドが終わりで止まれるように、終わりには無限ループを置く
+fn load_program(cpu: &mut Cpu, base: u32, source: &str) -> u32 {
+  let words = disasm::assemble(base, source).unwrap();
+  let end = base + words.len() as u32 * 4;
+  let terminator = disasm::assemble(end, "end: j end\n nop").unwrap();
+  for (i, &word) in words.iter().chain(&terminator).enumerate() {
+    cpu.inter.store32(base + i as u32 * 4, word);
+  }
+  cpu.set_pc(base);
+  end
+}
+
+fn run_until(cpu: &mut Cpu, target: u32) {
+  let mut state = StateWriter::new();
+  cpu.save(&mut state);
+  let state = state.finish();
+
+  for _ in 0..MAX_STEPS {
+    if cpu.pc() == target {
+      return check_backends(&state, target);
+    }
+    cpu.run_next_instruction();
+  }
+  panic!("Did not reach {:08X} (pc = {:08X})", target, cpu.pc());
+}
+
+// 同じ状態からブロック単位のバックエンドで target まで実行し、1ステップごとにインタプリタと比べる
+fn check_backends(state: &[u8], target: u32) {
+  for backend in [CpuBackend::CachedInterpreter, CpuBackend::Jit] {
+    let mut reference = new_cpu();
+    reference.load(&mut StateReader::new(state)).unwrap();
+    let mut test = new_cpu();
+    test.load(&mut StateReader::new(state)).unwrap();
+    test.set_backend(backend);
+
+    let mut lockstep = Lockstep::new(reference, test, None);
+    let mut reached = false;
+    for _ in 0..MAX_STEPS {
+      if lockstep.pc() == target {
+        reached = true;
+        break;
+      }
+      if let Err(divergence) = lockstep.step() {
+        panic!("{:?}: {}", backend, divergence);
+      }
+    }
+    assert!(reached, "{:?}: Did not reach {:08X} (pc = {:08X})", backend, target, lockstep.pc());
+  }
+}
+
+fn run_with(cpu: &mut Cpu, source: &str) {
+  let end = load_program(cpu, BASE, source);
+  run_until(cpu, end);
+}
+
+// プログラムの終わりまで実行する
+fn run(source: &str) -> Cpu {
+  let mut cpu = new_cpu();
+  run_with(&mut cpu, source);
+  cpu
+}
+
+// 例外のベクタに着くまで実行する
+fn run_to_exception(source: &str) -> Cpu {
+  let mut cpu = new_cpu();
+  load_program(&mut cpu, BASE, source);
+  run_until(&mut cpu, EXCEPTION_VECTOR);
+  cpu
+}
+
+fn exception_code(cpu: &Cpu) -> u32 {
+  (cpu.cause() >> 2) & 0x1F
+}
+
+fn branch_delay(cpu: &Cpu) -> bool {
+  cpu.cause() & (1 << 31) != 0
+}
+
+#[test]
+fn alu_register() {
+  let cpu = run("
+    li    $t0, 0x7FFF
+    li    $t1, -3
+    addu  $t2, $t0, $t1
+    subu  $t3, $t1, $t0
+    and   $t4, $t0, $t1
+    nop
+  ");
+  assert_eq!(cpu.gpr(T2), 0x7FFC);
+  assert_eq!(cpu.gpr(T3), 0xFFFF_7FFE);
+  assert_eq!(cpu.gpr(T4), 0x7FFD);
+
+  let cpu = run("
+    li    $t0, 0x1234F0F0
+    li    $t1, 0x0F0F
+    or    $t2, $t0, $t1
+    xor   $t3, $t0, $t1
+    nor   $t4, $t0, $t1
+  ");
+  assert_eq!(cpu.gpr(T2), 0x1234_FFFF);
+  assert_eq!(cpu.gpr(T3), 0x1234_FFFF);
+  assert_eq!(cpu.gpr(T4), 0xEDCB_0000);
+
+  let cpu = run("
+    li    $t0, -1
+    li    $t1, 1
+    slt   $t2, $t0, $t1
+    sltu  $t3, $t0, $t1
+    slt   $t4, $t1, $t0
+    add   $v0, $t0, $t1
+    sub   $a0, $t1, $t0
+  ");
+  assert_eq!(cpu.gpr(T2), 1);
+  assert_eq!(cpu.gpr(T3), 0);
+  assert_eq!(cpu.gpr(T4), 0);
+  assert_eq!(cpu.gpr(V0), 0);
+  assert_eq!(cpu.gpr(A0), 2);
+}
+
+#[test]
+fn alu_immediate() {
+  let cpu = run("
+    li    $t0, 0x10
+    addiu $t1, $t0, -0x20
+    addi  $t2, $t0, 0x7FFF
+    slti  $t3, $t1, -0x8
+    sltiu $t4, $t0, -0x1
+  ");
+  assert_eq!(cpu.gpr(T1), 0xFFFF_FFF0);
+  assert_eq!(cpu.gpr(T2), 0x800F);
+  assert_eq!(cpu.gpr(T3), 1);
+  // 即値は符号拡張してから符号なしで比べる
+  assert_eq!(cpu.gpr(T4), 1);
+
+  let cpu = run("
+    lui   $t0, 0xFFFF
+    ori   $t1, $t0, 0x8000
+    andi  $t2, $t1, 0x8001
+    xori  $t3, $t1, 0xFFFF
+  ");
+  assert_eq!(cpu.gpr(T0), 0xFFFF_0000);
+  // 論理演算の即値はゼロ拡張
+  assert_eq!(cpu.gpr(T1), 0xFFFF_8000);
+  assert_eq!(cpu.gpr(T2), 0x8000);
+  assert_eq!(cpu.gpr(T3), 0xFFFF_7FFF);
+}
+
+#[test]
+fn zero_register_is_not_written() {
+  let cpu = run("
+    li    $zero, 5
+    addiu $t0, $zero, 1
+  ");
+  assert_eq!(cpu.gpr(0), 0);
+  assert_eq!(cpu.gpr(T0), 1);
+}
+
+#[test]
+fn shifts() {
+  let cpu = run("
+    li    $t0, 0x80000011
+    sll   $t1, $t0, 4
+    srl   $t2, $t0, 4
+    sra   $t3, $t0, 4
+  ");
+  assert_eq!(cpu.gpr(T1), 0x0000_0110);
+  assert_eq!(cpu.gpr(T2), 0x0800_0001);
+  assert_eq!(cpu.gpr(T3), 0xF800_0001);
+
+  // シフト量は下位5ビットだけが使われる
+  let cpu = run("
+    li    $t0, 0x80000011
+    li    $a0, 0x24
+    sllv  $t1, $t0, $a0
+    srlv  $t2, $t0, $a0
+    srav  $t3, $t0, $a0
+  ");
+  assert_eq!(cpu.gpr(T1), 0x0000_0110);
+  assert_eq!(cpu.gpr(T2), 0x0800_0001);
+  assert_eq!(cpu.gpr(T3), 0xF800_0001);
+}
+
+#[test]
+fn multiply_and_divide() {
+  let cpu = run("
+    li    $t0, -2
+    li    $t1, 0x40000000
+    mult  $t0, $t1
+    mfhi  $t2
+    mflo  $t3
+    multu $t0, $t1
+    mfhi  $t4
+  ");
+  assert_eq!(cpu.gpr(T2), 0xFFFF_FFFF);
+  assert_eq!(cpu.gpr(T3), 0x8000_0000);
+  assert_eq!(cpu.gpr(T4), 0x3FFF_FFFF);
+  assert_eq!(cpu.hi_lo(), (0x3FFF_FFFF, 0x8000_0000));
+
+  let cpu = run("
+    li    $t0, -7
+    li    $t1, 2
+    div   $t0, $t1
+    mfhi  $t2
+    mflo  $t3
+    divu  $t0, $t1
+  ");
+  assert_eq!(cpu.gpr(T2), (-1i32) as u32);
+  assert_eq!(cpu.gpr(T3), (-3i32) as u32);
+  assert_eq!(cpu.hi_lo(), (1, 0x7FFF_FFFC));
+
+  let cpu = run("
+    li    $t0, 0x1234
+    li    $t1, 0x5678
+    mthi  $t0
+    mtlo  $t1
+  ");
+  assert_eq!(cpu.hi_lo(), (0x1234, 0x5678));
+}
+
+#[test]
+fn divide_edge_cases() {
+  // 0 で割ると商は被除数の符号で決まり、余りは被除数
+  let cpu = run("
+    li    $t0, 5
+    div   $t0, $zero
+  ");
+  assert_eq!(cpu.hi_lo(), (5, 0xFFFF_FFFF));
+
+  let cpu = run("
+    li    $t0, -5
+    div   $t0, $zero
+  ");
+  assert_eq!(cpu.hi_lo(), ((-5i32) as u32, 1));
+
+  let cpu = run("
+    li    $t0, 0x80000000
+    div   $t0, $zero
+  ");
+  assert_eq!(cpu.hi_lo(), (0x8000_0000, 1));
+
+  let cpu = run("
+    li    $t0, 0x80000000
+    divu  $t0, $zero
+  ");
+  assert_eq!(cpu.hi_lo(), (0x8000_0000, 0xFFFF_FFFF));
+
+  // 0x80000000 / -1 は商が 0x80000000、余りが0 (例外にはならない)
+  let cpu = run("
+    li    $t0, 0x80000000
+    li    $t1, -1
+    div   $t0, $t1
+  ");
+  assert_eq!(cpu.hi_lo(), (0, 0x8000_0000));
+}
+
+#[test]
+fn overflow_traps() {
+  for source in [
+    "li $t0, 0x7FFFFFFF\n li $t1, 1\n li $t2, 7\n add $t2, $t0, $t1",
+    "li $t0, 0x7FFFFFFF\n li $t2, 7\n addi $t2, $t0, 0x1",
+    "li $t0, 0x80000000\n li $t1, 1\n li $t2, 7\n sub $t2, $t0, $t1",
+  ] {
+    let cpu = run_to_exception(source);
+    assert_eq!(exception_code(&cpu), OVERFLOW, "{}", source);
+    // 書き込み先は変更されない
+    assert_eq!(cpu.gpr(T2), 7, "{}", source);
+    let words = disasm::assemble(BASE, source).unwrap();
+    assert_eq!(cpu.epc(), BASE + (words.len() as u32 - 1) * 4, "{}", source);
+    assert!(!branch_delay(&cpu));
+  }
+
+  // 符号なしの命令は例外にならない
+  let cpu = run("
+    li    $t0, 0x7FFFFFFF
+    addiu $t1, $t0, 0x1
+    addu  $t2, $t0, $t0
+    li    $t3, 0x80000000
+    subu  $t4, $t3, $t0
+  ");
+  assert_eq!(cpu.gpr(T1), 0x8000_0000);
+  assert_eq!(cpu.gpr(T2), 0xFFFF_FFFE);
+  assert_eq!(cpu.gpr(T4), 1);
+}
+
+#[test]
+fn loads() {
+  let mut cpu = new_cpu();
+  cpu.inter.store32(DATA, 0x8281_F0FF);
+  run_with(&mut cpu, "
+    lui   $a0, 0x8002
+    lb    $t0, 0($a0)
+    lbu   $t1, 0($a0)
+    lh    $t2, 2($a0)
+    lhu   $t3, 2($a0)
+    lw    $t4, 0($a0)
+    nop
+  ");
+  assert_eq!(cpu.gpr(T0), 0xFFFF_FFFF);
+  assert_eq!(cpu.gpr(T1), 0xFF);
+  assert_eq!(cpu.gpr(T2), 0xFFFF_8281);
+  assert_eq!(cpu.gpr(T3), 0x8281);
+  assert_eq!(cpu.gpr(T4), 0x8281_F0FF);
+}
+
+#[test]
+fn stores() {
+  let mut cpu = new_cpu();
+  cpu.inter.store32(DATA, 0);
+  cpu.inter.store32(DATA + 4, 0);
+  run_with(&mut cpu, "
+    lui   $a0, 0x8002
+    li    $t0, 0x12345678
+    sb    $t0, 1($a0)
+    sh    $t0, 2($a0)
+    sw    $t0, 4($a0)
+  ");
+  assert_eq!(cpu.inter.load32(DATA), 0x5678_7800);
+  assert_eq!(cpu.inter.load32(DATA + 4), 0x1234_5678);
+}
+
+#[test]
+fn load_delay_slot() {
+  let mut cpu = new_cpu();
+  cpu.inter.store32(DATA, 0x1111_1111);
+  // 遅延スロットの命令はロード前の値を読み、その次の命令から新しい値が見える
+  run_with(&mut cpu, "
+    lui   $a0, 0x8002
+    li    $t0, 5
+    lw    $t0, 0($a0)
+    move  $t1, $t0
+    move  $t2, $t0
+  ");
+  assert_eq!(cpu.gpr(T1), 5);
+  assert_eq!(cpu.gpr(T2), 0x1111_1111);
+
+  // 終わったときにはまだロードが反映されていない
+  let mut cpu = new_cpu();
+  cpu.inter.store32(DATA, 0x2222_2222);
+  run_with(&mut cpu, "
+    lui   $a0, 0x8002
+    li    $t0, 5
+    lw    $t0, 0($a0)
+  ");
+  assert_eq!(cpu.gpr(T0), 5);
+  assert_eq!(cpu.pending_load(), (T0 as u32, 0x2222_2222));
+}
+
+#[test]
+fn load_delay_overwritten_by_delay_slot() {
+  // 遅延スロットの命令が同じレジスタに書くと、その値が残る
+  let mut cpu = new_cpu();
+  cpu.inter.store32(DATA, 0x1111_1111);
+  run_with(&mut cpu, "
+    lui   $a0, 0x8002
+    lw    $t0, 0($a0)
+    li    $t0, 7
+    nop
+  ");
+  assert_eq!(cpu.gpr(T0), 7);
+
+  // 続けて同じレジスタにロードすると、後のロードの値が残る
+  let mut cpu = new_cpu();
+  cpu.inter.store32(DATA, 0x1111_1111);
+  cpu.inter.store32(DATA + 4, 0x2222_2222);
+  run_with(&mut cpu, "
+    lui   $a0, 0x8002
+    lw    $t0, 0($a0)
+    lw    $t0, 4($a0)
+    nop
+  ");
+  assert_eq!(cpu.gpr(T0), 0x2222_2222);
+
+  // 別のレジスタへのロードはそれぞれ反映される
+  let mut cpu = new_cpu();
+  cpu.inter.store32(DATA, 0x1111_1111);
+  cpu.inter.store32(DATA + 4, 0x2222_2222);
+  run_with(&mut cpu, "
+    lui   $a0, 0x8002
+    lw    $t0, 0($a0)
+    lw    $t1, 4($a0)
+    move  $t2, $t0
+    nop
+  ");
+  assert_eq!(cpu.gpr(T0), 0x1111_1111);
+  assert_eq!(cpu.gpr(T1), 0x2222_2222);
+  assert_eq!(cpu.gpr(T2), 0x1111_1111);
+}
+
+#[test]
+fn lwl_lwr_merge() {
+  let mut cpu = new_cpu();
+  cpu.inter.store32(DATA, 0x4433_2211);
+  cpu.inter.store32(DATA + 4, 0x8877_6655);
+  let expected_lwl = [0x11AA_AAAA, 0x2211_AAAA, 0x3322_11AA, 0x4433_2211];
+  let expected_lwr = [0x4433_2211, 0xAA44_3322, 0xAAAA_4433, 0xAAAA_AA44];
+  for offset in 0..4 {
+    let source = format!("
+      lui   $a0, 0x8002
+      li    $t0, 0xAAAAAAAA
+      li    $t1, 0xAAAAAAAA
+      lwl   $t0, {}($a0)
+      lwr   $t1, {}($a0)
+      nop
+    ", offset, offset);
+    run_with(&mut cpu, &source);
+    assert_eq!(cpu.gpr(T0), expected_lwl[offset], "lwl offset {}", offset);
+    assert_eq!(cpu.gpr(T1), expected_lwr[offset], "lwr offset {}", offset);
+  }
+
+  // LWR を LWL の遅延スロットに置くと、反映前のロード結果と合成される
+  run_with(&mut cpu, "
+    lui   $a0, 0x8002
+    li    $t0, 0
+    lwr   $t0, 1($a0)
+    lwl   $t0, 4($a0)
+    nop
+  ");
+  assert_eq!(cpu.gpr(T0), 0x5544_3322);
+}
+
+#[test]
+fn swl_swr_merge() {
+  let mut cpu = new_cpu();
+  let expected_swl = [0xAAAA_AA44, 0xAAAA_4433, 0xAA44_3322, 0x4433_2211];
+  let expected_swr = [0x4433_2211, 0x3322_11AA, 0x2211_AAAA, 0x11AA_AAAA];
+  for offset in 0..4 {
+    cpu.inter.store32(DATA, 0xAAAA_AAAA);
+    cpu.inter.store32(DATA + 4, 0xAAAA_AAAA);
+    let source = format!("
+      lui   $a0, 0x8002
+      li    $t0, 0x44332211
+      swl   $t0, {}($a0)
+      swr   $t0, {}($a0)
+    ", offset, offset + 4);
+    run_with(&mut cpu, &source);
+    assert_eq!(cpu.inter.load32(DATA), expected_swl[offset], "swl offset {}", offset);
+    assert_eq!(cpu.inter.load32(DATA + 4), expected_swr[offset], "swr offset {}", offset);
+  }
+}
+
+#[test]
+fn branches() {
+  // 分岐が成立したときも遅延スロットは実行される
+  for (branch, taken) in [
+    ("beq   $t0, $t0, target", true),
+    ("beq   $t0, $t1, target", false),
+    ("bne   $t0, $t1, target", true),
+    ("bne   $t0, $t0, target", false),
+    ("blez  $t1, target", true),
+    ("blez  $zero, target", true),
+    ("blez  $t0, target", false),
+    ("bgtz  $t0, target", true),
+    ("bgtz  $zero, target", false),
+    ("bltz  $t1, target", true),
+    ("bltz  $zero, target", false),
+    ("bgez  $zero, target", true),
+    ("bgez  $t1, target", false),
+  ] {
+    let source = format!("
+      li    $t0, 1
+      li    $t1, -1
+      li    $t2, 0
+      li    $t3, 0
+      {}
+      addiu $t2, $t2, 1
+      li    $t3, 1
+    target:
+      nop
+    ", branch);
+    let cpu = run(&source);
+    assert_eq!(cpu.gpr(T2), 1, "{}: delay slot", branch);
+    assert_eq!(cpu.gpr(T3), !taken as u32, "{}", branch);
+  }
+}
+
+#[test]
+fn jumps_and_links() {
+  let cpu = run("
+    jal   function
+    nop
+    b     end
+    nop
+  function:
+    jr    $ra
+    li    $t0, 1
+  end:
+  ");
+  assert_eq!(cpu.gpr(RA), BASE + 8);
+  assert_eq!(cpu.gpr(T0), 1);
+
+  // JALR の戻り先は遅延スロットの次
+  let cpu = run("
+    lui   $t1, 0x8001
+    ori   $t1, $t1, 0x14
+    jalr  $t2, $t1
+    nop
+    li    $t3, 1
+    nop
+  ");
+  assert_eq!(cpu.gpr(T2), BASE + 16);
+  assert_ne!(cpu.gpr(T3), 1);
+
+  for branch in ["bltzal $t1, target", "bgezal $t1, target"] {
+    let source = format!("
+      li    $t1, -1
+      li    $ra, 0
+      {}
+      nop
+      nop
+    target:
+      nop
+    ", branch);
+    let cpu = run(&source);
+    // BLTZAL/BGEZAL は分岐しなくても $ra に書く
+    assert_eq!(cpu.gpr(RA), BASE + 16, "{}", branch);
+  }
+}
+
+#[test]
+fn syscall_and_break() {
+  let cpu = run_to_exception("
+    nop
+    syscall
+  ");
+  assert_eq!(exception_code(&cpu), SYSCALL);
+  assert_eq!(cpu.epc(), BASE + 4);
+  assert!(!branch_delay(&cpu));
+
+  let cpu = run_to_exception("break 0x100");
+  assert_eq!(exception_code(&cpu), BREAK);
+  assert_eq!(cpu.epc(), BASE);
+}
+
+#[test]
+fn branch_delay_exception() {
+  // 遅延スロットでの例外は EPC が分岐命令を指し、CAUSE.BD が立つ
+  let cpu = run_to_exception("
+    nop
+    beq   $zero, $zero, 0x80010100
+    syscall
+  ");
+  assert_eq!(exception_code(&cpu), SYSCALL);
+  assert_eq!(cpu.epc(), BASE + 4);
+  assert!(branch_delay(&cpu));
+
+  // 分岐しないときも同じ
+  let cpu = run_to_exception("
+    bne   $zero, $zero, 0x80010100
+    break
+  ");
+  assert_eq!(exception_code(&cpu), BREAK);
+  assert_eq!(cpu.epc(), BASE);
+  assert!(branch_delay(&cpu));
+
+  let cpu = run_to_exception("
+    lui   $t0, 0x8001
+    jr    $t0
+    add   $t1, $t0, $t0
+  ");
+  assert_eq!(exception_code(&cpu), OVERFLOW);
+  assert_eq!(cpu.epc(), BASE + 4);
+  assert!(branch_delay(&cpu));
+}
+
+#[test]
+fn exception_mode_stack() {
+  // 例外で KU/IE のスタックが積まれ、RFE で戻る
+  let mut cpu = new_cpu();
+  load_program(&mut cpu, BASE, "
+    li    $t0, 0x3D
+    mtc0  $t0, $sr
+    syscall
+  ");
+  run_until(&mut cpu, EXCEPTION_VECTOR);
+  assert_eq!(cpu.sr() & 0x3F, 0x34);
+  run_with(&mut cpu, "
+    rfe
+    mfc0  $t0, $sr
+    nop
+  ");
+  assert_eq!(cpu.gpr(T0) & 0x3F, 0x3D);
+}
+
+#[test]
+fn cop0_registers() {
+  let cpu = run("
+    li    $t0, 0xFFFFFFFF
+    mtc0  $t0, $cause
+    mfc0  $t1, $cause
+    move  $t4, $t1
+    mfc0  $t2, $prid
+    mtc0  $t0, $16
+    mfc0  $t3, $16
+    nop
+  ");
+  // CAUSE はソフトウェア割り込みのビットだけ書ける
+  assert_eq!(cpu.gpr(T1), 0x300);
+  assert_eq!(cpu.gpr(T2), 2);
+  // 存在しないレジスタへの書き込みは無視され、0 が読める
+  assert_eq!(cpu.gpr(T3), 0);
+  // MFC0 にも遅延スロットがある
+  assert_ne!(cpu.gpr(T4), 0x300);
+}
+
+#[test]
+fn isolated_cache_partial_stores() {
+  // キャッシュ分離中の SB/SH は命令キャッシュのワードのうち、そのバイト/ハーフワードだけを書き換える
+  let cpu = run("
+    lui   $t0, 0xFFFE
+    li    $t1, 0x800
+    sw    $t1, 0x130($t0)
+    lui   $t2, 0x1
+    mtc0  $t2, $sr
+    lui   $a0, 0x8002
+    li    $t3, -1
+    sw    $t3, 0($a0)
+    sb    $zero, 1($a0)
+    li    $t4, 0x5678
+    sh    $t4, 2($a0)
+    lw    $t1, 0($a0)
+    mtc0  $zero, $sr
+    nop
+  ");
+  assert_eq!(cpu.gpr(T1), 0x5678_00FF);
+}
+
+#[test]
+fn coprocessor_unusable() {
+  for (source, coprocessor) in [
+    ("mfc1  $t0, $0", 1),
+    ("cop3  0x0", 3),
+    ("lwc1  $0, 0($zero)", 1),
+    ("swc3  $0, 0($zero)", 3),
+    ("lwc0  $0, 0($zero)", 0),
+    // CU2 が立っていないので GTE は使えない
+    ("rtps", 2),
+    ("mfc2  $t0, $0", 2),
+    ("lwc2  $0, 0($zero)", 2),
+  ] {
+    let cpu = run_to_exception(source);
+    assert_eq!(exception_code(&cpu), COPROCESSOR_UNUSABLE, "{}", source);
+    assert_eq!((cpu.cause() >> 28) & 3, coprocessor, "{}", source);
+    assert_eq!(cpu.epc(), BASE, "{}", source);
+  }
+
+  // ユーザーモードでは CU0 が立っていないと COP0 は使えない
+  let mut cpu = new_cpu();
+  let user_code = 0x0003_0000;
+  load_program(&mut cpu, user_code, "mfc0 $t0, $sr");
+  load_program(&mut cpu, BASE, "
+    li    $t0, 0x8
+    mtc0  $t0, $sr
+    lui   $t1, 0x3
+    jr    $t1
+    rfe
+  ");
+  run_until(&mut cpu, EXCEPTION_VECTOR);
+  assert_eq!(exception_code(&cpu), COPROCESSOR_UNUSABLE);
+  assert_eq!((cpu.cause() >> 28) & 3, 0);
+  assert_eq!(cpu.epc(), user_code);
+}
+
+#[test]
+fn illegal_instruction() {
+  let cpu = run_to_exception(".word 0xFC000000");
+  assert_eq!(exception_code(&cpu), ILLEGAL_INSTRUCTION);
+  assert_eq!(cpu.epc(), BASE);
+}
+
+#[test]
+fn address_errors() {
+  let cpu = run_to_exception("
+    lui   $a0, 0x8002
+    li    $t0, 7
+    lw    $t0, 2($a0)
+  ");
+  assert_eq!(exception_code(&cpu), LOAD_ADDRESS_ERROR);
+  assert_eq!(cpu.cop0(8), DATA + 2);
+  assert_eq!(cpu.gpr(T0), 7);
+
+  let cpu = run_to_exception("
+    lui   $a0, 0x8002
+    sh    $zero, 1($a0)
+  ");
+  assert_eq!(exception_code(&cpu), STORE_ADDRESS_ERROR);
+  assert_eq!(cpu.cop0(8), DATA + 1);
+
+  // 分岐先が揃っていないとフェッチでアドレスエラーになる
+  let cpu = run_to_exception("
+    lui   $t0, 0x8001
+    ori   $t0, $t0, 0x2
+    jr    $t0
+    nop
+  ");
+  assert_eq!(exception_code(&cpu), LOAD_ADDRESS_ERROR);
+  assert_eq!(cpu.cop0(8), BASE + 2);
+  assert_eq!(cpu.epc(), BASE + 2);
+}
+
+#[test]
+fn io8_access_past_region_end() {
+  // 拡張領域2 (0x1F80_2000～0x1F80_2041) の終わりをまたぐワードアクセス
+  let cpu = run("
+    lui   $a0, 0xBF80
+    li    $t0, 0x12345678
+    sw    $t0, 0x2040($a0)
+    lw    $t1, 0x2040($a0)
+    nop
+  ");
+  // 0x41 は POST 表示で、はみ出したバイトはオープンバス
+  assert_eq!(cpu.gpr(T1), 0xFFFF_56FF);
+}
+
+#[test]
+fn interrupt_after_store() {
+  // DICR の強制割り込みを書いた直後の命令の前で割り込みが入る
+  let cpu = run_to_exception("
+    lui   $t0, 0x1F80
+    li    $t1, 0x8
+    sw    $t1, 0x1074($t0)
+    li    $t1, 0x401
+    mtc0  $t1, $sr
+    li    $t2, 0
+    ori   $t1, $zero, 0x8000
+    sw    $t1, 0x10F4($t0)
+    addiu $t2, $t2, 1
+    addiu $t2, $t2, 1
+  ");
+  assert_eq!(exception_code(&cpu), 0);
+  assert_eq!(cpu.epc(), BASE + 32);
+  assert_eq!(cpu.gpr(T2), 0);
+}
+
+#[test]
+fn interrupt_during_chopped_dma() {
+  // チョッピングした OTC の DMA の間も CPU は進み、終わったサイクルで割り込みが入る
+  let mut cpu = run_to_exception("
+    lui   $t0, 0x1F80
+    li    $t1, 0x8
+    sw    $t1, 0x1074($t0)
+    li    $t1, 0x00C00000
+    sw    $t1, 0x10F4($t0)
+    lui   $t1, 0x0800
+    sw    $t1, 0x10F0($t0)
+    lui   $t1, 0x8002
+    ori   $t1, $t1, 0x7C
+    sw    $t1, 0x10E0($t0)
+    li    $t1, 32
+    sw    $t1, 0x10E4($t0)
+    li    $t1, 0x401
+    mtc0  $t1, $sr
+    li    $t2, 0
+    li    $t1, 0x11310102
+    sw    $t1, 0x10E8($t0)
+    addiu $t2, $t2, 1
+    addiu $t2, $t2, 1
+    addiu $t2, $t2, 1
+  loop:
+    b     loop
+    addiu $t2, $t2, 1
+  ");
+  assert_eq!(exception_code(&cpu), 0);
+  assert!(cpu.gpr(T2) > 3);
+  // OTC は末尾から逆順に前のエントリを指すリストを作る
+  assert_eq!(cpu.inter.load32(DATA), 0x00FF_FFFF);
+}
+
+#[test]
+fn expansion_base_address_is_latched() {
+  // 拡張領域の基底アドレスに変な値を書いても止まらず、書いた値が読める
+  let cpu = run("
+    lui   $t0, 0x1F80
+    lui   $t1, 0x1F10
+    sw    $t1, 0x1000($t0)
+    lw    $t2, 0x1000($t0)
+    sw    $zero, 0x1004($t0)
+    lw    $t3, 0x1004($t0)
+    nop
+  ");
+  assert_eq!(cpu.gpr(T2), 0x1F10_0000);
+  assert_eq!(cpu.gpr(T3), 0);
+}
+
+#[test]
+fn dma_to_unemulated_devices_completes() {
+  // エミュレートしていないデバイスや、リンクリストが使えないチャンネルでも転送は終わり、完了フラグが立つ
+  for (channel, chcr) in [
+    // MDEC 入力 (RAM -> デバイス)
+    (0, 0x1100_0001u32),
+    // CD-ROM (デバイス -> RAM)
+    (3, 0x1100_0000),
+    // PIO へのリンクリスト
+    (5, 0x0100_0401),
+    // OTC の RAM -> デバイス
+    (6, 0x1100_0001),
+  ] {
+    let base = 0x1080 + channel * 0x10;
+    let mut cpu = run(&format!("
+      lui   $t0, 0x1F80
+      lui   $t1, {dicr:#X}
+      sw    $t1, 0x10F4($t0)
+      li    $t1, {dpcr:#X}
+      sw    $t1, 0x10F0($t0)
+      lui   $t1, 0x8002
+      sw    $t1, {madr:#X}($t0)
+      li    $t1, 4
+      sw    $t1, {bcr:#X}($t0)
+      lui   $t1, {chcr_hi:#X}
+      ori   $t1, $t1, {chcr_lo:#X}
+      sw    $t1, {chcr:#X}($t0)
+      nop
+      nop
+      nop
+      nop
+    ", dicr = 0x80 | 1 << channel, dpcr = 8 << (channel * 4), madr = base, bcr = base + 4,
+      chcr_hi = chcr >> 16, chcr_lo = chcr & 0xFFFF, chcr = base + 8));
+    let dicr = cpu.inter.load32(0x1F80_10F4);
+    assert_ne!(dicr & (1 << (24 + channel)), 0, "channel {}", channel);
+    assert_eq!(cpu.inter.load32(0x1F80_1088 + channel * 0x10) & 0x0100_0000, 0, "channel {}", channel);
+  }
+}
+
+#[test]
+fn savestate_rejects_invalid_register_index() {
+  let mut index = RegisterIndex(0);
+  assert!(index.load(&mut StateReader::new(&31u32.to_le_bytes())).is_ok());
+  assert!(index.load(&mut StateReader::new(&32u32.to_le_bytes())).is_err());
+}
@@ -0,0 +1,546 @@
+// MIPS R3000A (+ COP0/GTE) の逆アセンブラとアセンブラ
+// 書式は両方向で同じ: "addiu   $sp, $sp, -0x18", "lw      $t0, 0x10($sp)", "beq     $t0, $zero, 0x80010020"
+// 符号付きの即値は符号付き16進数、符号なしの即値は16進数、シフト量は10進数、分岐先は絶対アドレスで書く。
+// trace-diff からも使うので、このモジュールは他のモジュールに依存しない
+use std::collections::HashMap;
+
+const REGISTER_NAMES: [&str; 32] = [
+  "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3",
+  "t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7",
+  "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7",
+  "t8", "t9", "k0", "k1", "gp", "sp", "fp", "ra",
+];
+
+// 名前のある COP0 レジスタ
+const COP0_NAMES: [(u32, &str); 11] = [
+  (3, "bpc"), (5, "bda"), (6, "tar"), (7, "dcic"), (8, "badvaddr"), (9, "bdam"),
+  (11, "bpcm"), (12, "sr"), (13, "cause"), (14, "epc"), (15, "prid"),
+];
+
+const SPECIAL_NAMES: [(u32, &str); 28] = [
+  (0x00, "sll"), (0x02, "srl"), (0x03, "sra"), (0x04, "sllv"), (0x06, "srlv"), (0x07, "srav"),
+  (0x08, "jr"), (0x09, "jalr"), (0x0C, "syscall"), (0x0D, "break"),
+  (0x10, "mfhi"), (0x11, "mthi"), (0x12, "mflo"), (0x13, "mtlo"),
+  (0x18, "mult"), (0x19, "multu"), (0x1A, "div"), (0x1B, "divu"),
+  (0x20, "add"), (0x21, "addu"), (0x22, "sub"), (0x23, "subu"),
+  (0x24, "and"), (0x25, "or"), (0x26, "xor"), (0x27, "nor"), (0x2A, "slt"), (0x2B, "sltu"),
+];
+
+const BXX_NAMES: [(u32, &str); 4] = [(0x00, "bltz"), (0x01, "bgez"), (0x10, "bltzal"), (0x11, "bgezal")];
+
+// 命令番号 (ビット26-31) ごとの即値命令、分岐、ロード/ストア
+const PRIMARY_NAMES: [(u32, &str); 26] = [
+  (0x02, "j"), (0x03, "jal"), (0x04, "beq"), (0x05, "bne"), (0x06, "blez"), (0x07, "bgtz"),
+  (0x08, "addi"), (0x09, "addiu"), (0x0A, "slti"), (0x0B, "sltiu"),
+  (0x0C, "andi"), (0x0D, "ori"), (0x0E, "xori"), (0x0F, "lui"),
+  (0x20, "lb"), (0x21, "lh"), (0x22, "lwl"), (0x23, "lw"), (0x24, "lbu"), (0x25, "lhu"), (0x26, "lwr"),
+  (0x28, "sb"), (0x29, "sh"), (0x2A, "swl"), (0x2B, "sw"), (0x2E, "swr"),
+];
+
+// GTE のコマンド (ビット0-5)
+const GTE_NAMES: [(u32, &str); 22] = [
+  (0x01, "rtps"), (0x06, "nclip"), (0x0C, "op"), (0x10, "dpcs"), (0x11, "intpl"), (0x12, "mvmva"),
+  (0x13, "ncds"), (0x14, "cdp"), (0x16, "ncdt"), (0x1B, "nccs"), (0x1C, "cc"), (0x1E, "ncs"),
+  (0x20, "nct"), (0x28, "sqr"), (0x29, "dcpl"), (0x2A, "dpct"), (0x2D, "avsz3"), (0x2E, "avsz4"),
+  (0x30, "rtpt"), (0x3D, "gpf"), (0x3E, "gpl"), (0x3F, "ncct"),
+];
+
+// GTE コマンドのフィールド (sf: ビット19, mx: 17-18, v: 15-16, cv: 13-14, lm: 10)
+const GTE_FIELDS: u32 = (1 << 19) | (3 << 17) | (3 << 15) | (3 << 13) | (1 << 10);
+
+fn lookup(table: &[(u32, &'static str)], key: u32) -> Option<&'static str> {
+  table.iter().find(|&&(k, _)| k == key).map(|&(_, name)| name)
+}
+
+fn reverse_lookup(table: &[(u32, &str)], name: &str) -> Option<u32> {
+  table.iter().find(|&&(_, n)| n == name).map(|&(k, _)| k)
+}
+
+fn reg(index: u32) -> String {
+  format!("${}", REGISTER_NAMES[index as usize & 31])
+}
+
+fn cop_reg(cop: u32, index: u32) -> String {
+  match (cop, lookup(&COP0_NAMES, index)) {
+    (0, Some(name)) => format!("${}", name),
+    _ => format!("${}", index),
+  }
+}
+
+fn signed_hex(val: i16) -> String {
+  match val < 0 {
+    true => format!("-0x{:X}", -(val as i32)),
+    false => format!("0x{:X}", val),
+  }
+}
+
+// pc はその命令のアドレス (分岐先の計算に使う)
+pub fn disassemble(pc: u32, word: u32) -> String {
+  let (mnemonic, operands) = decode(pc, word).unwrap_or_else(|| (".word", format!("0x{:08X}", word)));
+  match operands.is_empty() {
+    true => mnemonic.to_string(),
+    false => format!("{:<8}{}", mnemonic, operands),
+  }
+}
+
+fn decode(pc: u32, word: u32) -> Option<(&'static str, String)> {
+  let op = word >> 26;
+  let s = (word >> 21) & 0x1F;
+  let t = (word >> 16) & 0x1F;
+  let d = (word >> 11) & 0x1F;
+  let shift = (word >> 6) & 0x1F;
+  let imm = word & 0xFFFF;
+  let imm_se = imm as i16;
+  let branch_target = pc.wrapping_add(4).wrapping_add((imm_se as i32 as u32) << 2);
+
+  let decoded = match op {
+    0x00 => {
+      let funct = word & 0x3F;
+      let name = lookup(&SPECIAL_NAMES, funct)?;
+      let operands = match funct {
+        0x00 if word == 0 => return Some(("nop", String::new())),
+        0x00 | 0x02 | 0x03 => format!("{}, {}, {}", reg(d), reg(t), shift),
+        0x04 | 0x06 | 0x07 => format!("{}, {}, {}", reg(d), reg(t), reg(s)),
+        0x08 => reg(s),
+        0x09 if d == 31 => reg(s),
+        0x09 => format!("{}, {}", reg(d), reg(s)),
+        0x0C | 0x0D => match (word >> 6) & 0xF_FFFF {
+          0 => String::new(),
+          code => format!("0x{:X}", code),
+        },
+        0x10 | 0x12 => reg(d),
+        0x11 | 0x13 => reg(s),
+        0x18..=0x1B => format!("{}, {}", reg(s), reg(t)),
+        0x21 if t == 0 => return Some(("move", format!("{}, {}", reg(d), reg(s)))),
+        _ => format!("{}, {}, {}", reg(d), reg(s), reg(t)),
+      };
+      (name, operands)
+    }
+    // CPU と同じく rt のビット0で bgez/bltz、ビット1-4が 0b1000 のときだけリンクする
+    0x01 => {
+      let link = if t & 0x1E == 0x10 { 0x10 } else { 0x00 };
+      (lookup(&BXX_NAMES, link | (t & 1))?, format!("{}, 0x{:08X}", reg(s), branch_target))
+    }
+    0x02 | 0x03 => {
+      let target = (pc.wrapping_add(4) & 0xF000_0000) | ((word & 0x03FF_FFFF) << 2);
+      (lookup(&PRIMARY_NAMES, op)?, format!("0x{:08X}", target))
+    }
+    0x04 if s == 0 && t == 0 => ("b", format!("0x{:08X}", branch_target)),
+    0x04 | 0x05 => (lookup(&PRIMARY_NAMES, op)?, format!("{}, {}, 0x{:08X}", reg(s), reg(t), branch_target)),
+    0x06 | 0x07 => (lookup(&PRIMARY_NAMES, op)?, format!("{}, 0x{:08X}", reg(s), branch_target)),
+    0x09 if s == 0 => ("li", format!("{}, {}", reg(t), signed_hex(imm_se))),
+    0x08..=0x0B => (lookup(&PRIMARY_NAMES, op)?, format!("{}, {}, {}", reg(t), reg(s), signed_hex(imm_se))),
+    0x0C..=0x0E => (lookup(&PRIMARY_NAMES, op)?, format!("{}, {}, 0x{:X}", reg(t), reg(s), imm)),
+    0x0F => ("lui", format!("{}, 0x{:X}", reg(t), imm)),
+    0x10..=0x13 => return decode_cop(pc, op & 3, word),
+    0x20..=0x2E => (lookup(&PRIMARY_NAMES, op)?, format!("{}, {}({})", reg(t), signed_hex(imm_se), reg(s))),
+    0x30..=0x33 | 0x38..=0x3B => {
+      let name = ["lwc0", "lwc1", "lwc2", "lwc3", "swc0", "swc1", "swc2", "swc3"][(((op >> 1) & 4) | (op & 3)) as usize];
+      (name, format!("${}, {}({})", t, signed_hex(imm_se), reg(s)))
+    }
+    _ => return None,
+  };
+  Some(decoded)
+}
+
+fn decode_cop(pc: u32, cop: u32, word: u32) -> Option<(&'static str, String)> {
+  let t = (word >> 16) & 0x1F;
+  let d = (word >> 11) & 0x1F;
+  let names = |names: [&'static str; 4]| names[cop as usize];
+  if word & (1 << 25) != 0 {
+    return match cop {
+      // RFE
+      0 if word & 0x01FF_FFFF == 0x10 => Some(("rfe", String::new())),
+      2 => {
+        let name = lookup(&GTE_NAMES, word & 0x3F)?;
+        if word & 0x01FF_FFC0 & !GTE_FIELDS != 0 {
+          return None;
+        }
+        let mut fields = Vec::new();
+        if word & (1 << 19) != 0 {
+          fields.push("sf".to_string());
+        }
+        if word & (1 << 10) != 0 {
+          fields.push("lm".to_string());
+        }
+        for (field, shift) in [("mx", 17), ("v", 15), ("cv", 13)] {
+          let val = (word >> shift) & 3;
+          if val != 0 {
+            fields.push(format!("{}={}", field, val));
+          }
+        }
+        Some((name, fields.join(", ")))
+      }
+      _ => Some((names(["cop0", "cop1", "cop2", "cop3"]), format!("0x{:X}", word & 0x01FF_FFFF))),
+    };
+  }
+  let rs = (word >> 21) & 0x1F;
+  // MFCz などの下位11ビットは0
+  if rs <= 0x06 && word & 0x7FF != 0 {
+    return None;
+  }
+  let decoded = match rs {
+    0x00 => (names(["mfc0", "mfc1", "mfc2", "mfc3"]), format!("{}, {}", reg(t), cop_reg(cop, d))),
+    0x02 => (names(["cfc0", "cfc1", "cfc2", "cfc3"]), format!("{}, ${}", reg(t), d)),
+    0x04 => (names(["mtc0", "mtc1", "mtc2", "mtc3"]), format!("{}, {}", reg(t), cop_reg(cop, d))),
+    0x06 => (names(["ctc0", "ctc1", "ctc2", "ctc3"]), format!("{}, ${}", reg(t), d)),
+    0x08 if t <= 1 => {
+      let name = match t {
+        0 => names(["bc0f", "bc1f", "bc2f", "bc3f"]),
+        _ => names(["bc0t", "bc1t", "bc2t", "bc3t"]),
+      };
+      let target = pc.wrapping_add(4).wrapping_add(((word & 0xFFFF) as i16 as i32 as u32) << 2);
+      (name, format!("0x{:08X}", target))
+    }
+    _ => return None,
+  };
+  Some(decoded)
+}
+
+// 複数行のプログラムを base から並べてアセンブルする。
+// '#' 以降はコメント、"name:" はラベル (分岐/ジャンプ先に書ける)。
+// 擬似命令: nop, move, li (値に応じて addiu / lui / lui+ori), b、生の値は .word
+pub fn assemble(base: u32, source: &str) -> Result<Vec<u32>, String> {
+  let mut labels = HashMap::new();
+  let mut lines = Vec::new();
+  let mut pc = base;
+  for (number, line) in source.lines().enumerate() {
+    let mut line = line.split('#').next().unwrap().trim();
+    while let Some((label, rest)) = line.split_once(':') {
+      let label = label.trim();
+      if label.is_empty() || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+        break;
+      }
+      if labels.insert(label.to_string(), pc).is_some() {
+        return Err(format!("line {}: Duplicate label: {}", number + 1, label));
+      }
+      line = rest.trim();
+    }
+    if line.is_empty() {
+      continue;
+    }
+    let (mnemonic, operands) = match line.split_once(char::is_whitespace) {
+      Some((mnemonic, operands)) => (mnemonic, operands.split(',').map(str::trim).collect()),
+      None => (line, Vec::new()),
+    };
+    let size = match mnemonic {
+      "li" => li_words(operands.get(1).copied().unwrap_or("0"))
+        .map_err(|e| format!("line {}: {}", number + 1, e))?
+        .len() as u32,
+      _ => 1,
+    };
+    lines.push((number + 1, pc, mnemonic, operands));
+    pc = pc.wrapping_add(size * 4);
+  }
+
+  let mut words = Vec::new();
+  for (number, pc, mnemonic, operands) in lines {
+    let encoded = Assembler { pc, labels: &labels, operands: &operands }
+      .encode(mnemonic)
+      .map_err(|e| format!("line {}: {}", number, e))?;
+    words.extend(encoded);
+  }
+  Ok(words)
+}
+
+// li の即値をどの命令で作るか (rt は後で入れる)
+fn li_words(val: &str) -> Result<Vec<u32>, String> {
+  let val = parse_number(val)? as u32;
+  if (val as i32) >= i16::MIN as i32 && (val as i32) <= i16::MAX as i32 {
+    return Ok(vec![(0x09 << 26) | (val & 0xFFFF)]);
+  }
+  let lui = (0x0F << 26) | (val >> 16);
+  match val & 0xFFFF {
+    0 => Ok(vec![lui]),
+    low => Ok(vec![lui, (0x0D << 26) | low]),
+  }
+}
+
+fn parse_number(text: &str) -> Result<i64, String> {
+  let (negative, digits) = match text.strip_prefix('-') {
+    Some(rest) => (true, rest),
+    None => (false, text),
+  };
+  let val = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+    Some(hex) => i64::from_str_radix(hex, 16),
+    None => digits.parse(),
+  }.map_err(|_| format!("Invalid number: {}", text))?;
+  Ok(if negative { -val } else { val })
+}
+
+struct Assembler<'a> {
+  pc: u32,
+  labels: &'a HashMap<String, u32>,
+  operands: &'a [&'a str],
+}
+
+impl<'a> Assembler<'a> {
+  fn operand(&self, index: usize) -> Result<&'a str, String> {
+    self.operands.get(index).copied().ok_or_else(|| format!("Missing operand {}", index + 1))
+  }
+
+  fn expect_operands(&self, count: usize) -> Result<(), String> {
+    match self.operands.len() == count {
+      true => Ok(()),
+      false => Err(format!("Expected {} operands, found {}", count, self.operands.len())),
+    }
+  }
+
+  fn reg(&self, index: usize) -> Result<u32, String> {
+    let text = self.operand(index)?;
+    let name = text.strip_prefix('$').ok_or_else(|| format!("Invalid register: {}", text))?;
+    if let Ok(n) = name.parse::<u32>() {
+      if n < 32 {
+        return Ok(n);
+      }
+    }
+    match name {
+      "s8" => Ok(30),
+      _ => REGISTER_NAMES.iter().position(|&r| r == name)
+        .map(|n| n as u32)
+        .ok_or_else(|| format!("Invalid register: {}", text)),
+    }
+  }
+
+  // コプロセッサのレジスタ ($n、COP0 は名前も使える)
+  fn cop_reg(&self, index: usize, cop: u32) -> Result<u32, String> {
+    let text = self.operand(index)?;
+    let name = text.strip_prefix('$').ok_or_else(|| format!("Invalid coprocessor register: {}", text))?;
+    if let Ok(n) = name.parse::<u32>() {
+      if n < 32 {
+        return Ok(n);
+      }
+    }
+    match cop {
+      0 => reverse_lookup(&COP0_NAMES, name).ok_or_else(|| format!("Invalid COP0 register: {}", text)),
+      _ => Err(format!("Invalid coprocessor register: {}", text)),
+    }
+  }
+
+  fn number(&self, index: usize, min: i64, max: i64) -> Result<u32, String> {
+    let text = self.operand(index)?;
+    let val = parse_number(text)?;
+    match (min..=max).contains(&val) {
+      true => Ok(val as u32),
+      false => Err(format!("Value out of range: {}", text)),
+    }
+  }
+
+  fn signed16(&self, index: usize) -> Result<u32, String> {
+    Ok(self.number(index, i16::MIN as i64, i16::MAX as i64)? & 0xFFFF)
+  }
+
+  fn unsigned16(&self, index: usize) -> Result<u32, String> {
+    self.number(index, 0, 0xFFFF)
+  }
+
+  // ラベルまたは絶対アドレス
+  fn address(&self, index: usize) -> Result<u32, String> {
+    let text = self.operand(index)?;
+    match self.labels.get(text) {
+      Some(&addr) => Ok(addr),
+      None => self.number(index, 0, u32::MAX as i64),
+    }
+  }
+
+  fn branch_offset(&self, index: usize) -> Result<u32, String> {
+    let target = self.address(index)?;
+    let offset = target.wrapping_sub(self.pc.wrapping_add(4)) as i32;
+    if offset % 4 != 0 || offset / 4 < i16::MIN as i32 || offset / 4 > i16::MAX as i32 {
+      return Err(format!("Branch target out of range: {}", self.operand(index)?));
+    }
+    Ok((offset / 4) as u32 & 0xFFFF)
+  }
+
+  // offset(base)
+  fn memory(&self, index: usize) -> Result<(u32, u32), String> {
+    let text = self.operand(index)?;
+    let (offset, base) = text.strip_suffix(')')
+      .and_then(|t| t.split_once('('))
+      .ok_or_else(|| format!("Invalid memory operand: {}", text))?;
+    let offset = match offset.trim() {
+      "" => 0,
+      offset => parse_number(offset)?,
+    };
+    if offset < i16::MIN as i64 || offset > i16::MAX as i64 {
+      return Err(format!("Offset out of range: {}", text));
+    }
+    let base = Assembler { pc: self.pc, labels: self.labels, operands: &[base.trim()] }.reg(0)?;
+    Ok((offset as u32 & 0xFFFF, base))
+  }
+
+  fn encode(&self, mnemonic: &str) -> Result<Vec<u32>, String> {
+    let r = |s: u32, t: u32, d: u32, shift: u32, funct: u32| (s << 21) | (t << 16) | (d << 11) | (shift << 6) | funct;
+    let i = |op: u32, s: u32, t: u32, imm: u32| (op << 26) | (s << 21) | (t << 16) | imm;
+
+    if let Some(funct) = reverse_lookup(&SPECIAL_NAMES, mnemonic) {
+      let word = match funct {
+        0x00 | 0x02 | 0x03 => {
+          self.expect_operands(3)?;
+          r(0, self.reg(1)?, self.reg(0)?, self.number(2, 0, 31)?, funct)
+        }
+        0x04 | 0x06 | 0x07 => {
+          self.expect_operands(3)?;
+          r(self.reg(2)?, self.reg(1)?, self.reg(0)?, 0, funct)
+        }
+        0x08 => {
+          self.expect_operands(1)?;
+          r(self.reg(0)?, 0, 0, 0, funct)
+        }
+        0x09 => match self.operands.len() {
+          1 => r(self.reg(0)?, 0, 31, 0, funct),
+          _ => {
+            self.expect_operands(2)?;
+            r(self.reg(1)?, 0, self.reg(0)?, 0, funct)
+          }
+        },
+        0x0C | 0x0D => match self.operands.len() {
+          0 => funct,
+          _ => {
+            self.expect_operands(1)?;
+            (self.number(0, 0, 0xF_FFFF)? << 6) | funct
+          }
+        },
+        0x10 | 0x12 => {
+          self.expect_operands(1)?;
+          r(0, 0, self.reg(0)?, 0, funct)
+        }
+        0x11 | 0x13 => {
+          self.expect_operands(1)?;
+          r(self.reg(0)?, 0, 0, 0, funct)
+        }
+        0x18..=0x1B => {
+          self.expect_operands(2)?;
+          r(self.reg(0)?, self.reg(1)?, 0, 0, funct)
+        }
+        _ => {
+          self.expect_operands(3)?;
+          r(self.reg(1)?, self.reg(2)?, self.reg(0)?, 0, funct)
+        }
+      };
+      return Ok(vec![word]);
+    }
+
+    if let Some(rt) = reverse_lookup(&BXX_NAMES, mnemonic) {
+      self.expect_operands(2)?;
+      return Ok(vec![i(0x01, self.reg(0)?, rt, self.branch_offset(1)?)]);
+    }
+
+    if let Some(op) = reverse_lookup(&PRIMARY_NAMES, mnemonic) {
+      let word = match op {
+        0x02 | 0x03 => {
+          self.expect_operands(1)?;
+          let target = self.address(0)?;
+          if target & 3 != 0 || (target ^ self.pc.wrapping_add(4)) & 0xF000_0000 != 0 {
+            return Err(format!("Jump target out of range: {}", self.operand(0)?));
+          }
+          (op << 26) | ((target >> 2) & 0x03FF_FFFF)
+        }
+        0x04 | 0x05 => {
+          self.expect_operands(3)?;
+          i(op, self.reg(0)?, self.reg(1)?, self.branch_offset(2)?)
+        }
+        0x06 | 0x07 => {
+          self.expect_operands(2)?;
+          i(op, self.reg(0)?, 0, self.branch_offset(1)?)
+        }
+        0x08..=0x0B => {
+          self.expect_operands(3)?;
+          i(op, self.reg(1)?, self.reg(0)?, self.signed16(2)?)
+        }
+        0x0C..=0x0E => {
+          self.expect_operands(3)?;
+          i(op, self.reg(1)?, self.reg(0)?, self.unsigned16(2)?)
+        }
+        0x0F => {
+          self.expect_operands(2)?;
+          i(op, 0, self.reg(0)?, self.unsigned16(1)?)
+        }
+        _ => {
+          self.expect_operands(2)?;
+          let (offset, base) = self.memory(1)?;
+          i(op, base, self.reg(0)?, offset)
+        }
+      };
+      return Ok(vec![word]);
+    }
+
+    if let Some(command) = reverse_lookup(&GTE_NAMES, mnemonic) {
+      let mut word = (0x12 << 26) | (1 << 25) | command;
+      for &field in self.operands {
+        word |= match field.split_once('=') {
+          None if field == "sf" => 1 << 19,
+          None if field == "lm" => 1 << 10,
+          Some(("mx", val)) => (parse_number(val)? as u32 & 3) << 17,
+          Some(("v", val)) => (parse_number(val)? as u32 & 3) << 15,
+          Some(("cv", val)) => (parse_number(val)? as u32 & 3) << 13,
+          _ => return Err(format!("Invalid GTE field: {}", field)),
+        };
+      }
+      return Ok(vec![word]);
+    }
+
+    let coprocessor = |prefix: &str| mnemonic.strip_prefix(prefix)
+      .and_then(|n| n.parse::<u32>().ok())
+      .filter(|&n| n < 4);
+    for (prefix, rs) in [("mfc", 0x00), ("cfc", 0x02), ("mtc", 0x04), ("ctc", 0x06)] {
+      if let Some(cop) = coprocessor(prefix) {
+        self.expect_operands(2)?;
+        let d = self.cop_reg(1, cop)?;
+        return Ok(vec![((0x10 | cop) << 26) | (rs << 21) | (self.reg(0)? << 16) | (d << 11)]);
+      }
+    }
+    for (prefix, op) in [("lwc", 0x30), ("swc", 0x38)] {
+      if let Some(cop) = coprocessor(prefix) {
+        self.expect_operands(2)?;
+        let (offset, base) = self.memory(1)?;
+        return Ok(vec![i(op | cop, base, self.cop_reg(0, cop)?, offset)]);
+      }
+    }
+    for (suffix, t) in [("f", 0), ("t", 1)] {
+      if let Some(cop) = mnemonic.strip_prefix("bc").and_then(|m| m.strip_suffix(suffix)).and_then(|n| n.parse::<u32>().ok()).filter(|&n| n < 4) {
+        self.expect_operands(1)?;
+        return Ok(vec![i(0x10 | cop, 0x08, t, self.branch_offset(0)?)]);
+      }
+    }
+    if let Some(cop) = coprocessor("cop") {
+      self.expect_operands(1)?;
+      return Ok(vec![((0x10 | cop) << 26) | (1 << 25) | self.number(0, 0, 0x01FF_FFFF)?]);
+    }
+
+    match mnemonic {
+      "nop" => {
+        self.expect_operands(0)?;
+        Ok(vec![0])
+      }
+      "rfe" => {
+        self.expect_operands(0)?;
+        Ok(vec![(0x10 << 26) | (1 << 25) | 0x10])
+      }
+      "move" => {
+        self.expect_operands(2)?;
+        Ok(vec![r(self.reg(1)?, 0, self.reg(0)?, 0, 0x21)])
+      }
+      "li" => {
+        self.expect_operands(2)?;
+        let t = self.reg(0)?;
+        // lui の後の ori は rt 自身に重ねる
+        Ok(li_words(self.operand(1)?)?.into_iter()
+          .map(|word| match word >> 26 {
+            0x0D => word | (t << 21) | (t << 16),
+            _ => word | (t << 16),
+          })
+          .collect())
+      }
+      "b" => {
+        self.expect_operands(1)?;
+        Ok(vec![i(0x04, 0, 0, self.branch_offset(0)?)])
+      }
+      ".word" => {
+        self.expect_operands(1)?;
+        Ok(vec![self.number(0, i32::MIN as i64, u32::MAX as i64)?])
+      }
+      _ => Err(format!("Unknown instruction: {}", mnemonic)),
+    }
+  }
+}
@@ -0,0 +1,120 @@
+// 逆アセンブラとアセンブラのテスト
+// 逆アセンブルした結果をアセンブルし直すと同じ命令に戻ることを確かめる
+use crate::disasm::{assemble, disassemble};
+
+const BASE: u32 = 0x8001_0000;
+
+const REGISTER_NAMES: [&str; 32] = [
+  "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3",
+  "t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7",
+  "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7",
+  "t8", "t9", "k0", "k1", "gp", "sp", "fp", "ra",
+];
+
+const COP0_NAMES: [&str; 11] = ["bpc", "bda", "tar", "dcic", "badvaddr", "bdam", "bpcm", "sr", "cause", "epc", "prid"];
+
+const GTE_NAMES: [&str; 22] = [
+  "rtps", "nclip", "op", "dpcs", "intpl", "mvmva", "ncds", "cdp", "ncdt", "nccs", "cc", "ncs",
+  "nct", "sqr", "dcpl", "dpct", "avsz3", "avsz4", "rtpt", "gpf", "gpl", "ncct",
+];
+
+// 逆アセンブラの出力の形 ("addiu   $t0, $t1, 0x1")
+fn format(line: &str) -> String {
+  match line.split_once(' ') {
+    Some((mnemonic, operands)) => format!("{:<8}{}", mnemonic, operands),
+    None => line.to_string(),
+  }
+}
+
+fn assert_round_trip(pc: u32, line: &str) {
+  let words = assemble(pc, line).unwrap_or_else(|e| panic!("{}: {}", line, e));
+  assert_eq!(words.len(), 1, "{}", line);
+  assert_eq!(disassemble(pc, words[0]), format(line), "{:08X}", words[0]);
+}
+
+#[test]
+fn round_trip_instructions() {
+  let lines = [
+    "sll $t0, $t1, 31", "srl $a0, $a1, 1", "sra $v0, $v1, 16",
+    "sllv $t0, $t1, $t2", "srlv $t3, $t4, $t5", "srav $t6, $t7, $s0",
+    "jr $ra", "jalr $t0", "jalr $t1, $t2",
+    "syscall", "syscall 0x10", "break", "break 0xFFFFF",
+    "mfhi $t0", "mthi $t1", "mflo $t2", "mtlo $t3",
+    "mult $a0, $a1", "multu $a2, $a3", "div $t0, $t1", "divu $t2, $t3",
+    "add $t0, $t1, $t2", "addu $t0, $t1, $t2", "sub $t0, $t1, $t2", "subu $t0, $t1, $t2",
+    "and $t0, $t1, $t2", "or $t0, $t1, $t2", "xor $t0, $t1, $t2", "nor $t0, $t1, $t2",
+    "slt $t0, $t1, $t2", "sltu $t0, $t1, $t2",
+    "addi $t0, $t1, -0x8000", "addiu $t0, $t1, 0x7FFF", "slti $t0, $t1, -0x1", "sltiu $t0, $t1, 0x10",
+    "andi $t0, $t1, 0xFFFF", "ori $t0, $zero, 0x0", "xori $t0, $t1, 0x8000", "lui $t0, 0x8001",
+    "lb $t0, -0x8000($sp)", "lh $t0, 0x7FFF($t1)", "lwl $t0, 0x3($t1)", "lw $t0, 0x0($t1)",
+    "lbu $t0, 0x1($t1)", "lhu $t0, 0x2($t1)", "lwr $t0, -0x1($t1)",
+    "sb $t0, 0x1($t1)", "sh $t0, 0x2($t1)", "swl $t0, 0x3($t1)", "sw $t0, -0x4($sp)", "swr $t0, 0x0($t1)",
+    "lwc0 $1, 0x4($t0)", "lwc1 $2, 0x4($t0)", "lwc2 $31, -0x4($sp)", "lwc3 $0, 0x0($t0)",
+    "swc0 $1, 0x4($t0)", "swc1 $2, 0x4($t0)", "swc2 $31, -0x4($sp)", "swc3 $0, 0x0($t0)",
+    "mfc0 $t0, $16", "mtc0 $t0, $0", "mfc2 $t0, $5", "cfc2 $t0, $31", "mtc2 $t0, $0", "ctc2 $t0, $30",
+    "mfc1 $t0, $1", "mtc3 $t0, $2", "cfc0 $t0, $3", "ctc1 $t0, $4",
+    "rfe", "cop0 0x1", "cop1 0x1FFFFFF", "cop3 0x0",
+    "mvmva sf, lm, mx=3, v=2, cv=1", "rtps sf", "nclip lm", "op v=3",
+    ".word 0xFFFFFFFF",
+  ];
+  for line in lines {
+    assert_round_trip(BASE, line);
+  }
+  for name in COP0_NAMES {
+    assert_round_trip(BASE, &format!("mfc0 $t0, ${}", name));
+    assert_round_trip(BASE, &format!("mtc0 $t0, ${}", name));
+  }
+  for name in GTE_NAMES {
+    assert_round_trip(BASE, name);
+  }
+}
+
+#[test]
+fn round_trip_pseudo_instructions() {
+  for line in ["nop", "move $s0, $s1", "li $t0, -0x1", "li $t0, 0x7FFF", "li $t0, -0x8000", "b 0x80010008"] {
+    assert_round_trip(BASE, line);
+  }
+  // 16ビットに収まらない li は lui (+ ori) になる
+  assert_eq!(assemble(BASE, "li $t0, 0x12345678").unwrap().len(), 2);
+  assert_eq!(assemble(BASE, "li $t0, 0x10000").unwrap().len(), 1);
+}
+
+#[test]
+fn round_trip_register_names() {
+  for name in REGISTER_NAMES {
+    assert_round_trip(BASE, &format!("or ${}, ${}, ${}", name, name, name));
+    assert_round_trip(BASE, &format!("lw ${}, 0x0(${})", name, name));
+  }
+  // 番号と s8 でも書ける
+  for (index, name) in REGISTER_NAMES.iter().enumerate() {
+    let word = assemble(BASE, &format!("jr ${}", index)).unwrap()[0];
+    assert_eq!(disassemble(BASE, word), format(&format!("jr ${}", name)));
+  }
+  assert_eq!(assemble(BASE, "jr $s8").unwrap(), assemble(BASE, "jr $fp").unwrap());
+}
+
+#[test]
+fn round_trip_branch_targets() {
+  // 自分自身、遅延スロットの次、オフセットの最大と最小
+  for target in [BASE, BASE + 4, BASE + 8, BASE + 4 + 0x1FFFC, BASE + 4 - 0x20000] {
+    for mnemonic in ["bltz $t0,", "bgez $t0,", "bltzal $a0,", "bgezal $ra,", "beq $t0, $t1,", "bne $t0, $zero,", "blez $t0,", "bgtz $t0,", "bc0f", "bc2t"] {
+      assert_round_trip(BASE, &format!("{} 0x{:08X}", mnemonic, target));
+    }
+  }
+  for target in [BASE, 0x8000_0000, 0x8FFF_FFFC] {
+    assert_round_trip(BASE, &format!("j 0x{:08X}", target));
+    assert_round_trip(BASE, &format!("jal 0x{:08X}", target));
+  }
+  // 範囲外の分岐先はアセンブルできない
+  assert!(assemble(BASE, &format!("b 0x{:08X}", BASE + 4 + 0x20000)).is_err());
+  assert!(assemble(BASE, "j 0x90000000").is_err());
+}
+
+#[test]
+fn regimm_decodes_like_the_cpu() {
+  // rt のビット0で bgez/bltz、ビット1-4が 0b1000 のときだけリンク
+  let regimm = |rt: u32| disassemble(BASE, (0x01 << 26) | (8 << 21) | (rt << 16));
+  for (rt, mnemonic) in [(0x02, "bltz"), (0x03, "bgez"), (0x0E, "bltz"), (0x10, "bltzal"), (0x11, "bgezal"), (0x12, "bltz"), (0x13, "bgez"), (0x1F, "bgez")] {
+    assert_eq!(regimm(rt), format(&format!("{} $t0, 0x80010004", mnemonic)), "rt = {:02X}", rt);
+  }
+}
@@ -0,0 +1,139 @@
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+
+pub const SECTOR_DATA_SIZE: usize = 2048;
+const RAW_SECTOR_SIZE: u64 = 2352;
+const SYNC_PATTERN: [u8; 12] = [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
+
+// ディスクイメージの読み込み元 (ファイルやメモリ上のデータなど)
+pub trait DiscSource: Read + Seek {}
+
+impl<T: Read + Seek> DiscSource for T {}
+
+// ディスクイメージ (.iso の 2048 バイト/セクタ、または .bin の 2352 バイト/セクタ)
+pub struct Disc {
+  file: Box<dyn DiscSource>,
+  sector_size: u64,
+  // セクタ内のユーザーデータの位置 (Mode1: 16, Mode2 Form1: 24)
+  data_offset: u64,
+}
+
+impl Disc {
+  pub fn new(mut file: Box<dyn DiscSource>) -> Result<Self, Error> {
+    let mut head = [0u8; 16];
+    file.read_exact(&mut head)?;
+
+    let (sector_size, data_offset) = if head[0..12] == SYNC_PATTERN {
+      match head[15] {
+        1 => (RAW_SECTOR_SIZE, 16),
+        2 => (RAW_SECTOR_SIZE, 24),
+        mode => return Err(Error::new(ErrorKind::InvalidData, format!("Unsupported sector mode {}", mode))),
+      }
+    } else {
+      (SECTOR_DATA_SIZE as u64, 0)
+    };
+    Ok(Self { file, sector_size, data_offset })
+  }
+
+  pub fn read_sector(&mut self, lba: u32) -> Result<[u8; SECTOR_DATA_SIZE], Error> {
+    let mut data = [0u8; SECTOR_DATA_SIZE];
+    self.file.seek(SeekFrom::Start(lba as u64 * self.sector_size + self.data_offset))?;
+    self.file.read_exact(&mut data)?;
+    Ok(data)
+  }
+
+  fn read_extent(&mut self, lba: u32, size: u32) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::with_capacity(size as usize);
+    let mut lba = lba;
+    while data.len() < size as usize {
+      let sector = self.read_sector(lba)?;
+      let n = (size as usize - data.len()).min(SECTOR_DATA_SIZE);
+      data.extend_from_slice(&sector[..n]);
+      lba += 1;
+    }
+    Ok(data)
+  }
+
+  // ISO9660 のファイルを探す ("cdrom:\DIR\FILE.EXE;1" 形式も受け付ける)
+  pub fn find_file(&mut self, path: &str) -> Result<(u32, u32), Error> {
+    let path = path.strip_prefix("cdrom:").unwrap_or(path);
+    let not_found = || Error::new(ErrorKind::NotFound, format!("File not found on disc: {}", path));
+    let malformed = || Error::new(ErrorKind::InvalidData, "Malformed directory record");
+
+    // Primary Volume Descriptor の 156 バイト目にルートディレクトリのレコードがある
+    let pvd = self.read_sector(16)?;
+    if &pvd[1..6] != b"CD001" {
+      return Err(Error::new(ErrorKind::InvalidData, "Not an ISO9660 disc"));
+    }
+    let (mut lba, mut size, _) = parse_record(&pvd[156..]);
+
+    let components: Vec<&str> = path.split(['\\', '/']).filter(|c| !c.is_empty()).collect();
+    for (i, component) in components.iter().enumerate() {
+      let want = component.split(';').next().unwrap().to_ascii_uppercase();
+      let dir = self.read_extent(lba, size)?;
+      let mut found = None;
+      let mut offset = 0;
+      while offset < dir.len() {
+        let len = dir[offset] as usize;
+        if len == 0 {
+          // レコードはセクタをまたがないので次のセクタへ進む
+          offset = (offset / SECTOR_DATA_SIZE + 1) * SECTOR_DATA_SIZE;
+          continue;
+        }
+        // 名前の長さ (32 バイト目) までないレコードは壊れている
+        let record = dir.get(offset..offset + len).filter(|r| r.len() > 32).ok_or_else(malformed)?;
+        let name_len = record[32] as usize;
+        let name = String::from_utf8_lossy(record.get(33..33 + name_len).ok_or_else(malformed)?);
+        let name = name.split(';').next().unwrap().to_ascii_uppercase();
+        if name == want {
+          found = Some(parse_record(record));
+          break;
+        }
+        offset += len;
+      }
+
+      let (l, s, is_dir) = found.ok_or_else(not_found)?;
+      if i + 1 < components.len() && !is_dir {
+        return Err(not_found());
+      }
+      lba = l;
+      size = s;
+    }
+    Ok((lba, size))
+  }
+
+  pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>, Error> {
+    let (lba, size) = self.find_file(path)?;
+    self.read_extent(lba, size)
+  }
+}
+
+// ディレクトリレコード: 2 エクステント位置, 10 データ長, 25 フラグ (ビット1=ディレクトリ)
+fn parse_record(record: &[u8]) -> (u32, u32, bool) {
+  let lba = u32::from_le_bytes(record[2..6].try_into().unwrap());
+  let size = u32::from_le_bytes(record[10..14].try_into().unwrap());
+  (lba, size, record[25] & 2 != 0)
+}
+
+// SYSTEM.CNF の設定
+pub struct SystemCnf {
+  pub boot: String,
+  pub stack: Option<u32>,
+}
+
+impl SystemCnf {
+  pub fn parse(text: &str) -> Option<Self> {
+    let mut boot = None;
+    let mut stack = None;
+    for line in text.lines() {
+      let mut kv = line.splitn(2, '=');
+      let key = kv.next().unwrap_or("").trim().to_ascii_uppercase();
+      let value = kv.next().unwrap_or("").trim();
+      match key.as_str() {
+        "BOOT" => boot = Some(value.to_string()),
+        "STACK" => stack = u32::from_str_radix(value, 16).ok(),
+        _ => {}
+      }
+    }
+    Some(Self { boot: boot?, stack })
+  }
+}
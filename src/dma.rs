@@ -8,10 +8,20 @@ pub struct Dma {
   channel_irq_flags: u8,
   force_irq: bool,
   irq_dummy: u8,
+  // 前回のマスター割り込みフラグ (立ち上がりで IRQ3 を発生させる)
+  prev_irq: bool,
 
   channels: [Channel; 7],
 }
 
+impl_state!(Dma { control, irq_en, channel_irq_en, channel_irq_flags, force_irq, irq_dummy, prev_irq, channels });
+
+impl Default for Dma {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
 impl Dma {
   pub fn new() -> Self {
     Self {
@@ -21,6 +31,7 @@ impl Dma {
       channel_irq_flags: 0,
       force_irq: false,
       irq_dummy: 0,
+      prev_irq: false,
       channels: [Channel::new(); 7],
     }
   }
@@ -52,10 +63,54 @@ impl Dma {
     self.force_irq = (val >> 15) & 1 != 0;
     self.channel_irq_en = ((val >> 16) & 0x7F) as u8;
     self.irq_en = (val >> 23) & 1 != 0;
-    let ack = ((val >> 24) & 0x3F) as u8;
+    let ack = ((val >> 24) & 0x7F) as u8;
     self.channel_irq_flags = self.channel_irq_flags & !ack;
   }
 
+  // マスター割り込みフラグが 0 から 1 になったら true
+  pub fn irq_edge(&mut self) -> bool {
+    let irq = self.irq();
+    let edge = irq && !self.prev_irq;
+    self.prev_irq = irq;
+    edge
+  }
+
+  pub fn channel_done(&mut self, port: Port) {
+    self.channel_mut(port).done();
+    let bit = 1 << (port as u8);
+    if self.channel_irq_en & bit != 0 {
+      self.channel_irq_flags |= bit;
+    }
+  }
+
+  // DPCR: 各チャンネル4ビット (ビット0-2 優先度、ビット3 有効)
+  pub fn channel_enabled(&self, port: Port) -> bool {
+    (self.control >> (port as u32 * 4 + 3)) & 1 != 0
+  }
+
+  fn priority(&self, port: Port) -> u32 {
+    (self.control >> (port as u32 * 4)) & 7
+  }
+
+  // 実行可能なチャンネルのうち最も優先度の高いもの
+  // (値が小さいほど優先、同じならチャンネル番号の大きい方)
+  pub fn next_runnable<F: Fn(Port) -> bool>(&self, now: u64, request: F) -> Option<Port> {
+    (0..7)
+      .map(Port::from_index)
+      .filter(|&port| {
+        let channel = self.channel(port);
+        channel.active()
+          && self.channel_enabled(port)
+          && channel.resume_at() <= now
+          && request(port)
+      })
+      .min_by_key(|&port| (self.priority(port), 6 - port as u32))
+  }
+
+  pub fn any_active(&self) -> bool {
+    self.channels.iter().any(|channel| channel.active())
+  }
+
   pub fn channel(&self, port: Port) -> &Channel {
     &self.channels[port as usize]
   }
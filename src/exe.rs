@@ -0,0 +1,93 @@
+use std::io::{Error, ErrorKind};
+
+// PS-X EXE ファイル
+// 0x800 バイトのヘッダの後にテキスト (コード + データ) が続く
+pub struct PsxExe {
+  pub header: ExeHeader,
+  pub text: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExeHeader {
+  pub pc: u32,
+  pub gp: u32,
+  pub text_addr: u32,
+  pub text_size: u32,
+  pub data_addr: u32,
+  pub data_size: u32,
+  pub bss_addr: u32,
+  pub bss_size: u32,
+  pub stack_addr: u32,
+  pub stack_size: u32,
+}
+
+pub const EXE_HEADER_SIZE: usize = 0x800;
+
+// スタックの指定がないときに使うアドレス
+pub const DEFAULT_STACK: u32 = 0x801F_FFF0;
+
+impl PsxExe {
+  pub fn parse(data: &[u8]) -> Result<Self, Error> {
+    let header = ExeHeader::parse(data)?;
+    let end = EXE_HEADER_SIZE + header.text_size as usize;
+    if data.len() < end {
+      return Err(Error::new(ErrorKind::InvalidData, "Truncated PS-X EXE"));
+    }
+    let text = data[EXE_HEADER_SIZE..end].to_vec();
+    Ok(Self { header, text })
+  }
+}
+
+impl ExeHeader {
+  pub fn parse(data: &[u8]) -> Result<Self, Error> {
+    if data.len() < EXE_HEADER_SIZE || &data[0..8] != b"PS-X EXE" {
+      return Err(Error::new(ErrorKind::InvalidData, "Not a PS-X EXE"));
+    }
+    let word = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+    Ok(Self {
+      pc: word(0x10),
+      gp: word(0x14),
+      text_addr: word(0x18),
+      text_size: word(0x1C),
+      data_addr: word(0x20),
+      data_size: word(0x24),
+      bss_addr: word(0x28),
+      bss_size: word(0x2C),
+      stack_addr: word(0x30),
+      stack_size: word(0x34),
+    })
+  }
+
+  // BIOS の LoadExeHeader が書き出す形式 (ヘッダの 0x10～0x37)
+  pub fn to_words(&self) -> [u32; 10] {
+    [
+      self.pc, self.gp,
+      self.text_addr, self.text_size,
+      self.data_addr, self.data_size,
+      self.bss_addr, self.bss_size,
+      self.stack_addr, self.stack_size,
+    ]
+  }
+
+  pub fn from_words(words: &[u32; 10]) -> Self {
+    Self {
+      pc: words[0],
+      gp: words[1],
+      text_addr: words[2],
+      text_size: words[3],
+      data_addr: words[4],
+      data_size: words[5],
+      bss_addr: words[6],
+      bss_size: words[7],
+      stack_addr: words[8],
+      stack_size: words[9],
+    }
+  }
+
+  pub fn initial_sp(&self) -> Option<u32> {
+    match self.stack_addr {
+      0 => None,
+      addr => Some(addr.wrapping_add(self.stack_size)),
+    }
+  }
+}
@@ -0,0 +1,119 @@
+use std::io::{Error, ErrorKind};
+
+use crate::console::Console;
+
+// 0x1F00_0000～ 拡張領域1 (パラレルポート)
+// Caetla や Action Replay などの拡張 ROM をつなぐ。
+// BIOS は 0x1F00_0004 / 0x1F00_0084 に "Licensed by Sony Computer Entertainment Inc."
+// があれば 0x1F00_0080 (起動前) / 0x1F00_0000 (カーネル初期化後) を呼び出す
+#[derive(Clone)]
+pub struct Expansion1 {
+  rom: Option<Vec<u8>>,
+}
+
+const EXPANSION_ROM_MAX_SIZE: usize = 512 * 1024;
+
+impl Default for Expansion1 {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Expansion1 {
+  pub fn new() -> Self {
+    Self { rom: None }
+  }
+
+  pub fn with_rom(mut data: Vec<u8>) -> Result<Self, Error> {
+    data.truncate(EXPANSION_ROM_MAX_SIZE);
+    if data.is_empty() {
+      return Err(Error::new(ErrorKind::InvalidInput, "Empty expansion ROM"));
+    }
+    Ok(Self { rom: Some(data) })
+  }
+
+  pub fn load8(&self, offset: u32) -> u8 {
+    match &self.rom {
+      // 何もつながっていないときは 0xFF が読める
+      Some(rom) => rom.get(offset as usize).copied().unwrap_or(0xFF),
+      None => 0xFF,
+    }
+  }
+}
+
+// 0x1F80_2000～ 拡張領域2
+// 開発機の DUART (SCN2681) と POST 表示 (7セグメントLED) がある。
+// チャンネル A の送信レジスタに書かれた文字を TTY 出力としてホストに流す
+pub struct Expansion2 {
+  tty: Console,
+  line: Vec<u8>,
+  post: u8,
+}
+
+// TTY の出力先はホスト側のものなので残す
+impl_state!(Expansion2 { line, post });
+
+impl Expansion2 {
+  pub fn new(tty: Console) -> Self {
+    Self {
+      tty,
+      line: Vec::new(),
+      post: 0,
+    }
+  }
+
+  // セーブステートを読み込んだ新しい Expansion2 に、TTY の出力先を old から移す
+  pub fn take_host(&mut self, old: &mut Expansion2) {
+    std::mem::swap(&mut self.tty, &mut old.tty);
+  }
+
+  pub fn load8(&mut self, offset: u32) -> u8 {
+    match offset {
+      // 0x1F80_2021 / 0x1F80_2029 SRA/SRB
+      // 2 TxRDY, 3 TxEMT。送信は常に完了している
+      0x21 | 0x29 => 0x0C,
+      // 0x1F80_2023 / 0x1F80_202B RHRA/RHRB (受信データはない)
+      0x23 | 0x2B => 0x00,
+      // 0x1F80_2025 ISR
+      // 0 TxRDYA, 4 TxRDYB
+      0x25 => 0x11,
+      // 0x1F80_2041 POST
+      0x41 => self.post,
+      _ => 0xFF,
+    }
+  }
+
+  pub fn store8(&mut self, offset: u32, val: u8) {
+    match offset {
+      // 0x1F80_2023 THRA
+      0x23 => self.tty_write(val),
+      // 0x1F80_2041 POST
+      0x41 => self.post = val,
+      // モードやボーレート、割り込みマスクなどの設定は無視する
+      _ => {}
+    }
+  }
+
+  fn tty_write(&mut self, val: u8) {
+    match val {
+      b'\r' => {}
+      b'\n' => self.flush(),
+      _ => self.line.push(val),
+    }
+  }
+
+  fn flush(&mut self) {
+    self.line.push(b'\n');
+    self.tty.write(&self.line);
+    self.line.clear();
+  }
+}
+
+impl Drop for Expansion2 {
+  fn drop(&mut self) {
+    // 改行されずに残っている出力を書き出す
+    if !self.line.is_empty() {
+      self.flush();
+    }
+  }
+}
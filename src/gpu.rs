@@ -1,4 +1,9 @@
-use crate::renderer::{Color, Position, Renderer};
+use std::io::Error;
+
+use crate::{renderer::{Color, Framebuffer, Position, Renderer, VRAM_HEIGHT, VRAM_WIDTH}, savestate::invalid_state};
+
+// GP0 コマンドを実行する関数
+type Gp0Method = fn(&mut Gpu);
 
 pub struct Gpu {
   page_base_x: u8,
@@ -45,12 +50,23 @@ pub struct Gpu {
   gp0_command_method: fn(&mut Gpu),
   gp0_mode: Gp0Mode,
 
-  renderer: Renderer,
+  // None のときは描画しない (ロックステップの比較用など)
+  renderer: Option<Box<dyn Renderer>>,
   pub frame_updated: bool,
+  // 表示したフレームの数 (トレースのフレーム範囲の判定用)
+  frame_count: u64,
 }
 
 impl Gpu {
-  pub fn new(video_subsystem: sdl2::VideoSubsystem) -> Self {
+  pub fn new(renderer: Box<dyn Renderer>) -> Self {
+    Self::with_renderer(Some(renderer))
+  }
+
+  pub fn headless() -> Self {
+    Self::with_renderer(None)
+  }
+
+  fn with_renderer(renderer: Option<Box<dyn Renderer>>) -> Self {
     Self {
       page_base_x: 0,
       page_base_y: 0,
@@ -96,8 +112,9 @@ impl Gpu {
       gp0_command_method: Gpu::gp0_nop as fn(&mut Gpu),
       gp0_mode: Gp0Mode::Command,
 
-      renderer: Renderer::new(video_subsystem),
+      renderer,
       frame_updated: false,
+      frame_count: 0,
     }
   }
 
@@ -138,26 +155,51 @@ impl Gpu {
     0
   }
 
+  pub fn frame_count(&self) -> u64 {
+    self.frame_count
+  }
+
+  // セーブステートを読み込んだ新しい Gpu に、レンダラを old から移す
+  pub fn take_host(&mut self, old: &mut Gpu) {
+    std::mem::swap(&mut self.renderer, &mut old.renderer);
+  }
+
+  // セーブステートのレンダラのまとまりを今のレンダラに読み込む (空なら何もしない)
+  pub fn load_renderer_state(&mut self, data: &[u8]) -> Result<(), Error> {
+    match self.renderer.as_mut() {
+      Some(renderer) if !data.is_empty() => renderer.load_state(data),
+      _ => Ok(()),
+    }
+  }
+
+  // 表示領域の画像 (レンダラが VRAM を持っているときだけ読める)
+  pub fn framebuffer(&self) -> Option<Framebuffer> {
+    let renderer = self.renderer.as_ref()?;
+    renderer.framebuffer(self.display_vram_x_start, self.display_vram_y_start, self.hres.width(), self.vres.height())
+  }
+
+  // VRAM 全体の画像 (デバッグ表示用)
+  pub fn vram(&self) -> Option<Framebuffer> {
+    let renderer = self.renderer.as_ref()?;
+    renderer.framebuffer(0, 0, VRAM_WIDTH as u16, VRAM_HEIGHT as u16)
+  }
+
+  // レンダラを差し替える。描画済みの VRAM は新しいレンダラに引き継ぐ
+  pub fn set_renderer(&mut self, mut renderer: Option<Box<dyn Renderer>>) -> Result<(), Error> {
+    if let Some(new) = renderer.as_mut() {
+      let state = self.renderer.as_ref().map(|old| old.save_state()).unwrap_or_default();
+      if !state.is_empty() {
+        new.load_state(&state)?;
+      }
+      new.set_draw_offset(self.drawing_x_offset, self.drawing_y_offset);
+    }
+    self.renderer = renderer;
+    Ok(())
+  }
+
   pub fn gp0(&mut self, val: u32) {
     if self.gp0_words_remaining == 0 {
-      let opcode = (val >> 24) & 0xFF;
-      let (len, method) = match opcode {
-        0x00 => (1, Gpu::gp0_nop as fn(&mut Gpu)),
-        0x01 => (1, Gpu::gp0_clear_cache as fn(&mut Gpu)),
-        0x28 => (5, Gpu::gp0_quad_mono_opaque as fn(&mut Gpu)),
-        0x2C => (9, Gpu::gp0_quad_texture_blend_opaque as fn(&mut Gpu)),
-        0x30 => (6, Gpu::gp0_triangle_shaded_opaque as fn(&mut Gpu)),
-        0x38 => (8, Gpu::gp0_quad_shaded_opaque as fn(&mut Gpu)),
-        0xA0 => (3, Gpu::gp0_image_load as fn(&mut Gpu)),
-        0xC0 => (3, Gpu::gp0_image_store as fn(&mut Gpu)),
-        0xE1 => (1, Gpu::gp0_draw_mode as fn(&mut Gpu)),
-        0xE2 => (1, Gpu::gp0_texture_window as fn(&mut Gpu)),
-        0xE3 => (1, Gpu::gp0_drawing_area_top_left as fn(&mut Gpu)),
-        0xE4 => (1, Gpu::gp0_drawing_area_bottom_right as fn(&mut Gpu)),
-        0xE5 => (1, Gpu::gp0_drawing_offset as fn(&mut Gpu)),
-        0xE6 => (1, Gpu::gp0_mask_bit_setting as fn(&mut Gpu)),
-        _ => panic!("Unhandled GP0 command {:08X}", val)
-      };
+      let (len, method) = Gpu::gp0_command(val).unwrap_or_else(|| panic!("Unhandled GP0 command {:08X}", val));
       self.gp0_words_remaining = len;
       self.gp0_command_method = method;
       self.gp0_command.clear();
@@ -180,6 +222,39 @@ impl Gpu {
 
   }
 
+  // コマンドの語数と実行する関数
+  fn gp0_command(val: u32) -> Option<(u32, Gp0Method)> {
+    let opcode = (val >> 24) & 0xFF;
+    let command = match opcode {
+      0x00 => (1, Gpu::gp0_nop as fn(&mut Gpu)),
+      0x01 => (1, Gpu::gp0_clear_cache as fn(&mut Gpu)),
+      0x28 => (5, Gpu::gp0_quad_mono_opaque as fn(&mut Gpu)),
+      0x2C => (9, Gpu::gp0_quad_texture_blend_opaque as fn(&mut Gpu)),
+      0x30 => (6, Gpu::gp0_triangle_shaded_opaque as fn(&mut Gpu)),
+      0x38 => (8, Gpu::gp0_quad_shaded_opaque as fn(&mut Gpu)),
+      0xA0 => (3, Gpu::gp0_image_load as fn(&mut Gpu)),
+      0xC0 => (3, Gpu::gp0_image_store as fn(&mut Gpu)),
+      0xE1 => (1, Gpu::gp0_draw_mode as fn(&mut Gpu)),
+      0xE2 => (1, Gpu::gp0_texture_window as fn(&mut Gpu)),
+      0xE3 => (1, Gpu::gp0_drawing_area_top_left as fn(&mut Gpu)),
+      0xE4 => (1, Gpu::gp0_drawing_area_bottom_right as fn(&mut Gpu)),
+      0xE5 => (1, Gpu::gp0_drawing_offset as fn(&mut Gpu)),
+      0xE6 => (1, Gpu::gp0_mask_bit_setting as fn(&mut Gpu)),
+      _ => return None,
+    };
+    Some(command)
+  }
+
+  // 受け取り途中のコマンドの関数は保存できないので、先頭の語から引き直す
+  fn restore_command_method(&mut self) -> Result<(), Error> {
+    if self.gp0_command.len > 0 {
+      if let Some((_, method)) = Gpu::gp0_command(self.gp0_command.buffer[0]) {
+        self.gp0_command_method = method;
+      }
+    }
+    Ok(())
+  }
+
   fn gp0_nop(&mut self) {
     // NOPなので何もしない
   }
@@ -221,10 +296,12 @@ impl Gpu {
     let y = ((val >> 11) & 0x07FF) as u16;
     self.drawing_x_offset = ((x << 5) as i16) >> 5;
     self.drawing_y_offset = ((y << 5) as i16) >> 5;
-    self.renderer.set_draw_offset(self.drawing_x_offset, self.drawing_y_offset);
-
-    self.renderer.display();
+    if let Some(renderer) = self.renderer.as_mut() {
+      renderer.set_draw_offset(self.drawing_x_offset, self.drawing_y_offset);
+      renderer.display();
+    }
     self.frame_updated = true;
+    self.frame_count += 1;
   }
 
   fn gp0_texture_window(&mut self) {
@@ -249,7 +326,9 @@ impl Gpu {
       Position::from_gp0(self.gp0_command[4]),
     ];
     let colors = [ Color::from_gp0(self.gp0_command[0]); 4];
-    self.renderer.push_quad(positions, colors);
+    if let Some(renderer) = self.renderer.as_mut() {
+      renderer.push_quad(positions, colors);
+    }
   }
 
   fn gp0_clear_cache(&mut self) {
@@ -285,7 +364,9 @@ impl Gpu {
       Color::from_gp0(self.gp0_command[4]),
       Color::from_gp0(self.gp0_command[6]),
     ];
-    self.renderer.push_quad(positions, colors);
+    if let Some(renderer) = self.renderer.as_mut() {
+      renderer.push_quad(positions, colors);
+    }
   }
 
   fn gp0_triangle_shaded_opaque(&mut self) {
@@ -299,7 +380,9 @@ impl Gpu {
       Color::from_gp0(self.gp0_command[2]),
       Color::from_gp0(self.gp0_command[4]),
     ];
-    self.renderer.push_triangle(positions, colors);
+    if let Some(renderer) = self.renderer.as_mut() {
+      renderer.push_triangle(positions, colors);
+    }
   }
 
   fn gp0_quad_texture_blend_opaque(&mut self) {
@@ -312,7 +395,9 @@ impl Gpu {
     let colors = [
       Color(0x80, 0x00, 0x00); 4
     ];
-    self.renderer.push_quad(positions, colors);
+    if let Some(renderer) = self.renderer.as_mut() {
+      renderer.push_quad(positions, colors);
+    }
   }
 
   pub fn gp1(&mut self, val: u32) {
@@ -443,6 +528,21 @@ impl Gpu {
   }
 }
 
+impl_state!(Gpu {
+  page_base_x, page_base_y, semi_transparency, texture_depth, dithering,
+  draw_to_display, force_set_mask_bit, preserve_masked_pixels, field,
+  texture_disable, hres, vres, vmode, display_depth, interlaced,
+  display_disabled, interrupt, dma_direction,
+  rectangle_texture_x_flip, rectangle_texture_y_flip,
+  texture_window_x_mask, texture_window_y_mask, texture_window_x_offset, texture_window_y_offset,
+  drawing_area_left, drawing_area_top, drawing_area_right, drawing_area_bottom,
+  drawing_x_offset, drawing_y_offset,
+  display_vram_x_start, display_vram_y_start,
+  display_horiz_start, display_horiz_end, display_line_start, display_line_end,
+  gp0_command, gp0_words_remaining, gp0_mode,
+  renderer, frame_updated, frame_count,
+} => Gpu::restore_command_method);
+
 #[derive(Debug, Clone, Copy)]
 enum TextureDepth {
   T4Bit = 0,
@@ -450,15 +550,21 @@ enum TextureDepth {
   T15Bit = 2,
 }
 
+impl_state_enum!(TextureDepth { T4Bit, T8Bit, T15Bit });
+
 #[derive(Debug, Clone, Copy)]
 enum Field {
   Top = 1,
   Bottom = 0,
 }
 
+impl_state_enum!(Field { Top, Bottom });
+
 #[derive(Debug, Clone, Copy)]
 struct HorizontalRes(u8);
 
+impl_state!(HorizontalRes { 0 });
+
 impl HorizontalRes {
   fn from_fields(hr1: u8, hr2: u8) -> Self {
     let hr = (hr2 & 1) | ((hr1 & 3) << 1);
@@ -469,6 +575,14 @@ impl HorizontalRes {
     let HorizontalRes(hr) = self;
     (hr as u32) << 16
   }
+
+  fn width(self) -> u16 {
+    let HorizontalRes(hr) = self;
+    match hr & 1 {
+      1 => 368,
+      _ => [256, 320, 512, 640][(hr >> 1) as usize],
+    }
+  }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -477,18 +591,33 @@ enum VerticalRes {
   Y480Lines = 1,
 }
 
+impl_state_enum!(VerticalRes { Y240Lines, Y480Lines });
+
+impl VerticalRes {
+  fn height(self) -> u16 {
+    match self {
+      VerticalRes::Y240Lines => 240,
+      VerticalRes::Y480Lines => 480,
+    }
+  }
+}
+
 #[derive(Debug, Clone, Copy)]
 enum VMode {
   Ntsc = 0,
   Pal = 1,
 }
 
+impl_state_enum!(VMode { Ntsc, Pal });
+
 #[derive(Debug, Clone, Copy)]
 enum DisplayDepth {
   D15Bits = 0,
   D24Bits = 1,
 }
 
+impl_state_enum!(DisplayDepth { D15Bits, D24Bits });
+
 #[derive(Debug, Clone, Copy)]
 enum DmaDirection {
   Off = 0,
@@ -497,11 +626,18 @@ enum DmaDirection {
   VramToCpu = 3,
 }
 
+impl_state_enum!(DmaDirection { Off, Fifo, CpuToGp0, VramToCpu });
+
 struct CommandBuffer {
   buffer: [u32; 12],
   len: u8,
 }
 
+impl_state!(CommandBuffer { buffer, len } => |command| match command.len as usize <= command.buffer.len() {
+  true => Ok(()),
+  false => Err(invalid_state("Invalid GP0 command length")),
+});
+
 impl CommandBuffer {
   fn new() -> Self {
     Self {
@@ -530,7 +666,10 @@ impl ::std::ops::Index<usize> for CommandBuffer {
   }
 }
 
+#[derive(Clone, Copy)]
 enum Gp0Mode {
   Command,
   ImageLoad,
 }
+
+impl_state_enum!(Gp0Mode { Command, ImageLoad });
@@ -0,0 +1,1450 @@
+use std::{collections::VecDeque, io::{Error, ErrorKind}};
+
+use crate::{bios_trace::{self, BiosTable}, boot::{self, BootImage}, console::Console, cpu::Cpu, disc::Disc, exe::{self, ExeHeader, PsxExe}, savestate::{invalid_state, State, StateReader, StateWriter}};
+
+// BIOS ROM の代わりにカーネルの A/B/C 関数と例外ハンドラをホスト側で実行する。
+// RAM 上のベクタやテーブルは実機と同じ場所に置き、テーブルの各エントリは
+// BIOS ROM 内の HLE 用アドレスを指す。CPU がそのアドレスに来たら対応する関数を実行する
+
+const V0: usize = 2;
+const A0: usize = 4;
+const A1: usize = 5;
+const A2: usize = 6;
+const A3: usize = 7;
+const S0: usize = 16;
+const GP: usize = 28;
+const SP: usize = 29;
+const FP: usize = 30;
+const RA: usize = 31;
+
+const HLE_BASE: u32 = 0xBFC1_0000;
+const HLE_A_BASE: u32 = HLE_BASE;
+const HLE_B_BASE: u32 = HLE_BASE + 0x400;
+const HLE_C_BASE: u32 = HLE_BASE + 0x800;
+const HLE_EXCEPTION: u32 = HLE_BASE + 0xC00;
+const HLE_CALLBACK_RETURN: u32 = HLE_BASE + 0xC04;
+// A(40h) SystemErrorUnresolvedException
+const HLE_UNRESOLVED_EXCEPTION: u32 = HLE_A_BASE + 0x40 * 4;
+
+// カーネル領域 (RAM の先頭 64KB) の配置
+const A_TABLE: u32 = 0x0000_0200;
+const A_TABLE_SIZE: u32 = 0xC0;
+const C_TABLE: u32 = 0x0000_0674;
+const C_TABLE_SIZE: u32 = 0x40;
+const B_TABLE: u32 = 0x0000_0874;
+const B_TABLE_SIZE: u32 = 0x60;
+const DISPATCHER: u32 = 0x0000_0C00;
+const KERNEL_HEAP: u32 = 0x8000_A000;
+const KERNEL_HEAP_SIZE: u32 = 0x4000;
+const KERNEL_STACK: u32 = 0x8000_A000;
+
+const I_STAT: u32 = 0x1F80_1070;
+const I_MASK: u32 = 0x1F80_1074;
+const GP0: u32 = 0x1F80_1810;
+const GP1: u32 = 0x1F80_1814;
+const TIMERS: u32 = 0x1F80_1100;
+
+// 0x80: 例外ベクタ (k0 を使って HLE の例外ハンドラへ飛ぶ)
+const EXCEPTION_VECTOR: [u32; 4] = [
+  0x3C1A_BFC1, // lui k0, 0xBFC1
+  0x375A_0C00, // ori k0, k0, 0x0C00
+  0x0340_0008, // jr k0
+  0x0000_0000, // nop
+];
+
+// 0xA0/0xB0/0xC0: 各テーブルのディスパッチャへ飛ぶ
+const CALL_VECTORS: [[u32; 4]; 3] = [
+  [0x2408_0C00, 0x0100_0008, 0, 0], // addiu t0, zero, 0x0C00; jr t0
+  [0x2408_0C20, 0x0100_0008, 0, 0], // addiu t0, zero, 0x0C20; jr t0
+  [0x2408_0C40, 0x0100_0008, 0, 0], // addiu t0, zero, 0x0C40; jr t0
+];
+
+// テーブルから t1 番目のエントリを読んでジャンプする
+fn dispatcher(table: u32) -> [u32; 8] {
+  [
+    0x240A_0000 | table, // addiu t2, zero, table
+    0x0009_4880, // sll t1, t1, 2
+    0x012A_4821, // addu t1, t1, t2
+    0x8D29_0000, // lw t1, 0(t1)
+    0x0000_0000, // nop
+    0x0120_0008, // jr t1
+    0x0000_0000, // nop
+    0x0000_0000,
+  ]
+}
+
+// イベントの状態
+const EVENT_FREE: u32 = 0x0000;
+const EVENT_DISABLED: u32 = 0x1000;
+const EVENT_ENABLED: u32 = 0x2000;
+const EVENT_READY: u32 = 0x4000;
+// イベントのモード
+const EVENT_MODE_CALLBACK: u32 = 0x1000;
+const EVENT_MODE_READY: u32 = 0x2000;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Context {
+  regs: [u32; 32],
+  hi: u32,
+  lo: u32,
+  sr: u32,
+  epc: u32,
+}
+
+impl_state!(Context { regs, hi, lo, sr, epc });
+
+impl Context {
+  fn save(cpu: &Cpu, epc: u32) -> Self {
+    let mut regs = [0; 32];
+    for (i, r) in regs.iter_mut().enumerate() {
+      *r = cpu.gpr(i);
+    }
+    let (hi, lo) = cpu.hi_lo();
+    Self { regs, hi, lo, sr: cpu.sr(), epc }
+  }
+
+  fn restore(&self, cpu: &mut Cpu) {
+    for (i, &r) in self.regs.iter().enumerate() {
+      cpu.set_gpr(i, r);
+    }
+    cpu.set_hi_lo(self.hi, self.lo);
+    cpu.set_sr(self.sr);
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Event {
+  class: u32,
+  spec: u32,
+  mode: u32,
+  func: u32,
+  status: u32,
+}
+
+impl_state!(Event { class, spec, mode, func, status });
+
+#[derive(Default)]
+struct OpenFile {
+  data: Vec<u8>,
+  pos: usize,
+}
+
+impl_state!(OpenFile { data, pos });
+
+// ゲストのメモリ上のヒープ (管理情報はホスト側に持つ)
+struct Heap {
+  start: u32,
+  end: u32,
+  // (アドレス, サイズ) をアドレス順に並べる
+  blocks: Vec<(u32, u32)>,
+}
+
+impl_state!(Heap { start, end, blocks });
+
+impl Heap {
+  fn new(start: u32, size: u32) -> Self {
+    Self { start, end: start.wrapping_add(size), blocks: Vec::new() }
+  }
+
+  fn alloc(&mut self, size: u32) -> Option<u32> {
+    let size = size.max(1).checked_add(3)? & !3;
+    let mut addr = self.start;
+    // ゲストが壊したヒープやセーブステートの値でも桁あふれさせず、確保に失敗させる
+    for (i, &(a, s)) in self.blocks.iter().enumerate() {
+      if a.checked_sub(addr)? >= size {
+        self.blocks.insert(i, (addr, size));
+        return Some(addr);
+      }
+      addr = a.checked_add(s)?;
+    }
+    if self.end.checked_sub(addr)? >= size {
+      self.blocks.push((addr, size));
+      return Some(addr);
+    }
+    None
+  }
+
+  fn free(&mut self, addr: u32) -> Option<u32> {
+    let i = self.blocks.iter().position(|&(a, _)| a == addr)?;
+    Some(self.blocks.remove(i).1)
+  }
+}
+
+// ゲストの関数を呼んだ後に続ける処理
+#[derive(Default)]
+enum Work {
+  Call { func: u32, args: [u32; 2] },
+  // 割り込みチェーンのエントリ: [0] 次, [1] 2番目の関数, [2] 1番目の関数
+  ChainNode(u32),
+  ChainResult(u32),
+  #[default]
+  Interrupts,
+  ExitException,
+  Return { ra: u32, v0: u32 },
+  // チェーンの関数がどれも戻ってきた割り込み以外の例外
+  UnresolvedException,
+}
+
+// 種類の番号の後にフィールドを並べる
+impl State for Work {
+  fn save(&self, w: &mut StateWriter) {
+    match *self {
+      Work::Call { func, args } => {
+        0u8.save(w);
+        func.save(w);
+        args.save(w);
+      }
+      Work::ChainNode(entry) => {
+        1u8.save(w);
+        entry.save(w);
+      }
+      Work::ChainResult(entry) => {
+        2u8.save(w);
+        entry.save(w);
+      }
+      Work::Interrupts => 3u8.save(w),
+      Work::ExitException => 4u8.save(w),
+      Work::Return { ra, v0 } => {
+        5u8.save(w);
+        ra.save(w);
+        v0.save(w);
+      }
+      Work::UnresolvedException => 6u8.save(w),
+    }
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), Error> {
+    *self = match r.value::<u8>()? {
+      0 => Work::Call { func: r.value()?, args: r.value()? },
+      1 => Work::ChainNode(r.value()?),
+      2 => Work::ChainResult(r.value()?),
+      3 => Work::Interrupts,
+      4 => Work::ExitException,
+      5 => Work::Return { ra: r.value()?, v0: r.value()? },
+      6 => Work::UnresolvedException,
+      _ => return Err(invalid_state("Invalid HLE BIOS work")),
+    };
+    Ok(())
+  }
+}
+
+#[derive(Default)]
+struct Frame {
+  work: VecDeque<Work>,
+  exception: bool,
+}
+
+impl_state!(Frame { work, exception });
+
+// HLE BIOS の関数を実行した後の状態
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HleStatus {
+  Running,
+  // ゲストが exit を呼んだ (終了コード)
+  Exited(i32),
+  // SystemError などでそれ以上進めない
+  Halted,
+}
+
+pub struct HleBios {
+  disc: Option<Disc>,
+  // TTY 出力とメッセージの出力先
+  console: Console,
+  heap: Heap,
+  kernel_heap: Heap,
+  events: Vec<Event>,
+  threads: Vec<Option<Context>>,
+  current_thread: usize,
+  int_chains: [u32; 4],
+  custom_exit: u32,
+  frames: Vec<Frame>,
+  files: Vec<Option<OpenFile>>,
+  last_error: u32,
+  rand_seed: u32,
+  clear_rcnt: [bool; 4],
+  pad_buffers: [(u32, u32); 2],
+  pad_started: bool,
+  conf: [u32; 3],
+  tty_line: Vec<u8>,
+  // 止まったときは PC を進めないので、同じアドレスをもう一度実行しても同じ状態になる
+  status: HleStatus,
+}
+
+// ディスクはフロントエンドが読み込み直す。status は execute のたびに決まる
+impl_state!(HleBios {
+  heap, kernel_heap, events, threads, current_thread, int_chains, custom_exit,
+  frames, files, last_error, rand_seed, clear_rcnt, pad_buffers, pad_started, conf, tty_line,
+} => |hle| match hle.current_thread < hle.threads.len() {
+  true => Ok(()),
+  false => Err(invalid_state("Invalid HLE BIOS thread")),
+});
+
+// ディスクなしで起動した直後の状態 (セーブステートの読み込み先)
+impl Default for HleBios {
+  fn default() -> Self {
+    Self::new(None, Console::default())
+  }
+}
+
+impl HleBios {
+  pub fn new(disc: Option<Disc>, console: Console) -> Self {
+    Self {
+      disc,
+      console,
+      heap: Heap::new(0, 0),
+      kernel_heap: Heap::new(KERNEL_HEAP, KERNEL_HEAP_SIZE),
+      events: Vec::new(),
+      // スレッド0 は起動時のスレッド
+      threads: vec![Some(Context::default())],
+      current_thread: 0,
+      int_chains: [0; 4],
+      custom_exit: 0,
+      frames: Vec::new(),
+      // 0: 標準入力, 1: 標準出力
+      files: vec![None, None],
+      last_error: 0,
+      rand_seed: 0,
+      clear_rcnt: [true; 4],
+      pad_buffers: [(0, 0); 2],
+      pad_started: false,
+      conf: [16, 4, exe::DEFAULT_STACK],
+      tty_line: Vec::new(),
+      status: HleStatus::Running,
+    }
+  }
+
+  pub fn handles(&self, pc: u32) -> bool {
+    (pc & 0x1FFF_F000) == (HLE_BASE & 0x1FFF_F000)
+  }
+
+  // セーブステートを読み込んだ新しい HleBios に、ディスクと出力先を old から移す
+  pub fn take_host(&mut self, old: &mut HleBios) {
+    self.disc = old.disc.take();
+    std::mem::swap(&mut self.console, &mut old.console);
+  }
+
+  // EXE (指定がなければディスクの SYSTEM.CNF にある BOOT) を読み込んで実行を開始する
+  pub fn boot(&mut self, cpu: &mut Cpu, exe: Option<Vec<u8>>) -> Result<(), Error> {
+    self.install_kernel(cpu);
+
+    let image = match exe {
+      Some(data) => BootImage::from_exe(&data)?,
+      None => {
+        let disc = self.disc.as_mut()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "HLE BIOS needs an EXE or a disc to boot"))?;
+        BootImage::from_disc(disc)?
+      }
+    };
+
+    // 割り込みを許可した状態で EXE に入る
+    cpu.set_sr(0x0000_0401);
+    image.run(cpu);
+    Ok(())
+  }
+
+  fn install_kernel(&mut self, cpu: &mut Cpu) {
+    for (i, &w) in EXCEPTION_VECTOR.iter().enumerate() {
+      write32(cpu, 0x80 + i as u32 * 4, w);
+    }
+    for (v, words) in CALL_VECTORS.iter().enumerate() {
+      for (i, &w) in words.iter().enumerate() {
+        write32(cpu, 0xA0 + v as u32 * 0x10 + i as u32 * 4, w);
+      }
+    }
+    for (d, table) in [A_TABLE, B_TABLE, C_TABLE].iter().enumerate() {
+      for (i, &w) in dispatcher(*table).iter().enumerate() {
+        write32(cpu, DISPATCHER + d as u32 * 0x20 + i as u32 * 4, w);
+      }
+    }
+    for (table, size, base) in [(A_TABLE, A_TABLE_SIZE, HLE_A_BASE), (B_TABLE, B_TABLE_SIZE, HLE_B_BASE), (C_TABLE, C_TABLE_SIZE, HLE_C_BASE)] {
+      for n in 0..size {
+        write32(cpu, table + n * 4, base + n * 4);
+      }
+    }
+
+    // BIOS が設定するキャッシュコントロール
+    cpu.inter.store32(0xFFFE_0130, 0x0001_E988);
+    cpu.set_sr(0);
+  }
+
+  pub fn execute(&mut self, cpu: &mut Cpu) -> HleStatus {
+    self.status = HleStatus::Running;
+    let addr = (cpu.current_pc() & 0x1FFF_FFFF) | 0xA000_0000;
+    match addr {
+      HLE_EXCEPTION => self.exception(cpu),
+      HLE_CALLBACK_RETURN => self.run_frames(cpu),
+      a if (HLE_C_BASE..HLE_C_BASE + C_TABLE_SIZE * 4).contains(&a) => self.call(cpu, BiosTable::C, (a - HLE_C_BASE) / 4),
+      a if (HLE_B_BASE..HLE_B_BASE + B_TABLE_SIZE * 4).contains(&a) => self.call(cpu, BiosTable::B, (a - HLE_B_BASE) / 4),
+      a if a < HLE_A_BASE + A_TABLE_SIZE * 4 => self.call(cpu, BiosTable::A, (a - HLE_A_BASE) / 4),
+      _ => self.stop(HleStatus::Halted, &format!("jump to unmapped address {:08X}", addr)),
+    }
+    self.status
+  }
+
+  fn stop(&mut self, status: HleStatus, message: &str) {
+    self.console.message(&format!("HLE BIOS: {}", message));
+    self.status = status;
+  }
+
+  // 割り込みチェーンの関数を優先度の順に呼ぶ
+  fn chain_work(&self) -> VecDeque<Work> {
+    self.int_chains.iter().filter(|&&head| head != 0).map(|&head| Work::ChainNode(head)).collect()
+  }
+
+  fn exception(&mut self, cpu: &mut Cpu) {
+    let code = (cpu.cause() >> 2) & 0x1F;
+    let epc = cpu.epc();
+    match code {
+      // Interrupt
+      0x00 => {
+        self.threads[self.current_thread] = Some(Context::save(cpu, epc));
+        let mut work = self.chain_work();
+        work.push_back(Work::Interrupts);
+        work.push_back(Work::ExitException);
+        self.frames.push(Frame { work, exception: true });
+        self.run_frames(cpu);
+      }
+      // SysCall
+      0x08 => {
+        cpu.return_from_exception();
+        let mut sr = cpu.sr();
+        match cpu.gpr(A0) {
+          0 => {}
+          // EnterCriticalSection
+          1 => {
+            cpu.set_gpr(V0, (sr & 0x404 == 0x404) as u32);
+            sr &= !0x404;
+          }
+          // ExitCriticalSection
+          2 => sr |= 0x404,
+          n => self.console.message(&format!("HLE BIOS: unhandled syscall {:X}", n)),
+        }
+        cpu.set_sr(sr);
+        cpu.set_pc(epc.wrapping_add(4));
+      }
+      // それ以外の例外もゲストが登録したチェーンに渡す。
+      // どの関数も戻ってきたら実機と同じく SystemErrorUnresolvedException で止まる
+      _ => {
+        self.threads[self.current_thread] = Some(Context::save(cpu, epc));
+        let mut work = self.chain_work();
+        work.push_back(Work::UnresolvedException);
+        self.frames.push(Frame { work, exception: true });
+        self.run_frames(cpu);
+      }
+    }
+  }
+
+  // 保留中の処理を、ゲストの関数を呼ぶ必要があるところまで進める
+  fn run_frames(&mut self, cpu: &mut Cpu) {
+    while let Some(work) = self.frames.last_mut().and_then(|f| f.work.pop_front()) {
+      match work {
+        Work::Call { func, args } => return self.call_guest(cpu, func, args),
+        Work::ChainNode(0) => {}
+        Work::ChainNode(node) => {
+          let next = read32(cpu, node);
+          let func1 = read32(cpu, node + 8);
+          let frame = self.frames.last_mut().unwrap();
+          frame.work.push_front(Work::ChainNode(next));
+          frame.work.push_front(Work::ChainResult(node));
+          if func1 != 0 {
+            return self.call_guest(cpu, func1, [0, 0]);
+          }
+          cpu.set_gpr(V0, 0);
+        }
+        // 1番目の関数が 0 以外を返したら、それを引数に2番目の関数を呼ぶ
+        Work::ChainResult(node) => {
+          let v0 = cpu.gpr(V0);
+          let func2 = read32(cpu, node + 4);
+          if v0 != 0 && func2 != 0 {
+            return self.call_guest(cpu, func2, [v0, 0]);
+          }
+        }
+        Work::Interrupts => self.builtin_interrupts(cpu),
+        Work::ExitException => {
+          self.frames.pop();
+          return self.exit_exception(cpu);
+        }
+        Work::Return { ra, v0 } => {
+          self.frames.pop();
+          cpu.set_gpr(V0, v0);
+          cpu.set_gpr(RA, ra);
+          return cpu.set_pc(ra);
+        }
+        Work::UnresolvedException => {
+          self.frames.pop();
+          let code = (cpu.cause() >> 2) & 0x1F;
+          let epc = cpu.epc();
+          cpu.set_pc(HLE_UNRESOLVED_EXCEPTION);
+          return self.stop(HleStatus::Halted, &format!("unresolved exception {:02X} at {:08X}", code, epc));
+        }
+      }
+    }
+    self.stop(HleStatus::Halted, "callback returned without a pending frame");
+  }
+
+  fn call_guest(&mut self, cpu: &mut Cpu, func: u32, args: [u32; 2]) {
+    cpu.set_gpr(A0, args[0]);
+    cpu.set_gpr(A1, args[1]);
+    cpu.set_gpr(RA, HLE_CALLBACK_RETURN);
+    if self.frames.last().is_some_and(|f| f.exception) {
+      cpu.set_gpr(SP, KERNEL_STACK);
+    }
+    cpu.set_pc(func);
+  }
+
+  // ゲストの関数を順に呼んだ後、呼び出し元へ戻る
+  fn call_then_return(&mut self, cpu: &mut Cpu, funcs: Vec<u32>, v0: u32) -> Option<u32> {
+    if funcs.is_empty() {
+      return Some(v0);
+    }
+    let mut work: VecDeque<Work> = funcs.into_iter().map(|func| Work::Call { func, args: [0, 0] }).collect();
+    work.push_back(Work::Return { ra: cpu.gpr(RA), v0 });
+    self.frames.push(Frame { work, exception: false });
+    self.run_frames(cpu);
+    None
+  }
+
+  // VBlank とルートカウンタの割り込みをイベントとして配送する
+  fn builtin_interrupts(&mut self, cpu: &mut Cpu) {
+    let stat = cpu.inter.load16(I_STAT) & cpu.inter.load16(I_MASK);
+    let mut funcs = Vec::new();
+    for (bit, rcnt) in [(0, 3), (4, 0), (5, 1), (6, 2)] {
+      if stat & (1 << bit) == 0 {
+        continue;
+      }
+      funcs.extend(self.deliver_event(0xF200_0000 | rcnt, 0x0002));
+      if self.clear_rcnt[rcnt as usize] {
+        cpu.inter.store16(I_STAT, !(1 << bit));
+      }
+      if bit == 0 && self.pad_started {
+        for (port, &(buf, size)) in self.pad_buffers.iter().enumerate() {
+          if buf == 0 || size == 0 {
+            continue;
+          }
+          match cpu.inter.pad.buttons(port) {
+            // 0x00 (成功), ID, 押されているボタンが 0 のビット列
+            Some(buttons) if size >= 4 => {
+              write8(cpu, buf, 0x00);
+              write8(cpu, buf + 1, 0x41);
+              write8(cpu, buf + 2, !buttons as u8);
+              write8(cpu, buf + 3, !(buttons >> 8) as u8);
+            }
+            // コントローラが接続されていない
+            _ => write8(cpu, buf, 0xFF),
+          }
+        }
+      }
+    }
+    let frame = self.frames.last_mut().unwrap();
+    for func in funcs.into_iter().rev() {
+      frame.work.push_front(Work::Call { func, args: [0, 0] });
+    }
+  }
+
+  fn exit_exception(&mut self, cpu: &mut Cpu) {
+    if self.custom_exit != 0 {
+      // SetCustomExitFromException で登録された jmp_buf に戻る (例外処理中のまま)
+      let buf = self.custom_exit;
+      return restore_state(cpu, buf, 1);
+    }
+    self.return_from_exception(cpu);
+  }
+
+  fn return_from_exception(&mut self, cpu: &mut Cpu) {
+    let context = self.threads[self.current_thread].unwrap_or_default();
+    context.restore(cpu);
+    cpu.return_from_exception();
+    cpu.set_pc(context.epc);
+  }
+
+  fn deliver_event(&mut self, class: u32, spec: u32) -> Vec<u32> {
+    let mut funcs = Vec::new();
+    for event in self.events.iter_mut() {
+      if event.status != EVENT_ENABLED || event.class != class || event.spec != spec {
+        continue;
+      }
+      match event.mode {
+        EVENT_MODE_CALLBACK if event.func != 0 => funcs.push(event.func),
+        EVENT_MODE_READY => event.status = EVENT_READY,
+        _ => {}
+      }
+    }
+    funcs
+  }
+
+  fn event_mut(&mut self, handle: u32) -> Option<&mut Event> {
+    if handle & 0xFFFF_0000 != 0xF100_0000 {
+      return None;
+    }
+    self.events.get_mut((handle & 0xFFFF) as usize).filter(|e| e.status != EVENT_FREE)
+  }
+
+  fn tty_write(&mut self, val: u8) {
+    match val {
+      b'\r' => {}
+      b'\n' => {
+        self.tty_line.push(b'\n');
+        self.console.write(&self.tty_line);
+        self.tty_line.clear();
+      }
+      _ => self.tty_line.push(val),
+    }
+  }
+
+  fn open_file(&mut self, name: &str) -> Option<Vec<u8>> {
+    let path = name.strip_prefix("cdrom:")?;
+    self.disc.as_mut()?.read_file(path).ok()
+  }
+
+  fn call(&mut self, cpu: &mut Cpu, table: BiosTable, num: u32) {
+    let ra = cpu.gpr(RA);
+    let a = [cpu.gpr(A0), cpu.gpr(A1), cpu.gpr(A2), cpu.gpr(A3)];
+    let result = match table {
+      BiosTable::A => self.call_a(cpu, num, a),
+      BiosTable::B => self.call_b(cpu, num, a),
+      BiosTable::C => self.call_c(cpu, num, a),
+    };
+    // None のときは関数側で PC を設定している
+    if let Some(v0) = result {
+      cpu.set_gpr(V0, v0);
+      cpu.set_pc(ra);
+    }
+  }
+
+  fn unimplemented(&self, table: BiosTable, num: u32) -> Option<u32> {
+    let name = bios_trace::function_name(table, num);
+    match name {
+      "return_0" => {}
+      _ => self.console.message(&format!("HLE BIOS: unimplemented {:?}({:02X}h) {}", table, num, name)),
+    }
+    Some(0)
+  }
+
+  fn call_a(&mut self, cpu: &mut Cpu, num: u32, a: [u32; 4]) -> Option<u32> {
+    let result = match num {
+      0x00 => return self.file_open(cpu, a[0]),
+      0x01 => return self.file_seek(a[0], a[1], a[2]),
+      0x02 => return self.file_read(cpu, a[0], a[1], a[2]),
+      0x03 => return self.file_write(cpu, a[0], a[1], a[2]),
+      0x04 => return self.file_close(a[0]),
+      0x05 | 0x07 => 0,
+      0x06 => {
+        self.stop(HleStatus::Exited(a[0] as i32), &format!("exit({})", a[0] as i32));
+        return None;
+      }
+      0x08 => return self.file_getc(a[0]),
+      0x09 => {
+        if a[1] == 1 {
+          self.tty_write(a[0] as u8);
+        }
+        a[0]
+      }
+      // todigit
+      0x0A => match a[0] as u8 {
+        c @ b'0'..=b'9' => (c - b'0') as u32,
+        c @ b'a'..=b'z' => (c - b'a' + 10) as u32,
+        c @ b'A'..=b'Z' => (c - b'A' + 10) as u32,
+        _ => 9_999_999,
+      },
+      // strtoul / strtol
+      0x0C | 0x0D => {
+        let (v, end) = parse_int(&read_cstr(cpu, a[0]), a[2]);
+        if a[1] != 0 {
+          write32(cpu, a[1], a[0].wrapping_add(end as u32));
+        }
+        v as u32
+      }
+      0x0E | 0x0F => (a[0] as i32).wrapping_abs() as u32,
+      0x10 | 0x11 => parse_int(&read_cstr(cpu, a[0]), 10).0 as u32,
+      0x13 => save_state(cpu, a[0]),
+      0x14 => {
+        restore_state(cpu, a[0], a[1]);
+        return None;
+      }
+      // strcat / strncat
+      0x15 | 0x16 => {
+        if a[0] == 0 || a[1] == 0 {
+          return Some(0);
+        }
+        let dst = read_cstr(cpu, a[0]);
+        let mut src = read_cstr(cpu, a[1]);
+        if num == 0x16 {
+          src.truncate(a[2] as usize);
+        }
+        write_cstr(cpu, a[0].wrapping_add(dst.len() as u32), &src);
+        a[0]
+      }
+      // strcmp / strncmp
+      0x17 | 0x18 => {
+        if a[0] == 0 || a[1] == 0 {
+          return Some(((a[0] != 0) as i32 - (a[1] != 0) as i32) as u32);
+        }
+        let limit = if num == 0x18 { a[2] as usize } else { usize::MAX };
+        let s1 = read_cstr(cpu, a[0]);
+        let s2 = read_cstr(cpu, a[1]);
+        let mut result = 0;
+        for i in 0..limit.min(s1.len().max(s2.len()) + 1) {
+          let c1 = s1.get(i).copied().unwrap_or(0) as i32;
+          let c2 = s2.get(i).copied().unwrap_or(0) as i32;
+          if c1 != c2 {
+            result = c1 - c2;
+            break;
+          }
+        }
+        result as u32
+      }
+      // strcpy / strncpy
+      0x19 | 0x1A => {
+        if a[0] == 0 || a[1] == 0 {
+          return Some(0);
+        }
+        let mut src = read_cstr(cpu, a[1]);
+        if num == 0x1A {
+          // 足りない分は 0 で埋める。長さは書き込み先のメモリの終わりまでに切り詰める
+          let len = a[2].min(cpu.inter.memory_len(a[0]));
+          src.resize(len as usize, 0);
+          write_bytes(cpu, a[0], &src);
+        } else {
+          write_cstr(cpu, a[0], &src);
+        }
+        a[0]
+      }
+      0x1B => if a[0] == 0 { 0 } else { read_cstr(cpu, a[0]).len() as u32 },
+      // index / strchr
+      0x1C | 0x1E => {
+        let s = read_cstr(cpu, a[0]);
+        let c = a[1] as u8;
+        match s.iter().chain(std::iter::once(&0)).position(|&x| x == c) {
+          Some(i) => a[0].wrapping_add(i as u32),
+          None => 0,
+        }
+      }
+      // rindex / strrchr
+      0x1D | 0x1F => {
+        let s = read_cstr(cpu, a[0]);
+        let c = a[1] as u8;
+        // NUL を探したときは終端を返す
+        let found = if c == 0 { Some(s.len()) } else { s.iter().rposition(|&x| x == c) };
+        match found {
+          Some(i) => a[0].wrapping_add(i as u32),
+          None => 0,
+        }
+      }
+      // strpbrk
+      0x20 => {
+        let s = read_cstr(cpu, a[0]);
+        let set = read_cstr(cpu, a[1]);
+        match s.iter().position(|c| set.contains(c)) {
+          Some(i) => a[0].wrapping_add(i as u32),
+          None => 0,
+        }
+      }
+      // strspn / strcspn
+      0x21 | 0x22 => {
+        let s = read_cstr(cpu, a[0]);
+        let set = read_cstr(cpu, a[1]);
+        s.iter().take_while(|c| set.contains(c) == (num == 0x21)).count() as u32
+      }
+      // strstr
+      0x24 => {
+        let s = read_cstr(cpu, a[0]);
+        let needle = read_cstr(cpu, a[1]);
+        if needle.is_empty() {
+          a[0]
+        } else {
+          match s.windows(needle.len()).position(|w| w == &needle[..]) {
+            Some(i) => a[0].wrapping_add(i as u32),
+            None => 0,
+          }
+        }
+      }
+      0x25 => (a[0] as u8).to_ascii_uppercase() as u32,
+      0x26 => (a[0] as u8).to_ascii_lowercase() as u32,
+      // bcopy (src, dst, len)
+      0x27 => {
+        let data = read_bytes(cpu, a[0], a[2]);
+        write_bytes(cpu, a[1], &data);
+        a[1]
+      }
+      // bzero
+      0x28 => {
+        fill_bytes(cpu, a[0], 0, a[1]);
+        a[0]
+      }
+      // bcmp / memcmp
+      0x29 | 0x2D => {
+        let p1 = read_bytes(cpu, a[0], a[2]);
+        let p2 = read_bytes(cpu, a[1], a[2]);
+        match p1.iter().zip(p2.iter()).find(|(x, y)| x != y) {
+          Some((&x, &y)) => (x as i32 - y as i32) as u32,
+          None => 0,
+        }
+      }
+      // memcpy / memmove
+      0x2A | 0x2C => {
+        let data = read_bytes(cpu, a[1], a[2]);
+        write_bytes(cpu, a[0], &data);
+        a[0]
+      }
+      // memset
+      0x2B => {
+        fill_bytes(cpu, a[0], a[1] as u8, a[2]);
+        a[0]
+      }
+      // memchr
+      0x2E => {
+        let data = read_bytes(cpu, a[0], a[2]);
+        match data.iter().position(|&x| x == a[1] as u8) {
+          Some(i) => a[0].wrapping_add(i as u32),
+          None => 0,
+        }
+      }
+      // rand
+      0x2F => {
+        self.rand_seed = self.rand_seed.wrapping_mul(0x41C6_4E6D).wrapping_add(0x3039);
+        (self.rand_seed >> 16) & 0x7FFF
+      }
+      0x30 => {
+        self.rand_seed = a[0];
+        0
+      }
+      0x33 => self.heap.alloc(a[0]).unwrap_or(0),
+      0x34 => {
+        self.heap.free(a[0]);
+        0
+      }
+      // calloc
+      0x37 => {
+        let size = a[0].wrapping_mul(a[1]);
+        match self.heap.alloc(size) {
+          Some(addr) => {
+            fill_bytes(cpu, addr, 0, size);
+            addr
+          }
+          None => 0,
+        }
+      }
+      // realloc
+      0x38 => {
+        if a[0] == 0 {
+          return Some(self.heap.alloc(a[1]).unwrap_or(0));
+        }
+        let old_size = self.heap.free(a[0]).unwrap_or(0);
+        match self.heap.alloc(a[1]) {
+          Some(addr) => {
+            let data = read_bytes(cpu, a[0], old_size.min(a[1]));
+            write_bytes(cpu, addr, &data);
+            addr
+          }
+          None => 0,
+        }
+      }
+      0x39 => {
+        self.heap = Heap::new(a[0], a[1]);
+        0
+      }
+      0x3A | 0x40 | 0xA1 => {
+        self.stop(HleStatus::Halted, &format!("{} ({:08X}, {:08X})", bios_trace::function_name(BiosTable::A, num), a[0], a[1]));
+        return None;
+      }
+      0x3B | 0x3D => 0,
+      0x3C => {
+        self.tty_write(a[0] as u8);
+        a[0]
+      }
+      0x3E => {
+        for c in read_cstr(cpu, a[0]) {
+          self.tty_write(c);
+        }
+        1
+      }
+      0x3F => {
+        let sp = cpu.gpr(SP);
+        let inter = &cpu.inter;
+        let arg = |n: usize| match n {
+          0..=2 => a[n + 1],
+          _ => bios_trace::peek32(inter, sp.wrapping_add((n as u32 + 1) * 4)),
+        };
+        let fmt = bios_trace::peek_string(inter, a[0]);
+        let out = bios_trace::printf(&fmt, arg, |addr| bios_trace::peek_string(inter, addr));
+        for c in out.bytes() {
+          self.tty_write(c);
+        }
+        out.len() as u32
+      }
+      // LoadExeHeader / LoadExeFile
+      0x41 | 0x42 => {
+        let name = String::from_utf8_lossy(&read_cstr(cpu, a[0])).into_owned();
+        let exe = match self.open_file(&name).and_then(|data| PsxExe::parse(&data).ok()) {
+          Some(exe) => exe,
+          None => return Some(0),
+        };
+        for (i, &w) in exe.header.to_words().iter().enumerate() {
+          write32(cpu, a[1] + i as u32 * 4, w);
+        }
+        if num == 0x42 {
+          boot::load_exe(cpu, &exe);
+          cpu.flush_icache();
+        }
+        1
+      }
+      0x43 => {
+        let header = read_exe_header(cpu, a[0]);
+        let sp = header.initial_sp().unwrap_or(cpu.gpr(SP));
+        boot::execute_exe(cpu, &header, sp, a[1], a[2]);
+        return None;
+      }
+      0x44 => {
+        cpu.flush_icache();
+        0
+      }
+      0x45 => {
+        self.install_kernel(cpu);
+        0
+      }
+      // GPU_dw / gpu_send_dma
+      0x46 | 0x47 => {
+        let src = read32(cpu, cpu.gpr(SP).wrapping_add(16));
+        cpu.inter.store32(GP0, 0xA000_0000);
+        cpu.inter.store32(GP0, (a[1] << 16) | (a[0] & 0xFFFF));
+        cpu.inter.store32(GP0, (a[3] << 16) | (a[2] & 0xFFFF));
+        // 幅と高さは 16 ビット。転送元のメモリの終わりまでに切り詰める
+        let words = ((a[2] & 0xFFFF) * (a[3] & 0xFFFF)).div_ceil(2);
+        for i in 0..words.min(cpu.inter.memory_len(src) / 4) {
+          let w = read32(cpu, src.wrapping_add(i * 4));
+          cpu.inter.store32(GP0, w);
+        }
+        0
+      }
+      0x48 => {
+        cpu.inter.store32(GP1, a[0]);
+        0
+      }
+      0x49 => {
+        cpu.inter.store32(GP0, a[0]);
+        0
+      }
+      0x4A => {
+        for i in 0..a[1].min(cpu.inter.memory_len(a[0]) / 4) {
+          let w = read32(cpu, a[0].wrapping_add(i * 4));
+          cpu.inter.store32(GP0, w);
+        }
+        0
+      }
+      0x4B => {
+        let mut addr = a[0] & 0x00FF_FFFF;
+        while addr != 0x00FF_FFFF {
+          let header = read32(cpu, addr);
+          for i in 0..(header >> 24) {
+            let w = read32(cpu, addr + 4 + i * 4);
+            cpu.inter.store32(GP0, w);
+          }
+          addr = header & 0x00FF_FFFF;
+        }
+        0
+      }
+      0x4C => {
+        cpu.inter.store32(GP1, 0x0400_0000);
+        0
+      }
+      0x4D => cpu.inter.load32(GP1),
+      0x4E => 0,
+      0x51 => {
+        let name = String::from_utf8_lossy(&read_cstr(cpu, a[0])).into_owned();
+        let exe = match self.open_file(&name).and_then(|data| PsxExe::parse(&data).ok()) {
+          Some(exe) => exe,
+          None => {
+            self.stop(HleStatus::Halted, &format!("LoadAndExecute failed: {}", name));
+            return None;
+          }
+        };
+        boot::load_exe(cpu, &exe);
+        cpu.flush_icache();
+        let sp = match a[1] {
+          0 => exe.header.initial_sp().unwrap_or(exe::DEFAULT_STACK),
+          base => base.wrapping_add(a[2]),
+        };
+        boot::execute_exe(cpu, &exe.header, sp, 0, 0);
+        return None;
+      }
+      0x52 => cpu.gpr(SP),
+      // CdInit / _bu_init / CdRemove やデバイスの登録
+      0x54 | 0x55 | 0x56 | 0x71 | 0x72 | 0x70 | 0x96..=0x99 => 0,
+      0x9C => {
+        self.conf = [a[0], a[1], a[2]];
+        0
+      }
+      0x9D => {
+        write32(cpu, a[0], self.conf[0]);
+        write32(cpu, a[1], self.conf[1]);
+        write32(cpu, a[2], self.conf[2]);
+        0
+      }
+      0x9F => 0,
+      // _card_info / _card_load: メモリーカードはつながっていない (タイムアウト)
+      0xAB | 0xAC => {
+        let funcs = self.deliver_event(0xF400_0001, 0x0100);
+        return self.call_then_return(cpu, funcs, 1);
+      }
+      // GetSystemInfo (0: カーネルの日付)
+      0xB4 => match a[0] {
+        0 => 0x1995_1204,
+        _ => 0,
+      },
+      _ => return self.unimplemented(BiosTable::A, num),
+    };
+    Some(result)
+  }
+
+  fn call_b(&mut self, cpu: &mut Cpu, num: u32, a: [u32; 4]) -> Option<u32> {
+    let result = match num {
+      0x00 => self.kernel_heap.alloc(a[0]).unwrap_or(0),
+      0x01 => {
+        self.kernel_heap.free(a[0]);
+        0
+      }
+      // init_timer
+      0x02 => {
+        if a[0] < 3 {
+          let base = TIMERS + a[0] * 0x10;
+          cpu.inter.store16(base + 8, a[1] as u16);
+          cpu.inter.store16(base + 4, a[2] as u16);
+          cpu.inter.store16(base, 0);
+        }
+        1
+      }
+      0x03 => if a[0] < 3 { cpu.inter.load16(TIMERS + a[0] * 0x10) as u32 } else { 0 },
+      // enable_timer_irq / disable_timer_irq
+      0x04 | 0x05 => {
+        let bit = match a[0] {
+          0..=2 => 4 + a[0],
+          3 => 0,
+          _ => return Some(0),
+        };
+        let mask = cpu.inter.load16(I_MASK);
+        let mask = if num == 0x04 { mask | (1 << bit) } else { mask & !(1 << bit) };
+        cpu.inter.store16(I_MASK, mask);
+        1
+      }
+      0x06 => {
+        if a[0] < 3 {
+          cpu.inter.store16(TIMERS + a[0] * 0x10, 0);
+        }
+        1
+      }
+      0x07 => {
+        let funcs = self.deliver_event(a[0], a[1]);
+        return self.call_then_return(cpu, funcs, 0);
+      }
+      // OpenEvent
+      0x08 => {
+        let event = Event { class: a[0], spec: a[1], mode: a[2], func: a[3], status: EVENT_DISABLED };
+        let index = match self.events.iter().position(|e| e.status == EVENT_FREE) {
+          Some(i) => {
+            self.events[i] = event;
+            i
+          }
+          None => {
+            self.events.push(event);
+            self.events.len() - 1
+          }
+        };
+        0xF100_0000 | index as u32
+      }
+      0x09 => match self.event_mut(a[0]) {
+        Some(e) => {
+          e.status = EVENT_FREE;
+          1
+        }
+        None => 0,
+      },
+      // WaitEvent: 準備ができるまで同じ関数を繰り返し呼ぶ (その間に割り込みが入る)
+      0x0A => match self.event_mut(a[0]) {
+        Some(e) if e.status == EVENT_READY => {
+          e.status = EVENT_ENABLED;
+          1
+        }
+        Some(e) if e.status == EVENT_ENABLED => {
+          let pc = cpu.current_pc();
+          cpu.set_pc(pc);
+          return None;
+        }
+        _ => 0,
+      },
+      0x0B => match self.event_mut(a[0]) {
+        Some(e) if e.status == EVENT_READY => {
+          e.status = EVENT_ENABLED;
+          1
+        }
+        _ => 0,
+      },
+      0x0C | 0x0D => match self.event_mut(a[0]) {
+        Some(e) => {
+          e.status = if num == 0x0C { EVENT_ENABLED } else { EVENT_DISABLED };
+          1
+        }
+        None => 0,
+      },
+      // OpenThread
+      0x0E => {
+        let mut context = Context::default();
+        context.regs[SP] = a[1];
+        context.regs[FP] = a[1];
+        context.regs[GP] = a[2];
+        context.sr = cpu.sr();
+        context.epc = a[0];
+        let index = match self.threads.iter().position(|t| t.is_none()) {
+          Some(i) => {
+            self.threads[i] = Some(context);
+            i
+          }
+          None => {
+            self.threads.push(Some(context));
+            self.threads.len() - 1
+          }
+        };
+        0xFF00_0000 | index as u32
+      }
+      0x0F => {
+        let index = (a[0] & 0xFFFF) as usize;
+        if index != self.current_thread && index < self.threads.len() {
+          self.threads[index] = None;
+        }
+        1
+      }
+      // ChangeThread: 戻ってきたときは 1 を返す
+      0x10 => {
+        let index = (a[0] & 0xFFFF) as usize;
+        let target = match self.threads.get(index).copied().flatten() {
+          Some(context) => context,
+          None => return Some(0),
+        };
+        let mut current = Context::save(cpu, cpu.gpr(RA));
+        current.regs[V0] = 1;
+        self.threads[self.current_thread] = Some(current);
+        self.current_thread = index;
+        target.restore(cpu);
+        cpu.set_pc(target.epc);
+        return None;
+      }
+      // InitPad / StartPad / StopPad
+      0x12 => {
+        self.pad_buffers = [(a[0], a[1]), (a[2], a[3])];
+        1
+      }
+      0x13 | 0x14 => {
+        self.pad_started = num == 0x13;
+        1
+      }
+      0x17 => {
+        self.return_from_exception(cpu);
+        return None;
+      }
+      0x18 => {
+        self.custom_exit = 0;
+        0
+      }
+      0x19 => {
+        self.custom_exit = a[0];
+        0
+      }
+      // UnDeliverEvent
+      0x20 => {
+        for e in self.events.iter_mut() {
+          if e.class == a[0] && e.spec == a[1] && e.status == EVENT_READY && e.mode == EVENT_MODE_READY {
+            e.status = EVENT_ENABLED;
+          }
+        }
+        0
+      }
+      0x32 => return self.file_open(cpu, a[0]),
+      0x33 => return self.file_seek(a[0], a[1], a[2]),
+      0x34 => return self.file_read(cpu, a[0], a[1], a[2]),
+      0x35 => return self.file_write(cpu, a[0], a[1], a[2]),
+      0x36 => return self.file_close(a[0]),
+      0x37 | 0x39 => 0,
+      0x38 => {
+        self.stop(HleStatus::Exited(a[0] as i32), &format!("exit({})", a[0] as i32));
+        return None;
+      }
+      0x3A => return self.file_getc(a[0]),
+      0x3B => {
+        if a[1] == 1 {
+          self.tty_write(a[0] as u8);
+        }
+        a[0]
+      }
+      0x3C | 0x3E => 0,
+      0x3D => {
+        self.tty_write(a[0] as u8);
+        a[0]
+      }
+      0x3F => {
+        for c in read_cstr(cpu, a[0]) {
+          self.tty_write(c);
+        }
+        1
+      }
+      0x40 => 1,
+      // firstfile (name, direntry)
+      0x42 => {
+        let name = String::from_utf8_lossy(&read_cstr(cpu, a[0])).into_owned();
+        let found = name.strip_prefix("cdrom:")
+            .and_then(|path| self.disc.as_mut()?.find_file(path).ok());
+        match found {
+          Some((lba, size)) => {
+            let file_name = name.rsplit(['\\', ':']).next().unwrap_or("").as_bytes();
+            let mut entry = [0u8; 40];
+            let n = file_name.len().min(19);
+            entry[..n].copy_from_slice(&file_name[..n]);
+            entry[24..28].copy_from_slice(&size.to_le_bytes());
+            entry[32..36].copy_from_slice(&lba.to_le_bytes());
+            write_bytes(cpu, a[1], &entry);
+            a[1]
+          }
+          None => 0,
+        }
+      }
+      0x43 => 0,
+      0x47 | 0x48 | 0x4A..=0x4C | 0x50 => 0,
+      0x54 | 0x55 => self.last_error,
+      0x56 => C_TABLE,
+      0x57 => B_TABLE,
+      0x5B => 0,
+      _ => return self.unimplemented(BiosTable::B, num),
+    };
+    Some(result)
+  }
+
+  fn call_c(&mut self, cpu: &mut Cpu, num: u32, a: [u32; 4]) -> Option<u32> {
+    let result = match num {
+      0x00 | 0x01 | 0x07 | 0x08 | 0x09 | 0x0C | 0x12 | 0x13 | 0x1C => 0,
+      // SysEnqIntRP (priority, struct): チェーンの先頭に追加する
+      0x02 => {
+        let prio = (a[0] & 3) as usize;
+        write32(cpu, a[1], self.int_chains[prio]);
+        self.int_chains[prio] = a[1];
+        0
+      }
+      // SysDeqIntRP
+      0x03 => {
+        let prio = (a[0] & 3) as usize;
+        let next = read32(cpu, a[1]);
+        if self.int_chains[prio] == a[1] {
+          self.int_chains[prio] = next;
+        } else {
+          let mut node = self.int_chains[prio];
+          while node != 0 {
+            let n = read32(cpu, node);
+            if n == a[1] {
+              write32(cpu, node, next);
+              break;
+            }
+            node = n;
+          }
+        }
+        0
+      }
+      // ChangeClearRCnt (timer, flag): 前の値を返す
+      0x0A => {
+        let t = (a[0] & 3) as usize;
+        let old = self.clear_rcnt[t];
+        self.clear_rcnt[t] = a[1] != 0;
+        old as u32
+      }
+      0x0D => 0,
+      _ => return self.unimplemented(BiosTable::C, num),
+    };
+    Some(result)
+  }
+
+  fn file_open(&mut self, cpu: &mut Cpu, name: u32) -> Option<u32> {
+    let name = String::from_utf8_lossy(&read_cstr(cpu, name)).into_owned();
+    match self.open_file(&name) {
+      Some(data) => {
+        let file = Some(OpenFile { data, pos: 0 });
+        let fd = match self.files.iter().skip(2).position(|f| f.is_none()) {
+          Some(i) => {
+            self.files[i + 2] = file;
+            i + 2
+          }
+          None => {
+            self.files.push(file);
+            self.files.len() - 1
+          }
+        };
+        Some(fd as u32)
+      }
+      None => {
+        // ENOENT
+        self.last_error = 2;
+        Some(0xFFFF_FFFF)
+      }
+    }
+  }
+
+  fn file_seek(&mut self, fd: u32, offset: u32, whence: u32) -> Option<u32> {
+    let file = match self.files.get_mut(fd as usize) {
+      Some(Some(file)) => file,
+      _ => return Some(0xFFFF_FFFF),
+    };
+    file.pos = match whence {
+      0 => offset as usize,
+      _ => (file.pos as i64 + offset as i32 as i64).max(0) as usize,
+    };
+    Some(file.pos as u32)
+  }
+
+  fn file_read(&mut self, cpu: &mut Cpu, fd: u32, dst: u32, len: u32) -> Option<u32> {
+    let file = match self.files.get_mut(fd as usize) {
+      Some(Some(file)) => file,
+      _ => return Some(0xFFFF_FFFF),
+    };
+    let start = file.pos.min(file.data.len());
+    let end = (start + len as usize).min(file.data.len());
+    file.pos = end;
+    let data = file.data[start..end].to_vec();
+    write_bytes(cpu, dst, &data);
+    Some(data.len() as u32)
+  }
+
+  fn file_write(&mut self, cpu: &mut Cpu, fd: u32, src: u32, len: u32) -> Option<u32> {
+    if fd != 1 {
+      return Some(0xFFFF_FFFF);
+    }
+    for c in read_bytes(cpu, src, len) {
+      self.tty_write(c);
+    }
+    Some(len)
+  }
+
+  fn file_close(&mut self, fd: u32) -> Option<u32> {
+    match self.files.get_mut(fd as usize) {
+      Some(file @ Some(_)) => {
+        *file = None;
+        Some(fd)
+      }
+      _ => Some(0xFFFF_FFFF),
+    }
+  }
+
+  fn file_getc(&mut self, fd: u32) -> Option<u32> {
+    match self.files.get_mut(fd as usize) {
+      Some(Some(file)) if file.pos < file.data.len() => {
+        file.pos += 1;
+        Some(file.data[file.pos - 1] as u32)
+      }
+      _ => Some(0xFFFF_FFFF),
+    }
+  }
+}
+
+fn read_exe_header(cpu: &mut Cpu, addr: u32) -> ExeHeader {
+  let mut words = [0; 10];
+  for (i, w) in words.iter_mut().enumerate() {
+    *w = read32(cpu, addr + i as u32 * 4);
+  }
+  ExeHeader::from_words(&words)
+}
+
+// SaveState (setjmp): ra, sp, fp, s0-s7, gp を保存する
+fn save_state(cpu: &mut Cpu, buf: u32) -> u32 {
+  let regs = [RA, SP, FP, S0, S0 + 1, S0 + 2, S0 + 3, S0 + 4, S0 + 5, S0 + 6, S0 + 7, GP];
+  for (i, &r) in regs.iter().enumerate() {
+    let v = cpu.gpr(r);
+    write32(cpu, buf + i as u32 * 4, v);
+  }
+  0
+}
+
+// RestoreState (longjmp)
+fn restore_state(cpu: &mut Cpu, buf: u32, val: u32) {
+  let regs = [RA, SP, FP, S0, S0 + 1, S0 + 2, S0 + 3, S0 + 4, S0 + 5, S0 + 6, S0 + 7, GP];
+  for (i, &r) in regs.iter().enumerate() {
+    let v = read32(cpu, buf + i as u32 * 4);
+    cpu.set_gpr(r, v);
+  }
+  cpu.set_gpr(V0, val);
+  let ra = cpu.gpr(RA);
+  cpu.set_pc(ra);
+}
+
+// strtol 相当。base が 0 のときは接頭辞で判断する。(値, 読んだバイト数) を返す
+fn parse_int(s: &[u8], base: u32) -> (i32, usize) {
+  let mut i = 0;
+  while i < s.len() && s[i].is_ascii_whitespace() {
+    i += 1;
+  }
+  let negative = match s.get(i) {
+    Some(b'-') => { i += 1; true }
+    Some(b'+') => { i += 1; false }
+    _ => false,
+  };
+  let mut base = base;
+  if (base == 0 || base == 16) && s.get(i) == Some(&b'0') && matches!(s.get(i + 1), Some(b'x') | Some(b'X')) {
+    i += 2;
+    base = 16;
+  } else if base == 0 && s.get(i) == Some(&b'0') {
+    base = 8;
+  } else if base == 0 {
+    base = 10;
+  }
+  let mut v: i32 = 0;
+  while let Some(d) = s.get(i).and_then(|&c| (c as char).to_digit(base)) {
+    v = v.wrapping_mul(base as i32).wrapping_add(d as i32);
+    i += 1;
+  }
+  (if negative { v.wrapping_neg() } else { v }, i)
+}
+
+fn read8(cpu: &mut Cpu, addr: u32) -> u8 {
+  cpu.inter.load8(addr)
+}
+
+fn write8(cpu: &mut Cpu, addr: u32, val: u8) {
+  cpu.inter.store8(addr, val)
+}
+
+fn read32(cpu: &mut Cpu, addr: u32) -> u32 {
+  cpu.inter.load32(addr & !3)
+}
+
+fn write32(cpu: &mut Cpu, addr: u32, val: u32) {
+  cpu.inter.store32(addr & !3, val)
+}
+
+// ゲストが指定した長さは addr から続く RAM かスクラッチパッドの終わりまでに切り詰める
+fn read_bytes(cpu: &mut Cpu, addr: u32, len: u32) -> Vec<u8> {
+  let len = len.min(cpu.inter.memory_len(addr));
+  (0..len).map(|i| read8(cpu, addr.wrapping_add(i))).collect()
+}
+
+fn write_bytes(cpu: &mut Cpu, addr: u32, data: &[u8]) {
+  for (i, &b) in data.iter().enumerate() {
+    write8(cpu, addr.wrapping_add(i as u32), b);
+  }
+}
+
+fn fill_bytes(cpu: &mut Cpu, addr: u32, val: u8, len: u32) {
+  let len = len.min(cpu.inter.memory_len(addr));
+  for i in 0..len {
+    write8(cpu, addr.wrapping_add(i), val);
+  }
+}
+
+// NUL 終端の文字列を読む (NUL は含まない)
+fn read_cstr(cpu: &mut Cpu, addr: u32) -> Vec<u8> {
+  let mut s = Vec::new();
+  for i in 0..0x10000 {
+    match read8(cpu, addr.wrapping_add(i)) {
+      0 => break,
+      c => s.push(c),
+    }
+  }
+  s
+}
+
+fn write_cstr(cpu: &mut Cpu, addr: u32, s: &[u8]) {
+  write_bytes(cpu, addr, s);
+  write8(cpu, addr.wrapping_add(s.len() as u32), 0);
+}
@@ -1,198 +1,593 @@
-use core::panic;
-
-use crate::{bios::Bios, channel::{Direction, Step, Sync}, dma::{Dma, Port}, gpu::Gpu, ram::Ram, spu::Spu};
+use std::io::Write;
 
+use crate::{bios::Bios, channel::{Direction, Step, Sync}, console::Console, dma::{Dma, Port}, expansion::{Expansion1, Expansion2}, gpu::Gpu, irq::{Interrupt, InterruptState}, mem_control::{self, MemControl, Region, Width}, pad::PadMemCard, ram::{Ram, RamMapping}, scratchpad::ScratchPad, spu::Spu, trace::{Device, TraceEvent, Tracer}};
 
 pub struct Interconnect {
   bios: Bios,
   ram: Ram,
+  scratchpad: ScratchPad,
+  cache_control: CacheControl,
+  mem_control: MemControl,
+  // CPU に加算されていないバスアクセスのサイクル
+  pending_cycles: u32,
+  // 起動からの経過サイクル (DMA のスケジューリング用)
+  now: u64,
+  irq: InterruptState,
   dma: Dma,
+  expansion1: Expansion1,
+  expansion2: Expansion2,
+  // 直前のアクセスがバスエラーになった (CPU が例外にする)
+  bus_error: bool,
+  // バスへの書き込みの記録 (ロックステップの比較用)
+  write_log: Option<Vec<MemoryWrite>>,
+  tracer: Option<Tracer>,
+  // TTY 出力やメッセージの出力先 (拡張領域2 や HLE BIOS と共有する)
+  pub console: Console,
   pub gpu: Gpu,
   pub spu: Spu,
+  pub pad: PadMemCard,
 }
 
 impl Interconnect {
-  pub fn new(bios: Bios, gpu: Gpu, spu: Spu) -> Self {
+  pub fn new(bios: Bios, ram: Ram, gpu: Gpu, spu: Spu) -> Self {
+    let console = Console::default();
     Self {
       bios,
-      ram: Ram::new(),
+      ram,
+      scratchpad: ScratchPad::new(),
+      cache_control: CacheControl(0),
+      mem_control: MemControl::new(),
+      pending_cycles: 0,
+      now: 0,
+      irq: InterruptState::new(),
       dma: Dma::new(),
+      expansion1: Expansion1::new(),
+      expansion2: Expansion2::new(console.clone()),
+      console,
+      bus_error: false,
+      write_log: None,
+      tracer: None,
       gpu,
       spu,
+      pad: PadMemCard::new(),
     }
   }
 
-  pub fn load32(&self, addr: u32) -> u32 {
-    if addr % 4 != 0 {
-      panic!("Unalignd load32 address: {:08X}", addr);
+  // セーブステートを読み込んだ新しい Interconnect に、保存しないもの
+  // (BIOS、拡張 ROM、TTY やトレースの出力先、レンダラ、録音など) を old から移す
+  pub fn take_host(&mut self, old: &mut Interconnect) {
+    std::mem::swap(&mut self.bios, &mut old.bios);
+    std::mem::swap(&mut self.expansion1, &mut old.expansion1);
+    std::mem::swap(&mut self.write_log, &mut old.write_log);
+    std::mem::swap(&mut self.tracer, &mut old.tracer);
+    std::mem::swap(&mut self.console, &mut old.console);
+    self.expansion2.take_host(&mut old.expansion2);
+    self.gpu.take_host(&mut old.gpu);
+    self.spu.take_host(&mut old.spu);
+  }
+
+  pub fn ram_size(&self) -> usize {
+    self.ram.size()
+  }
+
+  pub fn set_expansion1(&mut self, expansion1: Expansion1) {
+    self.expansion1 = expansion1;
+  }
+
+  // TTY 出力やエミュレータからのメッセージの出力先を変更する
+  pub fn set_console(&mut self, out: Box<dyn Write>) {
+    self.console.set(out);
+  }
+
+  pub fn cache_control(&self) -> CacheControl {
+    self.cache_control
+  }
+
+  // スクラッチパッドは KUSEG/KSEG0 からしか見えない
+  fn scratchpad_offset(&self, addr: u32, abs_addr: u32) -> Option<u32> {
+    let offset = map::SCRATCHPAD.contains(abs_addr)?;
+    if (addr >> 29) == 5 {
+      return None;
     }
-    let abs_addr = mask_region(addr);
+    Some(offset)
+  }
+
+  // 前回呼び出し以降のバスアクセスにかかったサイクル数を取り出す
+  pub fn take_pending_cycles(&mut self) -> u32 {
+    let cycles = self.pending_cycles;
+    self.pending_cycles = 0;
+    cycles
+  }
+
+  fn access_cycles(&self, addr: u32, abs_addr: u32, width: Width, write: bool) -> u32 {
+    if map::RAM.contains(abs_addr).is_some() {
+      return if write { mem_control::RAM_WRITE_CYCLES } else { mem_control::RAM_READ_CYCLES };
+    }
+    if self.scratchpad_offset(addr, abs_addr).is_some() {
+      return mem_control::SCRATCHPAD_ACCESS_CYCLES;
+    }
+    let region = if map::BIOS.contains(abs_addr).is_some() {
+      Region::Bios
+    } else if map::SPU.contains(abs_addr).is_some() {
+      Region::Spu
+    } else if map::CDROM.contains(abs_addr).is_some() {
+      Region::CdRom
+    } else if map::EXPANTION_1.contains(abs_addr).is_some() {
+      Region::Expansion1
+    } else if map::EXPANTION_2.contains(abs_addr).is_some() {
+      Region::Expansion2
+    } else if map::EXPANTION_3.contains(abs_addr).is_some() {
+      Region::Expansion3
+    } else {
+      return mem_control::IO_ACCESS_CYCLES;
+    };
+    self.mem_control.access_cycles(region, width, write)
+  }
 
+  pub fn load32(&mut self, addr: u32) -> u32 {
+    self.load(addr, Width::Word)
+  }
+
+  pub fn store32(&mut self, addr: u32, val: u32) {
+    self.store(addr, Width::Word, val)
+  }
+
+  pub fn load16(&mut self, addr: u32) -> u16 {
+    self.load(addr, Width::HalfWord) as u16
+  }
+
+  pub fn store16(&mut self, addr: u32, val: u16) {
+    self.store(addr, Width::HalfWord, val as u32)
+  }
+
+  pub fn load8(&mut self, addr: u32) -> u8 {
+    self.load(addr, Width::Byte) as u8
+  }
+
+  pub fn store8(&mut self, addr: u32, val: u8) {
+    self.store(addr, Width::Byte, val as u32)
+  }
+
+  // デバッグ用: タイミングや副作用なしにメモリを読む (RAM/BIOS/スクラッチパッドのみ)
+  pub fn peek8(&self, addr: u32) -> Option<u8> {
+    let abs_addr = mask_region(addr);
+    if let Some(offset) = map::RAM.contains(abs_addr) {
+      return match self.ram.mapping(offset) {
+        RamMapping::Memory(offset) => Some(self.ram.load8(offset)),
+        RamMapping::HighZ | RamMapping::Locked => None,
+      };
+    }
+    if let Some(offset) = self.scratchpad_offset(addr, abs_addr) {
+      return Some(self.scratchpad.load8(offset));
+    }
     if let Some(offset) = map::BIOS.contains(abs_addr) {
-      return self.bios.load32(offset);
+      return Some(self.bios.load8(offset));
     }
+    None
+  }
+
+  // 命令の置き場所を表すキー (RAM はミラーを同一視したオフセット、BIOS は 0x1FC0_0000 以降)
+  // キャッシュできない場所では None
+  pub fn code_address(&self, addr: u32) -> Option<u32> {
+    let abs_addr = mask_region(addr);
     if let Some(offset) = map::RAM.contains(abs_addr) {
-      return self.ram.load32(offset);
+      return match self.ram.mapping(offset) {
+        RamMapping::Memory(offset) => Some(offset),
+        RamMapping::HighZ | RamMapping::Locked => None,
+      };
     }
-    if let Some(offset) = map::IRQ_CONTROL.contains(abs_addr) {
-      // println!("IRQ control read: {:X}", offset);
-      return 0;
+    map::BIOS.contains(abs_addr).map(|offset| map::BIOS.start() + offset)
+  }
+
+  // code_address で得たキーの位置の命令を副作用なしに読む
+  pub fn peek_code(&self, code_address: u32) -> u32 {
+    match map::BIOS.contains(code_address) {
+      Some(offset) => self.bios.load32(offset),
+      None => self.ram.load32(code_address),
     }
-    if let Some(offset) = map::DMA.contains(abs_addr) {
-      return self.dma_reg(offset);
+  }
+
+  // addr から続けて読み書きできるバイト数 (搭載している RAM かスクラッチパッドの終わりまで)
+  pub fn memory_len(&self, addr: u32) -> u32 {
+    let abs_addr = mask_region(addr);
+    if let Some(offset) = map::RAM.contains(abs_addr) {
+      let mask = self.ram.address_mask();
+      return mask + 1 - (offset & mask);
     }
-    if let Some(offset) = map::GPU.contains(abs_addr) {
-      return match offset {
-        0 => self.gpu.read(),
-        4 => self.gpu.status(),
-        _ => panic!("GPU read: {:08X}", offset),
-      };
+    if let Some(offset) = map::SCRATCHPAD.contains(abs_addr) {
+      return map::SCRATCHPAD.size() - offset;
     }
-    if let Some(offset) = map::TIMERS.contains(abs_addr) {
-      // println!("Unhandled write to timer register {:08X}", offset);
-      return 0;
+    0
+  }
+
+  pub fn is_bios_code(&self, code_address: u32) -> bool {
+    map::BIOS.contains(code_address).is_some()
+  }
+
+  pub fn mark_code(&mut self, code_address: u32) {
+    if map::BIOS.contains(code_address).is_none() {
+      self.ram.mark_code_page(code_address);
     }
+  }
 
-    panic!("unhandled load32 at address {:08X}", addr);
+  pub fn code_modified(&self) -> bool {
+    self.ram.code_modified()
   }
 
-  pub fn store32(&mut self, addr: u32, val: u32) {
-    if addr % 4 != 0 {
-      panic!("Unalignd store32 address: {:08X}", addr);
+  // 命令が書き換えられた RAM のページ
+  pub fn take_modified_code_pages(&mut self) -> Vec<u32> {
+    self.ram.take_modified_pages()
+  }
+
+  // キャッシュを通さない命令フェッチ1回のサイクル
+  pub fn fetch_cycles(&self, addr: u32) -> u32 {
+    self.access_cycles(addr, mask_region(addr), Width::Word, false)
+  }
+
+  pub fn add_pending_cycles(&mut self, cycles: u32) {
+    self.pending_cycles += cycles;
+  }
+
+  // 直前の load/store が何もつながっていない領域へのアクセスだったか
+  pub fn bus_error(&self) -> bool {
+    self.bus_error
+  }
+
+  pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+    self.tracer = tracer;
+  }
+
+  pub fn tracing(&self) -> bool {
+    self.tracer.is_some()
+  }
+
+  pub fn begin_trace(&mut self, cycle: u64, pc: u32) {
+    let frame = self.gpu.frame_count();
+    if let Some(tracer) = self.tracer.as_mut() {
+      tracer.begin_instruction(cycle, pc, frame);
     }
-    let abs_addr = mask_region(addr);
+  }
 
-    if let Some(offset) = map::MEM_CONTROL.contains(abs_addr) {
-      match offset {
-        0 => if val != 0x1F00_0000 {
-          panic!("Bad expansion 1 base address: 0x{:01X}", val);
-        }
-        4 => if val != 0x1F80_2000 {
-          panic!("Bad expansion 2 base address: 0x{:01X}", val);
-        }
-        _ => println!("Unhandled write to MEM_CONTROL register"),
-      }
-      return;
+  pub fn end_trace(&mut self, opcode: u32, changes: Vec<(u8, u32)>) {
+    if let Some(tracer) = self.tracer.as_mut() {
+      tracer.end_instruction(opcode, changes);
     }
-    if let Some(_) = map::RAM_SIZE.contains(abs_addr) {
-      println!("write to RAM_SIZE register {:08X}", abs_addr);
-      return;
+    self.drop_failed_tracer();
+  }
+
+  fn trace(&mut self, event: TraceEvent) {
+    if let Some(tracer) = self.tracer.as_mut() {
+      tracer.record(event);
     }
-    if let Some(_) = map::CACHE_CONTROL.contains(abs_addr) {
-      println!("write to CACHE_CONTROL register {:08X}", abs_addr);
-      return;
+    self.drop_failed_tracer();
+  }
+
+  // 書き込みに失敗したトレーサーは外して、トレースなしの速い経路に戻す
+  fn drop_failed_tracer(&mut self) {
+    if self.tracer.as_ref().is_some_and(Tracer::failed) {
+      self.tracer = None;
+    }
+  }
+
+  fn trace_access(&mut self, write: bool, addr: u32, width: Width, val: u32) {
+    if self.tracer.is_some() {
+      let device = self.device(addr);
+      self.trace(TraceEvent::Memory { write, addr, width: width.bytes() as u8, val, device });
     }
+  }
+
+  // トレースに記録するアクセス先の名前
+  fn device(&self, addr: u32) -> Device {
+    let abs_addr = mask_region(addr);
+    if map::RAM.contains(abs_addr).is_some() {
+      return Device::Ram;
+    }
+    if self.scratchpad_offset(addr, abs_addr).is_some() {
+      return Device::Scratchpad;
+    }
+    let devices = [
+      (map::BIOS, Device::Bios),
+      (map::EXPANTION_1, Device::Expansion1),
+      (map::EXPANTION_2, Device::Expansion2),
+      (map::EXPANTION_3, Device::Expansion3),
+      (map::MEM_CONTROL, Device::MemControl),
+      (map::RAM_SIZE, Device::RamSize),
+      (map::CACHE_CONTROL, Device::CacheControl),
+      (map::PAD_MEMCARD, Device::PadMemcard),
+      (map::SIO, Device::Sio),
+      (map::IRQ_CONTROL, Device::IrqControl),
+      (map::DMA, Device::Dma),
+      (map::TIMERS, Device::Timers),
+      (map::CDROM, Device::CdRom),
+      (map::GPU, Device::Gpu),
+      (map::SPU, Device::Spu),
+    ];
+    devices.into_iter()
+      .find(|&(range, _)| range.contains(abs_addr).is_some())
+      .map_or(Device::Unmapped, |(_, device)| device)
+  }
+
+  pub fn load(&mut self, addr: u32, width: Width) -> u32 {
+    let val = self.read(addr, width);
+    self.trace_access(false, addr, width, val);
+    val
+  }
+
+  // 命令フェッチ (トレースにはメモリアクセスとして記録しない)
+  pub fn fetch32(&mut self, addr: u32) -> u32 {
+    self.read(addr, Width::Word)
+  }
+
+  // アラインメントは CPU 側でチェック済み (アドレスエラー例外) なのでここでは見ない
+  fn read(&mut self, addr: u32, width: Width) -> u32 {
+    let abs_addr = mask_region(addr);
+    self.pending_cycles += self.access_cycles(addr, abs_addr, width, false);
+    self.bus_error = false;
+
     if let Some(offset) = map::RAM.contains(abs_addr) {
-      self.ram.store32(offset, val);
-      return;
+      let offset = match self.ram.mapping(offset) {
+        RamMapping::Memory(offset) => offset,
+        // 未使用領域はオープンバス
+        RamMapping::HighZ => return width.mask(OPEN_BUS),
+        RamMapping::Locked => {
+          self.bus_error = true;
+          return width.mask(OPEN_BUS);
+        }
+      };
+      return match width {
+        Width::Byte => self.ram.load8(offset) as u32,
+        Width::HalfWord => self.ram.load16(offset) as u32,
+        Width::Word => self.ram.load32(offset),
+      };
     }
-    if let Some(offset) = map::IRQ_CONTROL.contains(abs_addr) {
-      // println!("IRQ control: {:X} <- {:08X}", offset, val);
-      return;
+    if let Some(offset) = self.scratchpad_offset(addr, abs_addr) {
+      return match width {
+        Width::Byte => self.scratchpad.load8(offset) as u32,
+        Width::HalfWord => self.scratchpad.load16(offset) as u32,
+        Width::Word => self.scratchpad.load32(offset),
+      };
     }
-    if let Some(offset) = map::DMA.contains(abs_addr) {
-      return self.set_dma_reg(offset, val);
+    if let Some(offset) = map::BIOS.contains(abs_addr) {
+      return match width {
+        Width::Byte => self.bios.load8(offset) as u32,
+        Width::HalfWord => self.bios.load16(offset) as u32,
+        Width::Word => self.bios.load32(offset),
+      };
     }
-    if let Some(offset) = map::GPU.contains(abs_addr) {
-      match offset {
-        0 => self.gpu.gp0(val),
-        4 => self.gpu.gp1(val),
-        _ => panic!("GPU write: {:08X} {:08X}", offset, val),
+    if let Some(offset) = map::EXPANTION_1.contains(abs_addr) {
+      let mut v = 0;
+      for i in 0..width.bytes() {
+        v |= (self.expansion1.load8(offset + i) as u32) << (i * 8);
       }
-      return;
+      return v;
+    }
+
+    // 16ビットのレジスタを持つデバイス
+    if is_io16(abs_addr) {
+      return match width {
+        Width::Byte => (self.load_io16(abs_addr & !1) >> ((abs_addr & 1) * 8)) as u32 & 0xFF,
+        Width::HalfWord => self.load_io16(abs_addr) as u32,
+        Width::Word => {
+          let lo = self.load_io16(abs_addr) as u32;
+          let hi = self.load_io16(abs_addr + 2) as u32;
+          lo | (hi << 16)
+        }
+      };
     }
-    if let Some(offset) = map::TIMERS.contains(abs_addr) {
-      // println!("Unhandled write to timer register {:08X} {:08X}", offset, val);
-      return;
+    // 32ビットのレジスタを持つデバイス
+    if is_io32(abs_addr) {
+      let v = self.load_io32(abs_addr & !3);
+      return width.mask(v >> ((abs_addr & 3) * 8));
+    }
+    // 8ビットのレジスタを持つデバイス
+    if is_io8(abs_addr) {
+      let mut v = 0;
+      for i in 0..width.bytes() {
+        // 領域をはみ出したバイトはオープンバス
+        let byte = match is_io8(abs_addr + i) {
+          true => self.load_io8(abs_addr + i),
+          false => OPEN_BUS as u8,
+        };
+        v |= (byte as u32) << (i * 8);
+      }
+      return v;
     }
-    panic!("unhandled store32 at address {:08X}", addr)
+
+    // 何もつながっていない領域はオープンバス
+    self.bus_error = is_unmapped(abs_addr);
+    width.mask(OPEN_BUS)
+  }
+
+  pub fn set_write_log(&mut self, enabled: bool) {
+    self.write_log = enabled.then(Vec::new);
+  }
+
+  pub fn take_write_log(&mut self) -> Vec<MemoryWrite> {
+    self.write_log.as_mut().map(std::mem::take).unwrap_or_default()
   }
 
-  pub fn load16(&self, addr: u32) -> u16 {
-    if addr % 2 != 0 {
-      panic!("Unalignd load16 address: {:08X}", addr);
+  pub fn store(&mut self, addr: u32, width: Width, val: u32) {
+    if let Some(log) = self.write_log.as_mut() {
+      log.push(MemoryWrite { addr, width, val });
     }
+    self.trace_access(true, addr, width, val);
     let abs_addr = mask_region(addr);
+    self.pending_cycles += self.access_cycles(addr, abs_addr, width, true);
+    self.bus_error = false;
 
-    if let Some(offset) = map::SPU.contains(abs_addr) {
-      // println!("Unhandled read from SPU register {:08X}", abs_addr);
-      return self.spu.load(abs_addr, offset);
-    }
     if let Some(offset) = map::RAM.contains(abs_addr) {
-      return self.ram.load16(offset);
+      let offset = match self.ram.mapping(offset) {
+        RamMapping::Memory(offset) => offset,
+        RamMapping::HighZ => return,
+        RamMapping::Locked => {
+          self.bus_error = true;
+          return;
+        }
+      };
+      return match width {
+        Width::Byte => self.ram.store8(offset, val as u8),
+        Width::HalfWord => self.ram.store16(offset, val as u16),
+        Width::Word => self.ram.store32(offset, val),
+      };
+    }
+    if let Some(offset) = self.scratchpad_offset(addr, abs_addr) {
+      return match width {
+        Width::Byte => self.scratchpad.store8(offset, val as u8),
+        Width::HalfWord => self.scratchpad.store16(offset, val as u16),
+        Width::Word => self.scratchpad.store32(offset, val),
+      };
+    }
+    if map::BIOS.contains(abs_addr).is_some() || map::EXPANTION_1.contains(abs_addr).is_some() {
+      // ROM への書き込みは無視される
+      return;
     }
 
-    if let Some(offset) = map::IRQ_CONTROL.contains(abs_addr) {
-      // println!("IRQ control read {:08X}", offset);
-      return 0;
+    if is_io16(abs_addr) {
+      match width {
+        Width::Byte => self.store_io16(abs_addr & !1, (val as u16) << ((abs_addr & 1) * 8)),
+        Width::HalfWord => self.store_io16(abs_addr, val as u16),
+        Width::Word => {
+          self.store_io16(abs_addr, val as u16);
+          self.store_io16(abs_addr + 2, (val >> 16) as u16);
+        }
+      }
+      return;
+    }
+    if is_io32(abs_addr) {
+      // 8/16ビットの書き込みはシフトされた32ビットの書き込みとして扱われる
+      return self.store_io32(abs_addr & !3, val << ((abs_addr & 3) * 8));
+    }
+    if is_io8(abs_addr) {
+      // 領域をはみ出したバイトの書き込みは無視される
+      for i in (0..width.bytes()).filter(|&i| is_io8(abs_addr + i)) {
+        self.store_io8(abs_addr + i, (val >> (i * 8)) as u8);
+      }
+      return;
     }
 
-    panic!("unhandled load16 at address {:08X}", addr);
+    // 何もつながっていない領域への書き込みは無視される
+    self.bus_error = is_unmapped(abs_addr);
   }
 
-  pub fn store16(&mut self, addr: u32, val: u16) {
-    if addr % 2 != 0 {
-      panic!("Unalignd store16 address: {:08X}", addr);
+  fn load_io16(&mut self, abs_addr: u32) -> u16 {
+    if let Some(offset) = map::SPU.contains(abs_addr) {
+      return self.spu.load(abs_addr, offset);
     }
-
-    let abs_addr = mask_region(addr);
-
-    if let Some(offset) = map::RAM.contains(abs_addr) {
-      return self.ram.store16(offset, val);
+    if let Some(offset) = map::IRQ_CONTROL.contains(abs_addr) {
+      return match offset {
+        0 => self.irq.status(),
+        4 => self.irq.mask(),
+        _ => 0,
+      };
+    }
+    if map::TIMERS.contains(abs_addr).is_some() {
+      return 0;
     }
+    unreachable!("Not a 16bit register: {:08X}", abs_addr)
+  }
 
+  fn store_io16(&mut self, abs_addr: u32, val: u16) {
     if let Some(offset) = map::SPU.contains(abs_addr) {
-      // println!("Unhandled write to SPU register {:X}", offset);
+      self.trace(TraceEvent::Spu { offset: offset as u16, val });
       self.spu.store(abs_addr, offset, val);
+      // SPUCNT が DMA モードになったら待たされていた DMA4 を開始する
+      self.run_dma();
       return;
     }
-
-    if let Some(offset) = map::TIMERS.contains(abs_addr) {
-      // println!("Unhandled write to timer register {:X}", offset);
+    if let Some(offset) = map::IRQ_CONTROL.contains(abs_addr) {
+      match offset {
+        0 => self.irq.ack(val),
+        4 => self.irq.set_mask(val),
+        _ => {}
+      }
       return;
     }
-
-    if let Some(offset) = map::IRQ_CONTROL.contains(abs_addr) {
-      // println!("IRQ control write {:08X} {:04X}", offset, val);
+    if map::TIMERS.contains(abs_addr).is_some() {
       return;
     }
-
-    panic!("Unhandled store16 at address {:08X}", addr)
+    unreachable!("Not a 16bit register: {:08X}", abs_addr)
   }
 
+  fn load_io32(&mut self, abs_addr: u32) -> u32 {
+    if let Some(offset) = map::MEM_CONTROL.contains(abs_addr) {
+      return self.mem_control.load(offset);
+    }
+    if map::RAM_SIZE.contains(abs_addr).is_some() {
+      return self.ram.config();
+    }
+    if map::CACHE_CONTROL.contains(abs_addr).is_some() {
+      return self.cache_control.0;
+    }
+    if let Some(offset) = map::DMA.contains(abs_addr) {
+      return self.dma_reg(offset);
+    }
+    if let Some(offset) = map::GPU.contains(abs_addr) {
+      return match offset {
+        0 => self.gpu.read(),
+        _ => self.gpu.status(),
+      };
+    }
+    unreachable!("Not a 32bit register: {:08X}", abs_addr)
+  }
 
-  pub fn store8(&mut self, addr: u32, val: u8) {
-    let abs_addr = mask_region(addr);
-
-    if let Some(offset) = map::EXPANTION_2.contains(abs_addr) {
-      println!("Unhandled write to EXPANTION_2 register {:X}", offset);
+  fn store_io32(&mut self, abs_addr: u32, val: u32) {
+    if let Some(offset) = map::MEM_CONTROL.contains(abs_addr) {
+      return self.mem_control.store(offset, val);
+    }
+    if map::RAM_SIZE.contains(abs_addr).is_some() {
+      return self.ram.set_config(val);
+    }
+    if map::CACHE_CONTROL.contains(abs_addr).is_some() {
+      self.cache_control = CacheControl(val);
       return;
     }
-
-    if let Some(offset) = map::RAM.contains(abs_addr) {
-      self.ram.store8(offset, val);
+    if let Some(offset) = map::DMA.contains(abs_addr) {
+      return self.set_dma_reg(offset, val);
+    }
+    if let Some(offset) = map::GPU.contains(abs_addr) {
+      match offset {
+        0 => self.gp0(val),
+        _ => {
+          self.trace(TraceEvent::Gp1(val));
+          self.gpu.gp1(val);
+        }
+      }
       return;
     }
-
-    panic!("Unhandled store8 at address {:08X}", addr)
+    unreachable!("Not a 32bit register: {:08X}", abs_addr)
   }
 
-  pub fn load8(&self, addr: u32) -> u8 {
-    let abs_addr = mask_region(addr);
-
-    if let Some(offset) = map::BIOS.contains(abs_addr) {
-      return self.bios.load8(offset);
+  fn load_io8(&mut self, abs_addr: u32) -> u8 {
+    if let Some(offset) = map::PAD_MEMCARD.contains(abs_addr) {
+      return self.pad.load8(offset);
     }
-
-    if let Some(offset) = map::RAM.contains(abs_addr) {
-      return self.ram.load8(offset);
+    // CD-ROM / SIO はまだ実装されていない
+    if map::CDROM.contains(abs_addr).is_some()
+      || map::SIO.contains(abs_addr).is_some() {
+      return 0;
     }
-
-    if let Some(offset) = map::EXPANTION_1.contains(abs_addr) {
-      return 0xFF; // No expantion implemented
+    if let Some(offset) = map::EXPANTION_2.contains(abs_addr) {
+      return self.expansion2.load8(offset);
     }
+    unreachable!("Not an 8bit register: {:08X}", abs_addr)
+  }
 
-    panic!("Unhandled load8 at address {:08X}", addr);
+  fn store_io8(&mut self, abs_addr: u32, val: u8) {
+    if let Some(offset) = map::PAD_MEMCARD.contains(abs_addr) {
+      self.pad.store8(offset, val);
+      if self.pad.irq_edge() {
+        self.irq.request(Interrupt::PadMemCard);
+      }
+      return;
+    }
+    if map::CDROM.contains(abs_addr).is_some()
+      || map::SIO.contains(abs_addr).is_some() {
+      return;
+    }
+    if let Some(offset) = map::EXPANTION_2.contains(abs_addr) {
+      return self.expansion2.store8(offset, val);
+    }
+    unreachable!("Not an 8bit register: {:08X}", abs_addr)
   }
 
   fn dma_reg(&self, offset: u32) -> u32 {
@@ -204,15 +599,18 @@ impl Interconnect {
         let channel = self.dma.channel(Port::from_index(major));
 
         match minor {
+          0 => channel.base(),
+          4 => channel.block_control(),
           8 => channel.control(),
-          _ => panic!("Unhandled DMA read at {:08X}", offset)
+          // 0xC は何もつながっていない
+          _ => OPEN_BUS,
         }
       },
 
       7 => match minor {
         0 => self.dma.control(),
         4 => self.dma.interrupt(),
-        _ => panic!("Unhandled DMA read at {:08X}", offset)
+        _ => OPEN_BUS,
       }
       _ => panic!("Unhandled DMA read at {:08X}", offset)
     }
@@ -222,7 +620,7 @@ impl Interconnect {
     let major = (offset & 0x70) >> 4;
     let minor = offset & 0x0F;
 
-    let active_port = match major {
+    match major {
       0..=6 => {
         let port = Port::from_index(major);
         let channel = self.dma.channel_mut(port);
@@ -231,13 +629,8 @@ impl Interconnect {
           0 => channel.set_base(val),
           4 => channel.set_block_control(val),
           8 => channel.set_control(val),
-          _ => panic!("Unhandled DMA write at {:08X}: {:08X}", offset, val)
-        }
-
-        if channel.active() {
-          Some(port)
-        } else {
-          None
+          // 0xC への書き込みは無視される
+          _ => {}
         }
       },
 
@@ -245,50 +638,155 @@ impl Interconnect {
         match minor {
           0 => self.dma.set_control(val),
           4 => self.dma.set_interrupt(val),
-          _ => panic!("Unhandled DMA write at {:08X}: {:08X}", offset, val)
+          _ => {}
         }
-        None
       }
       _ => panic!("Unhandled DMA write at {:08X}: {:08X}", offset, val)
     };
 
-    if let Some(port) = active_port {
-      self.do_dma(port);
+    self.update_dma_irq();
+    self.run_dma();
+  }
+
+  // CPU が実行したサイクル分だけ時間を進め、待機中の DMA を再開する
+  pub fn tick(&mut self, cycles: u64) {
+    self.now += cycles;
+    self.run_dma();
+  }
+
+  // 転送中の DMA があると tick で割り込みが起きうる
+  pub fn dma_active(&self) -> bool {
+    self.dma.any_active()
+  }
+
+  pub fn irq_active(&self) -> bool {
+    self.irq.active()
+  }
+
+  fn update_dma_irq(&mut self) {
+    if self.dma.irq_edge() {
+      self.irq.request(Interrupt::Dma);
     }
   }
 
-  fn do_dma(&mut self, port: Port) {
-    match self.dma.channel(port).sync() {
-      Sync::LinkedList => self.do_dma_linked_list(port),
-      _ => self.do_dma_block(port),
+  // デバイス側の DMA リクエスト信号
+  fn dma_request(&self, port: Port) -> bool {
+    match port {
+      Port::Spu => self.spu.dma_request(),
+      _ => true,
+    }
+  }
+
+  fn run_dma(&mut self) {
+    loop {
+      let now = self.now;
+      let port = match self.dma.next_runnable(now, |port| self.dma_request(port)) {
+        Some(port) => port,
+        None => break,
+      };
+
+      if !self.dma.channel(port).running() {
+        self.dma.channel_mut(port).start();
+      }
+
+      if self.tracer.is_some() {
+        let channel = self.dma.channel(port);
+        let (to_ram, addr) = (channel.direction() == Direction::ToRam, channel.cur_addr());
+        let words = match channel.sync() {
+          Sync::Manual => channel.words_left(),
+          Sync::Request => channel.block_size(),
+          Sync::LinkedList => 1 + (self.ram.load32(addr & self.ram.address_mask() & !3) >> 24),
+        };
+        self.trace(TraceEvent::Dma { port: port as u8, to_ram, addr, words });
+      }
+
+      let (words, finished) = match self.dma.channel(port).sync() {
+        Sync::Manual => self.dma_step_manual(port),
+        Sync::Request => self.dma_step_request(port),
+        Sync::LinkedList => self.dma_step_linked_list(port),
+      };
+
+      // DMA 中は CPU がバスを使えない
+      self.pending_cycles += words;
+
+      if finished {
+        self.dma.channel_done(port);
+        self.update_dma_irq();
+      } else {
+        let channel = self.dma.channel_mut(port);
+        let mut resume_at = now + words as u64;
+        if channel.chop() {
+          // チョッピング: 指定サイクルだけ CPU にバスを返す
+          resume_at += channel.chop_cpu_cycles();
+        }
+        channel.set_resume_at(resume_at);
+      }
     }
   }
 
-  fn do_dma_block(&mut self, port: Port) {
+  fn gp0(&mut self, val: u32) {
+    self.trace(TraceEvent::Gp0(val));
+    self.gpu.gp0(val);
+  }
+
+  // 同期モード0: チョッピング無効なら一度に全ワード、有効ならウィンドウ単位で転送する
+  fn dma_step_manual(&mut self, port: Port) -> (u32, bool) {
+    let channel = self.dma.channel(port);
+    let left = channel.words_left();
+    let words = match channel.chop() {
+      true => left.min(channel.chop_dma_words()),
+      false => left,
+    };
+    self.dma_transfer_words(port, words, left);
+    let left = left - words;
     let channel = self.dma.channel_mut(port);
+    channel.set_words_left(left);
+    // チョッピング中は区切りごとに MADR も進む
+    if channel.chop() {
+      let addr = channel.cur_addr();
+      channel.set_base(addr);
+    }
+    (words, left == 0)
+  }
+
+  // 同期モード1: リクエストごとに1ブロックずつ転送し、MADR/BCR を更新する
+  fn dma_step_request(&mut self, port: Port) -> (u32, bool) {
+    let words = self.dma.channel(port).block_size();
+    self.dma_transfer_words(port, words, words);
+    let finished = self.dma.channel_mut(port).finish_block();
+    (words, finished)
+  }
+
+  fn dma_transfer_words(&mut self, port: Port, words: u32, total_left: u32) {
+    let channel = self.dma.channel(port);
     let increment = channel.step();
-    let mut addr = channel.base();
-    let mut remsz = match channel.transfer_size() {
-      Some(n) => n,
-      None => panic!("Couldn't figure out DMA block transfer size"),
-    };
-    while remsz > 0 {
-      let cur_addr = addr & 0x001F_FFFC;
-      match channel.direction() {
+    let direction = channel.direction();
+    let mut addr = channel.cur_addr();
+    let mask = self.ram.address_mask();
+
+    for i in 0..words {
+      let remsz = total_left - i;
+      let cur_addr = addr & mask & !3;
+      match direction {
         Direction::FromRam => {
           let src_word = self.ram.load32(cur_addr);
           match port {
-            Port::Gpu => self.gpu.gp0(src_word),
-            _ => panic!("Unhandled DMA destination port {}", port as u8),
+            Port::Gpu => self.gp0(src_word),
+            Port::Spu => self.spu.dma_write(src_word),
+            // まだエミュレートしていないデバイス (MDEC, CD-ROM, PIO) と OTC は受け取らずに捨てる
+            _ => {}
           }
         },
         Direction::ToRam => {
           let src_word = match port {
             Port::Otc => match remsz {
               1 => 0x00FF_FFFF,
-              _ => addr.wrapping_sub(4) & 0x001F_FFFF,
+              _ => addr.wrapping_sub(4) & mask,
             },
-            _ => panic!("Unhandled DMA source port: {}", port as u8),
+            Port::Spu => self.spu.dma_read(),
+            Port::Gpu => self.gpu.read(),
+            // まだエミュレートしていないデバイス (MDEC, CD-ROM, PIO) からは0が読める
+            _ => 0,
           };
           self.ram.store32(cur_addr, src_word);
         }
@@ -298,46 +796,53 @@ impl Interconnect {
         Step::Increment => addr.wrapping_add(4),
         Step::Decrement => addr.wrapping_sub(4)
       };
-      remsz = remsz - 1;
     }
-    channel.done();
+    self.dma.channel_mut(port).set_cur_addr(addr);
   }
 
-  fn do_dma_linked_list(&mut self, port: Port) {
-    let channel = self.dma.channel_mut(port);
-    let mut addr = channel.base() & 0x001F_FFFC;
-    if channel.direction() == Direction::ToRam {
-      panic!("Invalid DMA direction for linked list mode");
-    }
-    if port != Port::Gpu {
-      panic!("Attempt linked list DMA on port {}", port as u8);
+  // 同期モード2: 1ノード (ヘッダ + コマンド) ずつ転送する
+  fn dma_step_linked_list(&mut self, port: Port) -> (u32, bool) {
+    let channel = self.dma.channel(port);
+    let mask = self.ram.address_mask() & !3;
+    let mut addr = channel.cur_addr() & mask;
+    // リンクリストは GPU への転送にしか使えないので、それ以外は何も転送せずに終える
+    if channel.direction() == Direction::ToRam || port != Port::Gpu {
+      return (0, true);
     }
 
-    loop {
-      let header = self.ram.load32(addr);
-      let mut remsz = header >> 24;
-      while remsz > 0 {
-        addr = addr.wrapping_add(4) & 0x001F_FFFC;
-        let command = self.ram.load32(addr);
-        self.gpu.gp0(command);
-        remsz = remsz - 1;
-      }
-
-      if header & 0x0080_0000 != 0 {
-        break;
-      }
-
-      addr = header & 0x001F_FFFC;
+    let header = self.ram.load32(addr);
+    let mut remsz = header >> 24;
+    let words = 1 + remsz;
+    while remsz > 0 {
+      addr = addr.wrapping_add(4) & mask;
+      let command = self.ram.load32(addr);
+      self.gp0(command);
+      remsz = remsz - 1;
     }
-    channel.done();
+
+    // MADR は次のノードのアドレスを指す
+    let next = header & 0x00FF_FFFF;
+    let channel = self.dma.channel_mut(port);
+    channel.set_cur_addr(next);
+    channel.set_base(next);
+    (words, header & 0x0080_0000 != 0)
   }
 }
 
 mod map {
+  #[derive(Clone, Copy)]
   pub struct Range(u32, u32);
 
   impl Range {
 
+    pub fn start(self) -> u32 {
+      self.0
+    }
+
+    pub fn size(self) -> u32 {
+      self.1
+    }
+
     pub fn contains(self, addr: u32) -> Option<u32> {
       let Range(start, length) = self;
       if addr >= start && addr < start + length {
@@ -348,20 +853,87 @@ mod map {
     }
   }
 
-  pub const RAM: Range = Range(0x0000_0000, 2 * 1024 * 1024);
+  // RAM_SIZE の設定に応じて 2MB ごとにミラーされる
+  pub const RAM: Range = Range(0x0000_0000, 8 * 1024 * 1024);
   pub const BIOS: Range = Range(0x1FC0_0000, 512 * 1024);
   pub const MEM_CONTROL: Range = Range(0x1F80_1000, 36); // SYS_CONTROL
   pub const RAM_SIZE: Range = Range(0x1F80_1060, 4);
+  pub const SCRATCHPAD: Range = Range(0x1F80_0000, 1024);
   pub const CACHE_CONTROL: Range = Range(0xFFFE_0130, 4);
   pub const SPU: Range = Range(0x1F80_1C00, 640);
   pub const EXPANTION_2: Range = Range(0x1F80_2000, 66);
   pub const EXPANTION_1: Range = Range(0x1F00_0000, 512 * 1024);
+  pub const EXPANTION_3: Range = Range(0x1FA0_0000, 1024 * 1024);
+  pub const CDROM: Range = Range(0x1F80_1800, 4);
+  pub const PAD_MEMCARD: Range = Range(0x1F80_1040, 16);
+  pub const SIO: Range = Range(0x1F80_1050, 16);
   pub const IRQ_CONTROL: Range = Range(0x1F80_1070, 8);
   pub const TIMERS: Range = Range(0x1F80_1100, 16 * 3);
   pub const DMA: Range = Range(0x1F80_1080, 0x80);
   pub const GPU: Range = Range(0x1F80_1810, 8); // GP0, GP1
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryWrite {
+  pub addr: u32,
+  pub width: Width,
+  pub val: u32,
+}
+
+// BIOS と拡張 ROM はフロントエンドが読み込み直す。書き込みの記録とトレーサは含めない
+impl_state!(Interconnect {
+  ram, scratchpad, cache_control, mem_control, pending_cycles, now,
+  irq, dma, expansion2, bus_error, gpu, spu, pad,
+});
+
+// 0xFFFE_0130 キャッシュ制御レジスタ
+#[derive(Debug, Clone, Copy)]
+pub struct CacheControl(u32);
+
+impl_state!(CacheControl { 0 });
+
+impl CacheControl {
+  // ビット2: タグテストモード
+  pub fn tag_test_mode(self) -> bool {
+    self.0 & 0x04 != 0
+  }
+
+  // ビット11: 命令キャッシュ有効
+  pub fn icache_enabled(self) -> bool {
+    self.0 & 0x800 != 0
+  }
+}
+
+// 何もつながっていない領域を読んだときの値
+const OPEN_BUS: u32 = 0xFFFF_FFFF;
+
+// 物理アドレス空間 (512MB) の外はどのデバイスも応答せずバスエラーになる
+// (I/O 領域内の未実装レジスタはオープンバスとして扱う)
+fn is_unmapped(abs_addr: u32) -> bool {
+  abs_addr >= 0x2000_0000
+}
+
+fn is_io16(abs_addr: u32) -> bool {
+  map::SPU.contains(abs_addr).is_some()
+    || map::IRQ_CONTROL.contains(abs_addr).is_some()
+    || map::TIMERS.contains(abs_addr).is_some()
+}
+
+fn is_io32(abs_addr: u32) -> bool {
+  map::MEM_CONTROL.contains(abs_addr).is_some()
+    || map::RAM_SIZE.contains(abs_addr).is_some()
+    || map::CACHE_CONTROL.contains(abs_addr).is_some()
+    || map::DMA.contains(abs_addr).is_some()
+    || map::GPU.contains(abs_addr).is_some()
+}
+
+fn is_io8(abs_addr: u32) -> bool {
+  map::CDROM.contains(abs_addr).is_some()
+    || map::PAD_MEMCARD.contains(abs_addr).is_some()
+    || map::SIO.contains(abs_addr).is_some()
+    || map::EXPANTION_2.contains(abs_addr).is_some()
+}
+
 const REGION_MASK: [u32; 8] = [
   // KUSEG: 2048KB
   0xFFFF_FFFF, 0xFFFF_FFFF, 0xFFFF_FFFF, 0xFFFF_FFFF,
@@ -0,0 +1,63 @@
+// 0x1F80_1070 I_STAT / 0x1F80_1074 I_MASK
+pub struct InterruptState {
+  status: u16,
+  mask: u16,
+}
+
+impl_state!(InterruptState { status, mask });
+
+impl Default for InterruptState {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl InterruptState {
+  pub fn new() -> Self {
+    Self {
+      status: 0,
+      mask: 0,
+    }
+  }
+
+  // COP0 CAUSE のビット10 (IP2) につながる
+  pub fn active(&self) -> bool {
+    (self.status & self.mask) != 0
+  }
+
+  pub fn status(&self) -> u16 {
+    self.status
+  }
+
+  // 0を書き込んだビットがクリアされる
+  pub fn ack(&mut self, ack: u16) {
+    self.status &= ack;
+  }
+
+  pub fn mask(&self) -> u16 {
+    self.mask
+  }
+
+  pub fn set_mask(&mut self, mask: u16) {
+    self.mask = mask & 0x07FF;
+  }
+
+  pub fn request(&mut self, which: Interrupt) {
+    self.status |= 1 << (which as usize);
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Interrupt {
+  VBlank = 0,
+  Gpu = 1,
+  CdRom = 2,
+  Dma = 3,
+  Timer0 = 4,
+  Timer1 = 5,
+  Timer2 = 6,
+  PadMemCard = 7,
+  Sio = 8,
+  Spu = 9,
+  Lightpen = 10,
+}
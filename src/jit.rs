@@ -0,0 +1,487 @@
+// x86-64 の動的再コンパイラ
+// 基本ブロック全体を1つのネイティブ関数に変換する。演算、乗算、HI/LO、分岐と遅延スロット、遅延ロードは
+// ネイティブコードで処理し、ロード/ストアはアドレスと値をネイティブコードで計算してバスの関数を呼ぶ。
+// 除算、LWL/LWR、COP0/GTE、例外を起こしうる命令はインタプリタの関数で実行する。
+// 関数を呼ぶ前にそれまでの命令のフェッチとサイクルを精算し、割り込みや DMA の状態が変わったら
+// ブロックを抜けるので、割り込みのタイミングはインタプリタと同じになる
+use std::{ffi::c_void, ptr};
+
+use crate::{block_cache::{BlockCache, Op}, cpu::{decode, jit_layout as layout, Cpu, Instruction}};
+
+// 生成したコードの呼び出し規約: (cpu) -> 実行し終えた命令の数
+pub type NativeCode = unsafe extern "sysv64" fn(cpu: *mut Cpu) -> u32;
+
+pub struct JitBlock {
+  // コンパイルしたときの仮想アドレス (リンク先や分岐先はこれを元に埋め込んである)
+  pub pc: u32,
+  pub code: NativeCode,
+  // ネイティブコードを使えないときのためのデコード結果 (ブロックの全命令)
+  pub fallback: Box<[Op]>,
+}
+
+// 1命令あたりのコードの最大サイズ (遅延ロードの反映と関数呼び出しを含めて 200 バイト程度)
+const MAX_INSTRUCTION_CODE: usize = 256;
+const ARENA_SIZE: usize = 16 * 1024 * 1024;
+
+pub struct Jit {
+  arena: CodeArena,
+  pub blocks: BlockCache<JitBlock>,
+}
+
+impl Default for Jit {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Jit {
+  pub fn new() -> Self {
+    Self {
+      arena: CodeArena::new(ARENA_SIZE),
+      blocks: BlockCache::new(),
+    }
+  }
+
+  // pc はブロックの先頭の仮想アドレス
+  pub fn compile(&mut self, pc: u32, instructions: &[Instruction]) -> JitBlock {
+    // 領域が足りなくなったら全て捨てて最初から使う
+    let max_code = (instructions.len() + 1) * MAX_INSTRUCTION_CODE;
+    if self.arena.remaining() < max_code {
+      self.arena.reset();
+      self.blocks.flush();
+    }
+
+    let fallback: Box<[Op]> = instructions.iter()
+      .map(|&instruction| Op { instruction, handler: decode(instruction) })
+      .collect();
+
+    let mut code = Vec::with_capacity(max_code);
+    // push rbx; push r12; sub rsp, 8 (呼び出し先のためにスタックを 16 バイトに揃える); mov rbx, rdi
+    code.extend_from_slice(&[0x53, 0x41, 0x54, 0x48, 0x83, 0xEC, 0x08, 0x48, 0x89, 0xFB]);
+    for (i, &instruction) in instructions.iter().enumerate() {
+      let start = code.len();
+      emit_instruction(&mut code, instructions, i, pc.wrapping_add(i as u32 * 4));
+      debug_assert!(code.len() - start <= MAX_INSTRUCTION_CODE, "JIT code too long for {:08X}", instruction.0);
+    }
+
+    // ブロックの終わり。遅延スロットの後なら pc と next_pc は書いてある
+    let last = instructions.len() - 1;
+    let last_pc = pc.wrapping_add(last as u32 * 4);
+    if !in_delay_slot(instructions, last) {
+      store_imm(&mut code, layout::PC, last_pc.wrapping_add(4));
+      // 分岐命令で終わる (遅延スロットが次のページにある) ときは分岐先が next_pc に入っている
+      if !instructions[last].is_branch() {
+        store_imm(&mut code, layout::NEXT_PC, last_pc.wrapping_add(8));
+      }
+    }
+    exit(&mut code, instructions.len() as u32);
+
+    JitBlock { pc, code: self.arena.alloc(&code), fallback }
+  }
+}
+
+// 遅延スロットの後でレジスタに値が入る命令 (ロード、MFCz/CFCz)
+fn has_load_delay(instruction: Instruction) -> bool {
+  matches!(instruction.function(), 0x10..=0x13 | 0x20..=0x26)
+}
+
+// ブロックの先頭は遅延スロットではない (そのときは Cpu がデコード結果で実行する)
+fn in_delay_slot(instructions: &[Instruction], i: usize) -> bool {
+  i > 0 && instructions[i - 1].is_branch()
+}
+
+// 1命令分のコード。インタプリタの Cpu::execute と同じ順に状態を変える
+fn emit_instruction(code: &mut Vec<u8>, instructions: &[Instruction], i: usize, pc: u32) {
+  let instruction = instructions[i];
+  let delay_slot = in_delay_slot(instructions, i);
+
+  if delay_slot {
+    // pc = next_pc; next_pc += 4 (分岐先は実行するまでわからない)
+    load_field(code, EAX, layout::NEXT_PC);
+    store_field(code, EAX, layout::PC);
+    code.extend_from_slice(&[0x83, 0xC0, 0x04]); // add eax, 4
+    store_field(code, EAX, layout::NEXT_PC);
+    store_byte(code, layout::DELAY_SLOT, 1);
+    store_byte(code, layout::BRANCH, 0);
+  } else if i == 0 {
+    store_byte(code, layout::DELAY_SLOT, 0);
+  }
+
+  // 直前の命令の遅延ロードを out_regs に反映する。命令は反映前の regs を読み、
+  // 命令の後で反映したレジスタ (r12) を regs にも写す
+  let load = i == 0 || has_load_delay(instructions[i - 1]);
+  if load {
+    load_field(code, ECX, layout::LOAD_REGISTER);
+    load_field(code, EAX, layout::LOAD_VALUE);
+    // mov [rbx + rcx * 4 + out_regs], eax
+    code.extend_from_slice(&[0x89, 0x84, 0x8B]);
+    disp32(code, layout::OUT_REGS);
+    store_imm(code, layout::OUT_REGS, 0);
+    store_imm(code, layout::LOAD_REGISTER, 0);
+    store_imm(code, layout::LOAD_VALUE, 0);
+    code.extend_from_slice(&[0x41, 0x89, 0xCC]); // mov r12d, ecx
+  }
+
+  let call = if emit_alu(code, instruction) {
+    false
+  } else if instruction.is_branch() && !delay_slot {
+    emit_branch(code, instruction, pc);
+    false
+  } else {
+    // 呼び出す関数は pc などを読むので、遅延スロット以外では書いておく
+    if !delay_slot {
+      store_imm(code, layout::PC, pc.wrapping_add(4));
+      store_imm(code, layout::NEXT_PC, pc.wrapping_add(8));
+    }
+    emit_call(code, instruction, i as u32);
+    true
+  };
+
+  if load {
+    // mov edx, [rbx + r12 * 4 + out_regs]; mov [rbx + r12 * 4 + regs], edx
+    code.extend_from_slice(&[0x42, 0x8B, 0x94, 0xA3]);
+    disp32(code, layout::OUT_REGS);
+    code.extend_from_slice(&[0x42, 0x89, 0x94, 0xA3]);
+    disp32(code, layout::REGS);
+  }
+
+  if call {
+    // 関数が false を返したら (例外、割り込みや DMA の状態の変化、コードの書き換え) ブロックを抜ける。
+    // test al, al; jnz (exit を飛ばす)
+    code.extend_from_slice(&[0x84, 0xC0, 0x75, EXIT_SIZE]);
+    exit(code, i as u32 + 1);
+  }
+}
+
+const EAX: u8 = 0;
+const ECX: u8 = 1;
+const EDX: u8 = 2;
+
+fn disp32(code: &mut Vec<u8>, disp: usize) {
+  code.extend_from_slice(&(disp as u32).to_le_bytes());
+}
+
+// mov r32, [rbx + disp]
+fn load_field(code: &mut Vec<u8>, x86: u8, disp: usize) {
+  code.extend_from_slice(&[0x8B, 0x83 | (x86 << 3)]);
+  disp32(code, disp);
+}
+
+// mov [rbx + disp], r32
+fn store_field(code: &mut Vec<u8>, x86: u8, disp: usize) {
+  code.extend_from_slice(&[0x89, 0x83 | (x86 << 3)]);
+  disp32(code, disp);
+}
+
+// mov dword [rbx + disp], imm32
+fn store_imm(code: &mut Vec<u8>, disp: usize, imm: u32) {
+  code.extend_from_slice(&[0xC7, 0x83]);
+  disp32(code, disp);
+  code.extend_from_slice(&imm.to_le_bytes());
+}
+
+// mov byte [rbx + disp], imm8
+fn store_byte(code: &mut Vec<u8>, disp: usize, imm: u8) {
+  code.extend_from_slice(&[0xC6, 0x83]);
+  disp32(code, disp);
+  code.push(imm);
+}
+
+fn load_reg(code: &mut Vec<u8>, x86: u8, index: u32) {
+  load_field(code, x86, layout::REGS + index as usize * 4);
+}
+
+// regs と out_regs の両方に書く (命令の終わりで regs = out_regs になるのと同じ)
+fn store_reg(code: &mut Vec<u8>, x86: u8, index: u32) {
+  if index != 0 {
+    store_field(code, x86, layout::REGS + index as usize * 4);
+    store_field(code, x86, layout::OUT_REGS + index as usize * 4);
+  }
+}
+
+fn store_reg_imm(code: &mut Vec<u8>, index: u32, imm: u32) {
+  if index != 0 {
+    store_imm(code, layout::REGS + index as usize * 4, imm);
+    store_imm(code, layout::OUT_REGS + index as usize * 4, imm);
+  }
+}
+
+// op eax, imm32
+fn alu_imm(code: &mut Vec<u8>, opcode: u8, imm: u32) {
+  code.push(opcode);
+  code.extend_from_slice(&imm.to_le_bytes());
+}
+
+// setcc al; movzx eax, al
+fn set_cc(code: &mut Vec<u8>, cc: u8) {
+  code.extend_from_slice(&[0x0F, cc, 0xC0, 0x0F, 0xB6, 0xC0]);
+}
+
+// add rsp, 8; pop r12; pop rbx; ret の前に戻り値を入れる
+const EXIT_SIZE: u8 = 13;
+
+fn exit(code: &mut Vec<u8>, executed: u32) {
+  alu_imm(code, 0xB8, executed); // mov eax, imm32
+  code.extend_from_slice(&[0x48, 0x83, 0xC4, 0x08, 0x41, 0x5C, 0x5B, 0xC3]);
+}
+
+// 例外を起こさない演算命令。変換できない命令なら何も書かずに false を返す
+fn emit_alu(code: &mut Vec<u8>, instruction: Instruction) -> bool {
+  let s = instruction.s().0;
+  let t = instruction.t().0;
+  let d = instruction.d().0;
+
+  // HI/LO にだけ書く命令
+  if instruction.function() == 0x00 {
+    match instruction.subfunction() {
+      // MTHI, MTLO
+      0x11 | 0x13 => {
+        load_reg(code, EAX, s);
+        let dest = if instruction.subfunction() == 0x11 { layout::HI } else { layout::LO };
+        store_field(code, EAX, dest);
+        return true;
+      }
+      // MULT, MULTU: imul/mul ecx で edx:eax に 64 ビットの積
+      0x18 | 0x19 => {
+        load_reg(code, EAX, s);
+        load_reg(code, ECX, t);
+        let ext = if instruction.subfunction() == 0x18 { 0xE9 } else { 0xE1 };
+        code.extend_from_slice(&[0xF7, ext]);
+        store_field(code, EAX, layout::LO);
+        store_field(code, EDX, layout::HI);
+        return true;
+      }
+      _ => {}
+    }
+  }
+
+  let dest = match instruction.function() {
+    0x00 => match instruction.subfunction() {
+      0x00 | 0x02 | 0x03 | 0x04 | 0x06 | 0x07 | 0x10 | 0x12 | 0x21 | 0x23..=0x27 | 0x2A | 0x2B => d,
+      _ => return false,
+    },
+    0x09..=0x0F => t,
+    _ => return false,
+  };
+  // $zero への書き込みは何もしない
+  if dest == 0 {
+    return true;
+  }
+
+  match instruction.function() {
+    0x00 => {
+      let sub = instruction.subfunction();
+      match sub {
+        // SLL, SRL, SRA: shl/shr/sar eax, imm8
+        0x00 | 0x02 | 0x03 => {
+          load_reg(code, EAX, t);
+          let ext = [0xE0, 0, 0xE8, 0xF8][sub as usize];
+          code.extend_from_slice(&[0xC1, ext, instruction.shift() as u8]);
+        }
+        // SLLV, SRLV, SRAV: shl/shr/sar eax, cl (x86 もシフト量の下位5ビットだけを使う)
+        0x04 | 0x06 | 0x07 => {
+          load_reg(code, ECX, s);
+          load_reg(code, EAX, t);
+          let ext = match sub { 0x04 => 0xE0, 0x06 => 0xE8, _ => 0xF8 };
+          code.extend_from_slice(&[0xD3, ext]);
+        }
+        // MFHI, MFLO
+        0x10 => load_field(code, EAX, layout::HI),
+        0x12 => load_field(code, EAX, layout::LO),
+        _ => {
+          load_reg(code, EAX, s);
+          load_reg(code, ECX, t);
+          match sub {
+            0x21 => code.extend_from_slice(&[0x01, 0xC8]), // add eax, ecx
+            0x23 => code.extend_from_slice(&[0x29, 0xC8]), // sub eax, ecx
+            0x24 => code.extend_from_slice(&[0x21, 0xC8]), // and eax, ecx
+            0x25 => code.extend_from_slice(&[0x09, 0xC8]), // or eax, ecx
+            0x26 => code.extend_from_slice(&[0x31, 0xC8]), // xor eax, ecx
+            // or eax, ecx; not eax
+            0x27 => code.extend_from_slice(&[0x09, 0xC8, 0xF7, 0xD0]),
+            // cmp eax, ecx; setl / setb
+            0x2A => {
+              code.extend_from_slice(&[0x39, 0xC8]);
+              set_cc(code, 0x9C);
+            }
+            _ => {
+              code.extend_from_slice(&[0x39, 0xC8]);
+              set_cc(code, 0x92);
+            }
+          }
+        }
+      }
+    }
+    // LUI: mov eax, imm32
+    0x0F => alu_imm(code, 0xB8, instruction.imm() << 16),
+    function => {
+      load_reg(code, EAX, s);
+      match function {
+        0x09 => alu_imm(code, 0x05, instruction.imm_se()), // ADDIU: add eax, imm32
+        0x0A => {
+          alu_imm(code, 0x3D, instruction.imm_se()); // SLTI: cmp eax, imm32
+          set_cc(code, 0x9C);
+        }
+        0x0B => {
+          alu_imm(code, 0x3D, instruction.imm_se()); // SLTIU: 符号拡張した即値と符号なしで比較
+          set_cc(code, 0x92);
+        }
+        0x0C => alu_imm(code, 0x25, instruction.imm()), // ANDI: and eax, imm32
+        0x0D => alu_imm(code, 0x0D, instruction.imm()), // ORI: or eax, imm32
+        _ => alu_imm(code, 0x35, instruction.imm()),    // XORI: xor eax, imm32
+      }
+    }
+  }
+  store_reg(code, EAX, dest);
+  true
+}
+
+// 遅延スロットにない分岐命令 (pc は分岐命令のアドレス)。
+// 遅延スロットの後に進む先を next_pc に書き、分岐するときは TAR にも書く
+fn emit_branch(code: &mut Vec<u8>, instruction: Instruction, pc: u32) {
+  let s = instruction.s().0;
+  let t = instruction.t().0;
+  // 戻り先は遅延スロットの次
+  let ra = pc.wrapping_add(8);
+  let target = pc.wrapping_add(4).wrapping_add(instruction.imm_se() << 2);
+
+  match instruction.function() {
+    // J, JAL
+    0x02 | 0x03 => {
+      let target = (pc.wrapping_add(4) & 0xF000_0000) | (instruction.imm_jump() << 2);
+      if instruction.function() == 0x03 {
+        store_reg_imm(code, 31, ra);
+      }
+      store_imm(code, layout::NEXT_PC, target);
+      store_imm(code, layout::TAR, target);
+    }
+    // JR, JALR: 飛び先を読んでからリンクする
+    0x00 => {
+      load_reg(code, EAX, s);
+      if instruction.subfunction() == 0x09 {
+        store_reg_imm(code, instruction.d().0, ra);
+      }
+      store_field(code, EAX, layout::NEXT_PC);
+      store_field(code, EAX, layout::TAR);
+    }
+    function => {
+      // 比較して、分岐しないときに飛ばす条件 (jcc rel8) を決める
+      load_reg(code, EAX, s);
+      let skip = match function {
+        // BcondZ: ビット16 が立っていれば BGEZ(AL)、そうでなければ BLTZ(AL)
+        0x01 => {
+          code.extend_from_slice(&[0x85, 0xC0]); // test eax, eax
+          // リンクは分岐しなくても書く (mov はフラグを変えない)
+          if (instruction.0 >> 17) & 0x0F == 0x08 {
+            store_reg_imm(code, 31, ra);
+          }
+          if (instruction.0 >> 16) & 1 != 0 { 0x78 } else { 0x79 } // js / jns
+        }
+        0x04 | 0x05 => {
+          // cmp eax, [rbx + regs + t * 4]
+          code.extend_from_slice(&[0x3B, 0x83]);
+          disp32(code, layout::REGS + t as usize * 4);
+          if function == 0x04 { 0x75 } else { 0x74 } // jne / je
+        }
+        _ => {
+          code.extend_from_slice(&[0x85, 0xC0]); // test eax, eax
+          if function == 0x06 { 0x7F } else { 0x7E } // BLEZ: jg, BGTZ: jle
+        }
+      };
+      store_imm(code, layout::NEXT_PC, ra);
+      // 分岐先を書く2命令 (10 バイトずつ) を飛ばす
+      code.extend_from_slice(&[skip, 20]);
+      store_imm(code, layout::NEXT_PC, target);
+      store_imm(code, layout::TAR, target);
+    }
+  }
+  store_byte(code, layout::BRANCH, 1);
+}
+
+// ロード/ストアはアドレス (と値) を計算してバスの関数を、それ以外はインタプリタの関数を呼ぶ
+fn emit_call(code: &mut Vec<u8>, instruction: Instruction, index: u32) {
+  let s = instruction.s().0;
+  let t = instruction.t().0;
+  let helper = match instruction.function() {
+    // LB, LH, LW, LBU, LHU
+    0x20 | 0x21 | 0x23 | 0x24 | 0x25 => Cpu::jit_load as *const () as u64,
+    // SB, SH, SW: mov r8d, [rbx + regs + t * 4]
+    0x28 | 0x29 | 0x2B => {
+      code.extend_from_slice(&[0x44, 0x8B, 0x83]);
+      disp32(code, layout::REGS + t as usize * 4);
+      Cpu::jit_store as *const () as u64
+    }
+    _ => Cpu::jit_interpret as *const () as u64,
+  };
+  if matches!(instruction.function(), 0x20..=0x2B) {
+    // ecx = regs[s] + 符号拡張した即値
+    load_reg(code, ECX, s);
+    code.extend_from_slice(&[0x81, 0xC1]);
+    code.extend_from_slice(&instruction.imm_se().to_le_bytes());
+  }
+  // mov rdi, rbx; mov esi, index; mov edx, instruction
+  code.extend_from_slice(&[0x48, 0x89, 0xDF]);
+  alu_imm(code, 0xBE, index);
+  alu_imm(code, 0xBA, instruction.0);
+  // mov rax, imm64; call rax
+  code.extend_from_slice(&[0x48, 0xB8]);
+  code.extend_from_slice(&helper.to_le_bytes());
+  code.extend_from_slice(&[0xFF, 0xD0]);
+}
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 0x02;
+const MAP_ANONYMOUS: i32 = 0x20;
+
+extern "C" {
+  fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut c_void;
+  fn munmap(addr: *mut c_void, len: usize) -> i32;
+}
+
+// 生成したコードを置く実行可能なメモリ
+struct CodeArena {
+  base: *mut u8,
+  size: usize,
+  used: usize,
+}
+
+impl CodeArena {
+  fn new(size: usize) -> Self {
+    let base = unsafe {
+      mmap(ptr::null_mut(), size, PROT_READ | PROT_WRITE | PROT_EXEC, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0)
+    };
+    if base as isize == -1 {
+      panic!("Failed to allocate executable memory for the JIT");
+    }
+    Self { base: base as *mut u8, size, used: 0 }
+  }
+
+  fn remaining(&self) -> usize {
+    self.size.saturating_sub(self.used)
+  }
+
+  fn reset(&mut self) {
+    self.used = 0;
+  }
+
+  fn alloc(&mut self, code: &[u8]) -> NativeCode {
+    assert!(code.len() <= self.remaining(), "JIT code arena overflow");
+    unsafe {
+      let dst = self.base.add(self.used);
+      ptr::copy_nonoverlapping(code.as_ptr(), dst, code.len());
+      // 関数の先頭を 16 バイトに揃える
+      self.used = (self.used + code.len() + 15) & !15;
+      std::mem::transmute::<*mut u8, NativeCode>(dst)
+    }
+  }
+}
+
+impl Drop for CodeArena {
+  fn drop(&mut self) {
+    unsafe {
+      munmap(self.base as *mut c_void, self.size);
+    }
+  }
+}
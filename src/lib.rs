@@ -0,0 +1,42 @@
+// エミュレータのコア。SDL やファイルパスなどのホスト側の入出力はフロントエンドが受け持つ
+// 各デバイスが impl_state! を使うので最初に置く
+#[macro_use]
+pub mod savestate;
+pub mod system;
+pub mod cpu;
+pub mod breakpoint;
+pub mod block_cache;
+pub mod disasm;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub mod jit;
+pub mod lockstep;
+pub mod bios;
+pub mod bios_trace;
+pub mod trace;
+pub mod md5;
+pub mod hle_bios;
+pub mod boot;
+pub mod exe;
+pub mod disc;
+pub mod interconnect;
+pub mod ram;
+pub mod mem_control;
+pub mod scratchpad;
+pub mod dma;
+pub mod irq;
+pub mod channel;
+pub mod expansion;
+pub mod pad;
+pub mod gpu;
+pub mod renderer;
+pub mod spu;
+pub mod audio;
+pub mod console;
+#[cfg(test)]
+mod cpu_tests;
+#[cfg(test)]
+mod audio_tests;
+#[cfg(test)]
+mod system_tests;
+#[cfg(test)]
+mod disasm_tests;
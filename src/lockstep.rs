@@ -0,0 +1,128 @@
+use std::fmt;
+
+use crate::{boot::{self, BootImage}, cpu::Cpu, disasm, hle_bios::HleStatus, interconnect::MemoryWrite};
+
+// 比較する COP0 レジスタ (BPC, BDA, TAR, DCIC, BadVaddr, BDAM, BPCM, SR, CAUSE, EPC)
+const COP0_REGISTERS: [u32; 10] = [3, 5, 6, 7, 8, 9, 11, 12, 13, 14];
+
+// 基準のインタプリタとテストするバックエンドを同じ入力で動かし、1ステップごとに状態を比べる
+pub struct Lockstep {
+  reference: Cpu,
+  test: Cpu,
+  // ファストブートで SHELL_ENTRY に着いたときに両方で起動する EXE
+  fast_boot: Option<BootImage>,
+}
+
+// 最初に見つかった食い違い
+pub struct Divergence {
+  // 食い違いが見つかったステップで test が実行し始めた位置 (ブロックの先頭)
+  pub step_pc: u32,
+  // 基準側が最後に実行した命令
+  pub pc: u32,
+  pub instruction: Option<u32>,
+  pub cycles: u64,
+  pub differences: Vec<String>,
+}
+
+impl Lockstep {
+  pub fn new(mut reference: Cpu, mut test: Cpu, fast_boot: Option<BootImage>) -> Self {
+    reference.inter.set_write_log(true);
+    test.inter.set_write_log(true);
+    Self { reference, test, fast_boot }
+  }
+
+  pub fn cycles(&self) -> u64 {
+    self.test.cycles
+  }
+
+  // test が次に実行する命令のアドレス
+  pub fn pc(&self) -> u32 {
+    self.test.pc()
+  }
+
+  // HLE BIOS で止まったらそれ以上比べられない
+  pub fn status(&self) -> HleStatus {
+    self.test.hle_status()
+  }
+
+  // test を1ステップ (バックエンドによって1命令か1ブロック) 進め、
+  // 基準側を同じサイクルまで1命令ずつ進めてから比べる
+  pub fn step(&mut self) -> Result<(), Divergence> {
+    let step_pc = self.test.pc();
+    self.test.step();
+    while self.reference.cycles < self.test.cycles {
+      self.reference.run_next_instruction();
+    }
+
+    let result = self.compare(step_pc);
+    if result.is_ok() && self.test.pc() == boot::SHELL_ENTRY {
+      if let Some(image) = self.fast_boot.take() {
+        image.run(&mut self.reference);
+        image.run(&mut self.test);
+        // EXE の読み込みによる書き込みは比べない
+        self.reference.inter.take_write_log();
+        self.test.inter.take_write_log();
+      }
+    }
+    result
+  }
+
+  fn compare(&mut self, step_pc: u32) -> Result<(), Divergence> {
+    let (r, t) = (&self.reference, &self.test);
+    let mut differences = Vec::new();
+    let mut check = |name: String, a: u64, b: u64| {
+      if a != b {
+        differences.push(format!("{}: reference {:08X}, test {:08X}", name, a, b));
+      }
+    };
+
+    check("cycles".to_string(), r.cycles, t.cycles);
+    check("pc".to_string(), r.pc() as u64, t.pc() as u64);
+    for i in 1..32 {
+      check(format!("r{}", i), r.gpr(i) as u64, t.gpr(i) as u64);
+    }
+    let ((r_hi, r_lo), (t_hi, t_lo)) = (r.hi_lo(), t.hi_lo());
+    check("hi".to_string(), r_hi as u64, t_hi as u64);
+    check("lo".to_string(), r_lo as u64, t_lo as u64);
+    let ((r_reg, r_val), (t_reg, t_val)) = (r.pending_load(), t.pending_load());
+    check("load delay register".to_string(), r_reg as u64, t_reg as u64);
+    check("load delay value".to_string(), r_val as u64, t_val as u64);
+    for reg in COP0_REGISTERS {
+      check(format!("cop0r{}", reg), r.cop0(reg) as u64, t.cop0(reg) as u64);
+    }
+
+    let reference_writes = self.reference.inter.take_write_log();
+    let test_writes = self.test.inter.take_write_log();
+    if reference_writes != test_writes {
+      differences.push(format!("writes: reference {}, test {}", format_writes(&reference_writes), format_writes(&test_writes)));
+    }
+
+    if differences.is_empty() {
+      return Ok(());
+    }
+    let pc = self.reference.current_pc();
+    let instruction = self.reference.inter.code_address(pc).map(|addr| self.reference.inter.peek_code(addr));
+    Err(Divergence { step_pc, pc, instruction, cycles: self.reference.cycles, differences })
+  }
+}
+
+fn format_writes(writes: &[MemoryWrite]) -> String {
+  let writes: Vec<String> = writes.iter()
+    .map(|w| format!("[{:08X}]{:?}={:08X}", w.addr, w.width, w.val))
+    .collect();
+  format!("[{}]", writes.join(", "))
+}
+
+impl fmt::Display for Divergence {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "Divergence at cycle {} (step from {:08X})", self.cycles, self.step_pc)?;
+    match self.instruction {
+      Some(word) => writeln!(f, "  {:08X}: {:08X} {}", self.pc, word, disasm::disassemble(self.pc, word))?,
+      None => writeln!(f, "  {:08X}: ???", self.pc)?,
+    }
+    for difference in &self.differences {
+      writeln!(f, "  {}", difference)?;
+    }
+    Ok(())
+  }
+}
@@ -1,82 +1,289 @@
 use core::time;
-use std::{path::Path, time::Instant};
-
-use bios::Bios;
-use cpu::Cpu;
-use gpu::Gpu;
-use interconnect::Interconnect;
-use spu::Spu;
-
-mod cpu;
-mod bios;
-mod interconnect;
-mod ram;
-mod dma;
-mod channel;
-mod gpu;
-mod renderer;
-mod spu;
+use std::{fs::File, io::BufWriter, path::Path, time::Instant};
 
-fn main() {
-  let bios = Bios::new(&Path::new("bios/BIOS.ROM")).unwrap();
+use ps1_boot::{
+  audio::{AudioSink, AudioTap, NullAudioSink, WavWriter},
+  bios::{Bios, BiosPatch},
+  boot::BootMode,
+  cpu::CpuBackend,
+  disasm,
+  disc::Disc,
+  expansion::Expansion1,
+  hle_bios::HleStatus,
+  lockstep::Lockstep,
+  pad::Button,
+  ram,
+  system::{System, CPU_CLOCK},
+  trace::{TraceFilter, TraceFormat, Tracer},
+};
+use sdl2::keyboard::Scancode;
+
+use gl_renderer::GlRenderer;
+use sdl_audio::SdlAudioSink;
+
+mod gl_renderer;
+mod sdl_audio;
 
+// キーボードとパッドのボタンの対応
+const KEYMAP: [(Scancode, Button); 14] = [
+  (Scancode::Up, Button::Up),
+  (Scancode::Down, Button::Down),
+  (Scancode::Left, Button::Left),
+  (Scancode::Right, Button::Right),
+  (Scancode::Z, Button::Cross),
+  (Scancode::X, Button::Circle),
+  (Scancode::A, Button::Square),
+  (Scancode::S, Button::Triangle),
+  (Scancode::Q, Button::L1),
+  (Scancode::W, Button::R1),
+  (Scancode::Num1, Button::L2),
+  (Scancode::Num2, Button::R2),
+  (Scancode::Return, Button::Start),
+  (Scancode::RShift, Button::Select),
+];
+
+fn main() {
   let sdl_context = sdl2::init().unwrap();
   let video_subsystem = sdl_context.video().unwrap();
   let audio_subsystem = sdl_context.audio().unwrap();
 
-  let gpu = Gpu::new(video_subsystem);
-  let spu = Spu::new(audio_subsystem);
-  let inter = Interconnect::new(bios, gpu, spu);
-  let mut cpu = Cpu::new(inter);
-  let mut event_pump = sdl_context.event_pump().unwrap();
+  let mut no_audio = false;
+  let mut bios_path = "bios/BIOS.ROM".to_string();
+  let mut hle_bios = false;
+  let mut bios_patches = Vec::new();
+  let mut exe_path = None;
+  let mut disc_path = None;
+  let mut boot_mode = None;
+  let mut ram_size = ram::RAM_SIZE_2MB;
+  let mut expansion_rom = None;
+  let mut tty_log = None;
+  let mut bios_trace = None;
+  let mut bios_tty = false;
+  let mut recordings = Vec::new();
+  let mut cpu_backend = CpuBackend::Interpreter;
+  let mut benchmark = None;
+  let mut lockstep = false;
+  let mut trace_path = None;
+  let mut trace_format = TraceFormat::Text;
+  let mut trace_filter = TraceFilter::new();
+  let mut args = std::env::args().skip(1);
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--no-audio" => no_audio = true,
+      "--bios" => bios_path = args.next().expect("--bios requires a path"),
+      // --patch-bios <fast-boot|tty>
+      "--patch-bios" => {
+        let name = args.next().expect("--patch-bios requires a patch name");
+        bios_patches.push(BiosPatch::parse(&name).unwrap_or_else(|| panic!("Unknown BIOS patch: {}", name)));
+      }
+      // BIOS ROM を使わずにカーネルを HLE で動かす
+      "--hle-bios" => hle_bios = true,
+      "--exe" => exe_path = Some(args.next().expect("--exe requires a path")),
+      "--disc" => disc_path = Some(args.next().expect("--disc requires a path")),
+      // --boot <normal|fast|shell>
+      "--boot" => {
+        let name = args.next().expect("--boot requires a mode");
+        boot_mode = Some(BootMode::parse(&name).unwrap_or_else(|| panic!("Unknown boot mode: {}", name)));
+      }
+      // 開発機 (DTL-H) 相当の 8MB RAM
+      "--ram-8mb" => ram_size = ram::RAM_SIZE_8MB,
+      // 拡張領域1 に読み込む ROM (Caetla など)
+      "--exp-rom" => expansion_rom = Some(args.next().expect("--exp-rom requires a path")),
+      // TTY 出力を標準出力の代わりにファイルへ書き出す
+      "--tty-log" => tty_log = Some(args.next().expect("--tty-log requires a path")),
+      // --bios-trace <all|A3F,B35,C,...>
+      "--bios-trace" => bios_trace = Some(args.next().expect("--bios-trace requires a filter")),
+      // BIOS の putchar などの出力をコンソールに出す
+      "--bios-tty" => bios_tty = true,
+      // --record-wav <mixed|reverb|voiceN> <path>
+      "--record-wav" => {
+        let name = args.next().expect("--record-wav requires a tap");
+        let tap = AudioTap::parse(&name).unwrap_or_else(|| panic!("Unknown audio tap: {}", name));
+        let path = args.next().expect("--record-wav requires a path");
+        recordings.push((tap, path));
+      }
+      // --cpu <interpreter|cached|jit>
+      "--cpu" => {
+        let name = args.next().expect("--cpu requires a backend");
+        cpu_backend = CpuBackend::parse(&name).unwrap_or_else(|| panic!("Unknown CPU backend: {}", name));
+      }
+      // 指定したサイクル数だけ全速で実行して速度を表示して終了する
+      "--benchmark" => benchmark = Some(args.next().expect("--benchmark requires a cycle count").parse::<u64>().expect("Invalid cycle count")),
+      // --cpu で選んだバックエンドをインタプリタと並べて動かし、最初の食い違いを報告する
+      "--lockstep" => lockstep = true,
+      // 命令単位のトレースをファイルに書き出す
+      "--trace" => trace_path = Some(args.next().expect("--trace requires a path")),
+      // --trace-format <text|binary>
+      "--trace-format" => {
+        let name = args.next().expect("--trace-format requires a format");
+        trace_format = TraceFormat::parse(&name).unwrap_or_else(|| panic!("Unknown trace format: {}", name));
+      }
+      // --trace-pc <start-end> (16進数)
+      "--trace-pc" => trace_filter.set_address(&args.next().expect("--trace-pc requires a range")).unwrap(),
+      // --trace-frames <start-end>
+      "--trace-frames" => trace_filter.set_frames(&args.next().expect("--trace-frames requires a range")).unwrap(),
+      // --trace-subsystems <cpu,mem,dma,gpu,spu>
+      "--trace-subsystems" => trace_filter.set_subsystems(&args.next().expect("--trace-subsystems requires a list")).unwrap(),
+      _ => panic!("Unknown argument: {}", arg),
+    }
+  }
 
-  let spu_interval = 1_000_000_000 / 44100;
-  let mut spu_now = Instant::now();
-  let mut nanos: u128 = 0;
+  let bios = match hle_bios {
+    true => None,
+    false => match std::fs::read(&bios_path).and_then(Bios::new) {
+      Ok(mut bios) => {
+        for warning in bios.warnings() {
+          println!("Warning: {}", warning);
+        }
+        match bios.info() {
+          Some(info) => println!("BIOS: {} {} ({:?})", info.model, info.version, info.region),
+          None => println!("BIOS: {} (kernel {:08X})", bios.version_strings().join(" / "), bios.kernel_date()),
+        }
+        for patch in bios_patches {
+          bios.apply_patch(patch).unwrap();
+        }
+        Some(bios)
+      }
+      Err(e) => {
+        println!("Failed to load BIOS {} ({}), falling back to HLE BIOS", bios_path, e);
+        None
+      }
+    },
+  };
+  // EXE やディスクの指定があるときはファストブートが既定
+  let boot_mode = boot_mode.unwrap_or(match exe_path.is_some() || disc_path.is_some() {
+    true => BootMode::Fast,
+    false => BootMode::Normal,
+  });
+  let expansion1 = expansion_rom.map(|path| Expansion1::with_rom(std::fs::read(path).unwrap()).unwrap());
+  let exe = exe_path.map(|path| std::fs::read(path).unwrap());
+  // ロックステップでは比較用のインタプリタも同じディスク/EXE で起動する
+  let boot = |system: &mut System| {
+    if let Some(path) = &disc_path {
+      system.load_disc(Disc::new(Box::new(File::open(Path::new(path)).unwrap())).unwrap());
+    }
+    if let Some(exe) = &exe {
+      system.load_exe(exe.clone());
+    }
+    system.boot(boot_mode).unwrap();
+  };
 
-  let mut spu_counter = 0;
-  let mut gpu_counter = 0;
+  // 比較用のインタプリタは画面も音も持たない
+  let reference = match lockstep {
+    true => {
+      let mut reference = System::new(bios.clone(), ram_size, None);
+      if let Some(expansion1) = &expansion1 {
+        reference.cpu.inter.set_expansion1(expansion1.clone());
+      }
+      Some(reference)
+    }
+    false => None,
+  };
+  let mut system = System::new(bios, ram_size, Some(Box::new(GlRenderer::new(video_subsystem))));
+  for (tap, path) in recordings {
+    let writer = WavWriter::new(BufWriter::new(File::create(Path::new(&path)).unwrap())).unwrap();
+    system.cpu.inter.spu.record(tap, Box::new(writer)).unwrap();
+  }
+  if let Some(expansion1) = expansion1 {
+    system.cpu.inter.set_expansion1(expansion1);
+  }
+  // TTY 出力とエミュレータからのメッセージ (比較用のインタプリタのものは捨てる)
+  match tty_log {
+    Some(path) => system.set_console(Box::new(File::create(Path::new(&path)).unwrap())),
+    None => system.set_console(Box::new(std::io::stdout())),
+  }
+  if let Some(path) = trace_path {
+    let out = Box::new(File::create(Path::new(&path)).unwrap());
+    system.cpu.inter.set_tracer(Some(Tracer::new(out, trace_format, trace_filter, disasm::disassemble).unwrap()));
+  }
+  system.cpu.set_backend(cpu_backend);
+  if let Some(filter) = bios_trace {
+    system.cpu.bios_tracer.set_filter(&filter).unwrap();
+  }
+  system.cpu.bios_tracer.set_forward_tty(bios_tty);
+
+  boot(&mut system);
+
+  if let Some(mut reference) = reference {
+    boot(&mut reference);
+    let mut lockstep = Lockstep::new(reference.cpu, system.cpu, system.fast_boot);
+    loop {
+      if let Err(divergence) = lockstep.step() {
+        print!("{}", divergence);
+        return;
+      }
+      if lockstep.status() != HleStatus::Running {
+        println!("No divergence in {} cycles ({:?})", lockstep.cycles(), lockstep.status());
+        return;
+      }
+      if let Some(cycles) = benchmark {
+        if lockstep.cycles() >= cycles {
+          println!("No divergence in {} cycles", lockstep.cycles());
+          return;
+        }
+      }
+    }
+  }
+
+  if let Some(cycles) = benchmark {
+    let start = Instant::now();
+    while system.cpu.cycles < cycles && system.status() == HleStatus::Running {
+      system.run_frame();
+      system.audio_samples();
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    let mhz = system.cpu.cycles as f64 / elapsed / 1_000_000.0;
+    println!("{:?}: {} cycles in {:.3}s ({:.2} MHz, {:.0}% of real time)", cpu_backend, system.cpu.cycles, elapsed, mhz, mhz / (CPU_CLOCK as f64 / 1_000_000.0) * 100.0);
+    system.cpu.inter.spu.stop_recording();
+    system.cpu.inter.set_tracer(None);
+    return;
+  }
+
+  let mut sink: Box<dyn AudioSink> = match no_audio {
+    true => Box::new(NullAudioSink),
+    false => Box::new(SdlAudioSink::new(audio_subsystem, 50)),
+  };
+  let mut event_pump = sdl_context.event_pump().unwrap();
 
   let gpu_interval = 1_000_000_000 / 60;
   let mut gpu_now = Instant::now();
 
-  let mut now2 = Instant::now();
-
   loop {
-    cpu.run_next_instruction();
-
-    let n = spu_now.elapsed().as_nanos();
-    if n > spu_interval * 20 {
-      nanos += n;
-      while nanos >= spu_interval {
-        nanos -= spu_interval;
-        cpu.inter.spu.clock();
-        spu_counter += 1;
-      }
-      spu_now = Instant::now();
+    system.run_frame();
+    for frame in system.audio_samples().chunks_exact(2) {
+      sink.push_sample(frame[0], frame[1]);
+    }
+    // HLE BIOS でゲストが終了したか止まった
+    if let status @ (HleStatus::Exited(_) | HleStatus::Halted) = system.status() {
+      system.cpu.inter.spu.stop_recording();
+      system.cpu.inter.set_tracer(None);
+      println!("Stopped: {:?}", status);
+      std::process::exit(match status {
+        HleStatus::Exited(code) => code,
+        _ => 1,
+      });
     }
 
-    if cpu.inter.gpu.frame_updated {
-      cpu.inter.gpu.frame_updated = false;
-      for event in event_pump.poll_iter() {
-        match event {
-          sdl2::event::Event::Quit {..} => panic!("exit!"),
-          _ => {},
+    for event in event_pump.poll_iter() {
+      match event {
+        sdl2::event::Event::Quit {..} => {
+          // WavWriter は Drop でヘッダを確定させる
+          system.cpu.inter.spu.stop_recording();
+          system.cpu.inter.set_tracer(None);
+          panic!("exit!")
         }
+        _ => {},
       }
-      if gpu_now.elapsed().as_nanos() < gpu_interval {
-        std::thread::sleep(time::Duration::from_nanos((gpu_interval - gpu_now.elapsed().as_nanos()) as u64));
-      }
-      gpu_now = Instant::now();
-      gpu_counter += 1;
     }
+    let keyboard = event_pump.keyboard_state();
+    let buttons = KEYMAP.iter()
+        .filter(|(key, _)| keyboard.is_scancode_pressed(*key))
+        .fold(0, |buttons, (_, button)| buttons | button.mask());
+    system.set_input(0, buttons);
 
-    // let e = now2.elapsed().as_nanos();
-    // if e >= 1_000_000_000 {
-    // //   println!("SPU: {}, GPU: {}", spu_counter, gpu_counter);
-    // //   spu_counter = 0;
-    // //   gpu_counter = 0;
-    // //   now2 = Instant::now();
-    // }
+    if gpu_now.elapsed().as_nanos() < gpu_interval {
+      std::thread::sleep(time::Duration::from_nanos((gpu_interval - gpu_now.elapsed().as_nanos()) as u64));
+    }
+    gpu_now = Instant::now();
   }
 }
@@ -0,0 +1,53 @@
+// BIOS イメージの識別用の MD5 (RFC 1321)
+
+const S: [u32; 64] = [
+  7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22,
+  5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20,
+  4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23,
+  6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+];
+
+pub fn md5(data: &[u8]) -> [u8; 16] {
+  // K[i] = floor(abs(sin(i + 1)) * 2^32)
+  let k: Vec<u32> = (0..64).map(|i| ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32).collect();
+
+  let mut msg = data.to_vec();
+  msg.push(0x80);
+  while msg.len() % 64 != 56 {
+    msg.push(0);
+  }
+  msg.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+  let mut h: [u32; 4] = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476];
+  for chunk in msg.chunks(64) {
+    let m: Vec<u32> = chunk.chunks(4).map(|w| u32::from_le_bytes(w.try_into().unwrap())).collect();
+    let [mut a, mut b, mut c, mut d] = h;
+    for i in 0..64 {
+      let (f, g) = match i / 16 {
+        0 => ((b & c) | (!b & d), i),
+        1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+        2 => (b ^ c ^ d, (3 * i + 5) % 16),
+        _ => (c ^ (b | !d), (7 * i) % 16),
+      };
+      let f = f.wrapping_add(a).wrapping_add(k[i]).wrapping_add(m[g]);
+      a = d;
+      d = c;
+      c = b;
+      b = b.wrapping_add(f.rotate_left(S[i]));
+    }
+    h[0] = h[0].wrapping_add(a);
+    h[1] = h[1].wrapping_add(b);
+    h[2] = h[2].wrapping_add(c);
+    h[3] = h[3].wrapping_add(d);
+  }
+
+  let mut out = [0; 16];
+  for (i, v) in h.iter().enumerate() {
+    out[i * 4..i * 4 + 4].copy_from_slice(&v.to_le_bytes());
+  }
+  out
+}
+
+pub fn to_hex(digest: &[u8; 16]) -> String {
+  digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
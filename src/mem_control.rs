@@ -0,0 +1,132 @@
+// 0x1F80_1000～0x1F80_1023 メモリコントロール (各領域のウェイト設定)
+pub struct MemControl {
+  regs: [u32; 9],
+}
+
+impl_state!(MemControl { regs });
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+  Expansion1 = 2,
+  Expansion3 = 3,
+  Bios = 4,
+  Spu = 5,
+  CdRom = 6,
+  Expansion2 = 7,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Width {
+  Byte,
+  HalfWord,
+  Word,
+}
+
+impl Width {
+  pub fn bytes(self) -> u32 {
+    match self {
+      Width::Byte => 1,
+      Width::HalfWord => 2,
+      Width::Word => 4,
+    }
+  }
+
+  pub fn mask(self, val: u32) -> u32 {
+    match self {
+      Width::Byte => val & 0xFF,
+      Width::HalfWord => val & 0xFFFF,
+      Width::Word => val,
+    }
+  }
+}
+
+impl Default for MemControl {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl MemControl {
+  pub fn new() -> Self {
+    // BIOS が起動時に書き込む値
+    Self {
+      regs: [
+        0x1F00_0000, // Expansion 1 Base Address
+        0x1F80_2000, // Expansion 2 Base Address
+        0x0013_243F, // Expansion 1 Delay/Size
+        0x0000_3022, // Expansion 3 Delay/Size
+        0x0013_243F, // BIOS ROM Delay/Size
+        0x2009_31E1, // SPU Delay/Size
+        0x0002_0843, // CDROM Delay/Size
+        0x0007_0777, // Expansion 2 Delay/Size
+        0x0003_1125, // COM_DELAY
+      ],
+    }
+  }
+
+  pub fn load(&self, offset: u32) -> u32 {
+    self.regs[(offset >> 2) as usize]
+  }
+
+  // 拡張領域1/2 の基底アドレスを変えても領域は動かさない (値は読み返せるように残す)
+  pub fn store(&mut self, offset: u32, val: u32) {
+    self.regs[(offset >> 2) as usize] = val;
+  }
+
+  // 1回のアクセスにかかるサイクル数
+  pub fn access_cycles(&self, region: Region, width: Width, write: bool) -> u32 {
+    let delay_size = self.regs[region as usize];
+    let com_delay = self.regs[8];
+
+    // 0-3 Write Delay, 4-7 Read Delay (00h..0Fh=01h..10h Cycles)
+    let access_time = match write {
+      true => delay_size & 0x0F,
+      false => (delay_size >> 4) & 0x0F,
+    } as i32;
+    let com0 = (com_delay & 0x0F) as i32;
+    let com2 = ((com_delay >> 8) & 0x0F) as i32;
+    let com3 = ((com_delay >> 12) & 0x0F) as i32;
+
+    let mut first = 0;
+    let mut seq = 0;
+    let mut min = 0;
+    // 8 Recovery Period (COM0)
+    if delay_size & (1 << 8) != 0 {
+      first += com0 - 1;
+      seq += com0 - 1;
+    }
+    // 10 Floating Period (COM2)
+    if delay_size & (1 << 10) != 0 {
+      first += com2;
+      seq += com2;
+    }
+    // 11 Pre-strobe Period (COM3)
+    if delay_size & (1 << 11) != 0 {
+      min = com3;
+    }
+    if first < 6 {
+      first += 1;
+    }
+    first += access_time + 2;
+    seq += access_time + 2;
+    first = first.max(min + 6);
+    seq = seq.max(min + 2);
+
+    // 12 Data Bus-width (0=8bits, 1=16bits)
+    let bus_16bit = delay_size & (1 << 12) != 0;
+    let cycles = match (width, bus_16bit) {
+      (Width::Byte, _) => first,
+      (Width::HalfWord, true) => first,
+      (Width::HalfWord, false) => first + seq,
+      (Width::Word, true) => first + seq,
+      (Width::Word, false) => first + seq * 3,
+    };
+    cycles as u32
+  }
+}
+
+// メモリコントロールで設定できない領域のアクセスサイクル
+pub const RAM_READ_CYCLES: u32 = 5;
+pub const RAM_WRITE_CYCLES: u32 = 1;
+pub const IO_ACCESS_CYCLES: u32 = 2;
+pub const SCRATCHPAD_ACCESS_CYCLES: u32 = 0;
@@ -0,0 +1,202 @@
+// 0x1F80_1040～ コントローラとメモリーカードのシリアルポート (SIO0)
+// デジタルパッドだけを実装する。転送は書き込んだ時点で終わり、ボーレートによる遅延はない
+pub struct PadMemCard {
+  // ポートごとの押されているボタン (Button のビット)。None はコントローラがつながっていない
+  pads: [Option<u16>; 2],
+  mode: u16,
+  control: u16,
+  baud: u16,
+  rx: Option<u8>,
+  // 選択中のコントローラとのやりとりで何バイト目か
+  sequence: usize,
+  ack: bool,
+  irq: bool,
+  // 前回の割り込みフラグ (立ち上がりで IRQ7 を発生させる)
+  prev_irq: bool,
+}
+
+impl_state!(PadMemCard { pads, mode, control, baud, rx, sequence, ack, irq, prev_irq });
+
+// ボタンのビット (コントローラが返す順)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Button {
+  Select = 0,
+  L3 = 1,
+  R3 = 2,
+  Start = 3,
+  Up = 4,
+  Right = 5,
+  Down = 6,
+  Left = 7,
+  L2 = 8,
+  R2 = 9,
+  L1 = 10,
+  R1 = 11,
+  Triangle = 12,
+  Circle = 13,
+  Cross = 14,
+  Square = 15,
+}
+
+impl Button {
+  pub fn mask(self) -> u16 {
+    1 << (self as u16)
+  }
+}
+
+// デジタルパッドの ID
+const DIGITAL_PAD_ID: u16 = 0x5A41;
+
+impl Default for PadMemCard {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl PadMemCard {
+  pub fn new() -> Self {
+    Self {
+      // 1P 側にはパッドがつながっている
+      pads: [Some(0), None],
+      mode: 0,
+      control: 0,
+      baud: 0,
+      rx: None,
+      sequence: 0,
+      ack: false,
+      irq: false,
+      prev_irq: false,
+    }
+  }
+
+  // port は 0 (1P) か 1 (2P)。ボタンを設定するとそのポートにパッドがつながる
+  pub fn set_buttons(&mut self, port: usize, buttons: u16) {
+    self.pads[port] = Some(buttons);
+  }
+
+  pub fn disconnect(&mut self, port: usize) {
+    self.pads[port] = None;
+  }
+
+  pub fn buttons(&self, port: usize) -> Option<u16> {
+    self.pads[port]
+  }
+
+  pub fn load8(&mut self, offset: u32) -> u8 {
+    match offset {
+      // 0x1F80_1040 JOY_DATA
+      0x0 => self.rx.take().unwrap_or(0xFF),
+      // 0x1F80_1044 JOY_STAT
+      0x4..=0x7 => (self.status() >> ((offset - 0x4) * 8)) as u8,
+      // 0x1F80_1048 JOY_MODE
+      0x8 | 0x9 => (self.mode >> ((offset - 0x8) * 8)) as u8,
+      // 0x1F80_104A JOY_CTRL
+      0xA | 0xB => (self.control >> ((offset - 0xA) * 8)) as u8,
+      // 0x1F80_104E JOY_BAUD
+      0xE | 0xF => (self.baud >> ((offset - 0xE) * 8)) as u8,
+      _ => 0,
+    }
+  }
+
+  pub fn store8(&mut self, offset: u32, val: u8) {
+    match offset {
+      0x0 => self.transfer(val),
+      0x8 => self.mode = (self.mode & 0xFF00) | val as u16,
+      0x9 => self.mode = (self.mode & 0x00FF) | (val as u16) << 8,
+      0xA => {
+        // 4: ACK (割り込みフラグをクリア), 6: リセット は書き込みのみ
+        if val & 0x10 != 0 {
+          self.irq = false;
+        }
+        if val & 0x40 != 0 {
+          self.reset();
+          return;
+        }
+        self.control = (self.control & 0xFF00) | (val & !0x50) as u16;
+        // /JOYn を上げるとコントローラとのやりとりは終わる
+        if !self.selected() {
+          self.sequence = 0;
+        }
+      }
+      0xB => {
+        let port = self.port();
+        self.control = (self.control & 0x00FF) | (val as u16) << 8;
+        if self.port() != port {
+          self.sequence = 0;
+        }
+      }
+      0xE => self.baud = (self.baud & 0xFF00) | val as u16,
+      0xF => self.baud = (self.baud & 0x00FF) | (val as u16) << 8,
+      _ => {}
+    }
+  }
+
+  pub fn irq_edge(&mut self) -> bool {
+    let edge = self.irq && !self.prev_irq;
+    self.prev_irq = self.irq;
+    edge
+  }
+
+  fn reset(&mut self) {
+    self.mode = 0;
+    self.control = 0;
+    self.baud = 0;
+    self.rx = None;
+    self.sequence = 0;
+    self.ack = false;
+    self.irq = false;
+  }
+
+  fn status(&self) -> u32 {
+    // 0, 2: 送信可能
+    1 << 0 |
+    (self.rx.is_some() as u32) << 1 |
+    1 << 2 |
+    (self.ack as u32) << 7 |
+    (self.irq as u32) << 9
+  }
+
+  // 1: /JOYn 出力
+  fn selected(&self) -> bool {
+    self.control & 0x0002 != 0
+  }
+
+  // 13: 1P / 2P の選択
+  fn port(&self) -> usize {
+    ((self.control >> 13) & 1) as usize
+  }
+
+  fn transfer(&mut self, tx: u8) {
+    let (rx, ack) = match (self.pads[self.port()], self.control & 0x0001 != 0 && self.selected()) {
+      (Some(buttons), true) => self.pad_response(tx, buttons),
+      _ => (0xFF, false),
+    };
+    self.rx = Some(rx);
+    self.ack = ack;
+    // 12: ACK で割り込みを発生させる
+    if ack && self.control & 0x1000 != 0 {
+      self.irq = true;
+    }
+  }
+
+  // コントローラの応答と、続きがあるか (ACK を返すか)
+  fn pad_response(&mut self, tx: u8, buttons: u16) -> (u8, bool) {
+    let response = match (self.sequence, tx) {
+      // 0x01: コントローラの選択 (0x81 はメモリーカード)
+      (0, 0x01) => (0xFF, true),
+      // 0x42: ボタンの読み出し
+      (1, 0x42) => (DIGITAL_PAD_ID as u8, true),
+      (2, _) => ((DIGITAL_PAD_ID >> 8) as u8, true),
+      // 押されているボタンが 0
+      (3, _) => (!buttons as u8, true),
+      (4, _) => (!(buttons >> 8) as u8, false),
+      _ => (0xFF, false),
+    };
+    self.sequence = match response.1 {
+      true => self.sequence + 1,
+      // 選択し直されるまで応答しない
+      false => usize::MAX,
+    };
+    response
+  }
+}
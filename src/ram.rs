@@ -1,11 +1,118 @@
+use std::io::Error;
+
+use crate::savestate::{invalid_state, State, StateReader, StateWriter};
+
+// 実機は 2MB、開発機 (DTL-H) は 8MB
+pub const RAM_SIZE_2MB: usize = 2 * 1024 * 1024;
+pub const RAM_SIZE_8MB: usize = 8 * 1024 * 1024;
+
+// 自己書き換えの検出はこの単位 (4KB) で行う
+pub const CODE_PAGE_SHIFT: u32 = 12;
+
 pub struct Ram {
-  data: Vec<u8>
+  data: Vec<u8>,
+  // 0x1F80_1060 RAM_SIZE レジスタ
+  config: u32,
+  // ブロックキャッシュに命令が入っているページ
+  code_pages: Vec<bool>,
+  // 命令が入っていたのに書き換えられたページ
+  modified_pages: Vec<u32>,
+}
+
+// KUSEG 先頭 8MB のうち、あるオフセットが何につながっているか
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RamMapping {
+  Memory(u32),
+  HighZ,
+  Locked,
+}
+
+// 大きさは起動時の設定に合わせる。
+// ブロックキャッシュは読み込み後に作り直されるので、コードページの記録も消す
+impl State for Ram {
+  fn save(&self, w: &mut StateWriter) {
+    self.data.save(w);
+    self.config.save(w);
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), Error> {
+    let data: Vec<u8> = r.value()?;
+    if data.len() != self.data.len() {
+      return Err(invalid_state("RAM size does not match"));
+    }
+    self.data = data;
+    self.config.load(r)?;
+    self.code_pages.fill(false);
+    self.modified_pages.clear();
+    Ok(())
+  }
 }
 
 impl Ram {
-  pub fn new() -> Self {
-    let data = vec![0xCA; 2 * 1024 * 1024];
-    Ram { data }
+  pub fn new(size: usize) -> Self {
+    let data = vec![0xCA; size];
+    let code_pages = vec![false; size >> CODE_PAGE_SHIFT];
+    // BIOS が起動時に書き込む値
+    Ram { data, config: 0x0000_0B88, code_pages, modified_pages: Vec::new() }
+  }
+
+  pub fn mark_code_page(&mut self, offset: u32) {
+    self.code_pages[(offset >> CODE_PAGE_SHIFT) as usize] = true;
+  }
+
+  pub fn code_modified(&self) -> bool {
+    !self.modified_pages.is_empty()
+  }
+
+  pub fn take_modified_pages(&mut self) -> Vec<u32> {
+    std::mem::take(&mut self.modified_pages)
+  }
+
+  fn written(&mut self, offset: usize) {
+    let page = offset >> CODE_PAGE_SHIFT;
+    if self.code_pages[page] {
+      self.code_pages[page] = false;
+      self.modified_pages.push(page as u32);
+    }
+  }
+
+  pub fn size(&self) -> usize {
+    self.data.len()
+  }
+
+  // DMA などミラーを考慮しないアクセス用のアドレスマスク
+  pub fn address_mask(&self) -> u32 {
+    self.data.len() as u32 - 1
+  }
+
+  pub fn config(&self) -> u32 {
+    self.config
+  }
+
+  pub fn set_config(&mut self, val: u32) {
+    self.config = val;
+  }
+
+  // RAM_SIZE の 9-11 ビットで 8MB の窓の割り当てが決まる。
+  // 搭載量より大きい領域にはミラーが見える
+  pub fn mapping(&self, offset: u32) -> RamMapping {
+    const MB: u32 = 1024 * 1024;
+    let (memory, high_z) = match (self.config >> 9) & 7 {
+      0 => (MB, 0),
+      1 => (4 * MB, 0),
+      2 => (MB, MB),
+      3 => (4 * MB, 4 * MB),
+      4 => (2 * MB, 0),
+      6 => (2 * MB, 2 * MB),
+      _ => (8 * MB, 0), // 5, 7
+    };
+    if offset < memory {
+      RamMapping::Memory(offset & self.address_mask())
+    } else if offset < memory + high_z {
+      RamMapping::HighZ
+    } else {
+      RamMapping::Locked
+    }
   }
 
   pub fn load32(&self, offset: u32) -> u32 {
@@ -31,6 +138,7 @@ impl Ram {
     self.data[offset + 1] = b1;
     self.data[offset + 2] = b2;
     self.data[offset + 3] = b3;
+    self.written(offset);
   }
 
   pub fn load16(&self, offset: u32) -> u16 {
@@ -50,6 +158,7 @@ impl Ram {
 
     self.data[offset + 0] = b0;
     self.data[offset + 1] = b1;
+    self.written(offset);
   }
 
   pub fn load8(&self, offset: u32) -> u8 {
@@ -57,6 +166,7 @@ impl Ram {
   }
 
   pub fn store8(&mut self, offset: u32, val: u8) {
-    self.data[offset as usize] = val
+    self.data[offset as usize] = val;
+    self.written(offset as usize);
   }
 }
@@ -1,326 +1,239 @@
-use std::ffi::CString;
-
-use sdl2;
-use gl;
-use gl::types::{GLshort, GLubyte, GLuint, GLint, GLenum, GLsizei};
-use std::ptr;
-
-pub fn compile_shader(src: &str, shader_type: GLenum) -> GLuint {
-  let shader;
-  unsafe {
-    shader = gl::CreateShader(shader_type);
-    let c_str = CString::new(src.as_bytes()).unwrap();
-    gl::ShaderSource(shader, 1, &c_str.as_ptr(), ptr::null());
-    gl::CompileShader(shader);
-    let mut status = gl::FALSE as GLint;
-    gl::GetShaderiv(shader, gl::COMPILE_STATUS, &mut status);
-    if status != (gl::TRUE as GLint) {
-      panic!("Shader compilation failed!");
-    }
+use std::io::Error;
+
+use crate::savestate::{invalid_state, State, StateReader, StateWriter};
+
+// GPU の描画命令を受け取るレンダラ。
+// ウィンドウへの描画 (OpenGL など) はフロントエンドが実装し、コアはソフトウェアで VRAM に描くものを持つ
+pub trait Renderer {
+  fn push_triangle(&mut self, positions: [Position; 3], colors: [Color; 3]);
+  fn push_quad(&mut self, positions: [Position; 4], colors: [Color; 4]);
+  fn set_draw_offset(&mut self, x: i16, y: i16);
+  // フレームの終わり (描画オフセットの設定のたびに呼ばれる)
+  fn display(&mut self);
+
+  // VRAM の表示領域を読み出す (ウィンドウに直接描くレンダラは読み出せない)
+  fn framebuffer(&self, _x: u16, _y: u16, _width: u16, _height: u16) -> Option<Framebuffer> {
+    None
   }
-  shader
-}
-
-pub fn link_program(shaders: &[GLuint]) -> GLuint {
-  let program;
 
-  unsafe {
-    program = gl::CreateProgram();
-    for &shader in shaders {
-      gl::AttachShader(program, shader);
-    }
-    gl::LinkProgram(program);
-    let mut status = gl::FALSE as GLint;
-    gl::GetProgramiv(program, gl::LINK_STATUS, &mut status);
-    if status != (gl::TRUE as GLint) {
-      panic!("OpenGL program linking failed!");
-    }
+  // セーブステートに含める VRAM など (読み出せないレンダラは空)
+  fn save_state(&self) -> Vec<u8> {
+    Vec::new()
   }
-  program
-}
 
-pub fn find_program_attrib(program: GLuint, attr: &str) -> GLuint {
-  let cstr = CString::new(attr).unwrap();
-  let index = unsafe {
-    gl::GetAttribLocation(program, cstr.as_ptr())
-  };
-  if index < 0 {
-    panic!("Attribure \"{:?}\" not found in program", attr);
+  fn load_state(&mut self, _data: &[u8]) -> Result<(), Error> {
+    Ok(())
   }
-  index as GLuint
 }
 
-pub fn find_program_uniform(program: GLuint, uniform: &str) -> GLint {
-  let cstr = CString::new(uniform).unwrap();
-  let index = unsafe {
-    gl::GetUniformLocation(program, cstr.as_ptr())
-  };
-  if index < 0 {
-    panic!("Uniform \"{:?}\" not found in program", uniform);
+// レンダラの状態は長さ付きのまとまりで保存する。
+// 保存したときと違うレンダラ (空のまとまり) は読み飛ばせる
+impl State for Option<Box<dyn Renderer>> {
+  fn save(&self, w: &mut StateWriter) {
+    let data = self.as_ref().map(|renderer| renderer.save_state()).unwrap_or_default();
+    data.save(w);
   }
-  index as GLint
-}
-
-/*
-pub fn check_for_errors() {
-  let mut fatal = false;
-  loop {
-    let mut buffer = vec![0; 4096];
-
-    let mut severity = 0;
-    let mut source = 0;
-    let mut message_size = 0;
-    let mut mtype = 0;
-    let mut id = 0;
-
-    let count = unsafe {
-      gl::GetDebugMessageLog(1, buffer.len() as GLsizei, &mut source, &mut mtype, &mut id, &mut severity, &mut message_size, buffer.as_mut_ptr() as * mut GLchar)
-    };
-    if count == 0 {
-      break;
-    }
 
-    buffer.truncate(message_size as usize);
-    let message = match str::from_utf8(&buffer) {
-      Ok(m) => m,
-      Err(e) => panic!("Go invalid message: {}", e)
-    };
-    let source = DebugSource::from_raw(source);
-    let sevirity = DebugSrverity::from_raw(severity);
-    let mtype = DebugType::from_raw(mtype);
-
-    if severity.is_fatal() {
-      fatal = true;
+  fn load(&mut self, r: &mut StateReader) -> Result<(), Error> {
+    let mut data = Vec::<u8>::new();
+    data.load(r)?;
+    match self {
+      Some(renderer) if !data.is_empty() => renderer.load_state(&data),
+      _ => Ok(()),
     }
   }
-  if fatal {
-    panic!("Fatal OpenGL error");
-  }
 }
-*/
 
-pub struct Renderer {
-  video_subsystem: sdl2::VideoSubsystem,
-  window: sdl2::video::Window,
-  gl_context: sdl2::video::GLContext,
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Position(pub i16, pub i16);
 
-  vertex_shader: GLuint,
-  fragment_shader: GLuint,
-  program: GLuint,
-  positions: Vec<Position>,
-  colors: Vec<Color>,
-  nvertices: u32,
+impl Position {
+  pub fn from_gp0(val: u32) -> Self {
+    let x = val as i16;
+    let y = (val >> 16) as i16;
 
-  uniform_offset: GLint,
+    Self(x, y)
+  }
 }
 
-impl Renderer {
-  pub fn new(video_subsystem: sdl2::VideoSubsystem) -> Self {
-    let gl_attr = video_subsystem.gl_attr();
-    gl_attr.set_context_profile(sdl2::video::GLProfile::Core);
-    gl_attr.set_context_version(3, 3);
-    gl_attr.set_context_flags().debug().set();
-
-    let window = video_subsystem.window("PSX", 1024, 512)
-        .opengl()
-        .position_centered()
-        .build()
-        .unwrap();
-
-    let gl_context = window.gl_create_context().unwrap();
-
-    let _gl = gl::load_with(|s| video_subsystem.gl_get_proc_address(s) as *const std::os::raw::c_void);
-
-    unsafe {
-      // gl::Viewport(0, 0, 1024, 512);
-      gl::ClearColor(0., 0., 0., 1.0);
-      gl::Clear(gl::COLOR_BUFFER_BIT);
-    }
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Color(pub u8, pub u8, pub u8);
 
-    window.gl_swap_window();
+impl Color {
+  pub fn from_gp0(val: u32) -> Self {
+    let r = val as u8;
+    let g = (val >> 8) as u8;
+    let b = (val >> 16) as u8;
+    Self(r, g, b)
+  }
+}
 
-    let vs_src = include_str!("shader/vertex.glsl");
-    let fs_src = include_str!("shader/fragment.glsl");
+// 表示中の画像 (ピクセルは 0x00RRGGBB)
+pub struct Framebuffer {
+  pub width: u32,
+  pub height: u32,
+  pub pixels: Vec<u32>,
+}
 
-    let vertex_shader = compile_shader(vs_src, gl::VERTEX_SHADER);
-    let fragment_shader = compile_shader(fs_src, gl::FRAGMENT_SHADER);
+pub const VRAM_WIDTH: usize = 1024;
+pub const VRAM_HEIGHT: usize = 512;
 
-    let program = link_program(&[vertex_shader, fragment_shader]);
+// 1024x512 の VRAM (15bit BGR) に描くソフトウェアレンダラ
+pub struct SoftwareRenderer {
+  // 内部解像度の倍率 (VRAM を縦横 scale 倍の大きさで持つ)
+  scale: usize,
+  vram: Vec<u16>,
+  offset: (i16, i16),
+}
 
-    let positions = vec![Position(0, 0); VERTEX_BUFFER_LEN as usize];
-    let colors = vec![Color(0, 0, 0); VERTEX_BUFFER_LEN as usize];
+impl Default for SoftwareRenderer {
+  fn default() -> Self {
+    Self::new()
+  }
+}
 
-    let uniform_offset = find_program_uniform(program, "offset");
-    unsafe {
-      gl::Uniform2i(uniform_offset, 0, 0);
-    }
+impl SoftwareRenderer {
+  pub fn new() -> Self {
+    Self::with_scale(1)
+  }
 
+  pub fn with_scale(scale: usize) -> Self {
     Self {
-      video_subsystem,
-      window,
-      gl_context,
-      vertex_shader,
-      fragment_shader,
-      program,
-      positions,
-      colors,
-      nvertices: 0,
-      uniform_offset,
+      scale,
+      vram: vec![0; VRAM_WIDTH * scale * VRAM_HEIGHT * scale],
+      offset: (0, 0),
     }
   }
 
-  pub fn push_triangle(&mut self, positions: [Position; 3], colors: [Color; 3]) {
-    if self.nvertices + 3 > VERTEX_BUFFER_LEN {
-      println!("Vertex attrivute buffers full, forcing draw");
-      self.draw();
-    }
-    for i in 0..3 {
-      self.positions[self.nvertices as usize] = positions[i];
-      self.colors[self.nvertices as usize] = colors[i];
-      self.nvertices = self.nvertices + 1;
-    }
+  fn width(&self) -> usize {
+    VRAM_WIDTH * self.scale
   }
 
-  pub fn push_quad(&mut self, positions: [Position; 4], colors: [Color; 4]) {
-    if self.nvertices + 6 > VERTEX_BUFFER_LEN {
-      self.draw();
-    }
-
-    for i in 0..3 {
-      self.positions[self.nvertices as usize] = positions[i];
-      self.colors[self.nvertices as usize] = colors[i];
-      self.nvertices = self.nvertices + 1;
-    }
-
-    for i in 1..4 {
-      self.positions[self.nvertices as usize] = positions[i];
-      self.colors[self.nvertices as usize] = colors[i];
-      self.nvertices = self.nvertices + 1;
-    }
+  fn height(&self) -> usize {
+    VRAM_HEIGHT * self.scale
   }
 
-  pub fn draw(&mut self) {
-    let mut position_vbo: gl::types::GLuint = 0;
-    unsafe {
-        gl::GenBuffers(1, &mut position_vbo);
-        gl::BindBuffer(gl::ARRAY_BUFFER, position_vbo);
-        gl::BufferData(
-            gl::ARRAY_BUFFER,                                                       // target
-            (self.nvertices as usize * std::mem::size_of::<Position>()) as gl::types::GLsizeiptr, // size of data in bytes
-            self.positions.as_ptr() as *const gl::types::GLvoid, // pointer to data
-            gl::STATIC_DRAW,                               // usage
-        );
-        gl::BindBuffer(gl::ARRAY_BUFFER, 0);
-    }
-
-    let mut color_vbo: gl::types::GLuint = 0;
-    unsafe {
-        gl::GenBuffers(1, &mut color_vbo);
-        gl::BindBuffer(gl::ARRAY_BUFFER, color_vbo);
-        gl::BufferData(
-            gl::ARRAY_BUFFER,                                                       // target
-            (self.nvertices as usize * std::mem::size_of::<Color>()) as gl::types::GLsizeiptr, // size of data in bytes
-            self.colors.as_ptr() as *const gl::types::GLvoid, // pointer to data
-            gl::STATIC_DRAW,                               // usage
-        );
-        gl::BindBuffer(gl::ARRAY_BUFFER, 0);
-    }
-
-    let mut vao = 0;
-    unsafe {
-      gl::GenVertexArrays(1, &mut vao);
-      gl::BindVertexArray(vao);
-    }
-
-    unsafe {
-      gl::BindBuffer(gl::ARRAY_BUFFER, position_vbo);
-      let index = find_program_attrib(self.program, "vertex_position");
-      gl::EnableVertexAttribArray(index);
-      gl::VertexAttribIPointer(index, 2, gl::SHORT, 0, ptr::null());
-    }
-
-    unsafe {
-      gl::BindBuffer(gl::ARRAY_BUFFER, color_vbo);
-      let index = find_program_attrib(self.program, "vertex_color");
-      gl::EnableVertexAttribArray(index);
-      gl::VertexAttribIPointer(index, 3, gl::UNSIGNED_BYTE, 0, ptr::null());
-    }
-
-    unsafe {
-      gl::BindBuffer(gl::ARRAY_BUFFER, 0);
-      gl::BindVertexArray(0);
-    }
-
-    unsafe {
-      gl::UseProgram(self.program);
-    }
-
-    unsafe {
-      gl::BindVertexArray(vao);
-      gl::DrawArrays(gl::TRIANGLES, 0, self.nvertices as GLsizei);
-    }
-
-    unsafe {
-      let sync = gl::FenceSync(gl::SYNC_GPU_COMMANDS_COMPLETE, 0);
-      loop {
-        let r = gl::ClientWaitSync(
-          sync,
-          gl::SYNC_FLUSH_COMMANDS_BIT,
-          10000000
-        );
-        if r == gl::ALREADY_SIGNALED || r == gl::CONDITION_SATISFIED {
-          break;
+  fn draw_triangle(&mut self, positions: [Position; 3], colors: [Color; 3]) {
+    let v = positions.map(|p| (p.0 as i32 + self.offset.0 as i32, p.1 as i32 + self.offset.1 as i32));
+    let area = edge(v[0], v[1], v[2]);
+    if area == 0 {
+      return;
+    }
+
+    let min_x = v.iter().map(|p| p.0).min().unwrap();
+    let max_x = v.iter().map(|p| p.0).max().unwrap();
+    let min_y = v.iter().map(|p| p.1).min().unwrap();
+    let max_y = v.iter().map(|p| p.1).max().unwrap();
+    // 実機は幅 1024 / 高さ 512 以上のポリゴンを描かない
+    if max_x - min_x >= VRAM_WIDTH as i32 || max_y - min_y >= VRAM_HEIGHT as i32 {
+      return;
+    }
+
+    // 内部解像度の座標で描く
+    let scale = self.scale as i32;
+    let v = v.map(|p| (p.0 * scale, p.1 * scale));
+    let area = area * scale * scale;
+    let (min_x, max_x, min_y, max_y) = (min_x * scale, max_x * scale, min_y * scale, max_y * scale);
+
+    // 右端と下端のピクセルは描かない
+    let width = self.width();
+    for y in min_y.max(0)..max_y.min(self.height() as i32) {
+      for x in min_x.max(0)..max_x.min(width as i32) {
+        let p = (x, y);
+        let w = [edge(v[1], v[2], p), edge(v[2], v[0], p), edge(v[0], v[1], p)];
+        let inside = match area > 0 {
+          true => w.iter().all(|&w| w >= 0),
+          false => w.iter().all(|&w| w <= 0),
+        };
+        if !inside {
+          continue;
         }
+        // 内部解像度が高いと重みと色の積が i32 に収まらない
+        let mix = |c: [u8; 3]| ((w[0] as i64 * c[0] as i64 + w[1] as i64 * c[1] as i64 + w[2] as i64 * c[2] as i64) / area as i64) as i32;
+        let r = mix(colors.map(|c| c.0));
+        let g = mix(colors.map(|c| c.1));
+        let b = mix(colors.map(|c| c.2));
+        self.vram[y as usize * width + x as usize] = to_bgr555(r, g, b);
       }
     }
-    self.nvertices = 0;
   }
+}
 
-  pub fn set_draw_offset(&mut self, x: i16, y: i16) {
-    self.draw();
-    unsafe {
-      gl::Uniform2i(self.uniform_offset, x as GLint, y as GLint);
-    }
+impl Renderer for SoftwareRenderer {
+  fn push_triangle(&mut self, positions: [Position; 3], colors: [Color; 3]) {
+    self.draw_triangle(positions, colors);
   }
 
-  pub fn display(&mut self) {
-    self.draw();
-    self.window.gl_swap_window();
+  fn push_quad(&mut self, positions: [Position; 4], colors: [Color; 4]) {
+    self.draw_triangle([positions[0], positions[1], positions[2]], [colors[0], colors[1], colors[2]]);
+    self.draw_triangle([positions[1], positions[2], positions[3]], [colors[1], colors[2], colors[3]]);
   }
-}
 
-impl Drop for Renderer {
-  fn drop(&mut self) {
-      unsafe {
-        gl::DeleteShader(self.vertex_shader);
-        gl::DeleteShader(self.fragment_shader);
-        gl::DeleteProgram(self.program);
-      }
+  fn set_draw_offset(&mut self, x: i16, y: i16) {
+    self.offset = (x, y);
   }
-}
 
-#[derive(Copy, Clone, Default, Debug)]
-pub struct Position(pub GLshort, pub GLshort);
+  fn display(&mut self) {}
+
+  // 座標は VRAM のピクセル単位。画像は内部解像度の大きさになる
+  fn framebuffer(&self, x: u16, y: u16, width: u16, height: u16) -> Option<Framebuffer> {
+    let (x, y) = (x as usize * self.scale, y as usize * self.scale);
+    let (width, height) = (width as usize * self.scale, height as usize * self.scale);
+    let mut pixels = Vec::with_capacity(width * height);
+    for row in 0..height {
+      // 表示領域は VRAM の端で折り返す
+      let vy = (y + row) % self.height();
+      for column in 0..width {
+        let vx = (x + column) % self.width();
+        pixels.push(to_rgb888(self.vram[vy * self.width() + vx]));
+      }
+    }
+    Some(Framebuffer { width: width as u32, height: height as u32, pixels })
+  }
 
-impl Position {
-  pub fn from_gp0(val: u32) -> Self {
-    let x = val as i16;
-    let y = (val >> 16) as i16;
+  fn save_state(&self) -> Vec<u8> {
+    let mut w = StateWriter::new();
+    self.scale.save(&mut w);
+    self.vram.save(&mut w);
+    self.offset.save(&mut w);
+    w.finish()
+  }
 
-    Self(x as GLshort, y as GLshort)
+  // 倍率の違う VRAM は今の倍率に合わせて拡大/縮小する
+  fn load_state(&mut self, data: &[u8]) -> Result<(), Error> {
+    let mut r = StateReader::new(data);
+    let scale: usize = r.value()?;
+    let vram: Vec<u16> = r.value()?;
+    let offset = r.value()?;
+    if scale == 0 || vram.len() != VRAM_WIDTH * scale * VRAM_HEIGHT * scale {
+      return Err(invalid_state("Invalid VRAM size"));
+    }
+    let width = self.width();
+    for (y, line) in self.vram.chunks_exact_mut(width).enumerate() {
+      let src = y * scale / self.scale * VRAM_WIDTH * scale;
+      for (x, pixel) in line.iter_mut().enumerate() {
+        *pixel = vram[src + x * scale / self.scale];
+      }
+    }
+    self.offset = offset;
+    Ok(())
   }
 }
 
-#[derive(Copy, Clone, Default, Debug)]
-pub struct Color(pub GLubyte, pub GLubyte, pub GLubyte);
+// a→b の辺に対して p がどちら側にあるか (三角形の面積の2倍)
+fn edge(a: (i32, i32), b: (i32, i32), p: (i32, i32)) -> i32 {
+  (b.0 - a.0) * (p.1 - a.1) - (b.1 - a.1) * (p.0 - a.0)
+}
 
-impl Color {
-  pub fn from_gp0(val: u32) -> Self {
-    let r = val as u8;
-    let g = (val >> 8) as u8;
-    let b = (val >> 16) as u8;
-    Self(r as GLubyte, g as GLubyte, b as GLubyte)
-  }
+fn to_bgr555(r: i32, g: i32, b: i32) -> u16 {
+  ((r as u16 >> 3) & 0x1F) | (((g as u16 >> 3) & 0x1F) << 5) | (((b as u16 >> 3) & 0x1F) << 10)
 }
 
-const VERTEX_BUFFER_LEN: u32 = 64 * 1024;
+fn to_rgb888(pixel: u16) -> u32 {
+  let r = (pixel & 0x1F) as u32;
+  let g = ((pixel >> 5) & 0x1F) as u32;
+  let b = ((pixel >> 10) & 0x1F) as u32;
+  // 下位ビットに上位ビットを複製して 0x1F を 0xFF に広げる
+  let expand = |c: u32| (c << 3) | (c >> 2);
+  (expand(r) << 16) | (expand(g) << 8) | expand(b)
+}
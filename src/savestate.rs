@@ -0,0 +1,256 @@
+use std::{collections::VecDeque, io::{Error, ErrorKind}};
+
+// セーブステートの先頭
+pub const MAGIC: &[u8; 8] = b"PSXSTATE";
+// 保存する内容を変えたら上げる
+pub const VERSION: u32 = 1;
+
+// マシンの状態をバイト列に書き出す/読み込む。
+// ホスト側のもの (ファイル、出力先、レンダラのウィンドウ、命令のキャッシュなど) は含めない
+pub trait State {
+  fn save(&self, w: &mut StateWriter);
+  fn load(&mut self, r: &mut StateReader) -> Result<(), Error>;
+}
+
+// 値はリトルエンディアンで並べる
+#[derive(Default)]
+pub struct StateWriter {
+  data: Vec<u8>,
+}
+
+impl StateWriter {
+  pub fn new() -> Self {
+    Self { data: Vec::new() }
+  }
+
+  pub fn write(&mut self, bytes: &[u8]) {
+    self.data.extend_from_slice(bytes);
+  }
+
+  pub fn finish(self) -> Vec<u8> {
+    self.data
+  }
+}
+
+pub struct StateReader<'a> {
+  data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+  pub fn new(data: &'a [u8]) -> Self {
+    Self { data }
+  }
+
+  pub fn read(&mut self, len: usize) -> Result<&'a [u8], Error> {
+    if self.data.len() < len {
+      return Err(invalid_state("Truncated savestate"));
+    }
+    let (bytes, rest) = self.data.split_at(len);
+    self.data = rest;
+    Ok(bytes)
+  }
+
+  fn read_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+    Ok(self.read(N)?.try_into().unwrap())
+  }
+
+  // 値を1つ読む (列挙型のフィールドなど、読み込む先がまだないとき用)
+  pub fn value<T: State + Default>(&mut self) -> Result<T, Error> {
+    let mut val = T::default();
+    val.load(self)?;
+    Ok(val)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.data.is_empty()
+  }
+}
+
+pub fn invalid_state(message: &str) -> Error {
+  Error::new(ErrorKind::InvalidData, message)
+}
+
+// フィールドを並べた順に保存する
+macro_rules! impl_state {
+  ($type:ty { $($field:tt),* $(,)? }) => {
+    impl_state!($type { $($field),* } => |_| Ok(()));
+  };
+  // 読み込んだ後に after を呼ぶ (保存しないフィールドを作り直す、読み込んだ値を検証するなど)
+  ($type:ty { $($field:tt),* $(,)? } => $after:expr) => {
+    impl $crate::savestate::State for $type {
+      fn save(&self, w: &mut $crate::savestate::StateWriter) {
+        $( $crate::savestate::State::save(&self.$field, w); )*
+      }
+
+      fn load(&mut self, r: &mut $crate::savestate::StateReader) -> Result<(), std::io::Error> {
+        $( $crate::savestate::State::load(&mut self.$field, r)?; )*
+        let after: fn(&mut Self) -> Result<(), std::io::Error> = $after;
+        after(self)
+      }
+    }
+  };
+}
+
+// フィールドを持たない列挙型は並べた順の番号で保存する
+macro_rules! impl_state_enum {
+  ($type:ident { $($variant:ident),* $(,)? }) => {
+    impl $crate::savestate::State for $type {
+      fn save(&self, w: &mut $crate::savestate::StateWriter) {
+        let variants = [$($type::$variant),*];
+        let index = variants.iter().position(|v| std::mem::discriminant(v) == std::mem::discriminant(self)).unwrap();
+        $crate::savestate::State::save(&(index as u8), w);
+      }
+
+      fn load(&mut self, r: &mut $crate::savestate::StateReader) -> Result<(), std::io::Error> {
+        let variants = [$($type::$variant),*];
+        let mut index = 0u8;
+        $crate::savestate::State::load(&mut index, r)?;
+        *self = *variants.get(index as usize)
+            .ok_or_else(|| $crate::savestate::invalid_state(concat!("Invalid ", stringify!($type))))?;
+        Ok(())
+      }
+    }
+  };
+}
+
+macro_rules! impl_state_int {
+  ($($type:ty),*) => {
+    $(
+      impl State for $type {
+        fn save(&self, w: &mut StateWriter) {
+          w.write(&self.to_le_bytes());
+        }
+
+        fn load(&mut self, r: &mut StateReader) -> Result<(), Error> {
+          *self = <$type>::from_le_bytes(r.read_array()?);
+          Ok(())
+        }
+      }
+    )*
+  };
+}
+
+impl_state_int!(u8, u16, u32, u64, i16, i32);
+
+// ホストによって幅が変わらないように 64 ビットで保存する
+impl State for usize {
+  fn save(&self, w: &mut StateWriter) {
+    (*self as u64).save(w);
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), Error> {
+    let mut val = 0u64;
+    val.load(r)?;
+    *self = usize::try_from(val).map_err(|_| invalid_state("Value out of range"))?;
+    Ok(())
+  }
+}
+
+impl State for bool {
+  fn save(&self, w: &mut StateWriter) {
+    (*self as u8).save(w);
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), Error> {
+    let mut val = 0u8;
+    val.load(r)?;
+    *self = val != 0;
+    Ok(())
+  }
+}
+
+impl<T: State, const N: usize> State for [T; N] {
+  fn save(&self, w: &mut StateWriter) {
+    for item in self {
+      item.save(w);
+    }
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), Error> {
+    for item in self {
+      item.load(r)?;
+    }
+    Ok(())
+  }
+}
+
+impl<A: State, B: State> State for (A, B) {
+  fn save(&self, w: &mut StateWriter) {
+    self.0.save(w);
+    self.1.save(w);
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), Error> {
+    self.0.load(r)?;
+    self.1.load(r)
+  }
+}
+
+// 長さを先に書く。読み込むときは保存したときの長さに合わせる
+impl<T: State + Default> State for Vec<T> {
+  fn save(&self, w: &mut StateWriter) {
+    self.len().save(w);
+    for item in self {
+      item.save(w);
+    }
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), Error> {
+    let mut len = 0usize;
+    len.load(r)?;
+    if len > r.data.len() {
+      return Err(invalid_state("Truncated savestate"));
+    }
+    self.clear();
+    self.resize_with(len, T::default);
+    for item in self.iter_mut() {
+      item.load(r)?;
+    }
+    Ok(())
+  }
+}
+
+impl<T: State + Default> State for VecDeque<T> {
+  fn save(&self, w: &mut StateWriter) {
+    self.len().save(w);
+    for item in self {
+      item.save(w);
+    }
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), Error> {
+    let mut len = 0usize;
+    len.load(r)?;
+    if len > r.data.len() {
+      return Err(invalid_state("Truncated savestate"));
+    }
+    self.clear();
+    self.resize_with(len, T::default);
+    for item in self.iter_mut() {
+      item.load(r)?;
+    }
+    Ok(())
+  }
+}
+
+impl<T: State + Default> State for Option<T> {
+  fn save(&self, w: &mut StateWriter) {
+    self.is_some().save(w);
+    if let Some(val) = self {
+      val.save(w);
+    }
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), Error> {
+    let mut some = false;
+    some.load(r)?;
+    match some {
+      // 今ある値に読み込む (保存しないフィールドを残すため)
+      true => self.get_or_insert_with(T::default).load(r),
+      false => {
+        *self = None;
+        Ok(())
+      }
+    }
+  }
+}
@@ -0,0 +1,64 @@
+// データキャッシュをスクラッチパッドとして使う 1KB の高速RAM
+pub struct ScratchPad {
+  data: [u8; SCRATCHPAD_SIZE],
+}
+
+pub const SCRATCHPAD_SIZE: usize = 1024;
+
+impl_state!(ScratchPad { data });
+
+impl Default for ScratchPad {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl ScratchPad {
+  pub fn new() -> Self {
+    Self { data: [0; SCRATCHPAD_SIZE] }
+  }
+
+  pub fn load32(&self, offset: u32) -> u32 {
+    let offset = offset as usize;
+
+    let b0 = self.data[offset] as u32;
+    let b1 = self.data[offset + 1] as u32;
+    let b2 = self.data[offset + 2] as u32;
+    let b3 = self.data[offset + 3] as u32;
+
+    b0 | (b1 << 8) | (b2 << 16) | (b3 << 24)
+  }
+
+  pub fn store32(&mut self, offset: u32, val: u32) {
+    let offset = offset as usize;
+
+    self.data[offset] = val as u8;
+    self.data[offset + 1] = (val >> 8) as u8;
+    self.data[offset + 2] = (val >> 16) as u8;
+    self.data[offset + 3] = (val >> 24) as u8;
+  }
+
+  pub fn load16(&self, offset: u32) -> u16 {
+    let offset = offset as usize;
+
+    let b0 = self.data[offset] as u16;
+    let b1 = self.data[offset + 1] as u16;
+
+    b0 | (b1 << 8)
+  }
+
+  pub fn store16(&mut self, offset: u32, val: u16) {
+    let offset = offset as usize;
+
+    self.data[offset] = val as u8;
+    self.data[offset + 1] = (val >> 8) as u8;
+  }
+
+  pub fn load8(&self, offset: u32) -> u8 {
+    self.data[offset as usize]
+  }
+
+  pub fn store8(&mut self, offset: u32, val: u8) {
+    self.data[offset as usize] = val
+  }
+}
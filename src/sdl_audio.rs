@@ -0,0 +1,73 @@
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+
+use ps1_boot::audio::{AudioSink, Resampler, SAMPLE_RATE};
+
+// SDL の AudioQueue に一定量ずつまとめて送る。
+// キューに溜まっている量が目標レイテンシに近づくように再生レートを微調整する
+pub struct SdlAudioSink {
+  device: AudioQueue<i16>,
+  resampler: Resampler,
+  buffer: Vec<i16>,
+  chunk_frames: usize,
+  target_frames: u32,
+  max_rate_adjust: f64,
+}
+
+impl SdlAudioSink {
+  pub fn new(audio_subsystem: sdl2::AudioSubsystem, latency_ms: u32) -> Self {
+    let desired_spec = AudioSpecDesired {
+      freq: Some(SAMPLE_RATE as i32),
+      channels: Some(2),
+      samples: None,
+    };
+    let device: AudioQueue<i16> = audio_subsystem
+        .open_queue::<i16, _>(None, &desired_spec)
+        .unwrap();
+    device.resume();
+    let device_rate = device.spec().freq as u32;
+
+    Self {
+      device,
+      resampler: Resampler::new(SAMPLE_RATE, device_rate),
+      buffer: Vec::new(),
+      chunk_frames: 512,
+      target_frames: device_rate * latency_ms / 1000,
+      max_rate_adjust: 0.005,
+    }
+  }
+
+  fn queued_frames(&self) -> u32 {
+    // size() はバイト数 (i16 x 2ch)
+    self.device.size() / 4
+  }
+
+  fn flush(&mut self) {
+    let queued = self.queued_frames();
+    if queued > self.target_frames * 4 {
+      // 大きく遅れているときはまとめて捨てて追いつく
+      self.device.clear();
+    }
+
+    // キューが目標より少なければ少し多めに、多ければ少し少なめに出力する
+    let target = self.target_frames.max(1) as f64;
+    let error = (target - queued as f64) / target;
+    let adjust = error.clamp(-1.0, 1.0) * self.max_rate_adjust;
+    self.resampler.set_rate_adjust(1.0 + adjust);
+
+    self.device.queue_audio(&self.buffer).unwrap();
+    self.buffer.clear();
+  }
+}
+
+impl AudioSink for SdlAudioSink {
+  fn push_sample(&mut self, left: i16, right: i16) {
+    let buffer = &mut self.buffer;
+    self.resampler.push(left, right, |l, r| {
+      buffer.push(l);
+      buffer.push(r);
+    });
+    if self.buffer.len() >= self.chunk_frames * 2 {
+      self.flush();
+    }
+  }
+}
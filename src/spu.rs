@@ -1,6 +1,8 @@
-use std::{cmp, collections::VecDeque};
+use std::{cmp, collections::VecDeque, io::{Error, ErrorKind}};
 
-use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use crate::{audio::{AudioSink, AudioTap}, savestate::invalid_state};
+
+pub const VOICE_COUNT: usize = 24;
 
 fn decode_adpcm_block(block: &[u8], decoded: &mut [i16; 28], old_sample: &mut i16, older_sample: &mut i16) {
 
@@ -38,13 +40,33 @@ fn decode_adpcm_block(block: &[u8], decoded: &mut [i16; 28], old_sample: &mut i1
 }
 
 pub struct Spu {
-  voices: [Voice; 24],
-  device: AudioQueue<i16>,
+  voices: [Voice; VOICE_COUNT],
+  sink: Box<dyn AudioSink>,
+  recorders: Vec<(AudioTap, Box<dyn AudioSink>)>,
+  voice_outputs: [(i16, i16); VOICE_COUNT],
 
-  sound_ram: [u8; 512 * 1024],
+  sound_ram: Vec<u8>,
   sound_ram_start_address: u32,
-  main_volume_l: i16,
-  main_volume_r: i16,
+  transfer_address: u16,
+  transfer_control: u16,
+  transfer_fifo: [u16; 8],
+  transfer_fifo_len: usize,
+  control: u16,
+  noise_timer: i32,
+  noise_level: i16,
+
+  // ボイスごとのビットマスク (ビット0～23)
+  key_on: u32,
+  key_off: u32,
+  key_on_pending: u32,
+  key_off_pending: u32,
+  pitch_mod: u32,
+  noise_on: u32,
+  reverb_on: u32,
+  endx: u32,
+
+  main_volume_l: VolumeSweep,
+  main_volume_r: VolumeSweep,
   write_count: u32, // for debug
 
   reverb_start_address: u32,
@@ -90,25 +112,53 @@ pub struct Spu {
   far_input_r: VecDeque<i16>,
 }
 
+// 出力先と録音はホスト側のものなので残す
+impl_state!(Spu {
+  voices, voice_outputs,
+  sound_ram, sound_ram_start_address, transfer_address, transfer_control,
+  transfer_fifo, transfer_fifo_len, control, noise_timer, noise_level,
+  key_on, key_off, key_on_pending, key_off_pending, pitch_mod, noise_on, reverb_on, endx,
+  main_volume_l, main_volume_r, write_count,
+  reverb_start_address, reverb_write_address,
+  reverb_output_volume_l, reverb_output_volume_r, reverb_input_volume_l, reverb_input_volume_r, reverb_left,
+  mlsame, dlsame, mrsame, drsame, mrdiff, dldiff, mldiff, drdiff,
+  vwall, viir, vcomb1, vcomb2, vcomb3, vcomb4,
+  mlcomb1, mlcomb2, mlcomb3, mlcomb4, mrcomb1, mrcomb2, mrcomb3, mrcomb4,
+  dapf1, dapf2, vapf1, vapf2, mlapf1, mlapf2, mrapf1, mrapf2,
+  far_input_l, far_input_r,
+} => |spu| match spu.transfer_fifo_len < spu.transfer_fifo.len() {
+  // FIFO は埋まったところで書き出すので、8 個溜まったままにはならない
+  true => Ok(()),
+  false => Err(invalid_state("Invalid SPU transfer FIFO length")),
+});
+
 impl Spu {
-  pub fn new(audio_subsystem: sdl2::AudioSubsystem) -> Self {
-    let desired_spec = AudioSpecDesired {
-      freq: Some(44100),
-      channels: Some(2),
-      samples: None,
-    };
-    let device: AudioQueue<i16> = audio_subsystem
-        .open_queue::<i16, _>(None, &desired_spec)
-        .unwrap();
-    device.resume();
+  pub fn new(sink: Box<dyn AudioSink>) -> Self {
     Self {
-      voices: [Voice::new(); 24],
-      device,
-      sound_ram: [0; 512 * 1024],
+      voices: [Voice::new(); VOICE_COUNT],
+      sink,
+      recorders: Vec::new(),
+      voice_outputs: [(0, 0); VOICE_COUNT],
+      sound_ram: vec![0; 512 * 1024],
       sound_ram_start_address: 0x00,
+      transfer_address: 0,
+      transfer_control: 0x0004,
+      transfer_fifo: [0; 8],
+      transfer_fifo_len: 0,
+      control: 0,
+      noise_timer: 0,
+      noise_level: 1,
+      key_on: 0,
+      key_off: 0,
+      key_on_pending: 0,
+      key_off_pending: 0,
+      pitch_mod: 0,
+      noise_on: 0,
+      reverb_on: 0,
+      endx: 0,
       write_count: 0,
-      main_volume_l: 0x7FFF,
-      main_volume_r: 0x7FFF,
+      main_volume_l: VolumeSweep::new(0x7FFF),
+      main_volume_r: VolumeSweep::new(0x7FFF),
       reverb_start_address: 0,
       reverb_write_address: 0,
       reverb_output_volume_l: 0,
@@ -151,15 +201,157 @@ impl Spu {
     }
   }
 
+  pub fn set_sink(&mut self, sink: Box<dyn AudioSink>) {
+    self.sink = sink;
+  }
+
+  // 指定した信号を sink (WavWriter など) に記録する
+  pub fn record(&mut self, tap: AudioTap, recorder: Box<dyn AudioSink>) -> Result<(), Error> {
+    if let AudioTap::Voice(index) = tap {
+      if index >= VOICE_COUNT {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("No such voice: {}", index)));
+      }
+    }
+    self.recorders.push((tap, recorder));
+    Ok(())
+  }
+
+  pub fn stop_recording(&mut self) {
+    self.recorders.clear();
+  }
+
+  // セーブステートを読み込んだ新しい Spu に、出力先と録音を old から移す
+  pub fn take_host(&mut self, old: &mut Spu) {
+    std::mem::swap(&mut self.sink, &mut old.sink);
+    std::mem::swap(&mut self.recorders, &mut old.recorders);
+  }
+
   pub fn load(&self, abs_addr: u32, offset: u32) -> u16 {
     match offset {
+      0x0000..=0x017F => {  // 0x1F801C00..=0x1F801D7F
+        let index = (offset / 0x10) as usize;
+        self.voices[index].load(offset % 0x10)
+      }
+      0x0180 => { // 0x1F801D80 メイン左ボリューム
+        self.main_volume_l.register
+      }
+      0x0182 => { // 0x1F801D82 メイン右ボリューム
+        self.main_volume_r.register
+      }
+      0x0188 => self.key_on as u16,
+      0x018A => (self.key_on >> 16) as u16,
+      0x018C => self.key_off as u16,
+      0x018E => (self.key_off >> 16) as u16,
+      0x0190 => self.pitch_mod as u16,
+      0x0192 => (self.pitch_mod >> 16) as u16,
+      0x0194 => self.noise_on as u16,
+      0x0196 => (self.noise_on >> 16) as u16,
+      0x0198 => self.reverb_on as u16,
+      0x019A => (self.reverb_on >> 16) as u16,
+      0x019C => { // 0x1F801D9C ENDX ボイス0～15 (ループ終端フラグ)
+        self.endx as u16
+      }
+      0x019E => { // 0x1F801D9E ENDX ボイス16～23
+        (self.endx >> 16) as u16
+      }
+      0x01B8 => { // 0x1F801DB8 現在のメイン左ボリューム
+        self.main_volume_l.level() as u16
+      }
+      0x01BA => { // 0x1F801DBA 現在のメイン右ボリューム
+        self.main_volume_r.level() as u16
+      }
+      0x0200..=0x025F => { // 0x1F801E00..=0x1F801E5F 現在のボイス左右ボリューム
+        let voice = &self.voices[((offset - 0x0200) / 4) as usize];
+        match offset % 4 {
+          0 => voice.volume_l.level() as u16,
+          _ => voice.volume_r.level() as u16,
+        }
+      }
+      0x01A6 => { // 0x1F801DA6 サウンドRAMデータ転送アドレス
+        self.transfer_address
+      }
+      0x01AA => { // 0x1F801DAA SPU制御レジスタ (SPUCNT)
+        self.control
+      }
       0x01AC => { // 0x1F801DAC サウンド RAM データ転送制御
-        0x0004
+        self.transfer_control
+      }
+      0x01AE => { // 0x1F801DAE SPUステータスレジスタ (SPUSTAT)
+        self.status()
       }
       _ => 0
     }
   }
 
+  fn status(&self) -> u16 {
+    // 5-0 SPUCNT の下位6ビット
+    // 7   DMA読み書き要求
+    // 8   DMA書き込み要求
+    // 9   DMA読み込み要求
+    // 10  データ転送ビジーフラグ (常に即時完了するので0)
+    let mode = self.transfer_mode();
+    (self.control & 0x3F) |
+      ((mode.dma_request() as u16) << 7) |
+      (((mode == TransferMode::DmaWrite) as u16) << 8) |
+      (((mode == TransferMode::DmaRead) as u16) << 9)
+  }
+
+  fn transfer_mode(&self) -> TransferMode {
+    // 5-4 Sound RAM Transfer Mode (0=Stop, 1=ManualWrite, 2=DMAwrite, 3=DMAread)
+    match (self.control >> 4) & 3 {
+      1 => TransferMode::ManualWrite,
+      2 => TransferMode::DmaWrite,
+      3 => TransferMode::DmaRead,
+      _ => TransferMode::Stop,
+    }
+  }
+
+  // DMA4 のリクエスト信号。SPUCNT が DMA 転送モードの時だけ立つ
+  pub fn dma_request(&self) -> bool {
+    self.transfer_mode().dma_request()
+  }
+
+  // DMA4 (RAM -> SPU) の1ワード書き込み
+  pub fn dma_write(&mut self, val: u32) {
+    self.push_transfer_fifo(val as u16);
+    self.push_transfer_fifo((val >> 16) as u16);
+  }
+
+  // DMA4 (SPU -> RAM) の1ワード読み込み
+  pub fn dma_read(&mut self) -> u32 {
+    let lo = self.loadi16(self.sound_ram_start_address) as u16 as u32;
+    self.sound_ram_start_address = (self.sound_ram_start_address + 2) & 0x7FFFF;
+    let hi = self.loadi16(self.sound_ram_start_address) as u16 as u32;
+    self.sound_ram_start_address = (self.sound_ram_start_address + 2) & 0x7FFFF;
+    lo | (hi << 16)
+  }
+
+  fn push_transfer_fifo(&mut self, val: u16) {
+    self.transfer_fifo[self.transfer_fifo_len] = val;
+    self.transfer_fifo_len += 1;
+    if self.transfer_fifo_len == self.transfer_fifo.len() {
+      self.flush_transfer_fifo();
+    }
+  }
+
+  fn flush_transfer_fifo(&mut self) {
+    // 3-1 Sound RAM Data Transfer Type
+    //     (0,1,6,7=Fill, 2=Normal, 3=Rep2, 4=Rep4, 5=Rep8)
+    let pick: fn(usize) -> usize = match (self.transfer_control >> 1) & 7 {
+      2 => |i| i,
+      3 => |i| i & !1,
+      4 => |i| i & !3,
+      5 => |i| i & !7,
+      _ => |_| 7,
+    };
+    for i in 0..self.transfer_fifo_len {
+      let val = self.transfer_fifo[pick(i).min(self.transfer_fifo_len - 1)];
+      self.store16(self.sound_ram_start_address, val);
+      self.sound_ram_start_address = (self.sound_ram_start_address + 2) & 0x7FFFF;
+    }
+    self.transfer_fifo_len = 0;
+  }
+
   pub fn store(&mut self, abs_addr: u32, offset: u32, val: u16) {
     match offset {
       0x0000..=0x017F => {  // 0x1F801C00..=0x1F801D7F
@@ -168,79 +360,68 @@ impl Spu {
       }
       0x01A6 => { // 0x1F801DA6
         // サウンドRAMデータポート開始アドレス
+        self.transfer_address = val;
         self.sound_ram_start_address = (val as u32) << 3;
+        self.transfer_fifo_len = 0;
         self.write_count = 0;
       }
+      0x01AA => { // 0x1F801DAA SPU制御レジスタ (SPUCNT)
+        self.control = val;
+        if self.transfer_mode() == TransferMode::Stop {
+          self.flush_transfer_fifo();
+        }
+      }
+      0x01AC => { // 0x1F801DAC サウンド RAM データ転送制御
+        self.transfer_control = val;
+      }
       0x01A8 => { // 1F801DA8
         // サウンド RAM データ ポート (16 ビット)
         self.store16(self.sound_ram_start_address, val);
-        self.sound_ram_start_address = self.sound_ram_start_address + 2;
+        self.sound_ram_start_address = (self.sound_ram_start_address + 2) & 0x7FFFF;
         self.write_count = self.write_count + 1;
       }
-      0x0188 => { // 0x1F801D88
-        // キーオンボイス0～15（0=変更なし、1=キーオン）
-        for (i, voice) in self.voices[0..15].iter_mut().enumerate() {
-          let flag = (val & 0x01 << i) != 0;
-          if flag {
-            voice.key_on(&self.sound_ram);
-          }
-        }
+      0x0188 => { // 0x1F801D88 キーオン ボイス0～15（0=変更なし、1=キーオン）
+        set_voice_mask(&mut self.key_on, false, val);
+        self.key_on_pending |= val as u32;
       }
-      0x018A => { // 0x1F801D8A
-        // キーオンボイス16～23のキー
-        for (i, voice) in self.voices[15..].iter_mut().enumerate() {
-          let flag = (val & 0x01 << i) != 0;
-          if flag {
-            voice.key_on(&self.sound_ram);
-          }
-        }
+      0x018A => { // 0x1F801D8A キーオン ボイス16～23
+        set_voice_mask(&mut self.key_on, true, val);
+        self.key_on_pending |= ((val & 0xFF) as u32) << 16;
       }
-      0x018C => { // 0x1F801D8C
-        // キーオフボイス 0-15 (0=変更なし、1=キーオフ)
-        for (i, voice) in self.voices[0..15].iter_mut().enumerate() {
-          let flag = (val & 0x01 << i) != 0;
-          if flag {
-            voice.key_off();
-          }
-        }
+      0x018C => { // 0x1F801D8C キーオフ ボイス0-15 (0=変更なし、1=キーオフ)
+        set_voice_mask(&mut self.key_off, false, val);
+        self.key_off_pending |= val as u32;
       }
-      0x018E => { // 0x1F801D8E
-        // キーオフボイス16-23
-        for (i, voice) in self.voices[15..].iter_mut().enumerate() {
-          let flag = (val & 0x01 << i) != 0;
-          if flag {
-            voice.key_off();
-          }
-        }
+      0x018E => { // 0x1F801D8E キーオフ ボイス16-23
+        set_voice_mask(&mut self.key_off, true, val);
+        self.key_off_pending |= ((val & 0xFF) as u32) << 16;
+      }
+      0x0190 => { // 0x1F801D90 ピッチモジュレーション ボイス1～15 (ボイス0は無効)
+        set_voice_mask(&mut self.pitch_mod, false, val & !1);
+      }
+      0x0192 => { // 0x1F801D92 ピッチモジュレーション ボイス16～23
+        set_voice_mask(&mut self.pitch_mod, true, val);
+      }
+      0x0194 => { // 0x1F801D94 ノイズモード ボイス0～15 (0=ADPCM、1=ノイズ)
+        set_voice_mask(&mut self.noise_on, false, val);
+      }
+      0x0196 => { // 0x1F801D96 ノイズモード ボイス16～23
+        set_voice_mask(&mut self.noise_on, true, val);
       }
       0x0198 => { // 1F801D98 ボイス0～15にリバーブが有効
-        for (i, voice) in self.voices[0..15].iter_mut().enumerate() {
-          let flag = (val & 0x01 << i) != 0;
-          voice.reverb_enabled = flag;
-        }
+        set_voice_mask(&mut self.reverb_on, false, val);
       }
       0x019A => { // 1F801D9A ボイス16～23にリバーブが有効
-        for (i, voice) in self.voices[15..].iter_mut().enumerate() {
-          let flag = (val & 0x01 << i) != 0;
-          voice.reverb_enabled = flag;
-        }
+        set_voice_mask(&mut self.reverb_on, true, val);
+      }
+      0x019C | 0x019E => { // 0x1F801D9C ENDX (読み込み専用)
       }
 
       0x0180 => { // 0x1F801D80 メイン左ボリューム
-        if val & 0x8000 != 0 {
-          // エンベロープ有効
-        } else {
-          // 一定音量
-          self.main_volume_l = (val << 1) as i16;
-        }
+        self.main_volume_l.set(val);
       }
       0x0182 => { // 0x1F801D82 メイン右ボリューム
-        if val & 0x8000 != 0 {
-          // エンベロープ有効
-        } else {
-          // 一定音量
-          self.main_volume_r = (val << 1) as i16;
-        }
+        self.main_volume_r.set(val);
       }
       0x0184 => { // 1F801D84h spu   vLOUT   volume  Reverb Output Volume Left
         self.reverb_output_volume_l = val as i16;
@@ -363,19 +544,44 @@ impl Spu {
     let mut mixed_l = 0;
     let mut mixed_r = 0;
     let mut reverb: i32 = 0;
-    for voice in &mut self.voices {
+    self.apply_key_on_off();
+    self.clock_noise();
+
+    self.main_volume_l.clock();
+    self.main_volume_r.clock();
+
+    // ピッチモジュレーションは1つ前のボイスの出力 (ADSR適用後、音量適用前) を使う
+    let mut prev_output: i16 = 0;
+    for (i, voice) in self.voices.iter_mut().enumerate() {
+      let bit = 1 << i;
+
+      // スイープはキーオンと関係なく毎サンプル進む
+      voice.volume_l.clock();
+      voice.volume_r.clock();
+
       if !voice.keyed_on {
+        self.voice_outputs[i] = (0, 0);
+        prev_output = 0;
         continue;
       }
 
-      voice.clock(&self.sound_ram);
+      let modulator = if self.pitch_mod & bit != 0 { Some(prev_output) } else { None };
+      voice.clock(&self.sound_ram, modulator);
+      if voice.reached_end {
+        voice.reached_end = false;
+        self.endx |= bit;
+      }
+
+      let s = if self.noise_on & bit != 0 { self.noise_level } else { voice.current_sample };
+      let envelope_sample = voice.apply_envelope(s);
+      prev_output = envelope_sample;
 
-      let s = voice.current_sample;
-      let (voice_sample_l, voice_sample_r) = voice.apply_voice_volume(s);
+      let (voice_sample_l, voice_sample_r) = voice.apply_voice_volume(envelope_sample);
+      self.voice_outputs[i] = (voice_sample_l, voice_sample_r);
       mixed_l += i32::from(voice_sample_l);
       mixed_r += i32::from(voice_sample_r);
 
-      if voice.reverb_enabled {
+      if self.reverb_on & bit != 0 {
         reverb += i32::from(if self.reverb_left { voice_sample_l } else { voice_sample_r });
       }
     }
@@ -418,9 +624,59 @@ impl Spu {
     let with_reverb_l = clamped_l + output_reverb_l;
     let with_reverb_r = clamped_r + output_reverb_r;
 
-    let output_l = apply_volume(with_reverb_l, self.main_volume_l);
-    let output_r = apply_volume(with_reverb_r, self.main_volume_r);
-    self.device.queue_audio(&[output_l, output_r]).unwrap()
+    let output_l = apply_volume(with_reverb_l, self.main_volume_l.level());
+    let output_r = apply_volume(with_reverb_r, self.main_volume_r.level());
+    self.sink.push_sample(output_l, output_r);
+
+    for (tap, recorder) in &mut self.recorders {
+      let (l, r) = match *tap {
+        AudioTap::Mixed => (output_l, output_r),
+        AudioTap::Voice(index) => self.voice_outputs[index],
+        AudioTap::Reverb => (output_reverb_l, output_reverb_r),
+      };
+      recorder.push_sample(l, r);
+    }
+  }
+
+  fn apply_key_on_off(&mut self) {
+    // KON/KOFF は書き込み後の次のサンプルでまとめて処理される
+    let key_on = self.key_on_pending;
+    let key_off = self.key_off_pending;
+    self.key_on_pending = 0;
+    self.key_off_pending = 0;
+
+    for (i, voice) in self.voices.iter_mut().enumerate() {
+      let bit = 1 << i;
+      if key_off & bit != 0 {
+        voice.key_off();
+      }
+      if key_on & bit != 0 {
+        self.endx &= !bit;
+        voice.key_on(&self.sound_ram);
+        if voice.reached_end {
+          voice.reached_end = false;
+          self.endx |= bit;
+        }
+      }
+    }
+  }
+
+  fn clock_noise(&mut self) {
+    // 13-10 Noise Frequency Shift (0..0Fh = Low .. High Frequency)
+    // 9-8   Noise Frequency Step  (0..03h = Step "4,5,6,7")
+    let shift = (self.control >> 10) & 0x0F;
+    let step = ((self.control >> 8) & 0x03) + 4;
+
+    self.noise_timer -= i32::from(step);
+    let level = self.noise_level as u16;
+    let parity = ((level >> 15) ^ (level >> 12) ^ (level >> 11) ^ (level >> 10) ^ 1) & 1;
+    if self.noise_timer < 0 {
+      self.noise_level = ((level << 1) | parity) as i16;
+      self.noise_timer += 0x20000 >> shift;
+    }
+    if self.noise_timer < 0 {
+      self.noise_timer += 0x20000 >> shift;
+    }
   }
 
   fn reverb_relative_addr(&self, offset: u32) -> u32 {
@@ -501,6 +757,20 @@ impl Spu {
   }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum TransferMode {
+  Stop,
+  ManualWrite,
+  DmaWrite,
+  DmaRead,
+}
+
+impl TransferMode {
+  fn dma_request(self) -> bool {
+    matches!(self, TransferMode::DmaWrite | TransferMode::DmaRead)
+  }
+}
+
 #[derive(Copy, Clone, Debug)]
 struct Voice {
   start_address: u32,
@@ -508,6 +778,8 @@ struct Voice {
   current_address: u32,
   pitch_counter: u16,
   decode_buffer: [i16; 28],
+  // 前のブロックの最後の3サンプル (ガウス補間用)
+  prev_samples: [i16; 3],
   envelope: AdsrEnvelope,
 
   sample_rate: u16,
@@ -515,20 +787,19 @@ struct Voice {
   current_sample: i16,
 
   keyed_on: bool,
-  volume_l: i16,
-  volume_r: i16,
-
-  enable_sweep_l: bool,
-  enable_sweep_r: bool,
-  sweep_l: u16,
-  sweep_r: u16,
+  reached_end: bool,
+  volume_l: VolumeSweep,
+  volume_r: VolumeSweep,
   // envelope関連
   adsr1: u16,
   adsr2: u16,
-
-  reverb_enabled: bool,
 }
 
+impl_state!(Voice {
+  start_address, repeat_address, current_address, pitch_counter, decode_buffer, prev_samples, envelope,
+  sample_rate, current_buffer_idx, current_sample, keyed_on, reached_end, volume_l, volume_r, adsr1, adsr2,
+});
+
 impl Voice {
   fn new () -> Self {
     Self {
@@ -537,26 +808,22 @@ impl Voice {
       current_address: 0,
       pitch_counter: 0,
       decode_buffer: [0; 28],
+      prev_samples: [0; 3],
       envelope: AdsrEnvelope::new(),
       sample_rate: 0,
       current_buffer_idx: 0,
       current_sample: 0,
-      keyed_on: true,
-      volume_l: 0x7FFF,
-      volume_r: 0x7FFF,
-
-      enable_sweep_l: false,
-      enable_sweep_r: false,
-      sweep_l: 0,
-      sweep_r: 0,
+      keyed_on: false,
+      reached_end: false,
+      volume_l: VolumeSweep::new(0x7FFF),
+      volume_r: VolumeSweep::new(0x7FFF),
 
       adsr1: 0,
       adsr2: 0,
-      reverb_enabled: false,
     }
   }
 
-  fn clock(&mut self, sound_ram: &[u8]) {
+  fn clock(&mut self, sound_ram: &[u8], modulator: Option<i16>) {
     let mut direction = Direction::Increasing;
     let mut rate = ChangeRate::Linear;
     let mut shift: u8 = 0;
@@ -611,7 +878,12 @@ impl Voice {
     // self.envelope.update(direction, rate, shift, step);
     self.envelope.clock(direction, rate, shift, step);
 
-    let pitch_counter_step = cmp::min(0x4000, self.sample_rate);
+    let mut pitch_counter_step = u32::from(self.sample_rate);
+    if let Some(modulator) = modulator {
+      let factor = i32::from(modulator) + 0x8000;
+      pitch_counter_step = (((pitch_counter_step as i32 * factor) >> 15) as u32) & 0xFFFF;
+    }
+    let pitch_counter_step = cmp::min(0x4000, pitch_counter_step) as u16;
     self.pitch_counter = self.pitch_counter + pitch_counter_step;
 
     while self.pitch_counter >= 0x1000 {
@@ -623,25 +895,57 @@ impl Voice {
         self.decode_next_block(sound_ram);
       }
     }
-    self.current_sample = self.decode_buffer[self.current_buffer_idx as usize];
+    self.current_sample = self.interpolate();
+  }
+
+  fn sample_at(&self, idx: isize) -> i16 {
+    if idx < 0 {
+      self.prev_samples[(idx + 3) as usize]
+    } else {
+      self.decode_buffer[idx as usize]
+    }
+  }
+
+  fn interpolate(&self) -> i16 {
+    // ピッチカウンタの小数部 (ビット4-11) でテーブルを引く 4タップのガウス補間
+    let i = ((self.pitch_counter >> 4) & 0xFF) as usize;
+    let idx = self.current_buffer_idx as isize;
+
+    let oldest = i32::from(self.sample_at(idx - 3));
+    let older = i32::from(self.sample_at(idx - 2));
+    let old = i32::from(self.sample_at(idx - 1));
+    let new = i32::from(self.sample_at(idx));
+
+    let mut out = (i32::from(GAUSS_TABLE[0x0FF - i]) * oldest) >> 15;
+    out += (i32::from(GAUSS_TABLE[0x1FF - i]) * older) >> 15;
+    out += (i32::from(GAUSS_TABLE[0x100 + i]) * old) >> 15;
+    out += (i32::from(GAUSS_TABLE[i]) * new) >> 15;
+    out.clamp(-0x8000, 0x7FFF) as i16
   }
 
   fn key_on(&mut self, sound_ram: &[u8]) {
     self.envelope.key_on();
 
+    self.envelope.sustain_level = ((self.adsr1 & 0x000F) + 1) * 0x0800;
+
     self.current_address = self.start_address;
     self.pitch_counter = 0;
+    self.current_buffer_idx = 0;
+    // 前の音の補間用サンプルと ADPCM フィルタの履歴 (バッファの末尾) を残すとプチノイズになる
+    self.decode_buffer.fill(0);
+    self.prev_samples.fill(0);
     self.decode_next_block(sound_ram);
     self.keyed_on = true;
   }
 
   fn key_off(&mut self) {
+    // リリースフェーズの間も音は鳴り続ける
     self.envelope.key_off();
-    self.keyed_on = false;
   }
 
   fn decode_next_block(&mut self, sound_ram: &[u8]) {
     let block = &sound_ram[self.current_address as usize..(self.current_address + 16) as usize];
+    self.prev_samples.copy_from_slice(&self.decode_buffer[25..]);
     let mut old_sample = self.decode_buffer[self.decode_buffer.len() - 1];
     let mut older_sample = self.decode_buffer[self.decode_buffer.len() - 2];
     decode_adpcm_block(
@@ -660,9 +964,10 @@ impl Voice {
 
       if loop_end {
         self.current_address = self.repeat_address;
+        self.reached_end = true;
 
         if !loop_repeat {
-          self.envelope.volume = 0;
+          self.envelope.level = 0;
           self.envelope.key_off();
         }
       } else {
@@ -674,21 +979,11 @@ impl Voice {
     match offset { /* 0x00 ~ 0x0F */
       0x00 => {
         // 左音量
-        self.enable_sweep_l = val & 0x8000 != 0;
-        if self.enable_sweep_l {
-          self.sweep_l = val;
-        } else {
-          self.volume_l = (val << 1) as i16;
-        }
+        self.volume_l.set(val);
       }
       0x02 => {
         // 右音量
-        self.enable_sweep_r = val & 0x8000 != 0;
-        if self.enable_sweep_r {
-          self.sweep_r = val;
-        } else {
-          self.volume_r = (val << 1) as i16;
-        }
+        self.volume_r.set(val);
       }
       0x04 => {
         // ADPCMサンプルレート
@@ -719,14 +1014,39 @@ impl Voice {
     }
   }
 
-  fn apply_voice_volume(&self, adpcm_sample: i16) -> (i16, i16) {
-    let envelope_sample = apply_volume(adpcm_sample, self.envelope.level);
-    let output_l = apply_volume(envelope_sample, self.volume_l);
-    let output_r = apply_volume(envelope_sample, self.volume_r);
+  fn load(&self, offset: u32) -> u16 {
+    match offset { /* 0x00 ~ 0x0F */
+      0x00 => self.volume_l.register,
+      0x02 => self.volume_r.register,
+      0x04 => self.sample_rate,
+      0x06 => (self.start_address >> 3) as u16,
+      0x08 => self.adsr1,
+      0x0A => self.adsr2,
+      0x0C => self.envelope.level as u16, // 現在のADSRボリューム
+      0x0E => (self.repeat_address >> 3) as u16,
+      _ => 0,
+    }
+  }
+
+  fn apply_envelope(&self, sample: i16) -> i16 {
+    apply_volume(sample, self.envelope.level)
+  }
+
+  fn apply_voice_volume(&self, envelope_sample: i16) -> (i16, i16) {
+    let output_l = apply_volume(envelope_sample, self.volume_l.level());
+    let output_r = apply_volume(envelope_sample, self.volume_r.level());
     (output_l, output_r)
   }
 }
 
+fn set_voice_mask(mask: &mut u32, upper: bool, val: u16) {
+  if upper {
+    *mask = (*mask & 0x0000_FFFF) | (((val & 0xFF) as u32) << 16);
+  } else {
+    *mask = (*mask & 0xFFFF_0000) | val as u32;
+  }
+}
+
 fn apply_volume(sample: i16, volume: i16) -> i16 {
   ((i32::from(sample) * i32::from(volume)) >> 15) as i16
 }
@@ -740,6 +1060,8 @@ struct AdsrEnvelope {
   sustain_level: u16,
 }
 
+impl_state!(AdsrEnvelope { volume, level, counter, phase, sustain_level });
+
 impl AdsrEnvelope {
   fn new() -> Self {
     Self {
@@ -751,34 +1073,11 @@ impl AdsrEnvelope {
     }
   }
   fn clock(&mut self, direction: Direction, rate: ChangeRate, shift: u8, step: u8) {
-    let mut counter_decrement = ENVELOPE_CONTER_MAX >> shift.saturating_sub(11);
-
-    if direction == Direction::Increasing && rate == ChangeRate::Exponential && self.level > 0x6000 {
-      counter_decrement >>= 2;
-    }
-
-    self.counter = self.counter.saturating_sub(counter_decrement);
-    if self.counter == 0 {
-      self.counter = ENVELOPE_CONTER_MAX;
-      self.update(direction, rate, shift, step);
+    if clock_envelope(&mut self.level, &mut self.counter, direction, rate, shift, step) {
       self.check_for_phase_transition();
     }
   }
 
-  fn update(&mut self, direction: Direction, rate: ChangeRate, shift: u8, step: u8) {
-    let mut step = i32::from(7 - step);
-    if direction == Direction::Decreasing {
-      step = !step;
-    }
-    step <<= 11_u8.saturating_sub(shift);
-
-    let current_level: i32 = self.level.into();
-    if direction == Direction::Decreasing && rate == ChangeRate::Exponential {
-      step = (step * current_level) >> 15;
-    }
-    self.level = (current_level + step).clamp(0, 0x7FFF) as i16;
-  }
-
   fn key_on(&mut self) {
     self.level = 0;
     self.phase = AdsrPhase::Attack;
@@ -800,6 +1099,93 @@ impl AdsrEnvelope {
 
 }
 
+// ADSR とスイープで共通のレートテーブル。レベルが更新されたら true を返す
+fn clock_envelope(level: &mut i16, counter: &mut u32, direction: Direction, rate: ChangeRate, shift: u8, step: u8) -> bool {
+  let mut counter_decrement = ENVELOPE_CONTER_MAX >> shift.saturating_sub(11);
+
+  if direction == Direction::Increasing && rate == ChangeRate::Exponential && *level > 0x6000 {
+    counter_decrement >>= 2;
+  }
+
+  *counter = counter.saturating_sub(counter_decrement);
+  if *counter != 0 {
+    return false;
+  }
+  *counter = ENVELOPE_CONTER_MAX;
+
+  let mut step = i32::from(7 - step);
+  if direction == Direction::Decreasing {
+    step = !step;
+  }
+  step <<= 11_u8.saturating_sub(shift);
+
+  let current_level: i32 = (*level).into();
+  if direction == Direction::Decreasing && rate == ChangeRate::Exponential {
+    step = (step * current_level) >> 15;
+  }
+  *level = (current_level + step).clamp(0, 0x7FFF) as i16;
+  true
+}
+
+#[derive(Copy, Clone, Debug)]
+struct VolumeSweep {
+  register: u16,
+  level: i16,
+  counter: u32,
+}
+
+impl_state!(VolumeSweep { register, level, counter });
+
+impl VolumeSweep {
+  fn new(level: i16) -> Self {
+    Self {
+      register: 0,
+      level,
+      counter: 0,
+    }
+  }
+
+  fn set(&mut self, val: u16) {
+    self.register = val;
+    if val & 0x8000 == 0 {
+      // 一定音量
+      self.level = (val << 1) as i16;
+    } else {
+      // スイープは現在の音量の大きさから始まる
+      self.level = self.level.saturating_abs();
+      self.counter = 0;
+    }
+  }
+
+  fn sweep_enabled(&self) -> bool {
+    self.register & 0x8000 != 0
+  }
+
+  fn clock(&mut self) {
+    if !self.sweep_enabled() {
+      return;
+    }
+    // 14    Sweep Mode       (0=Linear, 1=Exponential)
+    // 13    Sweep Direction  (0=Increase, 1=Decrease)
+    // 12    Sweep Phase      (0=Positive, 1=Negative)
+    // 6-2   Sweep Shift      (0..1Fh = Fast..Slow)
+    // 1-0   Sweep Step       (0..3 = "+7,+6,+5,+4" or "-8,-7,-6,-5") (inc/dec)
+    let rate = if self.register & 0x4000 == 0 { ChangeRate::Linear } else { ChangeRate::Exponential };
+    let direction = if self.register & 0x2000 == 0 { Direction::Increasing } else { Direction::Decreasing };
+    let shift = ((self.register >> 2) & 0x1F) as u8;
+    let step = (self.register & 0x03) as u8;
+    clock_envelope(&mut self.level, &mut self.counter, direction, rate, shift, step);
+  }
+
+  fn level(&self) -> i16 {
+    if self.sweep_enabled() && self.register & 0x1000 != 0 {
+      -self.level
+    } else {
+      self.level
+    }
+  }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 enum Direction {
   Increasing,
@@ -822,6 +1208,76 @@ enum AdsrPhase {
   Release,
 }
 
+impl_state_enum!(AdsrPhase { Attack, Decay, Sustain, Release });
+
+// ガウス補間テーブル (psx-spx より)
+const GAUSS_TABLE: &[i16; 512] = &[
+  -0x0001, -0x0001, -0x0001, -0x0001, -0x0001, -0x0001, -0x0001, -0x0001,
+  -0x0001, -0x0001, -0x0001, -0x0001, -0x0001, -0x0001, -0x0001, -0x0001,
+   0x0000,  0x0000,  0x0000,  0x0000,  0x0000,  0x0000,  0x0000,  0x0001,
+   0x0001,  0x0001,  0x0001,  0x0002,  0x0002,  0x0002,  0x0003,  0x0003,
+   0x0003,  0x0004,  0x0004,  0x0005,  0x0005,  0x0006,  0x0007,  0x0007,
+   0x0008,  0x0009,  0x0009,  0x000A,  0x000B,  0x000C,  0x000D,  0x000E,
+   0x000F,  0x0010,  0x0011,  0x0012,  0x0013,  0x0015,  0x0016,  0x0018,
+   0x0019,  0x001B,  0x001C,  0x001E,  0x0020,  0x0021,  0x0023,  0x0025,
+   0x0027,  0x0029,  0x002C,  0x002E,  0x0030,  0x0033,  0x0035,  0x0038,
+   0x003A,  0x003D,  0x0040,  0x0043,  0x0046,  0x0049,  0x004D,  0x0050,
+   0x0054,  0x0057,  0x005B,  0x005F,  0x0063,  0x0067,  0x006B,  0x006F,
+   0x0074,  0x0078,  0x007D,  0x0082,  0x0087,  0x008C,  0x0091,  0x0096,
+   0x009C,  0x00A1,  0x00A7,  0x00AD,  0x00B3,  0x00BA,  0x00C0,  0x00C7,
+   0x00CD,  0x00D4,  0x00DB,  0x00E3,  0x00EA,  0x00F2,  0x00FA,  0x0101,
+   0x010A,  0x0112,  0x011B,  0x0123,  0x012C,  0x0135,  0x013F,  0x0148,
+   0x0152,  0x015C,  0x0166,  0x0171,  0x017B,  0x0186,  0x0191,  0x019C,
+   0x01A8,  0x01B4,  0x01C0,  0x01CC,  0x01D9,  0x01E5,  0x01F2,  0x0200,
+   0x020D,  0x021B,  0x0229,  0x0237,  0x0246,  0x0255,  0x0264,  0x0273,
+   0x0283,  0x0293,  0x02A3,  0x02B4,  0x02C4,  0x02D6,  0x02E7,  0x02F9,
+   0x030B,  0x031D,  0x0330,  0x0343,  0x0356,  0x036A,  0x037E,  0x0392,
+   0x03A7,  0x03BC,  0x03D1,  0x03E7,  0x03FC,  0x0413,  0x042A,  0x0441,
+   0x0458,  0x0470,  0x0488,  0x04A0,  0x04B9,  0x04D2,  0x04EC,  0x0506,
+   0x0520,  0x053B,  0x0556,  0x0572,  0x058E,  0x05AA,  0x05C7,  0x05E4,
+   0x0601,  0x061F,  0x063E,  0x065C,  0x067C,  0x069B,  0x06BB,  0x06DC,
+   0x06FD,  0x071E,  0x0740,  0x0762,  0x0784,  0x07A7,  0x07CB,  0x07EF,
+   0x0813,  0x0838,  0x085D,  0x0883,  0x08A9,  0x08D0,  0x08F7,  0x091E,
+   0x0946,  0x096F,  0x0998,  0x09C1,  0x09EB,  0x0A16,  0x0A40,  0x0A6C,
+   0x0A98,  0x0AC4,  0x0AF1,  0x0B1E,  0x0B4C,  0x0B7A,  0x0BA9,  0x0BD8,
+   0x0C07,  0x0C38,  0x0C68,  0x0C99,  0x0CCB,  0x0CFD,  0x0D30,  0x0D63,
+   0x0D97,  0x0DCB,  0x0E00,  0x0E35,  0x0E6B,  0x0EA1,  0x0ED7,  0x0F0F,
+   0x0F46,  0x0F7F,  0x0FB7,  0x0FF1,  0x102A,  0x1065,  0x109F,  0x10DB,
+   0x1116,  0x1153,  0x118F,  0x11CD,  0x120B,  0x1249,  0x1288,  0x12C7,
+   0x1307,  0x1347,  0x1388,  0x13C9,  0x140B,  0x144D,  0x1490,  0x14D4,
+   0x1517,  0x155C,  0x15A0,  0x15E6,  0x162C,  0x1672,  0x16B9,  0x1700,
+   0x1747,  0x1790,  0x17D8,  0x1821,  0x186B,  0x18B5,  0x1900,  0x194B,
+   0x1996,  0x19E2,  0x1A2E,  0x1A7B,  0x1AC8,  0x1B16,  0x1B64,  0x1BB3,
+   0x1C02,  0x1C51,  0x1CA1,  0x1CF1,  0x1D42,  0x1D93,  0x1DE5,  0x1E37,
+   0x1E89,  0x1EDC,  0x1F2F,  0x1F82,  0x1FD6,  0x202A,  0x207F,  0x20D4,
+   0x2129,  0x217F,  0x21D5,  0x222C,  0x2282,  0x22DA,  0x2331,  0x2389,
+   0x23E1,  0x2439,  0x2492,  0x24EB,  0x2545,  0x259E,  0x25F8,  0x2653,
+   0x26AD,  0x2708,  0x2763,  0x27BE,  0x281A,  0x2876,  0x28D2,  0x292E,
+   0x298B,  0x29E7,  0x2A44,  0x2AA1,  0x2AFF,  0x2B5C,  0x2BBA,  0x2C18,
+   0x2C76,  0x2CD4,  0x2D33,  0x2D91,  0x2DF0,  0x2E4F,  0x2EAE,  0x2F0D,
+   0x2F6C,  0x2FCC,  0x302B,  0x308B,  0x30EA,  0x314A,  0x31AA,  0x3209,
+   0x3269,  0x32C9,  0x3329,  0x3389,  0x33E9,  0x3449,  0x34A9,  0x3509,
+   0x3569,  0x35C9,  0x3629,  0x3689,  0x36E8,  0x3748,  0x37A8,  0x3807,
+   0x3867,  0x38C6,  0x3926,  0x3985,  0x39E4,  0x3A43,  0x3AA2,  0x3B00,
+   0x3B5F,  0x3BBD,  0x3C1B,  0x3C79,  0x3CD7,  0x3D34,  0x3D92,  0x3DEF,
+   0x3E4C,  0x3EA8,  0x3F05,  0x3F61,  0x3FBD,  0x4018,  0x4074,  0x40CF,
+   0x4129,  0x4184,  0x41DE,  0x4237,  0x4291,  0x42EA,  0x4342,  0x439A,
+   0x43F2,  0x4449,  0x44A0,  0x44F7,  0x454D,  0x45A3,  0x45F8,  0x464D,
+   0x46A1,  0x46F5,  0x4748,  0x479B,  0x47ED,  0x483F,  0x4890,  0x48E1,
+   0x4931,  0x4981,  0x49D0,  0x4A1E,  0x4A6C,  0x4AB9,  0x4B06,  0x4B52,
+   0x4B9E,  0x4BE9,  0x4C33,  0x4C7D,  0x4CC6,  0x4D0E,  0x4D56,  0x4D9D,
+   0x4DE3,  0x4E29,  0x4E6E,  0x4EB2,  0x4EF5,  0x4F38,  0x4F7A,  0x4FBB,
+   0x4FFB,  0x503B,  0x507A,  0x50B8,  0x50F5,  0x5131,  0x516D,  0x51A8,
+   0x51E2,  0x521B,  0x5253,  0x528A,  0x52C1,  0x52F6,  0x532B,  0x535F,
+   0x5392,  0x53C4,  0x53F5,  0x5425,  0x5454,  0x5483,  0x54B0,  0x54DD,
+   0x5508,  0x5533,  0x555C,  0x5585,  0x55AD,  0x55D3,  0x55F9,  0x561E,
+   0x5642,  0x5665,  0x5687,  0x56A8,  0x56C8,  0x56E7,  0x5705,  0x5722,
+   0x573E,  0x5759,  0x5773,  0x578C,  0x57A4,  0x57BB,  0x57D1,  0x57E6,
+   0x57FA,  0x580D,  0x581F,  0x5830,  0x5840,  0x584F,  0x585D,  0x586A,
+   0x5876,  0x5881,  0x588B,  0x5894,  0x589C,  0x58A3,  0x58A9,  0x58AE,
+   0x58B2,  0x58B5,  0x58B7,  0x58B8,  0x58B8,  0x58B7,  0x58B5,  0x58B2,
+];
+
 const FIR_FILTER: &[i16; 39] = &[
   -0x0001, 0x0000, 0x0002, 0x0000, -0x000A, 0x0000, 0x0023, 0x0000,
   -0x0067, 0x0000, 0x010A, 0x0000, -0x0268, 0x0000, 0x0534, 0x0000,
@@ -0,0 +1,207 @@
+use std::{cell::RefCell, io::{Error, ErrorKind, Write}, rc::Rc};
+
+use crate::{audio::{SampleBuffer, SAMPLE_RATE}, bios::Bios, boot::{self, BootImage, BootMode}, cpu::Cpu, disc::Disc, gpu::Gpu, hle_bios::{HleBios, HleStatus}, interconnect::Interconnect, ram::Ram, renderer::{Color, Framebuffer, Position, Renderer}, savestate::{self, invalid_state, State, StateReader, StateWriter}, spu::Spu};
+
+// 実機の CPU クロックは 33.8688MHz
+pub const CPU_CLOCK: u64 = 33_868_800;
+// SPU は CPU の 768 サイクルごとに1サンプルを出力する
+const CYCLES_PER_SAMPLE: u64 = CPU_CLOCK / SAMPLE_RATE as u64;
+// GPU がフレームを表示しなくても run_frame はこのサイクル数 (1/60 秒) で戻る
+const CYCLES_PER_FRAME: u64 = CPU_CLOCK / 60;
+
+// CPU とバス、各デバイスをまとめたエミュレータ本体。
+// フロントエンドは入力を設定して run_frame を呼び、画像と音声を取り出す
+pub struct System {
+  pub cpu: Cpu,
+  // ファストブートで SHELL_ENTRY に着いたときに起動する EXE
+  pub fast_boot: Option<BootImage>,
+  hle: bool,
+  disc: Option<Disc>,
+  exe: Option<Vec<u8>>,
+  samples: SampleBuffer,
+  // 次に SPU を動かすサイクル
+  next_sample: u64,
+}
+
+impl System {
+  // bios が None のときはカーネルを HLE で動かす。
+  // renderer が None のときは描画しない (ロックステップの比較用など)
+  pub fn new(bios: Option<Bios>, ram_size: usize, renderer: Option<Box<dyn Renderer>>) -> Self {
+    let hle = bios.is_none();
+    let gpu = match renderer {
+      Some(renderer) => Gpu::new(renderer),
+      None => Gpu::headless(),
+    };
+    let samples = SampleBuffer::new();
+    let spu = Spu::new(Box::new(samples.clone()));
+    let inter = Interconnect::new(bios.unwrap_or_else(Bios::hle), Ram::new(ram_size), gpu, spu);
+    Self {
+      cpu: Cpu::new(inter),
+      fast_boot: None,
+      hle,
+      disc: None,
+      exe: None,
+      samples,
+      next_sample: 0,
+    }
+  }
+
+  pub fn hle(&self) -> bool {
+    self.hle
+  }
+
+  // TTY 出力やエミュレータからのメッセージの出力先 (既定では捨てる)
+  pub fn set_console(&mut self, out: Box<dyn Write>) {
+    self.cpu.inter.set_console(out);
+  }
+
+  pub fn load_disc(&mut self, disc: Disc) {
+    self.disc = Some(disc);
+  }
+
+  pub fn load_exe(&mut self, data: Vec<u8>) {
+    self.exe = Some(data);
+  }
+
+  // 読み込んだ EXE (なければディスク) を起動する。
+  // HLE BIOS ではすぐに EXE に入り、BIOS ROM ではファストブートなら SHELL_ENTRY で差し替える
+  pub fn boot(&mut self, mode: BootMode) -> Result<(), Error> {
+    if self.hle {
+      if mode == BootMode::Shell {
+        return Err(Error::new(ErrorKind::InvalidInput, "Booting to the BIOS shell requires a real BIOS image"));
+      }
+      let mut hle = HleBios::new(self.disc.take(), self.cpu.inter.console.clone());
+      hle.boot(&mut self.cpu, self.exe.take())?;
+      self.cpu.set_hle(hle);
+      return Ok(());
+    }
+    match mode {
+      BootMode::Fast => {
+        let image = match (&self.exe, &mut self.disc) {
+          (Some(data), _) => BootImage::from_exe(data)?,
+          (None, Some(disc)) => BootImage::from_disc(disc)?,
+          (None, None) => return Err(Error::new(ErrorKind::InvalidInput, "Fast boot requires an EXE or a disc")),
+        };
+        self.fast_boot = Some(image);
+      }
+      // CD-ROM はまだエミュレートしていないので、BIOS はそのままシェルを起動する
+      BootMode::Normal => {}
+      // ファストブートは仕掛けず、ディスクや EXE が指定されていても BIOS のシェルに入る
+      BootMode::Shell => {}
+    }
+    Ok(())
+  }
+
+  // CPU を1ステップ進め、進んだサイクル分だけ SPU を動かす
+  pub fn step(&mut self) {
+    self.cpu.step();
+    if self.cpu.pc() == boot::SHELL_ENTRY {
+      if let Some(image) = self.fast_boot.take() {
+        image.run(&mut self.cpu);
+      }
+    }
+    while self.cpu.cycles >= self.next_sample {
+      self.cpu.inter.spu.clock();
+      self.next_sample += CYCLES_PER_SAMPLE;
+    }
+  }
+
+  // GPU が次のフレームを表示するまで実行する。HLE BIOS で止まったときはすぐに戻る
+  pub fn run_frame(&mut self) {
+    let end = self.cpu.cycles + CYCLES_PER_FRAME;
+    self.cpu.inter.gpu.frame_updated = false;
+    while self.cpu.cycles < end && !self.cpu.inter.gpu.frame_updated && self.status() == HleStatus::Running {
+      self.step();
+    }
+  }
+
+  // HLE BIOS でゲストが exit を呼んだか、SystemError などで止まったか
+  pub fn status(&self) -> HleStatus {
+    self.cpu.hle_status()
+  }
+
+  // port は 0 (1P) か 1 (2P)。buttons は押されているボタン (pad::Button) のビット
+  pub fn set_input(&mut self, port: usize, buttons: u16) {
+    self.cpu.inter.pad.set_buttons(port, buttons);
+  }
+
+  pub fn framebuffer(&self) -> Option<Framebuffer> {
+    self.cpu.inter.gpu.framebuffer()
+  }
+
+  pub fn vram(&self) -> Option<Framebuffer> {
+    self.cpu.inter.gpu.vram()
+  }
+
+  // 実行中にレンダラ (内部解像度など) を変える。None にすると描画しない
+  pub fn set_renderer(&mut self, renderer: Option<Box<dyn Renderer>>) -> Result<(), Error> {
+    self.cpu.inter.gpu.set_renderer(renderer)
+  }
+
+  // BIOS、ディスク、EXE は含めないので、読み込む側も同じものを読み込んで起動しておく
+  pub fn save_state(&self) -> Vec<u8> {
+    let mut w = StateWriter::new();
+    w.write(savestate::MAGIC);
+    savestate::VERSION.save(&mut w);
+    self.cpu.save(&mut w);
+    self.next_sample.save(&mut w);
+    // ファストブートの EXE はまだ SHELL_ENTRY に着いていなければ残す
+    self.fast_boot.is_some().save(&mut w);
+    w.finish()
+  }
+
+  // 読み込みに失敗しても今の状態を壊さないように、新しく作った System に読み込んでから入れ替える
+  pub fn load_state(&mut self, data: &[u8]) -> Result<(), Error> {
+    let renderer_state = RendererState::default();
+    let mut system = System::new(None, self.cpu.inter.ram_size(), Some(Box::new(renderer_state.clone())));
+    let mut r = StateReader::new(data);
+    if r.read(savestate::MAGIC.len())? != savestate::MAGIC {
+      return Err(invalid_state("Not a savestate"));
+    }
+    if r.value::<u32>()? != savestate::VERSION {
+      return Err(invalid_state("Unsupported savestate version"));
+    }
+    system.cpu.load(&mut r)?;
+    system.next_sample.load(&mut r)?;
+    let keep_fast_boot = r.value::<bool>()?;
+    if !r.is_empty() {
+      return Err(invalid_state("Trailing data in savestate"));
+    }
+    // レンダラは失敗したら何も変えないので、最後に今のレンダラへ読み込む
+    self.cpu.inter.gpu.load_renderer_state(&renderer_state.0.borrow())?;
+
+    system.cpu.take_host(&mut self.cpu);
+    system.hle = self.hle;
+    system.disc = self.disc.take();
+    system.exe = self.exe.take();
+    if keep_fast_boot {
+      system.fast_boot = self.fast_boot.take();
+    }
+    std::mem::swap(&mut system.samples, &mut self.samples);
+    // 保存する前に出力したサンプルは捨てる
+    system.samples.take();
+    *self = system;
+    Ok(())
+  }
+
+  // 前回から SPU が出力したサンプル (44.1kHz, L, R の順)
+  pub fn audio_samples(&mut self) -> Vec<i16> {
+    self.samples.take()
+  }
+}
+
+// セーブステートを読み込むときに、レンダラのまとまりを受け取っておく
+#[derive(Clone, Default)]
+struct RendererState(Rc<RefCell<Vec<u8>>>);
+
+impl Renderer for RendererState {
+  fn push_triangle(&mut self, _positions: [Position; 3], _colors: [Color; 3]) {}
+  fn push_quad(&mut self, _positions: [Position; 4], _colors: [Color; 4]) {}
+  fn set_draw_offset(&mut self, _x: i16, _y: i16) {}
+  fn display(&mut self) {}
+
+  fn load_state(&mut self, data: &[u8]) -> Result<(), Error> {
+    *self.0.borrow_mut() = data.to_vec();
+    Ok(())
+  }
+}
@@ -0,0 +1,392 @@
+// System を通したテスト
+// アセンブルしたプログラムを PS-X EXE にして HLE BIOS で起動する
+use std::{cell::RefCell, io::{self, Write}, rc::Rc};
+
+use crate::{bios::Bios, boot::BootMode, disasm, exe::EXE_HEADER_SIZE, hle_bios::HleStatus, ram, system::System, trace::{TraceFilter, TraceFormat, Tracer}};
+
+const BASE: u32 = 0x8001_0000;
+const MAX_STEPS: usize = 100_000;
+
+fn exe(source: &str) -> Vec<u8> {
+  let words = disasm::assemble(BASE, source).unwrap();
+  let mut data = vec![0; EXE_HEADER_SIZE];
+  data[..8].copy_from_slice(b"PS-X EXE");
+  data[0x10..0x14].copy_from_slice(&BASE.to_le_bytes());
+  data[0x18..0x1C].copy_from_slice(&BASE.to_le_bytes());
+  data[0x1C..0x20].copy_from_slice(&(words.len() as u32 * 4).to_le_bytes());
+  for word in words {
+    data.extend_from_slice(&word.to_le_bytes());
+  }
+  data
+}
+
+fn boot(source: &str) -> System {
+  let mut system = System::new(None, ram::RAM_SIZE_2MB, None);
+  system.load_exe(exe(source));
+  system.boot(BootMode::Fast).unwrap();
+  system
+}
+
+// 止まるまで CPU だけを進める
+fn run_until_stopped(system: &mut System) -> HleStatus {
+  for _ in 0..MAX_STEPS {
+    system.cpu.step();
+    if system.status() != HleStatus::Running {
+      return system.status();
+    }
+  }
+  panic!("Did not stop (pc = {:08X})", system.cpu.pc());
+}
+
+#[test]
+fn hle_exit() {
+  let mut system = boot("
+    li    $a0, 3
+    li    $t1, 0x06
+    li    $t0, 0xA0
+    jalr  $t0
+    nop
+  ");
+  assert_eq!(run_until_stopped(&mut system), HleStatus::Exited(3));
+  // 止まった後は run_frame も進まない
+  let cycles = system.cpu.cycles;
+  system.run_frame();
+  assert_eq!(system.cpu.cycles, cycles);
+  assert_eq!(system.status(), HleStatus::Exited(3));
+
+  let mut system = boot("
+    li    $a0, -1
+    li    $t1, 0x38
+    li    $t0, 0xB0
+    jalr  $t0
+    nop
+  ");
+  assert_eq!(run_until_stopped(&mut system), HleStatus::Exited(-1));
+}
+
+#[test]
+fn hle_system_error() {
+  let mut system = boot("
+    li    $t1, 0xA1
+    li    $t0, 0xA0
+    jalr  $t0
+    nop
+  ");
+  assert_eq!(run_until_stopped(&mut system), HleStatus::Halted);
+}
+
+#[test]
+fn hle_unresolved_exception() {
+  let mut system = boot("
+    break 0
+    nop
+  ");
+  assert_eq!(run_until_stopped(&mut system), HleStatus::Halted);
+  assert_eq!((system.cpu.cause() >> 2) & 0x1F, 0x09);
+  assert_eq!(system.cpu.epc(), BASE);
+}
+
+#[test]
+fn hle_jump_to_unmapped_address() {
+  let mut system = boot("
+    lui   $t0, 0xBFC1
+    ori   $t0, $t0, 0x0F00
+    jr    $t0
+    nop
+  ");
+  assert_eq!(run_until_stopped(&mut system), HleStatus::Halted);
+}
+
+#[test]
+fn hle_memset_is_clamped_to_memory() {
+  // 大きさが 4GB 近くても RAM の終わりまでしか書かない
+  let mut system = boot("
+    lui   $a0, 0x801F
+    ori   $a0, $a0, 0xFFF0
+    li    $a1, 0x5A
+    li    $a2, -1
+    li    $t1, 0x2B
+    li    $t0, 0xA0
+    jalr  $t0
+    nop
+    li    $a0, 0
+    li    $t1, 0x06
+    li    $t0, 0xA0
+    jalr  $t0
+    nop
+  ");
+  assert_eq!(run_until_stopped(&mut system), HleStatus::Exited(0));
+  assert_eq!(system.cpu.inter.load32(0x801F_FFFC), 0x5A5A_5A5A);
+  // 2MB の RAM の終わりで止まり、ミラーの先 (カーネル領域) は書き換えない
+  assert_ne!(system.cpu.inter.load32(0x8000_0080), 0x5A5A_5A5A);
+}
+
+#[test]
+fn hle_nonsense_arguments_do_not_panic() {
+  let mut system = boot("
+    # enable_timer_irq(12) / disable_timer_irq(-1) は何もせず 0 を返す
+    li    $a0, 12
+    li    $t1, 0x04
+    li    $t0, 0xB0
+    jalr  $t0
+    nop
+    move  $s0, $v0
+    li    $a0, -1
+    li    $t1, 0x05
+    li    $t0, 0xB0
+    jalr  $t0
+    nop
+    or    $s0, $s0, $v0
+    # strncpy の長さは RAM の終わりまでに切り詰める
+    lui   $a0, 0x801F
+    ori   $a0, $a0, 0xFFF0
+    lui   $a1, 0x8001
+    li    $a2, -1
+    li    $t1, 0x1A
+    li    $t0, 0xA0
+    jalr  $t0
+    nop
+    # GPU_dw(0, 0, 0xFFFF, 0xFFFF, src) は転送元のメモリの終わりまで
+    addiu $sp, $sp, -24
+    lui   $t2, 0x801F
+    sw    $t2, 16($sp)
+    li    $a0, 0
+    li    $a1, 0
+    ori   $a2, $zero, 0xFFFF
+    ori   $a3, $zero, 0xFFFF
+    li    $t1, 0x46
+    li    $t0, 0xA0
+    jalr  $t0
+    nop
+    addiu $sp, $sp, 24
+    # 終わりが桁あふれするヒープからは確保できない
+    lui   $a0, 0x8010
+    li    $a1, -1
+    li    $t1, 0x39
+    li    $t0, 0xA0
+    jalr  $t0
+    nop
+    li    $a0, 16
+    li    $t1, 0x33
+    li    $t0, 0xA0
+    jalr  $t0
+    nop
+    or    $a0, $s0, $v0
+    li    $t1, 0x06
+    li    $t0, 0xA0
+    jalr  $t0
+    nop
+  ");
+  let mask = system.cpu.inter.load16(0x1F80_1074);
+  assert_eq!(run_until_stopped(&mut system), HleStatus::Exited(0));
+  assert_eq!(system.cpu.inter.load16(0x1F80_1074), mask);
+  assert_eq!(system.cpu.inter.load32(0x801F_FFFC), 0);
+  assert_ne!(system.cpu.inter.load32(0x8000_0080), 0);
+}
+
+#[test]
+fn bios_size_must_match() {
+  assert!(Bios::new(vec![0; 512 * 1024 - 1]).is_err());
+  assert!(Bios::new(vec![0; 512 * 1024 + 1]).is_err());
+  let bios = Bios::new(vec![0; 512 * 1024]).unwrap();
+  assert_eq!(bios.warnings().len(), 1);
+}
+
+#[test]
+fn boot_to_shell() {
+  assert_eq!(BootMode::parse("shell"), Some(BootMode::Shell));
+  // HLE BIOS にはシェルがない
+  let mut system = System::new(None, ram::RAM_SIZE_2MB, None);
+  system.load_exe(exe("nop"));
+  assert!(system.boot(BootMode::Shell).is_err());
+  // BIOS ROM では EXE を指定してもファストブートを仕掛けない
+  let bios = Bios::new(vec![0; 512 * 1024]).unwrap();
+  let mut system = System::new(Some(bios), ram::RAM_SIZE_2MB, None);
+  system.load_exe(exe("nop"));
+  system.boot(BootMode::Shell).unwrap();
+  assert!(system.fast_boot.is_none());
+}
+
+// 書き込まれた内容を後から確かめられる出力先
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.0.borrow_mut().extend_from_slice(buf);
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+#[test]
+fn console_receives_tty_and_messages() {
+  let mut system = boot("
+    li    $a0, 0x68
+    li    $t1, 0x3C
+    li    $t0, 0xA0
+    jalr  $t0
+    nop
+    li    $a0, 0x0A
+    li    $t1, 0x3C
+    li    $t0, 0xA0
+    jalr  $t0
+    nop
+    li    $a0, 0
+    li    $t1, 0x06
+    li    $t0, 0xA0
+    jalr  $t0
+    nop
+  ");
+  let out = SharedBuffer::default();
+  system.set_console(Box::new(out.clone()));
+  let state = system.save_state();
+  assert_eq!(run_until_stopped(&mut system), HleStatus::Exited(0));
+  assert_eq!(&out.0.borrow()[..], b"h\nHLE BIOS: exit(0)\n");
+  // セーブステートを読み込んでも出力先は変わらない
+  system.load_state(&state).unwrap();
+  out.0.borrow_mut().clear();
+  assert_eq!(run_until_stopped(&mut system), HleStatus::Exited(0));
+  assert_eq!(&out.0.borrow()[..], b"h\nHLE BIOS: exit(0)\n");
+}
+
+#[test]
+fn load_state_rejects_invalid_spu_fifo() {
+  let mut system = boot(COUNTER_LOOP);
+  let state = system.save_state();
+  // 0 を書き込むと FIFO の長さだけが変わる
+  system.cpu.inter.spu.dma_write(0);
+  let written = system.save_state();
+  let diff: Vec<usize> = (0..state.len()).filter(|&i| state[i] != written[i]).collect();
+  assert_eq!(diff.len(), 1);
+  let mut corrupt = state.clone();
+  corrupt[diff[0]] = 7;
+  assert!(system.load_state(&corrupt).is_ok());
+  corrupt[diff[0]] = 8;
+  assert!(system.load_state(&corrupt).is_err());
+}
+
+#[test]
+fn load_state_rejects_invalid_hle_thread() {
+  // SetCustomExitFromException で目印を置く。current_thread、int_chains、custom_exit の順に並ぶ
+  const MARKER: u32 = 0x8012_3450;
+  let mut system = boot(&format!("
+    lui   $a0, 0x{:X}
+    ori   $a0, $a0, 0x{:X}
+    li    $t1, 0x19
+    li    $t0, 0xB0
+    jalr  $t0
+    nop
+  end:
+    b     end
+    nop
+  ", MARKER >> 16, MARKER & 0xFFFF));
+  run_steps(&mut system, 100);
+  let state = system.save_state();
+  // 目印はレジスタや RAM にもあるので、最後に保存される HLE BIOS のものを使う
+  let marker = state.windows(4).rposition(|w| w == MARKER.to_le_bytes()).unwrap();
+  let current_thread = marker - 16 - 8;
+  assert_eq!(state[current_thread..current_thread + 8], [0; 8]);
+  // スレッドは起動時のスレッド 0 の1つだけ
+  let mut corrupt = state.clone();
+  corrupt[current_thread] = 1;
+  assert!(system.load_state(&corrupt).is_err());
+  assert!(system.load_state(&state).is_ok());
+}
+
+// 常に書き込みに失敗する出力先
+struct FailingWriter;
+
+impl Write for FailingWriter {
+  fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+    Err(io::Error::other("disk full"))
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Err(io::Error::other("disk full"))
+  }
+}
+
+#[test]
+fn trace_write_error_stops_tracing() {
+  // トレースが書けなくなってもゲストはそのまま最後まで動く
+  let mut system = boot("
+    li    $t2, 2000
+  loop:
+    addiu $t2, $t2, -1
+    bne   $t2, $zero, loop
+    nop
+    li    $a0, 0
+    li    $t1, 0x06
+    li    $t0, 0xA0
+    jalr  $t0
+    nop
+  ");
+  let tracer = Tracer::new(Box::new(FailingWriter), TraceFormat::Text, TraceFilter::new(), disasm::disassemble).unwrap();
+  system.cpu.inter.set_tracer(Some(tracer));
+  assert_eq!(run_until_stopped(&mut system), HleStatus::Exited(0));
+  assert!(!system.cpu.inter.tracing());
+}
+
+const COUNTER_LOOP: &str = "
+    li    $t2, 0
+    lui   $t3, 0x8002
+  loop:
+    addiu $t2, $t2, 1
+    sw    $t2, 0($t3)
+    j     loop
+    nop
+  ";
+
+fn run_steps(system: &mut System, steps: usize) {
+  for _ in 0..steps {
+    system.cpu.step();
+  }
+}
+
+#[test]
+fn load_state_is_atomic() {
+  let mut system = boot(COUNTER_LOOP);
+  run_steps(&mut system, 100);
+  let state = system.save_state();
+  let saved = (system.cpu.pc(), system.cpu.gpr(10), system.cpu.cycles);
+  run_steps(&mut system, 100);
+  let current = (system.cpu.pc(), system.cpu.gpr(10), system.cpu.cycles);
+
+  // 途中で切れたもの、壊れたもの、後ろに余計なデータがあるものは読み込まず、今の状態も変えない
+  let mut trailing = state.clone();
+  trailing.push(0);
+  for data in [&state[..state.len() / 2], &state[..state.len() - 1], &state[1..], &trailing[..]] {
+    assert!(system.load_state(data).is_err());
+    assert_eq!((system.cpu.pc(), system.cpu.gpr(10), system.cpu.cycles), current);
+  }
+  run_steps(&mut system, 100);
+  assert!(system.cpu.gpr(10) > current.1);
+
+  // 保存しないもの (トレーサー) は読み込んだ後も残る
+  let tracer = Tracer::new(Box::new(io::sink()), TraceFormat::Text, TraceFilter::new(), disasm::disassemble).unwrap();
+  system.cpu.inter.set_tracer(Some(tracer));
+  system.load_state(&state).unwrap();
+  assert_eq!((system.cpu.pc(), system.cpu.gpr(10), system.cpu.cycles), saved);
+  assert!(system.cpu.inter.tracing());
+  run_steps(&mut system, 100);
+  assert_eq!((system.cpu.pc(), system.cpu.gpr(10), system.cpu.cycles), current);
+}
+
+#[test]
+fn load_state_rejects_other_ram_size() {
+  let mut system = boot(COUNTER_LOOP);
+  run_steps(&mut system, 100);
+  let state = system.save_state();
+
+  let mut large = System::new(None, ram::RAM_SIZE_8MB, None);
+  large.load_exe(exe(COUNTER_LOOP));
+  large.boot(BootMode::Fast).unwrap();
+  let pc = large.cpu.pc();
+  assert!(large.load_state(&state).is_err());
+  assert_eq!(large.cpu.pc(), pc);
+  assert_eq!(large.cpu.cycles, 0);
+}
@@ -0,0 +1,479 @@
+// 命令単位の実行トレース
+// 命令 (PC, 命令語, 値が変わったレジスタ)、メモリアクセス、DMA 転送、GP0/GP1、SPU レジスタへの書き込みを記録する。
+// トレース同士の比較 (trace-diff) でも使うので、このモジュールは他のモジュールに依存しない
+use std::{fmt, io::{self, BufRead, BufReader, BufWriter, Read, Write}};
+
+// バイナリ形式のファイルの先頭
+const MAGIC: &[u8; 8] = b"PSXTRC01";
+
+// 命令語を逆アセンブルする関数 (pc, 命令語)
+pub type Disassembler = fn(u32, u32) -> String;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+  Text,
+  Binary,
+}
+
+impl TraceFormat {
+  pub fn parse(name: &str) -> Option<Self> {
+    match name {
+      "text" => Some(TraceFormat::Text),
+      "binary" => Some(TraceFormat::Binary),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Subsystem {
+  Cpu,
+  Memory,
+  Dma,
+  Gpu,
+  Spu,
+}
+
+impl Subsystem {
+  pub fn parse(name: &str) -> Option<Self> {
+    match name {
+      "cpu" => Some(Subsystem::Cpu),
+      "mem" | "memory" => Some(Subsystem::Memory),
+      "dma" => Some(Subsystem::Dma),
+      "gpu" => Some(Subsystem::Gpu),
+      "spu" => Some(Subsystem::Spu),
+      _ => None,
+    }
+  }
+
+  fn bit(self) -> u32 {
+    1 << self as u32
+  }
+}
+
+// アクセス先のデバイス (バイナリ形式では番号で記録する)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Device {
+  Ram,
+  Scratchpad,
+  Bios,
+  Expansion1,
+  Expansion2,
+  Expansion3,
+  MemControl,
+  RamSize,
+  CacheControl,
+  PadMemcard,
+  Sio,
+  IrqControl,
+  Dma,
+  Timers,
+  CdRom,
+  Gpu,
+  Spu,
+  Unmapped,
+}
+
+const DEVICES: [Device; 18] = [
+  Device::Ram, Device::Scratchpad, Device::Bios, Device::Expansion1, Device::Expansion2, Device::Expansion3,
+  Device::MemControl, Device::RamSize, Device::CacheControl, Device::PadMemcard, Device::Sio, Device::IrqControl,
+  Device::Dma, Device::Timers, Device::CdRom, Device::Gpu, Device::Spu, Device::Unmapped,
+];
+
+impl Device {
+  pub fn name(self) -> &'static str {
+    match self {
+      Device::Ram => "RAM",
+      Device::Scratchpad => "SCRATCHPAD",
+      Device::Bios => "BIOS",
+      Device::Expansion1 => "EXP1",
+      Device::Expansion2 => "EXP2",
+      Device::Expansion3 => "EXP3",
+      Device::MemControl => "MEMCTRL",
+      Device::RamSize => "RAM_SIZE",
+      Device::CacheControl => "CACHE_CTRL",
+      Device::PadMemcard => "PAD",
+      Device::Sio => "SIO",
+      Device::IrqControl => "IRQ",
+      Device::Dma => "DMA",
+      Device::Timers => "TIMERS",
+      Device::CdRom => "CDROM",
+      Device::Gpu => "GPU",
+      Device::Spu => "SPU",
+      Device::Unmapped => "UNMAPPED",
+    }
+  }
+
+  fn from_index(index: u8) -> Option<Self> {
+    DEVICES.get(index as usize).copied()
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceEvent {
+  // 値が変わったレジスタは (番号, 新しい値)。32 は hi、33 は lo
+  Instruction { pc: u32, opcode: u32, changes: Vec<(u8, u32)> },
+  // width はバイト数
+  Memory { write: bool, addr: u32, width: u8, val: u32, device: Device },
+  // DMA の1回の転送 (ブロックやリンクリストの1ノード単位)
+  Dma { port: u8, to_ram: bool, addr: u32, words: u32 },
+  Gp0(u32),
+  Gp1(u32),
+  // SPU レジスタのオフセット (0x1F80_1C00 から)
+  Spu { offset: u16, val: u16 },
+}
+
+impl TraceEvent {
+  pub fn subsystem(&self) -> Subsystem {
+    match self {
+      TraceEvent::Instruction { .. } => Subsystem::Cpu,
+      TraceEvent::Memory { .. } => Subsystem::Memory,
+      TraceEvent::Dma { .. } => Subsystem::Dma,
+      TraceEvent::Gp0(_) | TraceEvent::Gp1(_) => Subsystem::Gpu,
+      TraceEvent::Spu { .. } => Subsystem::Spu,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+  // 命令の実行を始めたときの CPU のサイクル
+  pub cycle: u64,
+  pub event: TraceEvent,
+}
+
+impl TraceRecord {
+  // テキスト形式の1行。逆アセンブル結果は ';' の後に付ける (trace-diff は比較しない)
+  pub fn to_text(&self, disassemble: Option<Disassembler>) -> String {
+    let mut line = format!("{:>12} {}", self.cycle, self.event);
+    if let (TraceEvent::Instruction { pc, opcode, .. }, Some(disassemble)) = (&self.event, disassemble) {
+      line.push_str(" ; ");
+      line.push_str(&disassemble(*pc, *opcode));
+    }
+    line
+  }
+
+  fn write_binary(&self, out: &mut dyn Write) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(32);
+    let tag = match self.event {
+      TraceEvent::Instruction { .. } => 0,
+      TraceEvent::Memory { .. } => 1,
+      TraceEvent::Dma { .. } => 2,
+      TraceEvent::Gp0(_) => 3,
+      TraceEvent::Gp1(_) => 4,
+      TraceEvent::Spu { .. } => 5,
+    };
+    buf.push(tag);
+    buf.extend_from_slice(&self.cycle.to_le_bytes());
+    match &self.event {
+      TraceEvent::Instruction { pc, opcode, changes } => {
+        buf.extend_from_slice(&pc.to_le_bytes());
+        buf.extend_from_slice(&opcode.to_le_bytes());
+        buf.push(changes.len() as u8);
+        for &(reg, val) in changes {
+          buf.push(reg);
+          buf.extend_from_slice(&val.to_le_bytes());
+        }
+      }
+      &TraceEvent::Memory { write, addr, width, val, device } => {
+        buf.push(width << 1 | write as u8);
+        buf.extend_from_slice(&addr.to_le_bytes());
+        buf.extend_from_slice(&val.to_le_bytes());
+        buf.push(device as u8);
+      }
+      &TraceEvent::Dma { port, to_ram, addr, words } => {
+        buf.push(port);
+        buf.push(to_ram as u8);
+        buf.extend_from_slice(&addr.to_le_bytes());
+        buf.extend_from_slice(&words.to_le_bytes());
+      }
+      TraceEvent::Gp0(val) | TraceEvent::Gp1(val) => buf.extend_from_slice(&val.to_le_bytes()),
+      &TraceEvent::Spu { offset, val } => {
+        buf.extend_from_slice(&offset.to_le_bytes());
+        buf.extend_from_slice(&val.to_le_bytes());
+      }
+    }
+    out.write_all(&buf)
+  }
+
+  // ファイルの終わりでは None
+  fn read_binary(input: &mut dyn Read) -> io::Result<Option<Self>> {
+    let mut tag = [0; 1];
+    if input.read(&mut tag)? == 0 {
+      return Ok(None);
+    }
+    let cycle = read_u64(input)?;
+    let event = match tag[0] {
+      0 => {
+        let pc = read_u32(input)?;
+        let opcode = read_u32(input)?;
+        let count = read_u8(input)?;
+        let mut changes = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+          changes.push((read_u8(input)?, read_u32(input)?));
+        }
+        TraceEvent::Instruction { pc, opcode, changes }
+      }
+      1 => {
+        let flags = read_u8(input)?;
+        let addr = read_u32(input)?;
+        let val = read_u32(input)?;
+        let device = Device::from_index(read_u8(input)?).ok_or_else(|| invalid_data("Invalid device"))?;
+        TraceEvent::Memory { write: flags & 1 != 0, addr, width: flags >> 1, val, device }
+      }
+      2 => TraceEvent::Dma {
+        port: read_u8(input)?,
+        to_ram: read_u8(input)? != 0,
+        addr: read_u32(input)?,
+        words: read_u32(input)?,
+      },
+      3 => TraceEvent::Gp0(read_u32(input)?),
+      4 => TraceEvent::Gp1(read_u32(input)?),
+      5 => TraceEvent::Spu { offset: read_u16(input)?, val: read_u16(input)? },
+      tag => return Err(invalid_data(&format!("Invalid record tag: {}", tag))),
+    };
+    Ok(Some(Self { cycle, event }))
+  }
+}
+
+impl fmt::Display for TraceEvent {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      TraceEvent::Instruction { pc, opcode, changes } => {
+        write!(f, "CPU {:08X} {:08X}", pc, opcode)?;
+        for &(reg, val) in changes {
+          match reg {
+            32 => write!(f, " hi={:08X}", val)?,
+            33 => write!(f, " lo={:08X}", val)?,
+            _ => write!(f, " r{}={:08X}", reg, val)?,
+          }
+        }
+        Ok(())
+      }
+      TraceEvent::Memory { write, addr, width, val, device } => {
+        let dir = match write { true => 'W', false => 'R' };
+        write!(f, "MEM {}{} {:08X} {:0w$X} {}", dir, width * 8, addr, val, device.name(), w = *width as usize * 2)
+      }
+      TraceEvent::Dma { port, to_ram, addr, words } => {
+        let dir = match to_ram { true => "to RAM", false => "from RAM" };
+        write!(f, "DMA{} {} {:08X} {} words", port, dir, addr, words)
+      }
+      TraceEvent::Gp0(val) => write!(f, "GP0 {:08X}", val),
+      TraceEvent::Gp1(val) => write!(f, "GP1 {:08X}", val),
+      TraceEvent::Spu { offset, val } => write!(f, "SPU {:03X} {:04X}", offset, val),
+    }
+  }
+}
+
+// どの命令/フレーム/サブシステムをトレースするか
+pub struct TraceFilter {
+  // PC の範囲 (両端を含む)。命令の中で起きたアクセスもその命令の PC で判定する
+  address: Option<(u32, u32)>,
+  // フレームの範囲 (両端を含む)
+  frames: Option<(u64, u64)>,
+  subsystems: u32,
+}
+
+impl Default for TraceFilter {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl TraceFilter {
+  pub fn new() -> Self {
+    Self {
+      address: None,
+      frames: None,
+      subsystems: !0,
+    }
+  }
+
+  // "80010000-8001FFFF" (16進数)
+  pub fn set_address(&mut self, spec: &str) -> Result<(), String> {
+    let (start, end) = parse_range(spec, 16)?;
+    self.address = Some((start as u32, end as u32));
+    Ok(())
+  }
+
+  // "100-200" (10進数)
+  pub fn set_frames(&mut self, spec: &str) -> Result<(), String> {
+    self.frames = Some(parse_range(spec, 10)?);
+    Ok(())
+  }
+
+  // カンマ区切りの "cpu,mem,dma,gpu,spu"
+  pub fn set_subsystems(&mut self, spec: &str) -> Result<(), String> {
+    let mut subsystems = 0;
+    for name in spec.split(',') {
+      subsystems |= Subsystem::parse(name).ok_or_else(|| format!("Unknown subsystem: {}", name))?.bit();
+    }
+    self.subsystems = subsystems;
+    Ok(())
+  }
+
+  fn matches(&self, subsystem: Subsystem, pc: u32, frame: u64) -> bool {
+    self.subsystems & subsystem.bit() != 0
+      && self.address.is_none_or(|(start, end)| (start..=end).contains(&pc))
+      && self.frames.is_none_or(|(start, end)| (start..=end).contains(&frame))
+  }
+}
+
+fn parse_range(spec: &str, radix: u32) -> Result<(u64, u64), String> {
+  let parse = |s: &str| u64::from_str_radix(s.trim_start_matches("0x"), radix)
+    .map_err(|_| format!("Invalid range: {}", spec));
+  match spec.split_once('-') {
+    Some((start, end)) => Ok((parse(start)?, parse(end)?)),
+    None => Err(format!("Invalid range: {}", spec)),
+  }
+}
+
+pub struct Tracer {
+  out: BufWriter<Box<dyn Write>>,
+  format: TraceFormat,
+  filter: TraceFilter,
+  disassemble: Disassembler,
+  cycle: u64,
+  pc: u32,
+  frame: u64,
+  // 実行中の命令 (命令の記録をその中で起きたアクセスより前に書くため、命令の終わりまで溜める)
+  instruction: Option<Vec<TraceRecord>>,
+  // 書き込みに失敗したら、それ以降は何も書かない
+  error: Option<io::Error>,
+}
+
+impl Tracer {
+  pub fn new(out: Box<dyn Write>, format: TraceFormat, filter: TraceFilter, disassemble: Disassembler) -> io::Result<Self> {
+    let mut out = BufWriter::new(out);
+    if format == TraceFormat::Binary {
+      out.write_all(MAGIC)?;
+    }
+    Ok(Self {
+      out,
+      format,
+      filter,
+      disassemble,
+      cycle: 0,
+      pc: 0,
+      frame: 0,
+      instruction: None,
+      error: None,
+    })
+  }
+
+  // 書き込みに失敗して止まったか
+  pub fn failed(&self) -> bool {
+    self.error.is_some()
+  }
+
+  // 命令の実行前に呼ぶ
+  pub fn begin_instruction(&mut self, cycle: u64, pc: u32, frame: u64) {
+    self.cycle = cycle;
+    self.pc = pc;
+    self.frame = frame;
+    self.instruction = Some(Vec::new());
+  }
+
+  pub fn end_instruction(&mut self, opcode: u32, changes: Vec<(u8, u32)>) {
+    let records = self.instruction.take().unwrap_or_default();
+    self.record(TraceEvent::Instruction { pc: self.pc, opcode, changes });
+    for record in records {
+      self.write(&record);
+    }
+  }
+
+  pub fn record(&mut self, event: TraceEvent) {
+    if !self.filter.matches(event.subsystem(), self.pc, self.frame) {
+      return;
+    }
+    let record = TraceRecord { cycle: self.cycle, event };
+    match self.instruction.as_mut() {
+      Some(records) if !matches!(record.event, TraceEvent::Instruction { .. }) => records.push(record),
+      _ => self.write(&record),
+    }
+  }
+
+  fn write(&mut self, record: &TraceRecord) {
+    if self.error.is_some() {
+      return;
+    }
+    let result = match self.format {
+      TraceFormat::Text => writeln!(self.out, "{}", record.to_text(Some(self.disassemble))),
+      TraceFormat::Binary => record.write_binary(&mut self.out),
+    };
+    if let Err(e) = result {
+      eprintln!("Failed to write the trace, tracing stopped: {}", e);
+      self.error = Some(e);
+    }
+  }
+}
+
+// テキスト/バイナリ形式のトレースを1行ずつテキストとして読む
+pub struct TraceReader {
+  input: BufReader<Box<dyn Read>>,
+  format: TraceFormat,
+  disassemble: Option<Disassembler>,
+}
+
+impl TraceReader {
+  // 形式は入力の先頭で判定する
+  pub fn new(input: Box<dyn Read>, disassemble: Option<Disassembler>) -> io::Result<Self> {
+    let mut input = BufReader::new(input);
+    let format = match input.fill_buf()?.starts_with(MAGIC) {
+      true => {
+        input.consume(MAGIC.len());
+        TraceFormat::Binary
+      }
+      false => TraceFormat::Text,
+    };
+    Ok(Self { input, format, disassemble })
+  }
+
+  pub fn format(&self) -> TraceFormat {
+    self.format
+  }
+
+  pub fn next_line(&mut self) -> io::Result<Option<String>> {
+    match self.format {
+      TraceFormat::Text => {
+        let mut line = String::new();
+        match self.input.read_line(&mut line)? {
+          0 => Ok(None),
+          _ => Ok(Some(line.trim_end().to_string())),
+        }
+      }
+      TraceFormat::Binary => {
+        let record = TraceRecord::read_binary(&mut self.input)?;
+        Ok(record.map(|record| record.to_text(self.disassemble)))
+      }
+    }
+  }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_u8(input: &mut dyn Read) -> io::Result<u8> {
+  let mut buf = [0; 1];
+  input.read_exact(&mut buf)?;
+  Ok(buf[0])
+}
+
+fn read_u16(input: &mut dyn Read) -> io::Result<u16> {
+  let mut buf = [0; 2];
+  input.read_exact(&mut buf)?;
+  Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32(input: &mut dyn Read) -> io::Result<u32> {
+  let mut buf = [0; 4];
+  input.read_exact(&mut buf)?;
+  Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(input: &mut dyn Read) -> io::Result<u64> {
+  let mut buf = [0; 8];
+  input.read_exact(&mut buf)?;
+  Ok(u64::from_le_bytes(buf))
+}
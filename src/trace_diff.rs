@@ -0,0 +1,69 @@
+use std::{collections::VecDeque, fs::File};
+
+use ps1_boot::{disasm, trace::TraceReader};
+
+// 2つのトレースを先頭から比べ、最初に食い違った記録とその直前を表示する
+// 使い方: trace-diff [--ignore-cycles] [--context N] <trace A> <trace B>
+fn main() {
+  let mut ignore_cycles = false;
+  let mut context = 8;
+  let mut paths = Vec::new();
+  let mut args = std::env::args().skip(1);
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      // バックエンドごとにサイクル数がずれることがあるので、サイクルを比べない
+      "--ignore-cycles" => ignore_cycles = true,
+      "--context" => context = args.next().and_then(|n| n.parse().ok()).expect("--context requires a number"),
+      _ => paths.push(arg),
+    }
+  }
+  if paths.len() != 2 {
+    eprintln!("Usage: trace-diff [--ignore-cycles] [--context N] <trace A> <trace B>");
+    std::process::exit(2);
+  }
+
+  let open = |path: &str| File::open(path).and_then(|file| TraceReader::new(Box::new(file), Some(disasm::disassemble)))
+    .unwrap_or_else(|e| panic!("Failed to open {}: {}", path, e));
+  let mut a = open(&paths[0]);
+  let mut b = open(&paths[1]);
+  if a.format() != b.format() {
+    eprintln!("Warning: comparing a {:?} trace with a {:?} trace", a.format(), b.format());
+  }
+
+  let mut history = VecDeque::new();
+  let mut index: u64 = 0;
+  loop {
+    let line_a = a.next_line().unwrap();
+    let line_b = b.next_line().unwrap();
+    let (line_a, line_b) = match (line_a, line_b) {
+      (None, None) => break,
+      (Some(line_a), Some(line_b)) if key(&line_a, ignore_cycles) == key(&line_b, ignore_cycles) => {
+        history.push_back(line_a);
+        if history.len() > context {
+          history.pop_front();
+        }
+        index += 1;
+        continue;
+      }
+      pair => pair,
+    };
+
+    println!("Traces diverge at record {}", index);
+    for line in &history {
+      println!("    {}", line);
+    }
+    println!("A: {}", line_a.as_deref().unwrap_or("(end of trace)"));
+    println!("B: {}", line_b.as_deref().unwrap_or("(end of trace)"));
+    std::process::exit(1);
+  }
+  println!("Traces are identical ({} records)", index);
+}
+
+// 比べる部分 (逆アセンブル結果のコメントを除き、必要ならサイクルも除く)
+fn key(line: &str, ignore_cycles: bool) -> &str {
+  let line = line.split(" ; ").next().unwrap_or(line).trim();
+  match ignore_cycles {
+    true => line.split_once(' ').map_or(line, |(_, rest)| rest.trim_start()),
+    false => line,
+  }
+}